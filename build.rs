@@ -0,0 +1,16 @@
+//! Generates the static tree mounted by the tests of `staticfs`, the same way
+//! users of the generator do.
+
+#[path = "src/fs/staticfs/generate.rs"]
+mod generate;
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    const TESTDATA: &str = "src/fs/staticfs/testdata";
+    let code = generate::generate(TESTDATA.as_ref()).unwrap();
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("staticfs_testdata.rs"), code).unwrap();
+    println!("cargo:rerun-if-changed={TESTDATA}");
+    println!("cargo:rerun-if-changed=src/fs/staticfs/generate.rs");
+}
@@ -0,0 +1,443 @@
+//! POSIX access control lists.
+//!
+//! ACLs are stored in the `system.posix_acl_access` and
+//! `system.posix_acl_default` extended attributes of nodes, in the format
+//! used by Linux: a version number followed by entries of a tag, a
+//! permission and an ID, all little-endian.
+//!
+//! The entries of the owner, the owning group and others mirror the
+//! permission bits of the node, except that the group bits follow the mask
+//! entry when there is one. The VFS keeps them in sync on `chmod`, applies
+//! the default ACL of a directory to the nodes created in it, and
+//! evaluates the access ACL in access checks.
+
+use alloc::{vec, vec::Vec};
+
+use axerrno::LinuxError;
+
+use crate::{Access, Credentials, NodeOps, NodePermission, VfsError, VfsResult};
+
+/// Name of the extended attribute holding the access ACL of a node.
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+/// Name of the extended attribute holding the default ACL of a directory.
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// Version of the format of ACLs.
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+/// ID of entries without a qualifier.
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Whom an ACL entry applies to.
+///
+/// Tags are ordered as their entries must be in an ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    /// The owner of the node.
+    UserObj,
+    /// The user of the given ID.
+    User(u32),
+    /// The owning group of the node.
+    GroupObj,
+    /// The group of the given ID.
+    Group(u32),
+    /// The upper bound of the permissions granted to named users and
+    /// groups, and to the owning group.
+    Mask,
+    /// Everyone else.
+    Other,
+}
+
+/// An entry of an ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: Access,
+}
+
+/// A valid ACL.
+///
+/// It has exactly one entry for the owner, the owning group and others, a
+/// mask if it has named entries, and its entries are sorted and unique.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Makes an ACL of `entries`, in any order.
+    pub fn new(mut entries: Vec<AclEntry>) -> VfsResult<Self> {
+        entries.sort_by_key(|it| it.tag);
+        if entries.windows(2).any(|it| it[0].tag == it[1].tag) {
+            return Err(VfsError::InvalidInput);
+        }
+        let has = |tag| entries.iter().any(|it| it.tag == tag);
+        let named = entries
+            .iter()
+            .any(|it| matches!(it.tag, AclTag::User(_) | AclTag::Group(_)));
+        if !has(AclTag::UserObj)
+            || !has(AclTag::GroupObj)
+            || !has(AclTag::Other)
+            || (named && !has(AclTag::Mask))
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Makes the minimal ACL equivalent to `mode`.
+    pub fn from_mode(mode: NodePermission) -> Self {
+        let bits = |shift: u16| Access::from_bits_truncate(mode.bits() >> shift);
+        Self {
+            entries: vec![
+                AclEntry {
+                    tag: AclTag::UserObj,
+                    perm: bits(6),
+                },
+                AclEntry {
+                    tag: AclTag::GroupObj,
+                    perm: bits(3),
+                },
+                AclEntry {
+                    tag: AclTag::Other,
+                    perm: bits(0),
+                },
+            ],
+        }
+    }
+
+    /// Parses an ACL in the format of extended attributes.
+    pub fn parse(data: &[u8]) -> VfsResult<Self> {
+        let (header, body) = data
+            .split_at_checked(HEADER_SIZE)
+            .ok_or(VfsError::InvalidInput)?;
+        if u32::from_le_bytes(header.try_into().unwrap()) != VERSION || body.len() % ENTRY_SIZE != 0
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        let entries = body
+            .chunks_exact(ENTRY_SIZE)
+            .map(|raw| {
+                let perm = u16::from_le_bytes([raw[2], raw[3]]);
+                let id = u32::from_le_bytes(raw[4..8].try_into().unwrap());
+                let tag = match u16::from_le_bytes([raw[0], raw[1]]) {
+                    USER_OBJ => AclTag::UserObj,
+                    USER => AclTag::User(id),
+                    GROUP_OBJ => AclTag::GroupObj,
+                    GROUP => AclTag::Group(id),
+                    MASK => AclTag::Mask,
+                    OTHER => AclTag::Other,
+                    _ => return Err(VfsError::InvalidInput),
+                };
+                let perm = Access::from_bits(perm).ok_or(VfsError::InvalidInput)?;
+                Ok(AclEntry { tag, perm })
+            })
+            .collect::<VfsResult<_>>()?;
+        Self::new(entries)
+    }
+
+    /// Serializes the ACL in the format of extended attributes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                AclTag::UserObj => (USER_OBJ, UNDEFINED_ID),
+                AclTag::User(id) => (USER, id),
+                AclTag::GroupObj => (GROUP_OBJ, UNDEFINED_ID),
+                AclTag::Group(id) => (GROUP, id),
+                AclTag::Mask => (MASK, UNDEFINED_ID),
+                AclTag::Other => (OTHER, UNDEFINED_ID),
+            };
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.bits().to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data
+    }
+
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    /// Returns whether the ACL says no more than permission bits.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    fn perm_mut(&mut self, tag: AclTag) -> &mut Access {
+        &mut self
+            .entries
+            .iter_mut()
+            .find(|it| it.tag == tag)
+            .expect("valid ACLs have the entry")
+            .perm
+    }
+
+    fn perm(&self, tag: AclTag) -> Option<Access> {
+        self.entries
+            .iter()
+            .find(|it| it.tag == tag)
+            .map(|it| it.perm)
+    }
+
+    /// Returns the tag of the entry standing for the group bits.
+    fn group_class(&self) -> AclTag {
+        if self.perm(AclTag::Mask).is_some() {
+            AclTag::Mask
+        } else {
+            AclTag::GroupObj
+        }
+    }
+
+    /// Returns the permission bits the ACL corresponds to.
+    pub fn mode(&self) -> NodePermission {
+        let bits = |tag| self.perm(tag).unwrap().bits();
+        NodePermission::from_bits_truncate(
+            bits(AclTag::UserObj) << 6 | bits(self.group_class()) << 3 | bits(AclTag::Other),
+        )
+    }
+
+    /// Updates the ACL for the permission bits of the node changing to
+    /// `mode`.
+    pub fn chmod(&mut self, mode: NodePermission) {
+        let bits = |shift: u16| Access::from_bits_truncate(mode.bits() >> shift);
+        *self.perm_mut(AclTag::UserObj) = bits(6);
+        *self.perm_mut(self.group_class()) = bits(3);
+        *self.perm_mut(AclTag::Other) = bits(0);
+    }
+
+    /// Returns the access ACL of a node created with permission `mode` in a
+    /// directory of default ACL `self`, along with the actual permission
+    /// of the node.
+    pub fn inherit(&self, mode: NodePermission) -> (Self, NodePermission) {
+        let mut acl = self.clone();
+        let bits = |shift: u16| Access::from_bits_truncate(mode.bits() >> shift);
+        *acl.perm_mut(AclTag::UserObj) &= bits(6);
+        *acl.perm_mut(acl.group_class()) &= bits(3);
+        *acl.perm_mut(AclTag::Other) &= bits(0);
+        let special =
+            mode & (NodePermission::SET_UID | NodePermission::SET_GID | NodePermission::STICKY);
+        let mode = acl.mode() | special;
+        (acl, mode)
+    }
+
+    /// Returns whether the ACL grants `access` to the caller of `cred`, on
+    /// a node owned by `owner`.
+    ///
+    /// Capabilities are not taken into account.
+    pub fn permits(&self, owner: (u32, u32), cred: &Credentials, access: Access) -> bool {
+        let mask = self.perm(AclTag::Mask).unwrap_or(Access::all());
+        if cred.fsuid == owner.0 {
+            return self.perm(AclTag::UserObj).unwrap().contains(access);
+        }
+        if let Some(perm) = self.perm(AclTag::User(cred.fsuid)) {
+            return (perm & mask).contains(access);
+        }
+        let mut matched = false;
+        for entry in &self.entries {
+            let member = match entry.tag {
+                AclTag::GroupObj => cred.in_group(owner.1),
+                AclTag::Group(gid) => cred.in_group(gid),
+                _ => false,
+            };
+            if member {
+                if (entry.perm & mask).contains(access) {
+                    return true;
+                }
+                matched = true;
+            }
+        }
+        !matched && self.perm(AclTag::Other).unwrap().contains(access)
+    }
+}
+
+/// Reads the ACL `name` of `node`, if it has one.
+pub(crate) fn read(node: &dyn NodeOps, name: &str) -> VfsResult<Option<Acl>> {
+    match node.get_xattr(name) {
+        Ok(data) => Acl::parse(&data).map(Some),
+        Err(VfsError::Unsupported | VfsError::OperationNotSupported) => Ok(None),
+        Err(err) if LinuxError::from(err) == LinuxError::ENODATA => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MetadataUpdate, NodeType, OpenOptions, XattrFlags, test_util::memory_fs};
+
+    fn user(uid: u32, groups: &[u32]) -> Credentials {
+        Credentials::new(uid, uid, groups.to_vec())
+    }
+
+    #[test]
+    fn test_acl() {
+        let entry = |tag, perm| AclEntry {
+            tag,
+            perm: Access::from_bits_truncate(perm),
+        };
+        let acl = Acl::new(vec![
+            entry(AclTag::Other, 0),
+            entry(AclTag::Group(20), 6),
+            entry(AclTag::User(1000), 7),
+            entry(AclTag::Mask, 5),
+            entry(AclTag::GroupObj, 4),
+            entry(AclTag::UserObj, 6),
+        ])
+        .unwrap();
+        let data = acl.to_bytes();
+        assert_eq!(data.len(), 4 + 6 * 8);
+        assert_eq!(&data[4..12], [1, 0, 6, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Acl::parse(&data).unwrap(), acl);
+        assert_eq!(acl.mode().bits(), 0o650);
+        assert_eq!(Acl::parse(&data[..10]).err(), Some(VfsError::InvalidInput));
+        // A named entry without a mask.
+        assert_eq!(
+            Acl::new(vec![
+                entry(AclTag::UserObj, 6),
+                entry(AclTag::User(1), 6),
+                entry(AclTag::GroupObj, 4),
+                entry(AclTag::Other, 4),
+            ])
+            .err(),
+            Some(VfsError::InvalidInput)
+        );
+
+        let owner = (0, 0);
+        assert!(acl.permits(owner, &user(0, &[]), Access::READ | Access::WRITE));
+        // The mask limits named users.
+        assert!(acl.permits(owner, &user(1000, &[]), Access::READ | Access::EXECUTE));
+        assert!(!acl.permits(owner, &user(1000, &[]), Access::WRITE));
+        assert!(acl.permits(owner, &user(1, &[20]), Access::READ));
+        assert!(!acl.permits(owner, &user(1, &[20]), Access::WRITE));
+        // Matching a group denies what it does not grant, without falling
+        // back to others.
+        assert!(acl.permits(owner, &user(1, &[0, 20]), Access::READ));
+        assert!(!acl.permits(owner, &user(1, &[30]), Access::READ));
+
+        let mut acl = acl;
+        acl.chmod(NodePermission::from_bits_truncate(0o704));
+        assert_eq!(acl.mode().bits(), 0o704);
+        assert!(!acl.permits(owner, &user(1000, &[]), Access::READ));
+        assert_eq!(acl.perm(AclTag::GroupObj), Some(Access::READ));
+
+        let (child, mode) = acl.inherit(NodePermission::from_bits_truncate(0o2600));
+        assert_eq!(mode.bits(), 0o2600);
+        assert_eq!(child.perm(AclTag::User(1000)), Some(Access::all()));
+        let minimal = Acl::from_mode(NodePermission::from_bits_truncate(0o751));
+        assert!(minimal.is_minimal());
+        assert_eq!(minimal.mode().bits(), 0o751);
+    }
+
+    #[test]
+    fn test_acl_nodes() {
+        let root = memory_fs();
+        let mode = |bits| NodePermission::from_bits_truncate(bits);
+        let dir = root
+            .create("dir", NodeType::Directory, mode(0o755))
+            .unwrap();
+        let entry = |tag, perm| AclEntry {
+            tag,
+            perm: Access::from_bits_truncate(perm),
+        };
+        let default = Acl::new(vec![
+            entry(AclTag::UserObj, 7),
+            entry(AclTag::User(1000), 6),
+            entry(AclTag::GroupObj, 5),
+            entry(AclTag::Mask, 7),
+            entry(AclTag::Other, 5),
+        ])
+        .unwrap();
+        dir.set_xattr(ACL_DEFAULT, &default.to_bytes(), XattrFlags::empty())
+            .unwrap();
+        assert_eq!(
+            dir.set_xattr(ACL_DEFAULT, b"bad", XattrFlags::empty())
+                .err(),
+            Some(VfsError::InvalidInput)
+        );
+
+        // Children get the default ACL, limited by the mode they ask for.
+        let file = dir
+            .entry()
+            .as_dir()
+            .unwrap()
+            .open_file(
+                "file",
+                &OpenOptions {
+                    create: true,
+                    permission: mode(0o640),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(file.metadata().unwrap().mode.bits(), 0o640);
+        let access = Acl::parse(&file.get_xattr(ACL_ACCESS).unwrap()).unwrap();
+        assert_eq!(access.perm(AclTag::Mask), Some(Access::READ));
+        assert!(file.check_access(&user(1000, &[]), Access::READ).is_ok());
+        assert!(file.check_access(&user(1000, &[]), Access::WRITE).is_err());
+        assert!(file.check_access(&user(1001, &[]), Access::READ).is_err());
+        assert!(file.get_xattr(ACL_DEFAULT).is_err());
+        let sub = dir.create("sub", NodeType::Directory, mode(0o777)).unwrap();
+        assert_eq!(sub.get_xattr(ACL_DEFAULT).unwrap(), default.to_bytes());
+        let link = dir.create("link", NodeType::Symlink, mode(0o777)).unwrap();
+        assert_eq!(link.metadata().unwrap().mode.bits(), 0o777);
+        assert!(link.get_xattr(ACL_ACCESS).is_err());
+
+        // The mask follows the group bits.
+        file.update_metadata(MetadataUpdate {
+            mode: Some(mode(0o660)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(file.check_access(&user(1000, &[]), Access::WRITE).is_ok());
+
+        // Setting the access ACL sets the mode, and a minimal one goes away.
+        file.set_xattr(
+            ACL_ACCESS,
+            &Acl::from_mode(mode(0o604)).to_bytes(),
+            XattrFlags::empty(),
+        )
+        .unwrap();
+        assert_eq!(file.metadata().unwrap().mode.bits(), 0o604);
+        assert!(file.get_xattr(ACL_ACCESS).is_err());
+        assert!(file.check_access(&user(1000, &[]), Access::READ).is_ok());
+        assert!(file.check_access(&user(1000, &[0]), Access::READ).is_err());
+        // Flags are checked before the mode changes.
+        assert_eq!(
+            file.set_xattr(
+                ACL_ACCESS,
+                &Acl::from_mode(mode(0o600)).to_bytes(),
+                XattrFlags::REPLACE,
+            )
+            .err()
+            .map(LinuxError::from),
+            Some(LinuxError::ENODATA)
+        );
+        file.set_xattr(ACL_ACCESS, &access.to_bytes(), XattrFlags::CREATE)
+            .unwrap();
+        assert_eq!(
+            file.set_xattr(
+                ACL_ACCESS,
+                &Acl::from_mode(mode(0o600)).to_bytes(),
+                XattrFlags::CREATE,
+            )
+            .err(),
+            Some(VfsError::AlreadyExists)
+        );
+        assert_eq!(file.metadata().unwrap().mode.bits(), 0o640);
+        assert_eq!(
+            file.set_xattr(ACL_DEFAULT, &default.to_bytes(), XattrFlags::empty())
+                .err(),
+            Some(VfsError::PermissionDenied)
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! Unpacking of cpio archives in the "newc" and "crc" formats, as used by the
+//! Linux initramfs.
+//!
+//! Like the kernel, concatenated archives (optionally separated by zero
+//! padding) are supported, hard links are recognized by their inode numbers
+//! within each archive, and directory modification times are applied after
+//! all entries have been unpacked.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::time::Duration;
+
+use hashbrown::HashMap;
+
+use super::{ArchiveSource, clean_path, copy_data, resolve_parent, set_metadata};
+use crate::{DeviceId, Location, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult};
+
+const MAGIC_NEWC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MAX_SYMLINK_LEN: u32 = 4096;
+
+const fn align4(value: u64) -> u64 {
+    (value + 3) & !3
+}
+
+#[derive(Debug)]
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    name_size: u32,
+    check: Option<u32>,
+}
+
+impl Header {
+    fn parse(buf: &[u8; HEADER_LEN]) -> VfsResult<Self> {
+        let crc = match &buf[..6] {
+            MAGIC_NEWC => false,
+            MAGIC_CRC => true,
+            _ => return Err(VfsError::InvalidData),
+        };
+        let mut fields = [0u32; 13];
+        for (i, field) in fields.iter_mut().enumerate() {
+            let hex = &buf[6 + i * 8..14 + i * 8];
+            let hex = core::str::from_utf8(hex).map_err(|_| VfsError::InvalidData)?;
+            *field = u32::from_str_radix(hex, 16).map_err(|_| VfsError::InvalidData)?;
+        }
+        let [
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            file_size,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+            name_size,
+            check,
+        ] = fields;
+        Ok(Self {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            file_size,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+            name_size,
+            check: crc.then_some(check),
+        })
+    }
+
+    fn node_type(&self) -> VfsResult<NodeType> {
+        match NodeType::from(((self.mode >> 12) & 0o17) as u8) {
+            NodeType::Unknown => Err(VfsError::InvalidData),
+            node_type => Ok(node_type),
+        }
+    }
+}
+
+struct Unpacker<'a, S: ArchiveSource + ?Sized> {
+    source: &'a S,
+    target: &'a Location,
+    /// First entry of each hard-linked inode in the current archive.
+    links: HashMap<(u32, u32, u32), Location>,
+    /// Directories whose modification time is applied at the end.
+    dir_times: Vec<(Location, Duration)>,
+}
+
+impl<S: ArchiveSource + ?Sized> Unpacker<'_, S> {
+    /// Copies the data of an entry into `dst`, verifying the checksum if
+    /// present.
+    fn copy_data(&self, header: &Header, offset: u64, dst: &Location) -> VfsResult<()> {
+        let mut sum = 0u32;
+        copy_data(self.source, offset, header.file_size as u64, dst, |data| {
+            sum = data
+                .iter()
+                .fold(sum, |sum, &byte| sum.wrapping_add(byte as u32));
+        })?;
+        if header.check.is_some_and(|check| check != sum) {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(())
+    }
+
+    fn unpack_entry(&mut self, header: &Header, path: &str, data_offset: u64) -> VfsResult<()> {
+        let node_type = header.node_type()?;
+        let permission = NodePermission::from_bits_truncate((header.mode & 0o7777) as u16);
+        let mtime = Duration::from_secs(header.mtime as u64);
+        let mut update = MetadataUpdate {
+            mode: Some(permission),
+            owner: Some((header.uid, header.gid)),
+            rdev: matches!(node_type, NodeType::CharacterDevice | NodeType::BlockDevice)
+                .then(|| DeviceId::new(header.rdev_major, header.rdev_minor)),
+            atime: Some(mtime),
+            mtime: Some(mtime),
+        };
+
+        let (parent, name) = resolve_parent(self.target, path)?;
+        let Some(name) = name else {
+            // The archive root (".") refers to the target itself.
+            if node_type == NodeType::Directory {
+                update.mtime = None;
+                set_metadata(self.target, update)?;
+                self.dir_times.push((self.target.clone(), mtime));
+            }
+            return Ok(());
+        };
+        let existing = clean_path(&parent, name, node_type)?;
+
+        let link_key = (node_type != NodeType::Directory && header.nlink >= 2).then_some((
+            header.ino,
+            header.dev_major,
+            header.dev_minor,
+        ));
+        if let Some(first) = link_key.and_then(|key| self.links.get(&key)) {
+            let loc = parent.link(name, first)?;
+            if header.file_size > 0 {
+                self.copy_data(header, data_offset, &loc)?;
+            }
+            return Ok(());
+        }
+
+        let loc = match existing {
+            Some(loc) => loc,
+            None => parent.create(name, node_type, permission)?,
+        };
+        match node_type {
+            NodeType::RegularFile => self.copy_data(header, data_offset, &loc)?,
+            NodeType::Symlink => {
+                if header.file_size > MAX_SYMLINK_LEN {
+                    return Err(VfsError::InvalidData);
+                }
+                let mut target = vec![0; header.file_size as usize];
+                self.source.read_exact_at(&mut target, data_offset)?;
+                let target = String::from_utf8(target).map_err(|_| VfsError::InvalidData)?;
+                loc.entry().as_file()?.set_symlink(&target)?;
+            }
+            NodeType::Directory => {
+                update.mtime = None;
+                self.dir_times.push((loc.clone(), mtime));
+            }
+            _ => {}
+        }
+        set_metadata(&loc, update)?;
+        if let Some(key) = link_key {
+            self.links.insert(key, loc);
+        }
+        Ok(())
+    }
+}
+
+/// Unpacks a cpio archive (or several concatenated ones) from `source` into
+/// the directory `target`.
+///
+/// Existing entries are replaced, except for directories which are kept and
+/// have their metadata updated. Malformed archives are reported as
+/// `InvalidData`.
+pub fn unpack<S: ArchiveSource + ?Sized>(source: &S, target: &Location) -> VfsResult<()> {
+    target.check_is_dir()?;
+    let mut unpacker = Unpacker {
+        source,
+        target,
+        links: HashMap::new(),
+        dir_times: Vec::new(),
+    };
+
+    let len = source.len()?;
+    let mut offset = 0;
+    while offset < len {
+        // Skip zero padding between archives.
+        let mut word = [0; 4];
+        let word = &mut word[..4.min((len - offset) as usize)];
+        source.read_exact_at(word, offset)?;
+        if word.iter().all(|&it| it == 0) {
+            offset += word.len() as u64;
+            continue;
+        }
+
+        if len - offset < HEADER_LEN as u64 {
+            return Err(VfsError::InvalidData);
+        }
+        let mut buf = [0; HEADER_LEN];
+        source.read_exact_at(&mut buf, offset)?;
+        let header = Header::parse(&buf)?;
+
+        let name_offset = offset + HEADER_LEN as u64;
+        let data_offset = align4(name_offset + header.name_size as u64);
+        let next = align4(data_offset + header.file_size as u64);
+        if header.name_size == 0 || data_offset + header.file_size as u64 > len {
+            return Err(VfsError::InvalidData);
+        }
+        let mut name = vec![0; header.name_size as usize];
+        source.read_exact_at(&mut name, name_offset)?;
+        if name.pop() != Some(0) {
+            return Err(VfsError::InvalidData);
+        }
+        let name = String::from_utf8(name).map_err(|_| VfsError::InvalidData)?;
+
+        if name == TRAILER {
+            unpacker.links.clear();
+        } else {
+            unpacker.unpack_entry(&header, &name, data_offset)?;
+        }
+        offset = next;
+    }
+
+    for (dir, mtime) in unpacker.dir_times {
+        dir.update_metadata(MetadataUpdate {
+            mtime: Some(mtime),
+            ..Default::default()
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, sync::Arc};
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    use super::*;
+    use crate::{
+        Filesystem, Mountpoint,
+        block::RamDisk,
+        fs::fat::FatFs,
+        test_util::{TempDir, fat_image, memory_fs, mount},
+    };
+
+    struct Entry<'a> {
+        name: &'a str,
+        mode: u32,
+        ino: u32,
+        owner: (u32, u32),
+        rdev: (u32, u32),
+        nlink: u32,
+        mtime: u32,
+        data: &'a [u8],
+    }
+
+    fn push_entry(out: &mut Vec<u8>, entry: &Entry, crc: bool) {
+        let check = if crc {
+            entry.data.iter().map(|&it| it as u32).sum()
+        } else {
+            0
+        };
+        let fields = [
+            entry.ino,
+            entry.mode,
+            entry.owner.0,
+            entry.owner.1,
+            entry.nlink,
+            entry.mtime,
+            entry.data.len() as u32,
+            0,
+            0,
+            entry.rdev.0,
+            entry.rdev.1,
+            entry.name.len() as u32 + 1,
+            check,
+        ];
+        out.extend_from_slice(if crc { MAGIC_CRC } else { MAGIC_NEWC });
+        for field in fields {
+            out.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        out.extend_from_slice(entry.name.as_bytes());
+        out.push(0);
+        out.resize(align4(out.len() as u64) as usize, 0);
+        out.extend_from_slice(entry.data);
+        out.resize(align4(out.len() as u64) as usize, 0);
+    }
+
+    fn archive(entries: &[Entry], crc: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for entry in entries {
+            push_entry(&mut out, entry, crc);
+        }
+        push_entry(
+            &mut out,
+            &Entry {
+                name: TRAILER,
+                mode: 0,
+                ino: 0,
+                owner: (0, 0),
+                rdev: (0, 0),
+                nlink: 1,
+                mtime: 0,
+                data: &[],
+            },
+            crc,
+        );
+        out
+    }
+
+    fn entry<'a>(name: &'a str, mode: u32, data: &'a [u8]) -> Entry<'a> {
+        Entry {
+            name,
+            mode,
+            ino: 0,
+            owner: (0, 0),
+            rdev: (0, 0),
+            nlink: 1,
+            mtime: 1_000_000,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_unpack() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+        let mut data = archive(
+            &[
+                entry(".", 0o40755, &[]),
+                entry("etc", 0o40700, &[]),
+                entry("etc/hostname", 0o100600, b"starry\n"),
+                entry("bin/sh", 0o100755, b"#!"),
+                entry("sh", 0o120777, b"bin/sh"),
+                Entry {
+                    ino: 42,
+                    nlink: 2,
+                    ..entry("a", 0o100644, &[])
+                },
+                Entry {
+                    ino: 42,
+                    nlink: 2,
+                    ..entry("b", 0o100644, b"linked")
+                },
+            ],
+            true,
+        );
+        // A second archive after some padding.
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&archive(&[entry("etc/hostname", 0o100644, b"x")], false));
+        unpack(data.as_slice(), &root).unwrap();
+
+        let etc = fs::metadata(tmp.0.join("etc")).unwrap();
+        assert_eq!(etc.mode() & 0o7777, 0o700);
+        assert_eq!(etc.mtime(), 1_000_000);
+        assert_eq!(fs::read(tmp.0.join("etc/hostname")).unwrap(), b"x");
+        assert_eq!(fs::read(tmp.0.join("bin/sh")).unwrap(), b"#!");
+        assert_eq!(
+            fs::read_link(tmp.0.join("sh")).unwrap().to_str(),
+            Some("bin/sh")
+        );
+        assert_eq!(fs::read(tmp.0.join("a")).unwrap(), b"linked");
+        assert_eq!(fs::metadata(tmp.0.join("b")).unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn test_unpack_owner() {
+        let root = memory_fs();
+        let data = archive(
+            &[
+                Entry {
+                    owner: (1000, 100),
+                    ..entry("file", 0o104755, b"")
+                },
+                Entry {
+                    owner: (1000, 100),
+                    rdev: (4, 64),
+                    ..entry("tty", 0o20620, &[])
+                },
+            ],
+            false,
+        );
+        unpack(data.as_slice(), &root).unwrap();
+
+        let file = root.lookup_no_follow("file").unwrap().metadata().unwrap();
+        assert_eq!((file.uid, file.gid), (1000, 100));
+        // The set-user-ID bit survives the change of owner.
+        assert_eq!(file.mode.bits(), 0o4755);
+        let tty = root.lookup_no_follow("tty").unwrap().metadata().unwrap();
+        assert_eq!(tty.node_type, NodeType::CharacterDevice);
+        assert_eq!((tty.uid, tty.gid), (1000, 100));
+        assert_eq!(tty.rdev, DeviceId::new(4, 64));
+    }
+
+    #[test]
+    fn test_unpack_fat() {
+        // Archives made by other users unpack, without their owners.
+        let disk = Arc::new(RamDisk::from_vec(fat_image(8192, 4, false)));
+        let fs = FatFs::new(disk).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs)).root_location();
+        let data = archive(
+            &[
+                Entry {
+                    owner: (1000, 1000),
+                    ..entry("dir", 0o40755, &[])
+                },
+                Entry {
+                    owner: (1000, 1000),
+                    ..entry("dir/file", 0o100644, b"data")
+                },
+            ],
+            false,
+        );
+        unpack(data.as_slice(), &root).unwrap();
+
+        let file = root
+            .lookup_no_follow("dir")
+            .and_then(|it| it.lookup_no_follow("file"))
+            .unwrap();
+        let mut buf = [0; 8];
+        let read = file
+            .entry()
+            .as_file()
+            .unwrap()
+            .read_at(&mut buf, 0)
+            .unwrap();
+        assert_eq!(&buf[..read], b"data");
+        assert_eq!(file.metadata().unwrap().uid, 0);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+
+        let mut data = archive(&[entry("f", 0o100644, b"data")], true);
+        data[6] = b'z';
+        assert_eq!(unpack(data.as_slice(), &root), Err(VfsError::InvalidData));
+
+        let mut data = archive(&[entry("f", 0o100644, b"data")], true);
+        let pos = data.windows(4).position(|it| it == b"data").unwrap();
+        data[pos] ^= 1;
+        assert_eq!(unpack(data.as_slice(), &root), Err(VfsError::InvalidData));
+
+        let data = archive(&[entry("../f", 0o100644, b"")], false);
+        assert_eq!(unpack(data.as_slice(), &root), Err(VfsError::InvalidData));
+
+        let data = archive(&[entry("f", 0o100644, b"")], false);
+        assert_eq!(unpack(&data[..50], &root), Err(VfsError::InvalidData));
+    }
+}
@@ -0,0 +1,178 @@
+//! Archive formats that can be unpacked into any mounted directory.
+
+pub mod cpio;
+pub mod tar;
+
+use alloc::vec;
+
+use crate::{
+    FileNode, Location, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult,
+    path::{Component, Path},
+};
+
+/// Random-access source of archive data.
+#[allow(clippy::len_without_is_empty)]
+pub trait ArchiveSource {
+    /// Reads exactly `buf.len()` bytes starting from `offset`.
+    ///
+    /// Returns `UnexpectedEof` if the source is too short.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<()>;
+
+    /// Returns the total length of the source.
+    fn len(&self) -> VfsResult<u64>;
+}
+
+impl ArchiveSource for [u8] {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<()> {
+        let start = usize::try_from(offset).map_err(|_| VfsError::UnexpectedEof)?;
+        let data = start
+            .checked_add(buf.len())
+            .and_then(|end| self.get(start..end))
+            .ok_or(VfsError::UnexpectedEof)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+}
+
+impl ArchiveSource for FileNode {
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> VfsResult<()> {
+        while !buf.is_empty() {
+            let read = self.read_at(buf, offset)?;
+            if read == 0 {
+                return Err(VfsError::UnexpectedEof);
+            }
+            buf = &mut buf[read..];
+            offset += read as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        (**self).len()
+    }
+}
+
+const COPY_CHUNK_SIZE: usize = 16 * 1024;
+
+fn normal_components(path: &str) -> impl Iterator<Item = VfsResult<&str>> {
+    Path::new(path).components().filter_map(|it| match it {
+        Component::Normal(name) => Some(Ok(name)),
+        Component::ParentDir => Some(Err(VfsError::InvalidData)),
+        Component::RootDir | Component::CurDir => None,
+    })
+}
+
+/// Looks up an existing entry by its relative archive path under `target`.
+pub(crate) fn lookup_path(target: &Location, path: &str) -> VfsResult<Location> {
+    let mut loc = target.clone();
+    for name in normal_components(path) {
+        loc = loc.lookup_no_follow(name?)?;
+    }
+    Ok(loc)
+}
+
+/// Copies `len` bytes starting from `offset` of `source` into the file `dst`,
+/// passing each chunk to `inspect` as well.
+pub(crate) fn copy_data<S: ArchiveSource + ?Sized>(
+    source: &S,
+    offset: u64,
+    len: u64,
+    dst: &Location,
+    mut inspect: impl FnMut(&[u8]),
+) -> VfsResult<()> {
+    let file = dst.entry().as_file()?;
+    let mut buf = vec![0; COPY_CHUNK_SIZE.min(len as usize)];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = buf.len().min((len - copied) as usize);
+        let buf = &mut buf[..chunk];
+        source.read_exact_at(buf, offset + copied)?;
+        inspect(buf);
+        let mut written = 0;
+        while written < chunk {
+            written += file.write_at(&buf[written..], copied + written as u64)?;
+        }
+        copied += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Looks up the parent directory of a relative archive path under `target`,
+/// creating missing directories along the way.
+///
+/// Returns the parent and the final component, or `None` as the name if the
+/// path refers to `target` itself.
+pub(crate) fn resolve_parent<'a>(
+    target: &Location,
+    path: &'a str,
+) -> VfsResult<(Location, Option<&'a str>)> {
+    let mut names = normal_components(path);
+    let mut dir = target.clone();
+    let Some(mut name) = names.next().transpose()? else {
+        return Ok((dir, None));
+    };
+    for next in names {
+        dir = match dir.lookup_no_follow(name) {
+            Ok(loc) => loc,
+            Err(err) if err.canonicalize() == VfsError::NotFound => dir.create(
+                name,
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o755),
+            )?,
+            Err(err) => return Err(err),
+        };
+        name = next?;
+    }
+    Ok((dir, Some(name)))
+}
+
+/// Applies the metadata of an archive entry to `loc`.
+///
+/// The owner and device number are only set when they differ, and are left
+/// alone on filesystems without them, such as FAT.
+pub(crate) fn set_metadata(loc: &Location, update: MetadataUpdate) -> VfsResult<()> {
+    let metadata = loc.metadata()?;
+    let special = MetadataUpdate {
+        owner: update
+            .owner
+            .filter(|&it| it != (metadata.uid, metadata.gid)),
+        rdev: update.rdev.filter(|&it| it != metadata.rdev),
+        ..Default::default()
+    };
+    // The owner goes first, as changing it may clear set-ID bits.
+    if special.owner.is_some() || special.rdev.is_some() {
+        match loc.update_metadata(special) {
+            Ok(()) | Err(VfsError::Unsupported) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    loc.update_metadata(MetadataUpdate {
+        owner: None,
+        rdev: None,
+        ..update
+    })
+}
+
+/// Removes the entry `name` in `dir` if it exists, unless both it and the
+/// entry to be created are directories.
+///
+/// Returns the existing directory in the latter case.
+pub(crate) fn clean_path(
+    dir: &Location,
+    name: &str,
+    node_type: NodeType,
+) -> VfsResult<Option<Location>> {
+    match dir.lookup_no_follow(name) {
+        Ok(loc) if loc.is_dir() && node_type == NodeType::Directory => Ok(Some(loc)),
+        Ok(loc) => {
+            dir.unlink(name, loc.is_dir())?;
+            Ok(None)
+        }
+        Err(err) if err.canonicalize() == VfsError::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,734 @@
+//! Packing and unpacking of tar archives in the ustar format, with pax
+//! extended headers.
+//!
+//! Packing only uses the public VFS APIs, so any [`Location`] subtree can be
+//! exported. Long paths and link targets, as well as sub-second modification
+//! times, are stored in pax headers; numbers too large for the octal fields
+//! use the base-256 encoding understood by GNU tar. Hard links are detected by
+//! inode number and sockets, which tar can not represent, are skipped.
+//!
+//! Unpacking additionally understands the GNU long name and long link
+//! entries.
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::time::Duration;
+
+use hashbrown::HashMap;
+
+use super::{
+    ArchiveSource, COPY_CHUNK_SIZE, clean_path, copy_data, lookup_path, resolve_parent,
+    set_metadata,
+};
+use crate::{
+    DeviceId, Location, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult,
+    path::{DOT, DOTDOT},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR: u8 = b'3';
+const TYPE_BLOCK: u8 = b'4';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+
+const fn align_block(value: u64) -> u64 {
+    value.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64
+}
+
+/// A sink that receives the packed archive stream.
+pub trait ArchiveSink {
+    /// Writes all of `data` to the sink.
+    fn write_all(&mut self, data: &[u8]) -> VfsResult<()>;
+}
+
+impl<F: FnMut(&[u8]) -> VfsResult<()>> ArchiveSink for F {
+    fn write_all(&mut self, data: &[u8]) -> VfsResult<()> {
+        self(data)
+    }
+}
+
+impl ArchiveSink for Vec<u8> {
+    fn write_all(&mut self, data: &[u8]) -> VfsResult<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Header block offsets of the ustar format.
+mod field {
+    use core::ops::Range;
+
+    pub const NAME: Range<usize> = 0..100;
+    pub const MODE: Range<usize> = 100..108;
+    pub const UID: Range<usize> = 108..116;
+    pub const GID: Range<usize> = 116..124;
+    pub const SIZE: Range<usize> = 124..136;
+    pub const MTIME: Range<usize> = 136..148;
+    pub const CHECKSUM: Range<usize> = 148..156;
+    pub const TYPE: usize = 156;
+    pub const LINK_NAME: Range<usize> = 157..257;
+    pub const MAGIC: Range<usize> = 257..265;
+    pub const DEV_MAJOR: Range<usize> = 329..337;
+    pub const DEV_MINOR: Range<usize> = 337..345;
+    pub const PREFIX: Range<usize> = 345..500;
+}
+
+const USTAR_MAGIC: &[u8] = b"ustar\x0000";
+
+fn write_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits >= 22 || value < 1 << (3 * digits) {
+        let octal = format!("{value:0digits$o}");
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        // Base-256 encoding, marked by the highest bit of the first byte.
+        field.fill(0);
+        for (i, byte) in field.iter_mut().rev().take(8).enumerate() {
+            *byte = (value >> (i * 8)) as u8;
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn parse_number(field: &[u8]) -> VfsResult<u64> {
+    if field.first().is_some_and(|it| it & 0x80 != 0) {
+        if field[0] & 0x40 != 0 {
+            // Negative numbers are not meaningful for any field we read.
+            return Err(VfsError::InvalidData);
+        }
+        return field[1..]
+            .iter()
+            .try_fold((field[0] & 0x3f) as u64, |acc, &it| {
+                if acc >> 56 != 0 {
+                    return Err(VfsError::InvalidData);
+                }
+                Ok((acc << 8) | it as u64)
+            });
+    }
+    let text = core::str::from_utf8(field).map_err(|_| VfsError::InvalidData)?;
+    let text = text.trim_matches(|c| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| VfsError::InvalidData)
+}
+
+fn parse_str(field: &[u8]) -> VfsResult<&str> {
+    let len = field.iter().position(|&it| it == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| VfsError::InvalidData)
+}
+
+fn checksum(block: &[u8; BLOCK_SIZE]) -> u64 {
+    block
+        .iter()
+        .enumerate()
+        .map(|(i, &it)| {
+            if field::CHECKSUM.contains(&i) {
+                b' ' as u64
+            } else {
+                it as u64
+            }
+        })
+        .sum()
+}
+
+fn parse_time(value: &str) -> VfsResult<Duration> {
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    if secs.starts_with('-') {
+        return Ok(Duration::ZERO);
+    }
+    let secs = secs.parse().map_err(|_| VfsError::InvalidData)?;
+    let mut nanos = 0;
+    for (i, digit) in frac.bytes().take(9).enumerate() {
+        if !digit.is_ascii_digit() {
+            return Err(VfsError::InvalidData);
+        }
+        nanos += (digit - b'0') as u32 * 10u32.pow(8 - i as u32);
+    }
+    Ok(Duration::new(secs, nanos))
+}
+
+/// Appends a pax record, whose length field counts itself.
+fn push_pax_record(out: &mut String, key: &str, value: &str) {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    out.push_str(&format!("{len} {key}={value}\n"));
+}
+
+fn parse_pax_records(data: &[u8], records: &mut HashMap<String, String>) -> VfsResult<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&it| it == b' ')
+            .ok_or(VfsError::InvalidData)?;
+        let len: usize = parse_str(&rest[..space])?
+            .parse()
+            .map_err(|_| VfsError::InvalidData)?;
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            return Err(VfsError::InvalidData);
+        }
+        let record =
+            core::str::from_utf8(&rest[space + 1..len - 1]).map_err(|_| VfsError::InvalidData)?;
+        let (key, value) = record.split_once('=').ok_or(VfsError::InvalidData)?;
+        records.insert(key.to_owned(), value.to_owned());
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+struct Packer<'a> {
+    sink: &'a mut dyn ArchiveSink,
+    /// Archive paths of nodes with multiple links, keyed by (device, inode).
+    links: HashMap<(u64, u64), String>,
+}
+
+impl Packer<'_> {
+    fn write_header(
+        &mut self,
+        path: &str,
+        link_name: &str,
+        type_flag: u8,
+        size: u64,
+        metadata: &crate::Metadata,
+    ) -> VfsResult<()> {
+        let mut pax = String::new();
+        let mut block = [0u8; BLOCK_SIZE];
+
+        match split_path(path) {
+            Some((prefix, name)) => {
+                block[field::PREFIX][..prefix.len()].copy_from_slice(prefix.as_bytes());
+                block[field::NAME][..name.len()].copy_from_slice(name.as_bytes());
+            }
+            None => {
+                push_pax_record(&mut pax, "path", path);
+                let name = truncate(path, NAME_LEN);
+                block[field::NAME][..name.len()].copy_from_slice(name.as_bytes());
+            }
+        }
+        if link_name.len() > NAME_LEN {
+            push_pax_record(&mut pax, "linkpath", link_name);
+        }
+        let link = truncate(link_name, NAME_LEN);
+        block[field::LINK_NAME][..link.len()].copy_from_slice(link.as_bytes());
+        if metadata.mtime.subsec_nanos() != 0 {
+            let mtime = format!(
+                "{}.{:09}",
+                metadata.mtime.as_secs(),
+                metadata.mtime.subsec_nanos()
+            );
+            push_pax_record(&mut pax, "mtime", &mtime);
+        }
+
+        write_number(
+            &mut block[field::MODE],
+            metadata.mode.bits() as u64 & 0o7777,
+        );
+        write_number(&mut block[field::UID], metadata.uid as u64);
+        write_number(&mut block[field::GID], metadata.gid as u64);
+        write_number(&mut block[field::SIZE], size);
+        write_number(&mut block[field::MTIME], metadata.mtime.as_secs());
+        block[field::TYPE] = type_flag;
+        block[field::MAGIC].copy_from_slice(USTAR_MAGIC);
+        if matches!(type_flag, TYPE_CHAR | TYPE_BLOCK) {
+            write_number(&mut block[field::DEV_MAJOR], metadata.rdev.major() as u64);
+            write_number(&mut block[field::DEV_MINOR], metadata.rdev.minor() as u64);
+        }
+
+        if !pax.is_empty() {
+            let mut pax_block = [0u8; BLOCK_SIZE];
+            let name = format!("PaxHeaders/{}", truncate(path, NAME_LEN - 11));
+            pax_block[field::NAME][..name.len()].copy_from_slice(name.as_bytes());
+            write_number(&mut pax_block[field::MODE], 0o644);
+            write_number(&mut pax_block[field::UID], 0);
+            write_number(&mut pax_block[field::GID], 0);
+            write_number(&mut pax_block[field::SIZE], pax.len() as u64);
+            write_number(&mut pax_block[field::MTIME], metadata.mtime.as_secs());
+            pax_block[field::TYPE] = TYPE_PAX;
+            pax_block[field::MAGIC].copy_from_slice(USTAR_MAGIC);
+            self.write_block(&mut pax_block)?;
+            self.write_padded(pax.as_bytes())?;
+        }
+        self.write_block(&mut block)
+    }
+
+    fn write_block(&mut self, block: &mut [u8; BLOCK_SIZE]) -> VfsResult<()> {
+        let sum = checksum(block);
+        let field = &mut block[field::CHECKSUM];
+        field[..6].copy_from_slice(format!("{sum:06o}").as_bytes());
+        field[6] = 0;
+        field[7] = b' ';
+        self.sink.write_all(block)
+    }
+
+    fn write_padded(&mut self, data: &[u8]) -> VfsResult<()> {
+        self.sink.write_all(data)?;
+        self.pad(data.len() as u64)
+    }
+
+    fn pad(&mut self, len: u64) -> VfsResult<()> {
+        let padding = (align_block(len) - len) as usize;
+        self.sink.write_all(&[0; BLOCK_SIZE][..padding])
+    }
+
+    fn pack_entry(&mut self, loc: &Location, path: &str) -> VfsResult<()> {
+        let metadata = loc.metadata()?;
+        if metadata.node_type != NodeType::Directory && metadata.nlink > 1 {
+            let key = (metadata.device, metadata.inode);
+            if let Some(first) = self.links.get(&key) {
+                let first = first.clone();
+                return self.write_header(path, &first, TYPE_HARD_LINK, 0, &metadata);
+            }
+            self.links.insert(key, path.to_owned());
+        }
+
+        match metadata.node_type {
+            NodeType::Directory => {
+                let dir_path = if path.is_empty() {
+                    "./".to_owned()
+                } else {
+                    format!("{path}/")
+                };
+                self.write_header(&dir_path, "", TYPE_DIRECTORY, 0, &metadata)?;
+                for name in list_dir(loc)? {
+                    let child = loc.lookup_no_follow(&name)?;
+                    let child_path = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{path}/{name}")
+                    };
+                    self.pack_entry(&child, &child_path)?;
+                }
+                Ok(())
+            }
+            NodeType::RegularFile => {
+                self.write_header(path, "", TYPE_REGULAR, metadata.size, &metadata)?;
+                let file = loc.entry().as_file()?;
+                let mut buf = vec![0; COPY_CHUNK_SIZE];
+                let mut offset = 0;
+                while offset < metadata.size {
+                    let len = buf.len().min((metadata.size - offset) as usize);
+                    let read = file.read_at(&mut buf[..len], offset)?;
+                    if read == 0 {
+                        // The file shrank while packing, pad with zeros.
+                        buf[..len].fill(0);
+                        self.sink.write_all(&buf[..len])?;
+                        offset += len as u64;
+                        continue;
+                    }
+                    self.sink.write_all(&buf[..read])?;
+                    offset += read as u64;
+                }
+                self.pad(metadata.size)
+            }
+            NodeType::Symlink => {
+                let target = loc.read_link()?;
+                self.write_header(path, &target, TYPE_SYMLINK, 0, &metadata)
+            }
+            NodeType::CharacterDevice => self.write_header(path, "", TYPE_CHAR, 0, &metadata),
+            NodeType::BlockDevice => self.write_header(path, "", TYPE_BLOCK, 0, &metadata),
+            NodeType::Fifo => self.write_header(path, "", TYPE_FIFO, 0, &metadata),
+            NodeType::Socket | NodeType::Unknown => Ok(()),
+        }
+    }
+}
+
+/// Splits a path into the ustar prefix and name fields, if it fits.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME_LEN {
+        return Some(("", path));
+    }
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX_LEN && name.len() <= NAME_LEN)
+}
+
+fn truncate(s: &str, len: usize) -> &str {
+    let mut end = s.len().min(len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Lists the names in a directory, sorted so that the output is stable.
+fn list_dir(dir: &Location) -> VfsResult<Vec<String>> {
+    let mut names = Vec::new();
+    let mut offset = 0;
+    loop {
+        let read = dir.read_dir(offset, &mut |name: &str, _, _, next| {
+            if name != DOT && name != DOTDOT {
+                names.push(name.to_owned());
+            }
+            offset = next;
+            true
+        })?;
+        if read == 0 {
+            break;
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// Packs the subtree rooted at `source` into a tar stream written to `sink`.
+///
+/// Entry paths are relative to `source`, which itself is stored as `./`.
+pub fn pack(source: &Location, sink: &mut dyn ArchiveSink) -> VfsResult<()> {
+    let mut packer = Packer {
+        sink,
+        links: HashMap::new(),
+    };
+    packer.pack_entry(source, "")?;
+    packer.sink.write_all(&[0; BLOCK_SIZE * 2])
+}
+
+/// Unpacks a tar stream from `source` into the directory `target`.
+///
+/// Existing entries are replaced, except for directories which are kept and
+/// have their metadata updated. Malformed archives are reported as
+/// `InvalidData`.
+pub fn unpack<S: ArchiveSource + ?Sized>(source: &S, target: &Location) -> VfsResult<()> {
+    target.check_is_dir()?;
+    let len = source.len()?;
+    let mut offset = 0;
+    let mut global = HashMap::new();
+    let mut local = HashMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut dir_times = Vec::new();
+
+    while offset + BLOCK_SIZE as u64 <= len {
+        let mut block = [0u8; BLOCK_SIZE];
+        source.read_exact_at(&mut block, offset)?;
+        if block.iter().all(|&it| it == 0) {
+            break;
+        }
+        if parse_number(&block[field::CHECKSUM])? != checksum(&block) {
+            return Err(VfsError::InvalidData);
+        }
+        let data_offset = offset + BLOCK_SIZE as u64;
+        let mut size = parse_number(&block[field::SIZE])?;
+        let type_flag = block[field::TYPE];
+
+        if matches!(
+            type_flag,
+            TYPE_PAX | TYPE_PAX_GLOBAL | TYPE_GNU_LONG_NAME | TYPE_GNU_LONG_LINK
+        ) {
+            if size > len - data_offset || size > u32::MAX as u64 {
+                return Err(VfsError::InvalidData);
+            }
+            let mut data = vec![0; size as usize];
+            source.read_exact_at(&mut data, data_offset)?;
+            match type_flag {
+                TYPE_PAX => parse_pax_records(&data, &mut local)?,
+                TYPE_PAX_GLOBAL => parse_pax_records(&data, &mut global)?,
+                TYPE_GNU_LONG_NAME => long_name = Some(parse_str(&data)?.to_owned()),
+                _ => long_link = Some(parse_str(&data)?.to_owned()),
+            }
+            offset = data_offset + align_block(size);
+            continue;
+        }
+
+        let record = |key: &str| local.get(key).or_else(|| global.get(key));
+        let mut path = match (record("path"), long_name.take()) {
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) => path,
+            (None, None) => {
+                let name = parse_str(&block[field::NAME])?;
+                let prefix = if matches!(&block[field::MAGIC], USTAR_MAGIC) {
+                    parse_str(&block[field::PREFIX])?
+                } else {
+                    ""
+                };
+                if prefix.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{prefix}/{name}")
+                }
+            }
+        };
+        let link_name = match (record("linkpath"), long_link.take()) {
+            (Some(link), _) => link.clone(),
+            (None, Some(link)) => link,
+            (None, None) => parse_str(&block[field::LINK_NAME])?.to_owned(),
+        };
+        if let Some(value) = record("size") {
+            size = value.parse().map_err(|_| VfsError::InvalidData)?;
+        }
+        let number = |key: &str, field| -> VfsResult<u64> {
+            match record(key) {
+                Some(value) => value.parse().map_err(|_| VfsError::InvalidData),
+                None => parse_number(field),
+            }
+        };
+        let id = |key: &str, field| -> VfsResult<u32> {
+            u32::try_from(number(key, field)?).map_err(|_| VfsError::InvalidData)
+        };
+        let uid = id("uid", &block[field::UID])?;
+        let gid = id("gid", &block[field::GID])?;
+        let mtime = match record("mtime") {
+            Some(value) => parse_time(value)?,
+            None => Duration::from_secs(parse_number(&block[field::MTIME])?),
+        };
+        let atime = record("atime").map(|it| parse_time(it)).transpose()?;
+        let mode = parse_number(&block[field::MODE])?;
+        let device = |field| -> VfsResult<u32> {
+            u32::try_from(parse_number(field)?).map_err(|_| VfsError::InvalidData)
+        };
+        let rdev = DeviceId::new(
+            device(&block[field::DEV_MAJOR])?,
+            device(&block[field::DEV_MINOR])?,
+        );
+        local.clear();
+
+        let node_type = match type_flag {
+            TYPE_HARD_LINK => None,
+            TYPE_SYMLINK => Some(NodeType::Symlink),
+            TYPE_CHAR => Some(NodeType::CharacterDevice),
+            TYPE_BLOCK => Some(NodeType::BlockDevice),
+            TYPE_DIRECTORY => Some(NodeType::Directory),
+            TYPE_FIFO => Some(NodeType::Fifo),
+            // Regular and contiguous files, as well as unknown types which
+            // POSIX requires to be treated as regular files.
+            _ => {
+                if path.ends_with('/') {
+                    Some(NodeType::Directory)
+                } else {
+                    Some(NodeType::RegularFile)
+                }
+            }
+        };
+        let has_data = matches!(node_type, Some(NodeType::RegularFile));
+        if has_data && size > len - data_offset {
+            return Err(VfsError::InvalidData);
+        }
+        while path.ends_with('/') && path.len() > 1 {
+            path.pop();
+        }
+
+        let (parent, name) = resolve_parent(target, &path)?;
+        let permission = NodePermission::from_bits_truncate((mode & 0o7777) as u16);
+        let mut update = MetadataUpdate {
+            mode: Some(permission),
+            owner: Some((uid, gid)),
+            rdev: matches!(
+                node_type,
+                Some(NodeType::CharacterDevice | NodeType::BlockDevice)
+            )
+            .then_some(rdev),
+            atime: Some(atime.unwrap_or(mtime)),
+            mtime: Some(mtime),
+        };
+        match (node_type, name) {
+            (Some(NodeType::Directory), None) => {
+                update.mtime = None;
+                set_metadata(target, update)?;
+                dir_times.push((target.clone(), mtime));
+            }
+            (_, None) => return Err(VfsError::InvalidData),
+            (None, Some(name)) => {
+                let first = lookup_path(target, &link_name)?;
+                clean_path(&parent, name, first.node_type())?;
+                parent.link(name, &first)?;
+            }
+            (Some(node_type), Some(name)) => {
+                let loc = match clean_path(&parent, name, node_type)? {
+                    Some(loc) => loc,
+                    None => parent.create(name, node_type, permission)?,
+                };
+                match node_type {
+                    NodeType::RegularFile => copy_data(source, data_offset, size, &loc, |_| {})?,
+                    NodeType::Symlink => loc.entry().as_file()?.set_symlink(&link_name)?,
+                    NodeType::Directory => {
+                        update.mtime = None;
+                        dir_times.push((loc.clone(), mtime));
+                    }
+                    _ => {}
+                }
+                set_metadata(&loc, update)?;
+            }
+        }
+
+        offset = data_offset + if has_data { align_block(size) } else { 0 };
+    }
+
+    for (dir, mtime) in dir_times {
+        dir.update_metadata(MetadataUpdate {
+            mtime: Some(mtime),
+            ..Default::default()
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    use super::*;
+    use crate::test_util::{TempDir, memory_fs, mount};
+
+    #[test]
+    fn test_round_trip() {
+        let src = TempDir::new();
+        let long_dir = "d".repeat(120);
+        let long_name = "f".repeat(120);
+        fs::create_dir_all(src.0.join("a/b")).unwrap();
+        fs::create_dir_all(src.0.join(&long_dir)).unwrap();
+        fs::write(src.0.join("a/b/file"), "hello").unwrap();
+        fs::write(src.0.join(&long_dir).join(&long_name), vec![7; 1000]).unwrap();
+        fs::hard_link(src.0.join("a/b/file"), src.0.join("a/link")).unwrap();
+        std::os::unix::fs::symlink("b/file", src.0.join("a/sym")).unwrap();
+        fs::set_permissions(
+            src.0.join("a/b/file"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o640),
+        )
+        .unwrap();
+        fs::File::open(src.0.join("a/b"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::new(1_000_000, 500))
+            .unwrap();
+
+        let mut data = Vec::new();
+        pack(&mount(&src), &mut data).unwrap();
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+
+        let dst = TempDir::new();
+        unpack(data.as_slice(), &mount(&dst)).unwrap();
+
+        assert_eq!(fs::read(dst.0.join("a/b/file")).unwrap(), b"hello");
+        assert_eq!(fs::read(dst.0.join("a/link")).unwrap(), b"hello");
+        let file = fs::metadata(dst.0.join("a/b/file")).unwrap();
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(file.mode() & 0o7777, 0o640);
+        assert_eq!(
+            fs::read_link(dst.0.join("a/sym")).unwrap().to_str(),
+            Some("b/file")
+        );
+        assert_eq!(
+            fs::read(dst.0.join(&long_dir).join(&long_name)).unwrap(),
+            vec![7; 1000]
+        );
+        let dir = fs::metadata(dst.0.join("a/b")).unwrap();
+        assert_eq!((dir.mtime(), dir.mtime_nsec()), (1_000_000, 500));
+    }
+
+    #[test]
+    fn test_devices() {
+        let src = memory_fs();
+        let rdev = DeviceId::new(259, 1 << 12);
+        src.create(
+            "nvme",
+            NodeType::BlockDevice,
+            NodePermission::from_bits_truncate(0o660),
+        )
+        .unwrap()
+        .update_metadata(MetadataUpdate {
+            rdev: Some(rdev),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut data = Vec::new();
+        pack(&src, &mut data).unwrap();
+
+        let dst = memory_fs();
+        unpack(data.as_slice(), &dst).unwrap();
+        let metadata = dst.lookup_no_follow("nvme").unwrap().metadata().unwrap();
+        assert_eq!(metadata.node_type, NodeType::BlockDevice);
+        assert_eq!(metadata.rdev, rdev);
+    }
+
+    #[test]
+    fn test_numbers() {
+        let mut field = [0u8; 12];
+        write_number(&mut field, 0o777);
+        assert_eq!(&field, b"00000000777\0");
+        assert_eq!(parse_number(&field).unwrap(), 0o777);
+        write_number(&mut field, 1 << 40);
+        assert_eq!(field[0], 0x80);
+        assert_eq!(parse_number(&field).unwrap(), 1 << 40);
+        field[0] = 0x81;
+        assert_eq!(parse_number(&field), Err(VfsError::InvalidData));
+
+        let mut pax = String::new();
+        push_pax_record(&mut pax, "path", "abcdefg");
+        assert_eq!(pax, "16 path=abcdefg\n");
+        let mut records = HashMap::new();
+        parse_pax_records(pax.as_bytes(), &mut records).unwrap();
+        assert_eq!(records["path"], "abcdefg");
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let src = TempDir::new();
+        fs::write(src.0.join("f"), "x").unwrap();
+        let mut data = Vec::new();
+        pack(&mount(&src), &mut data).unwrap();
+        data[BLOCK_SIZE] ^= 1;
+        let dst = TempDir::new();
+        assert_eq!(
+            unpack(data.as_slice(), &mount(&dst)),
+            Err(VfsError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_bad_numbers() {
+        let src = TempDir::new();
+        fs::write(src.0.join("f"), "x").unwrap();
+        let mut data = Vec::new();
+        pack(&mount(&src), &mut data).unwrap();
+        // Blocks of the pax header of `.`, and of the header of `f`.
+        let (pax, file) = (0, 5 * BLOCK_SIZE);
+        assert_eq!(parse_str(&data[file..file + 100]), Ok("f"));
+        let with_header = |pos: usize, edit: &dyn Fn(&mut [u8])| {
+            let mut data = data.clone();
+            let block = &mut data[pos..pos + BLOCK_SIZE];
+            edit(block);
+            let sum = checksum((&*block).try_into().unwrap());
+            write_number(&mut block[field::CHECKSUM], sum);
+            data
+        };
+
+        // Sizes too large for the archive, or for 64 bits.
+        let huge_size = |block: &mut [u8]| write_number(&mut block[field::SIZE], u64::MAX);
+        let wide_size = |block: &mut [u8]| {
+            block[field::SIZE].fill(0xff);
+            block[field::SIZE][0] = 0x80;
+        };
+        let wide_uid = |block: &mut [u8]| write_number(&mut block[field::UID], 1 << 32);
+        for data in [
+            with_header(pax, &huge_size),
+            with_header(file, &huge_size),
+            with_header(file, &wide_size),
+            with_header(file, &wide_uid),
+        ] {
+            let dst = TempDir::new();
+            assert_eq!(
+                unpack(data.as_slice(), &mount(&dst)),
+                Err(VfsError::InvalidData)
+            );
+        }
+    }
+}
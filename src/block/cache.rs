@@ -0,0 +1,368 @@
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use hashbrown::HashMap;
+
+use super::BlockDevice;
+use crate::{Mutex, VfsError, VfsResult};
+
+struct BufferData {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Whether the data was loaded, which is only false while the buffer is
+    /// being loaded or after the loading failed.
+    loaded: bool,
+}
+
+struct BufferInner {
+    block: u64,
+    data: Mutex<BufferData>,
+    /// Value of the cache clock when the buffer was last looked up.
+    last_used: AtomicU64,
+}
+
+/// A cached block, shared by all users of the same block.
+///
+/// The buffer stays in the cache at least as long as a handle to it is held.
+#[derive(Clone)]
+pub struct Buffer(Arc<BufferInner>);
+
+impl Buffer {
+    /// Returns the block number of the buffer.
+    pub fn block(&self) -> u64 {
+        self.0.block
+    }
+
+    /// Calls `f` with the contents of the buffer.
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        f(&self.0.data.lock().data)
+    }
+
+    /// Calls `f` with the mutable contents of the buffer, and marks the
+    /// buffer dirty.
+    pub fn write<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut data = self.0.data.lock();
+        data.dirty = true;
+        f(&mut data.data)
+    }
+
+    /// Returns whether the buffer has modifications not written back yet.
+    pub fn is_dirty(&self) -> bool {
+        self.0.data.lock().dirty
+    }
+}
+
+/// Writes the buffer back if it is dirty.
+fn write_back(
+    device: &dyn BlockDevice,
+    sectors_per_block: u64,
+    buffer: &BufferInner,
+) -> VfsResult<()> {
+    let mut data = buffer.data.lock();
+    if data.dirty {
+        device.write_sectors(buffer.block * sectors_per_block, &data.data)?;
+        data.dirty = false;
+    }
+    Ok(())
+}
+
+/// A write-back cache of the blocks of a [`BlockDevice`].
+///
+/// Blocks are looked up through [`BufferCache::get`], which returns a
+/// reference-counted [`Buffer`]. Modified buffers are written back when they
+/// are evicted, or when [`BufferCache::sync`] is called, which filesystems
+/// should do from [`NodeOps::sync`](crate::NodeOps::sync) and
+/// [`FilesystemOps::flush`](crate::FilesystemOps::flush).
+///
+/// Once the cache holds `capacity` buffers, the least recently used ones
+/// that are not referenced outside of the cache are evicted. Referenced
+/// buffers are never evicted, so the cache may temporarily grow larger.
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    capacity: usize,
+    buffers: Mutex<HashMap<u64, Arc<BufferInner>>>,
+    clock: AtomicU64,
+}
+
+impl BufferCache {
+    /// Creates a cache of `device` with blocks of `block_size` bytes, holding
+    /// around `capacity` blocks.
+    ///
+    /// The block size must be a power of two and a multiple of the sector
+    /// size.
+    pub fn new(
+        device: Arc<dyn BlockDevice>,
+        block_size: usize,
+        capacity: usize,
+    ) -> VfsResult<Self> {
+        if !block_size.is_power_of_two() || !block_size.is_multiple_of(device.sector_size()) {
+            return Err(VfsError::InvalidInput);
+        }
+        Ok(Self {
+            device,
+            block_size,
+            capacity: capacity.max(1),
+            buffers: Mutex::default(),
+            clock: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the underlying device.
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the number of whole blocks of the device.
+    pub fn num_blocks(&self) -> u64 {
+        self.device.num_sectors() / self.sectors_per_block()
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size / self.device.sector_size()) as u64
+    }
+
+    fn lookup(
+        &self,
+        block: u64,
+        load: impl FnOnce(&mut [u8]) -> VfsResult<bool>,
+    ) -> VfsResult<Buffer> {
+        if block >= self.num_blocks() {
+            return Err(VfsError::InvalidInput);
+        }
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        loop {
+            let mut buffers = self.buffers.lock();
+            if let Some(buffer) = buffers.get(&block).cloned() {
+                drop(buffers);
+                buffer.last_used.store(now, Ordering::Relaxed);
+                // Wait for the buffer to be loaded, and look it up again if
+                // the loading failed.
+                if buffer.data.lock().loaded {
+                    return Ok(Buffer(buffer));
+                }
+                continue;
+            }
+
+            if buffers.len() >= self.capacity {
+                drop(buffers);
+                self.evict()?;
+                buffers = self.buffers.lock();
+                if buffers.contains_key(&block) {
+                    continue;
+                }
+            }
+            // The buffer is published before it is loaded, with its data
+            // locked, so that the device is read without holding the map.
+            let buffer = Arc::new(BufferInner {
+                block,
+                data: Mutex::new(BufferData {
+                    data: vec![0; self.block_size].into_boxed_slice(),
+                    dirty: false,
+                    loaded: false,
+                }),
+                last_used: AtomicU64::new(now),
+            });
+            let mut data = buffer.data.lock();
+            buffers.insert(block, buffer.clone());
+            drop(buffers);
+            match load(&mut data.data) {
+                Ok(dirty) => {
+                    data.dirty = dirty;
+                    data.loaded = true;
+                }
+                Err(err) => {
+                    drop(data);
+                    self.buffers.lock().remove(&block);
+                    return Err(err);
+                }
+            }
+            drop(data);
+            return Ok(Buffer(buffer));
+        }
+    }
+
+    /// Evicts about an eighth of the cache, starting from the least recently
+    /// used buffers.
+    ///
+    /// Buffers are written back without holding the map, and only dropped if
+    /// they are still clean and unreferenced afterwards.
+    fn evict(&self) -> VfsResult<()> {
+        let mut candidates: Vec<_> = self
+            .buffers
+            .lock()
+            .values()
+            .filter(|it| Arc::strong_count(it) == 1)
+            .cloned()
+            .collect();
+        candidates.sort_unstable_by_key(|it| it.last_used.load(Ordering::Relaxed));
+        candidates.truncate((self.capacity / 8).max(1));
+        for buffer in &candidates {
+            write_back(&*self.device, self.sectors_per_block(), buffer)?;
+        }
+
+        let blocks: Vec<_> = candidates.into_iter().map(|it| it.block).collect();
+        let mut buffers = self.buffers.lock();
+        for block in blocks {
+            if buffers
+                .get(&block)
+                .is_some_and(|it| Arc::strong_count(it) == 1 && !it.data.lock().dirty)
+            {
+                buffers.remove(&block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the buffer of `block`, reading it from the device if it is
+    /// not cached.
+    pub fn get(&self, block: u64) -> VfsResult<Buffer> {
+        self.lookup(block, |data| {
+            self.device
+                .read_sectors(block * self.sectors_per_block(), data)?;
+            Ok(false)
+        })
+    }
+
+    /// Returns the buffer of `block` filled with zeros and marked dirty,
+    /// without reading the device.
+    ///
+    /// This is meant for blocks that are about to be overwritten entirely.
+    pub fn get_zeroed(&self, block: u64) -> VfsResult<Buffer> {
+        if self.device.is_read_only() {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        let buffer = self.lookup(block, |_| Ok(true))?;
+        buffer.write(|data| data.fill(0));
+        Ok(buffer)
+    }
+
+    /// Reads bytes starting from the byte `offset` of the device.
+    pub fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> VfsResult<()> {
+        let block_size = self.block_size as u64;
+        while !buf.is_empty() {
+            let start = (offset % block_size) as usize;
+            let len = buf.len().min(self.block_size - start);
+            self.get(offset / block_size)?
+                .read(|data| buf[..len].copy_from_slice(&data[start..start + len]));
+            buf = &mut buf[len..];
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes bytes starting from the byte `offset` of the device.
+    pub fn write_at(&self, mut offset: u64, mut buf: &[u8]) -> VfsResult<()> {
+        if self.device.is_read_only() {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        let block_size = self.block_size as u64;
+        while !buf.is_empty() {
+            let start = (offset % block_size) as usize;
+            let len = buf.len().min(self.block_size - start);
+            let buffer = if len == self.block_size {
+                self.get_zeroed(offset / block_size)?
+            } else {
+                self.get(offset / block_size)?
+            };
+            buffer.write(|data| data[start..start + len].copy_from_slice(&buf[..len]));
+            buf = &buf[len..];
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty buffers and flushes the device.
+    pub fn sync(&self) -> VfsResult<()> {
+        let buffers: Vec<_> = self.buffers.lock().values().cloned().collect();
+        let mut dirty: Vec<_> = buffers
+            .into_iter()
+            .filter(|it| it.data.lock().dirty)
+            .collect();
+        dirty.sort_unstable_by_key(|it| it.block);
+        for buffer in dirty {
+            write_back(&*self.device, self.sectors_per_block(), &buffer)?;
+        }
+        self.device.flush()
+    }
+
+    /// Discards `count` blocks starting from `block` on the device, and
+    /// drops them from the cache without writing them back.
+    ///
+    /// Buffers still referenced are kept along with their contents, so that
+    /// their users never end up with a second copy of the block.
+    pub fn discard(&self, block: u64, count: u64) -> VfsResult<()> {
+        let sectors_per_block = self.sectors_per_block();
+        self.device
+            .discard(block * sectors_per_block, count * sectors_per_block)?;
+        let end = block.saturating_add(count);
+        self.buffers
+            .lock()
+            .retain(|&it, buffer| !(block..end).contains(&it) || Arc::strong_count(buffer) > 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test]
+    fn test_cache() {
+        let disk = Arc::new(RamDisk::new(64 * 1024));
+        let cache = BufferCache::new(disk.clone(), 1024, 4).unwrap();
+        assert_eq!(cache.num_blocks(), 64);
+
+        cache.write_at(1000, b"hello world").unwrap();
+        let mut buf = [0; 11];
+        cache.read_at(1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+        // Not written back yet.
+        assert_eq!(&disk.to_vec()[1000..1011], &[0; 11]);
+
+        let held = cache.get(0).unwrap();
+        assert!(held.is_dirty());
+        // Held buffers are not evicted.
+        for block in 2..20 {
+            cache.get(block).unwrap();
+        }
+        assert!(held.is_dirty());
+        assert_eq!(&disk.to_vec()[1000..1011], &[0; 11]);
+        cache.sync().unwrap();
+        assert!(!held.is_dirty());
+        assert_eq!(&disk.to_vec()[1000..1011], b"hello world");
+
+        drop(held);
+        cache.get(1).unwrap().write(|data| data[0] = 1);
+        for block in 2..20 {
+            cache.get(block).unwrap();
+        }
+        // Written back on eviction.
+        assert_eq!(disk.to_vec()[1024], 1);
+
+        assert_eq!(cache.get(64).err(), Some(VfsError::InvalidInput));
+    }
+
+    #[test]
+    fn test_discard() {
+        let disk = Arc::new(RamDisk::new(64 * 1024));
+        let cache = BufferCache::new(disk.clone(), 1024, 4).unwrap();
+        cache.write_at(0, &[1; 2048]).unwrap();
+        cache.sync().unwrap();
+
+        // Referenced buffers stay the only copy of their block.
+        let held = cache.get(1).unwrap();
+        cache.discard(0, 2).unwrap();
+        held.write(|data| data[0] = 2);
+        assert_eq!(cache.get(1).unwrap().read(|data| data[0]), 2);
+        cache.sync().unwrap();
+        assert_eq!(disk.to_vec()[1024], 2);
+        assert_eq!(cache.get(0).unwrap().read(|data| data[0]), 0);
+    }
+}
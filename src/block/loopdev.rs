@@ -0,0 +1,196 @@
+use alloc::{format, sync::Arc};
+use core::any::Any;
+
+use super::{BlockDevice, check_request};
+use crate::{DeviceId, FileNode, VfsError, VfsResult, devfs::DevFs};
+
+/// Major device number of loop devices.
+pub const LOOP_MAJOR: u32 = 7;
+
+const SECTOR_SIZE: usize = 512;
+
+/// Options for setting up a [`LoopDevice`].
+#[derive(Debug, Clone, Default)]
+pub struct LoopOptions {
+    /// Offset of the device in the backing file.
+    pub offset: u64,
+    /// Maximum size of the device, by default the rest of the file.
+    pub size_limit: Option<u64>,
+    pub read_only: bool,
+}
+
+/// A block device backed by a regular file.
+///
+/// The size of the device is fixed when it is created, rounded down to
+/// whole sectors. Sectors past the end of a file that shrank later read as
+/// zeros.
+pub struct LoopDevice {
+    file: FileNode,
+    offset: u64,
+    num_sectors: u64,
+    read_only: bool,
+}
+
+impl LoopDevice {
+    /// Creates a loop device over `file`.
+    pub fn new(file: FileNode, options: LoopOptions) -> VfsResult<Self> {
+        let mut size = file
+            .len()?
+            .checked_sub(options.offset)
+            .ok_or(VfsError::InvalidInput)?;
+        if let Some(limit) = options.size_limit {
+            size = size.min(limit);
+        }
+        Ok(Self {
+            file,
+            offset: options.offset,
+            num_sectors: size / SECTOR_SIZE as u64,
+            read_only: options.read_only,
+        })
+    }
+
+    /// Returns the backing file.
+    pub fn file(&self) -> &FileNode {
+        &self.file
+    }
+
+    /// Registers the device in `devfs` as the first free `loopN`, and
+    /// returns `N`.
+    pub fn attach(self, devfs: &DevFs) -> VfsResult<u32> {
+        let device = Arc::new(self);
+        for index in 0.. {
+            match devfs.add_block_device(
+                &format!("loop{index}"),
+                DeviceId::new(LOOP_MAJOR, index),
+                device.clone(),
+            ) {
+                Err(err) if err == VfsError::AlreadyExists => continue,
+                result => return result.map(|_| index),
+            }
+        }
+        Err(VfsError::NoSuchDevice)
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn read_sectors(&self, sector: u64, mut buf: &mut [u8]) -> VfsResult<()> {
+        check_request(self, sector, buf.len())?;
+        let mut offset = self.offset + sector * SECTOR_SIZE as u64;
+        while !buf.is_empty() {
+            let read = self.file.read_at(buf, offset)?;
+            if read == 0 {
+                buf.fill(0);
+                break;
+            }
+            buf = &mut buf[read..];
+            offset += read as u64;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, mut buf: &[u8]) -> VfsResult<()> {
+        if self.read_only {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        check_request(self, sector, buf.len())?;
+        let mut offset = self.offset + sector * SECTOR_SIZE as u64;
+        while !buf.is_empty() {
+            let written = self.file.write_at(buf, offset)?;
+            if written == 0 {
+                return Err(VfsError::WriteZero);
+            }
+            buf = &buf[written..];
+            offset += written as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.file.sync(true)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        Filesystem, Mountpoint, NodeType,
+        test_util::{TempDir, list, mount},
+    };
+
+    #[test]
+    fn test_loop() {
+        let tmp = TempDir::new();
+        let image: alloc::vec::Vec<u8> = (0..4096u32).map(|it| (it / 512) as u8).collect();
+        fs::write(tmp.0.join("disk.img"), &image).unwrap();
+        let file = mount(&tmp).lookup_no_follow("disk.img").unwrap();
+        let node = || FileNode::new(file.entry().as_file().unwrap().inner().clone());
+
+        let options = LoopOptions {
+            offset: 512,
+            size_limit: Some(2048),
+            read_only: false,
+        };
+        let device = LoopDevice::new(node(), options.clone()).unwrap();
+        assert_eq!(device.num_sectors(), 4);
+        let mut buf = [0; 1024];
+        device.read_sectors(1, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1023]), (2, 3));
+        assert_eq!(
+            device.read_sectors(3, &mut buf).err(),
+            Some(VfsError::InvalidInput)
+        );
+        device.write_sectors(0, &[0xaa; 512]).unwrap();
+        assert_eq!(
+            fs::read(tmp.0.join("disk.img")).unwrap()[512..1024],
+            [0xaa; 512]
+        );
+
+        let read_only = LoopDevice::new(
+            node(),
+            LoopOptions {
+                read_only: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_only.write_sectors(0, &[0; 512]).err(),
+            Some(VfsError::ReadOnlyFilesystem)
+        );
+
+        let devfs = DevFs::new();
+        assert_eq!(device.attach(&devfs).unwrap(), 0);
+        assert_eq!(read_only.attach(&devfs).unwrap(), 1);
+        let dev = Mountpoint::new_root(&Filesystem::new(devfs)).root_location();
+        assert_eq!(list(&dev), [".", "..", "loop0", "loop1"]);
+
+        let loop1 = dev.lookup_no_follow("loop1").unwrap();
+        assert_eq!(loop1.node_type(), NodeType::BlockDevice);
+        let rdev = loop1.metadata().unwrap().rdev;
+        assert_eq!((rdev.major(), rdev.minor()), (LOOP_MAJOR, 1));
+        assert!(loop1.block_device().unwrap().is_read_only());
+
+        let mut buf = [0; 4];
+        let loop0 = dev.lookup_no_follow("loop0").unwrap();
+        let file = loop0.entry().as_file().unwrap();
+        assert_eq!(file.read_at(&mut buf, 1022).unwrap(), 4);
+        assert_eq!(buf, [2, 2, 3, 3]);
+        assert_eq!(file.write_at(b"xy", 2047).unwrap(), 1);
+        assert_eq!(fs::read(tmp.0.join("disk.img")).unwrap()[2559], b'x');
+    }
+}
@@ -0,0 +1,86 @@
+//! Block devices and a shared buffer cache for disk-backed filesystems.
+
+mod cache;
+mod loopdev;
+mod partition;
+mod probe;
+mod ram;
+mod recording;
+
+pub use cache::*;
+pub use loopdev::*;
+pub use partition::*;
+pub use probe::*;
+pub use ram::*;
+pub use recording::*;
+
+use alloc::sync::Arc;
+use core::any::Any;
+
+use crate::{VfsError, VfsResult};
+
+/// A random-access device addressed in fixed-size sectors.
+#[allow(clippy::len_without_is_empty)]
+pub trait BlockDevice: Send + Sync + 'static {
+    /// Returns the size of a sector in bytes, which must be a power of two.
+    fn sector_size(&self) -> usize {
+        512
+    }
+
+    /// Returns the number of sectors of the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Reads whole sectors starting from `sector` into `buf`.
+    ///
+    /// The length of `buf` must be a multiple of the sector size.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> VfsResult<()>;
+
+    /// Writes whole sectors starting from `sector` from `buf`.
+    ///
+    /// The length of `buf` must be a multiple of the sector size.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> VfsResult<()>;
+
+    /// Flushes volatile write caches of the device, so that all completed
+    /// writes are persistent.
+    fn flush(&self) -> VfsResult<()>;
+
+    /// Hints that the contents of `count` sectors starting from `sector` are
+    /// no longer needed.
+    fn discard(&self, _sector: u64, _count: u64) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Returns whether the device rejects writes.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the size of the device in bytes.
+    fn len(&self) -> u64 {
+        self.num_sectors() * self.sector_size() as u64
+    }
+
+    /// Casts the device to a `&dyn core::any::Any`.
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+/// Checks that a request of `len` bytes starting from `sector` is sector
+/// aligned and lies within `device`.
+pub fn check_request<D: BlockDevice + ?Sized>(
+    device: &D,
+    sector: u64,
+    len: usize,
+) -> VfsResult<()> {
+    let sector_size = device.sector_size();
+    if !len.is_multiple_of(sector_size) {
+        return Err(VfsError::InvalidInput);
+    }
+    let count = (len / sector_size) as u64;
+    if sector
+        .checked_add(count)
+        .is_none_or(|end| end > device.num_sectors())
+    {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok(())
+}
@@ -0,0 +1,556 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use super::{BlockDevice, check_request};
+use crate::{DeviceId, VfsError, VfsResult, devfs::DevFs};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Upper bound of logical partitions, guarding against cyclic EBR chains.
+const MAX_LOGICAL_PARTITIONS: usize = 256;
+
+/// Minor numbers of each disk, the disk included, as Linux gives SCSI disks.
+const DISK_MINORS: u32 = 16;
+/// Major number of the partitions beyond [`DISK_MINORS`], as Linux
+/// `BLOCK_EXT_MAJOR`.
+const BLOCK_EXT_MAJOR: u32 = 259;
+static NEXT_EXT_MINOR: AtomicU32 = AtomicU32::new(0);
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC-32 (IEEE 802.3) checksum of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &it| {
+        CRC32_TABLE[((crc ^ it as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn le<const N: usize>(buf: &[u8], offset: usize) -> u64 {
+    buf[offset..offset + N]
+        .iter()
+        .rev()
+        .fold(0, |acc, &it| (acc << 8) | it as u64)
+}
+
+/// A GUID in its on-disk, mixed-endian layout.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Self = Self([0; 16]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            le::<4>(b, 0),
+            le::<2>(b, 4),
+            le::<2>(b, 6),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|it| write!(f, "{it:02x}"))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guid({self})")
+    }
+}
+
+/// Type of a partition, as recorded in the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR system ID.
+    Mbr(u8),
+    /// GPT partition type GUID.
+    Gpt(Guid),
+}
+
+/// Description of a partition.
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    /// Partition number, starting from 1. Logical MBR partitions start
+    /// from 5.
+    pub number: u32,
+    /// First sector of the partition.
+    pub start: u64,
+    /// Number of sectors of the partition.
+    pub num_sectors: u64,
+    pub partition_type: PartitionType,
+    /// Partition name, only present in GPT.
+    pub label: Option<String>,
+    /// Unique partition ID, in the format of Linux `PARTUUID`.
+    pub uuid: String,
+}
+
+/// Reads the partition table of `device`.
+///
+/// Returns an empty list if the device has no partition table. GPT headers
+/// and entry arrays are verified by their checksums, and the backup header
+/// at the end of the device is used if the primary one is damaged.
+pub fn read_partitions(device: &dyn BlockDevice) -> VfsResult<Vec<PartitionInfo>> {
+    let sector_size = device.sector_size();
+    if device.num_sectors() == 0 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = vec![0; sector_size];
+    device.read_sectors(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    if (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_TYPE_PROTECTIVE) {
+        return read_gpt(device);
+    }
+    read_mbr(device, &mbr)
+}
+
+fn read_mbr(device: &dyn BlockDevice, mbr: &[u8]) -> VfsResult<Vec<PartitionInfo>> {
+    // Boot indicators are 0x00 or 0x80. Anything else is not a partition
+    // table, but e.g. the boot code of a FAT filesystem without partitions.
+    if (0..4).any(|i| mbr[446 + i * 16] & 0x7f != 0) {
+        return Ok(Vec::new());
+    }
+    let disk_id = le::<4>(mbr, 440);
+    let mut partitions = Vec::new();
+    let mut push = |number: u32, partition_type: u8, start: u64, num_sectors: u64| {
+        if num_sectors == 0
+            || start
+                .checked_add(num_sectors)
+                .is_none_or(|end| end > device.num_sectors())
+        {
+            return;
+        }
+        partitions.push(PartitionInfo {
+            number,
+            start,
+            num_sectors,
+            partition_type: PartitionType::Mbr(partition_type),
+            label: None,
+            uuid: format!("{disk_id:08x}-{number:02x}"),
+        });
+    };
+
+    let mut extended = None;
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..462 + i * 16];
+        let partition_type = entry[4];
+        let start = le::<4>(entry, 8);
+        let num_sectors = le::<4>(entry, 12);
+        if partition_type == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&partition_type) {
+            extended.get_or_insert(start);
+            continue;
+        }
+        push(i as u32 + 1, partition_type, start, num_sectors);
+    }
+
+    if let Some(extended_start) = extended {
+        let mut ebr = vec![0; device.sector_size()];
+        let mut current = extended_start;
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS as u32 {
+            if current >= device.num_sectors() {
+                break;
+            }
+            device.read_sectors(current, &mut ebr)?;
+            if ebr[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            let logical = &ebr[446..462];
+            if logical[4] != 0 {
+                push(
+                    number,
+                    logical[4],
+                    current + le::<4>(logical, 8),
+                    le::<4>(logical, 12),
+                );
+            }
+            let next = &ebr[462..478];
+            if next[4] == 0 || le::<4>(next, 8) == 0 {
+                break;
+            }
+            current = extended_start + le::<4>(next, 8);
+        }
+    }
+    Ok(partitions)
+}
+
+/// Reads and verifies the GPT header at `lba`, returning it along with the
+/// partition entry array.
+fn read_gpt_header(device: &dyn BlockDevice, lba: u64) -> VfsResult<(Vec<u8>, Vec<u8>)> {
+    let sector_size = device.sector_size();
+    let mut header = vec![0; sector_size];
+    device.read_sectors(lba, &mut header)?;
+    let header_size = le::<4>(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE
+        || !(GPT_HEADER_MIN_SIZE..=sector_size).contains(&header_size)
+        || le::<8>(&header, 24) != lba
+    {
+        return Err(VfsError::InvalidData);
+    }
+    let expected = le::<4>(&header, 16) as u32;
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != expected {
+        return Err(VfsError::InvalidData);
+    }
+
+    let entries_lba = le::<8>(&header, 72);
+    let entry_count = le::<4>(&header, 80) as usize;
+    let entry_size = le::<4>(&header, 84) as usize;
+    let entries_size = entry_count.saturating_mul(entry_size);
+    if entry_size < GPT_ENTRY_MIN_SIZE || entries_size > GPT_MAX_ENTRIES_SIZE {
+        return Err(VfsError::InvalidData);
+    }
+    let sectors = entries_size.div_ceil(sector_size) as u64;
+    if entries_lba
+        .checked_add(sectors)
+        .is_none_or(|end| end > device.num_sectors())
+    {
+        return Err(VfsError::InvalidData);
+    }
+    let mut entries = vec![0; sectors as usize * sector_size];
+    device.read_sectors(entries_lba, &mut entries)?;
+    entries.truncate(entries_size);
+    if crc32(&entries) != le::<4>(&header, 88) as u32 {
+        return Err(VfsError::InvalidData);
+    }
+    Ok((header, entries))
+}
+
+fn read_gpt(device: &dyn BlockDevice) -> VfsResult<Vec<PartitionInfo>> {
+    let (header, entries) = read_gpt_header(device, 1)
+        .or_else(|_| read_gpt_header(device, device.num_sectors() - 1))?;
+    let entry_size = le::<4>(&header, 84) as usize;
+    let first_usable = le::<8>(&header, 40);
+    let last_usable = le::<8>(&header, 48);
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Guid(entry[..16].try_into().unwrap());
+        if type_guid == Guid::ZERO {
+            continue;
+        }
+        let start = le::<8>(entry, 32);
+        let end = le::<8>(entry, 40);
+        if start > end || start < first_usable || end > last_usable {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|it| u16::from_le_bytes([it[0], it[1]]))
+            .take_while(|&it| it != 0)
+            .collect();
+        partitions.push(PartitionInfo {
+            number: i as u32 + 1,
+            start,
+            num_sectors: end - start + 1,
+            partition_type: PartitionType::Gpt(type_guid),
+            label: Some(String::from_utf16_lossy(&name)),
+            uuid: Guid(entry[16..32].try_into().unwrap()).to_string(),
+        });
+    }
+    Ok(partitions)
+}
+
+/// A partition of a block device, exposed as a block device of its own.
+pub struct Partition {
+    parent: Arc<dyn BlockDevice>,
+    info: PartitionInfo,
+}
+
+impl Partition {
+    /// Creates a device covering the partition `info` of `parent`.
+    pub fn new(parent: Arc<dyn BlockDevice>, info: PartitionInfo) -> VfsResult<Self> {
+        if info
+            .start
+            .checked_add(info.num_sectors)
+            .is_none_or(|end| end > parent.num_sectors())
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        Ok(Self { parent, info })
+    }
+
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    pub fn parent(&self) -> &Arc<dyn BlockDevice> {
+        &self.parent
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.parent.sector_size()
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.info.num_sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> VfsResult<()> {
+        check_request(self, sector, buf.len())?;
+        self.parent.read_sectors(self.info.start + sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> VfsResult<()> {
+        check_request(self, sector, buf.len())?;
+        self.parent.write_sectors(self.info.start + sector, buf)
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.parent.flush()
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> VfsResult<()> {
+        let len = count
+            .checked_mul(self.sector_size() as u64)
+            .and_then(|it| usize::try_from(it).ok())
+            .ok_or(VfsError::InvalidInput)?;
+        check_request(self, sector, len)?;
+        self.parent.discard(self.info.start + sector, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.parent.is_read_only()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Reads the partition table of the disk `device` and registers each
+/// partition in `devfs`.
+///
+/// Partitions are named after `disk_name` like Linux does, e.g. `sda1`, or
+/// `loop0p1` if the disk name ends with a digit. Partitions 1 to 15 take the
+/// minor numbers following the one of `disk_rdev`, so disks are expected to
+/// be 16 minor numbers apart as in Linux. Further partitions get numbers of
+/// the extended major 259, which never collide with other disks.
+pub fn add_partitions(
+    devfs: &DevFs,
+    disk_name: &str,
+    disk_rdev: DeviceId,
+    device: Arc<dyn BlockDevice>,
+) -> VfsResult<Vec<Arc<Partition>>> {
+    let separator = if disk_name.ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+    let mut partitions = Vec::new();
+    for info in read_partitions(&*device)? {
+        let name = format!("{disk_name}{separator}{}", info.number);
+        let rdev = if info.number < DISK_MINORS {
+            DeviceId::new(disk_rdev.major(), disk_rdev.minor() + info.number)
+        } else {
+            DeviceId::new(
+                BLOCK_EXT_MAJOR,
+                NEXT_EXT_MINOR.fetch_add(1, Ordering::Relaxed),
+            )
+        };
+        let partition = Arc::new(Partition::new(device.clone(), info)?);
+        devfs.add_block_device(&name, rdev, partition.clone())?;
+        partitions.push(partition);
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Filesystem, Mountpoint, block::RamDisk, test_util::list};
+
+    fn put(buf: &mut [u8], offset: usize, value: u64, len: usize) {
+        buf[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+
+    fn mbr_entry(sector: &mut [u8], slot: usize, partition_type: u8, start: u64, len: u64) {
+        let entry = 446 + slot * 16;
+        sector[entry + 4] = partition_type;
+        put(sector, entry + 8, start, 4);
+        put(sector, entry + 12, len, 4);
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_mbr() {
+        let disk = RamDisk::new(8192 * 512);
+        let mut sector = [0; 512];
+        put(&mut sector, 440, 0x1234_abcd, 4);
+        mbr_entry(&mut sector, 0, 0x83, 2048, 100);
+        mbr_entry(&mut sector, 1, 0x05, 4096, 1000);
+        disk.write_sectors(0, &sector).unwrap();
+
+        let mut ebr = [0; 512];
+        mbr_entry(&mut ebr, 0, 0x83, 10, 50);
+        mbr_entry(&mut ebr, 1, 0x05, 200, 100);
+        disk.write_sectors(4096, &ebr).unwrap();
+        let mut ebr = [0; 512];
+        mbr_entry(&mut ebr, 0, 0x07, 10, 20);
+        disk.write_sectors(4296, &ebr).unwrap();
+
+        let partitions = read_partitions(&disk).unwrap();
+        let summary: Vec<_> = partitions
+            .iter()
+            .map(|it| (it.number, it.start, it.num_sectors, it.partition_type))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, 2048, 100, PartitionType::Mbr(0x83)),
+                (5, 4106, 50, PartitionType::Mbr(0x83)),
+                (6, 4306, 20, PartitionType::Mbr(0x07)),
+            ]
+        );
+        assert_eq!(partitions[2].uuid, "1234abcd-06");
+
+        // Boot code, not a partition table.
+        sector[446] = 0xeb;
+        disk.write_sectors(0, &sector).unwrap();
+        assert!(read_partitions(&disk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_minors() {
+        let disk = Arc::new(RamDisk::new(8192 * 512));
+        let mut sector = [0; 512];
+        mbr_entry(&mut sector, 0, 0x05, 1000, 1000);
+        disk.write_sectors(0, &sector).unwrap();
+        // Logical partitions 5 to 16.
+        for i in 0..12 {
+            let mut ebr = [0; 512];
+            mbr_entry(&mut ebr, 0, 0x83, 1, 5);
+            if i < 11 {
+                mbr_entry(&mut ebr, 1, 0x05, (i + 1) * 10, 10);
+            }
+            disk.write_sectors(1000 + i * 10, &ebr).unwrap();
+        }
+
+        let devfs = DevFs::new();
+        let partitions = add_partitions(&devfs, "sdb", DeviceId::new(8, 16), disk).unwrap();
+        assert_eq!(partitions.len(), 12);
+        let dev = Mountpoint::new_root(&Filesystem::new(devfs)).root_location();
+        let rdev = |name| dev.lookup_no_follow(name).unwrap().metadata().unwrap().rdev;
+        assert_eq!((rdev("sdb15").major(), rdev("sdb15").minor()), (8, 31));
+        assert_eq!(rdev("sdb16").major(), BLOCK_EXT_MAJOR);
+    }
+
+    #[test]
+    fn test_gpt() {
+        const SECTORS: u64 = 256;
+        let disk = Arc::new(RamDisk::new(SECTORS as usize * 512));
+        let mut mbr = [0; 512];
+        mbr_entry(&mut mbr, 0, MBR_TYPE_PROTECTIVE, 1, SECTORS - 1);
+        disk.write_sectors(0, &mbr).unwrap();
+
+        let type_guid = Guid(*b"\xaf\x3d\xc6\x0f\x83\x84\x72\x47\x8e\x79\x3d\x69\xd8\x47\x7d\xe4");
+        let mut entries = [0; 512];
+        for (i, (start, end, name)) in [(34, 99, "root"), (100, 199, "data")].iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[..16].copy_from_slice(&type_guid.0);
+            entry[16] = i as u8 + 1;
+            put(entry, 32, *start, 8);
+            put(entry, 40, *end, 8);
+            for (j, c) in name.encode_utf16().enumerate() {
+                put(entry, 56 + j * 2, c as u64, 2);
+            }
+        }
+        let header = |lba: u64, backup: u64, entries_lba: u64| {
+            let mut header = [0; 512];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            put(&mut header, 12, 92, 4);
+            put(&mut header, 24, lba, 8);
+            put(&mut header, 32, backup, 8);
+            put(&mut header, 40, 34, 8);
+            put(&mut header, 48, SECTORS - 34, 8);
+            put(&mut header, 72, entries_lba, 8);
+            put(&mut header, 80, 4, 4);
+            put(&mut header, 84, 128, 4);
+            put(&mut header, 88, crc32(&entries) as u64, 4);
+            let crc = crc32(&header[..92]);
+            put(&mut header, 16, crc as u64, 4);
+            header
+        };
+        disk.write_sectors(1, &header(1, SECTORS - 1, 2)).unwrap();
+        disk.write_sectors(2, &entries).unwrap();
+        disk.write_sectors(SECTORS - 1, &header(SECTORS - 1, 1, SECTORS - 2))
+            .unwrap();
+        disk.write_sectors(SECTORS - 2, &entries).unwrap();
+
+        let partitions = read_partitions(&*disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[1].label.as_deref(), Some("data"));
+        assert_eq!(partitions[1].num_sectors, 100);
+        assert_eq!(partitions[0].partition_type, PartitionType::Gpt(type_guid));
+        assert_eq!(
+            type_guid.to_string(),
+            "0fc63daf-8483-4772-8e79-3d69d8477de4"
+        );
+        assert_eq!(partitions[0].uuid, "00000001-0000-0000-0000-000000000000");
+
+        // Damage the primary entry array, falling back to the backup.
+        disk.write_sectors(2, &[0xff; 512]).unwrap();
+        let devfs = DevFs::new();
+        let partitions = add_partitions(&devfs, "sda", DeviceId::new(8, 0), disk.clone()).unwrap();
+        assert_eq!(partitions.len(), 2);
+        partitions[1].write_sectors(0, &[0x42; 512]).unwrap();
+        assert_eq!(disk.to_vec()[100 * 512], 0x42);
+
+        let dev = Mountpoint::new_root(&Filesystem::new(devfs)).root_location();
+        assert_eq!(list(&dev), [".", "..", "sda1", "sda2"]);
+        let sda2 = dev.lookup_no_follow("sda2").unwrap();
+        let rdev = sda2.metadata().unwrap().rdev;
+        assert_eq!((rdev.major(), rdev.minor()), (8, 2));
+        let device = sda2.block_device().unwrap().into_any();
+        let partition = device.downcast::<Partition>().unwrap();
+        assert_eq!(partition.info().label.as_deref(), Some("data"));
+    }
+}
@@ -0,0 +1,350 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use super::BlockDevice;
+use crate::{
+    VfsResult,
+    fs::{le16, le32},
+};
+
+/// What a prober learned about the filesystem on a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeInfo {
+    /// Name of the filesystem type, as used by the mount command.
+    pub fs_type: &'static str,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+/// A function recognizing a filesystem by its superblock.
+///
+/// Returns `Ok(None)` if the device does not hold the filesystem.
+pub type Prober = fn(&dyn BlockDevice) -> VfsResult<Option<ProbeInfo>>;
+
+/// Probers for the filesystems recognized out of the box, keyed by the name
+/// of the filesystem type.
+pub const BUILTIN_PROBERS: &[(&str, Prober)] = &[
+    ("squashfs", probe_squashfs),
+    ("axfs", probe_axfs),
+    ("xfs", probe_xfs),
+    ("ext4", probe_ext),
+    ("iso9660", probe_iso9660),
+    ("vfat", probe_fat),
+    ("btrfs", probe_btrfs),
+];
+
+/// Recognizes the filesystem on `device` with the built-in probers.
+pub fn probe(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    for (_, prober) in BUILTIN_PROBERS {
+        if let Some(info) = prober(device)? {
+            return Ok(Some(info));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads `len` bytes starting from the byte `offset` of `device`.
+///
+/// Returns `None` if the range extends past the end of the device.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, len: usize) -> VfsResult<Option<Vec<u8>>> {
+    if offset.saturating_add(len as u64) > device.len() {
+        return Ok(None);
+    }
+    let sector_size = device.sector_size() as u64;
+    let start = offset / sector_size;
+    let end = (offset + len as u64).div_ceil(sector_size);
+    let mut buf = vec![0; ((end - start) * sector_size) as usize];
+    device.read_sectors(start, &mut buf)?;
+    let skip = (offset - start * sector_size) as usize;
+    buf.drain(..skip);
+    buf.truncate(len);
+    Ok(Some(buf))
+}
+
+/// Formats a big-endian UUID as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+fn format_uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|&it| it == 0) {
+        return None;
+    }
+    let mut uuid = String::with_capacity(36);
+    for (i, byte) in bytes[..16].iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            uuid.push('-');
+        }
+        uuid.push_str(&format!("{byte:02x}"));
+    }
+    Some(uuid)
+}
+
+/// Decodes a label padded with NULs or spaces.
+fn format_label(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&it| it == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[..end]);
+    let label = label.trim_end_matches(' ');
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+const EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Incompatible features understood by ext3: filetype, recover and meta_bg.
+const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+const EXT_INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+/// Read-only compatible features understood by ext3: sparse_super,
+/// large_file and btree_dir.
+const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+
+/// Recognizes ext2, ext3 and ext4, telling them apart by their features.
+pub fn probe_ext(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(sb) = read_bytes(device, EXT_SUPERBLOCK_OFFSET, 1024)? else {
+        return Ok(None);
+    };
+    if le16(&sb, 56) != EXT_MAGIC {
+        return Ok(None);
+    }
+    let (compat, incompat, ro_compat) = (le32(&sb, 92), le32(&sb, 96), le32(&sb, 100));
+    if incompat & EXT_INCOMPAT_JOURNAL_DEV != 0 {
+        // An external journal, not a filesystem.
+        return Ok(None);
+    }
+    let fs_type = if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        "ext4"
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+    Ok(Some(ProbeInfo {
+        fs_type,
+        uuid: format_uuid(&sb[104..120]),
+        label: format_label(&sb[120..136]),
+    }))
+}
+
+/// Recognizes FAT12, FAT16 and FAT32 by their BIOS parameter block.
+pub fn probe_fat(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(bs) = read_bytes(device, 0, 512)? else {
+        return Ok(None);
+    };
+    let bytes_per_sector = le16(&bs, 11);
+    let sectors_per_cluster = bs[13];
+    if bs[510..512] != [0x55, 0xaa]
+        || !matches!(bs[0], 0xeb | 0xe9)
+        || !bytes_per_sector.is_power_of_two()
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !sectors_per_cluster.is_power_of_two()
+        || le16(&bs, 14) == 0
+        || bs[16] == 0
+    {
+        return Ok(None);
+    }
+    // FAT32 has no 16-bit FAT size, and its extended BPB comes later.
+    let ext = if le16(&bs, 22) == 0 { 64 } else { 36 };
+    let (uuid, label) = match bs[ext + 2] {
+        0x29 => {
+            let serial = le32(&bs, ext + 3);
+            let label = format_label(&bs[ext + 7..ext + 18]).filter(|it| it != "NO NAME");
+            (Some(serial), label)
+        }
+        0x28 => (Some(le32(&bs, ext + 3)), None),
+        _ => (None, None),
+    };
+    Ok(Some(ProbeInfo {
+        fs_type: "vfat",
+        uuid: uuid.map(|it| format!("{:04X}-{:04X}", it >> 16, it & 0xffff)),
+        label,
+    }))
+}
+
+const ISO_SECTOR_SIZE: u64 = 2048;
+const ISO_FIRST_DESCRIPTOR: u64 = 16;
+/// Upper bound of volume descriptors to look through.
+const ISO_MAX_DESCRIPTORS: u64 = 32;
+const ISO_TYPE_PRIMARY: u8 = 1;
+const ISO_TYPE_TERMINATOR: u8 = 255;
+
+/// Recognizes ISO9660 by its primary volume descriptor.
+///
+/// As ISO9660 has no UUID, the volume creation time is used as one, in the
+/// form `YYYY-MM-DD-hh-mm-ss-cc`.
+pub fn probe_iso9660(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    for index in ISO_FIRST_DESCRIPTOR..ISO_FIRST_DESCRIPTOR + ISO_MAX_DESCRIPTORS {
+        let Some(vd) = read_bytes(device, index * ISO_SECTOR_SIZE, 2048)? else {
+            return Ok(None);
+        };
+        if &vd[1..6] != b"CD001" || vd[0] == ISO_TYPE_TERMINATOR {
+            return Ok(None);
+        }
+        if vd[0] != ISO_TYPE_PRIMARY {
+            continue;
+        }
+        let date = &vd[813..829];
+        let uuid =
+            (date.iter().all(u8::is_ascii_digit) && date.iter().any(|&it| it != b'0')).then(|| {
+                let date = core::str::from_utf8(date).unwrap();
+                format!(
+                    "{}-{}-{}-{}-{}-{}-{}",
+                    &date[..4],
+                    &date[4..6],
+                    &date[6..8],
+                    &date[8..10],
+                    &date[10..12],
+                    &date[12..14],
+                    &date[14..16]
+                )
+            });
+        return Ok(Some(ProbeInfo {
+            fs_type: "iso9660",
+            uuid,
+            label: format_label(&vd[40..72]),
+        }));
+    }
+    Ok(None)
+}
+
+/// Recognizes squashfs 4.0, which has neither UUID nor label.
+pub fn probe_squashfs(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(sb) = read_bytes(device, 0, 96)? else {
+        return Ok(None);
+    };
+    if &sb[..4] != b"hsqs" || le16(&sb, 28) != 4 {
+        return Ok(None);
+    }
+    Ok(Some(ProbeInfo {
+        fs_type: "squashfs",
+        uuid: None,
+        label: None,
+    }))
+}
+
+/// Recognizes the filesystem of [`axfs`](crate::axfs).
+pub fn probe_axfs(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(sb) = read_bytes(device, 0, 128)? else {
+        return Ok(None);
+    };
+    if &sb[..4] != b"AXFS" {
+        return Ok(None);
+    }
+    Ok(Some(ProbeInfo {
+        fs_type: "axfs",
+        uuid: format_uuid(&sb[72..88]),
+        label: format_label(&sb[88..120]),
+    }))
+}
+
+/// Recognizes XFS.
+pub fn probe_xfs(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(sb) = read_bytes(device, 0, 512)? else {
+        return Ok(None);
+    };
+    if &sb[..4] != b"XFSB" {
+        return Ok(None);
+    }
+    Ok(Some(ProbeInfo {
+        fs_type: "xfs",
+        uuid: format_uuid(&sb[32..48]),
+        label: format_label(&sb[108..120]),
+    }))
+}
+
+const BTRFS_SUPERBLOCK_OFFSET: u64 = 64 * 1024;
+
+/// Recognizes btrfs.
+pub fn probe_btrfs(device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+    let Some(sb) = read_bytes(device, BTRFS_SUPERBLOCK_OFFSET, 4096)? else {
+        return Ok(None);
+    };
+    if &sb[64..72] != b"_BHRfS_M" {
+        return Ok(None);
+    }
+    Ok(Some(ProbeInfo {
+        fs_type: "btrfs",
+        uuid: format_uuid(&sb[32..48]),
+        label: format_label(&sb[299..555]),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test]
+    fn test_probe() {
+        let mut image = vec![0; 1 << 20];
+        image[1024 + 56..1024 + 58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        image[1024 + 92] = EXT_COMPAT_HAS_JOURNAL as u8;
+        image[1024 + 104..1024 + 120].copy_from_slice(&[0x12; 16]);
+        image[1024 + 120..1024 + 124].copy_from_slice(b"root");
+        let info = probe(&RamDisk::from_vec(image.clone())).unwrap().unwrap();
+        assert_eq!(info.fs_type, "ext3");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("12121212-1212-1212-1212-121212121212")
+        );
+        assert_eq!(info.label.as_deref(), Some("root"));
+        // Extents make it ext4.
+        image[1024 + 96] = 0x40;
+        let info = probe(&RamDisk::from_vec(image)).unwrap().unwrap();
+        assert_eq!(info.fs_type, "ext4");
+
+        let mut image = vec![0; 1 << 20];
+        image[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        image[11..14].copy_from_slice(&[0, 2, 8]);
+        image[14] = 32;
+        image[16] = 2;
+        image[66] = 0x29;
+        image[67..71].copy_from_slice(&0x1234_abcd_u32.to_le_bytes());
+        image[71..82].copy_from_slice(b"BOOT       ");
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let info = probe(&RamDisk::from_vec(image)).unwrap().unwrap();
+        assert_eq!(info.fs_type, "vfat");
+        assert_eq!(info.uuid.as_deref(), Some("1234-ABCD"));
+        assert_eq!(info.label.as_deref(), Some("BOOT"));
+
+        let mut image = vec![0; 1 << 20];
+        let pvd = &mut image[16 * 2048..17 * 2048];
+        pvd[0] = ISO_TYPE_PRIMARY;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[40..72].copy_from_slice(&[b' '; 32]);
+        pvd[40..45].copy_from_slice(b"DISC1");
+        pvd[813..829].copy_from_slice(b"2024010203040500");
+        let info = probe(&RamDisk::from_vec(image)).unwrap().unwrap();
+        assert_eq!(info.fs_type, "iso9660");
+        assert_eq!(info.uuid.as_deref(), Some("2024-01-02-03-04-05-00"));
+        assert_eq!(info.label.as_deref(), Some("DISC1"));
+
+        let mut image = vec![0; 1 << 20];
+        image[..4].copy_from_slice(b"XFSB");
+        image[32..48].copy_from_slice(&[0xab; 16]);
+        image[108..112].copy_from_slice(b"data");
+        let info = probe(&RamDisk::from_vec(image)).unwrap().unwrap();
+        assert_eq!(info.fs_type, "xfs");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("abababab-abab-abab-abab-abababababab")
+        );
+        assert_eq!(info.label.as_deref(), Some("data"));
+
+        let mut image = vec![0; 1 << 20];
+        let sb = &mut image[BTRFS_SUPERBLOCK_OFFSET as usize..][..4096];
+        sb[32..48].copy_from_slice(&[0x34; 16]);
+        sb[64..72].copy_from_slice(b"_BHRfS_M");
+        sb[299..303].copy_from_slice(b"pool");
+        let info = probe(&RamDisk::from_vec(image)).unwrap().unwrap();
+        assert_eq!(info.fs_type, "btrfs");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("34343434-3434-3434-3434-343434343434")
+        );
+        assert_eq!(info.label.as_deref(), Some("pool"));
+
+        assert_eq!(probe(&RamDisk::new(1 << 20)).unwrap(), None);
+        // Too small for most superblocks.
+        assert_eq!(probe(&RamDisk::new(512)).unwrap(), None);
+    }
+}
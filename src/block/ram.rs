@@ -0,0 +1,94 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use super::{BlockDevice, check_request};
+use crate::{Mutex, VfsError, VfsResult};
+
+/// A block device backed by memory.
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+    sector_size: usize,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Creates a zero-filled device of `size` bytes, rounded up to whole
+    /// 512-byte sectors.
+    pub fn new(size: usize) -> Self {
+        Self::from_vec(vec![0; size])
+    }
+
+    /// Creates a device holding `data`, padded with zeros to whole 512-byte
+    /// sectors.
+    pub fn from_vec(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(512), 0);
+        Self {
+            data: Mutex::new(data),
+            sector_size: 512,
+            read_only: false,
+        }
+    }
+
+    /// Makes the device reject writes.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Returns a copy of the contents of the device.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn num_sectors(&self) -> u64 {
+        (self.data.lock().len() / self.sector_size) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> VfsResult<()> {
+        check_request(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> VfsResult<()> {
+        if self.read_only {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        check_request(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> VfsResult<()> {
+        if self.read_only {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        let len = count
+            .checked_mul(self.sector_size as u64)
+            .and_then(|it| usize::try_from(it).ok())
+            .ok_or(VfsError::InvalidInput)?;
+        check_request(self, sector, len)?;
+        let start = sector as usize * self.sector_size;
+        self.data.lock()[start..start + len].fill(0);
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
@@ -0,0 +1,356 @@
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use super::{BlockDevice, RamDisk};
+use crate::{Filesystem, Location, Mountpoint, Mutex, VfsError, VfsResult};
+
+/// A request recorded by a [`RecordingDevice`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockEvent {
+    /// `data` was written at byte `offset`.
+    Write { offset: u64, data: Box<[u8]> },
+    /// `len` bytes starting from byte `offset` were discarded, which reads
+    /// back as zeros.
+    Discard { offset: u64, len: u64 },
+    /// The device was flushed.
+    Flush,
+}
+
+impl BlockEvent {
+    fn apply(&self, image: &mut [u8]) {
+        match self {
+            Self::Write { offset, data } => {
+                let start = *offset as usize;
+                image[start..start + data.len()].copy_from_slice(data);
+            }
+            Self::Discard { offset, len } => {
+                image[*offset as usize..(offset + len) as usize].fill(0);
+            }
+            Self::Flush => {}
+        }
+    }
+}
+
+/// A block device logging the writes and flushes made to another.
+///
+/// The contents of the device when it is created are kept, so that the
+/// states the device could be left in by a power loss can be rebuilt with
+/// [`Recording`].
+pub struct RecordingDevice {
+    inner: Arc<dyn BlockDevice>,
+    base: Vec<u8>,
+    events: Mutex<Vec<BlockEvent>>,
+}
+
+impl RecordingDevice {
+    /// Starts recording the requests made to `inner`.
+    pub fn new(inner: Arc<dyn BlockDevice>) -> VfsResult<Self> {
+        let len = usize::try_from(inner.len()).map_err(|_| VfsError::InvalidInput)?;
+        let mut base = vec![0; len];
+        inner.read_sectors(0, &mut base)?;
+        Ok(Self {
+            inner,
+            base,
+            events: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns the requests recorded so far.
+    pub fn events(&self) -> Vec<BlockEvent> {
+        self.events.lock().clone()
+    }
+
+    /// Returns the recording of the requests made so far.
+    pub fn recording(&self) -> Recording {
+        Recording {
+            base: self.base.clone(),
+            events: self.events(),
+        }
+    }
+}
+
+impl BlockDevice for RecordingDevice {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.inner.num_sectors()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> VfsResult<()> {
+        self.inner.read_sectors(sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> VfsResult<()> {
+        self.inner.write_sectors(sector, buf)?;
+        self.events.lock().push(BlockEvent::Write {
+            offset: sector * self.sector_size() as u64,
+            data: buf.into(),
+        });
+        Ok(())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.inner.flush()?;
+        self.events.lock().push(BlockEvent::Flush);
+        Ok(())
+    }
+
+    fn discard(&self, sector: u64, count: u64) -> VfsResult<()> {
+        self.inner.discard(sector, count)?;
+        let sector_size = self.sector_size() as u64;
+        self.events.lock().push(BlockEvent::Discard {
+            offset: sector * sector_size,
+            len: count * sector_size,
+        });
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Options for [`Recording::check`].
+///
+/// Each write request is treated as atomic, even when it spans several
+/// sectors: torn writes, with only some of the sectors reaching the device,
+/// are not modeled.
+#[derive(Debug, Clone)]
+pub struct CrashOptions {
+    /// Largest number of writes between two flushes for which every subset
+    /// is tried, as the device may complete them in any order. Longer runs
+    /// are only tried in the order they were made.
+    ///
+    /// Values above 63 are treated as 63, although trying every subset is
+    /// only practical for much shorter runs.
+    pub max_reordered: usize,
+}
+
+impl Default for CrashOptions {
+    fn default() -> Self {
+        Self { max_reordered: 8 }
+    }
+}
+
+/// A state a device can be left in by a power loss.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashPoint {
+    /// Number of flushes that completed.
+    pub flushes: usize,
+    /// Indices in the recording of the writes made after the last of those
+    /// flushes that reached the device.
+    pub writes: Vec<usize>,
+}
+
+/// A crash state for which mounting or an invariant failed.
+#[derive(Debug, Clone)]
+pub struct CrashFailure {
+    pub point: CrashPoint,
+    pub error: VfsError,
+}
+
+/// The initial contents of a device and the requests made to it, as
+/// recorded by a [`RecordingDevice`].
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub base: Vec<u8>,
+    pub events: Vec<BlockEvent>,
+}
+
+impl Recording {
+    /// Returns the indices of the writes and discards between each flush,
+    /// the last run following the last flush.
+    fn runs(&self) -> Vec<Vec<usize>> {
+        let mut runs = vec![Vec::new()];
+        for (i, event) in self.events.iter().enumerate() {
+            match event {
+                BlockEvent::Flush => runs.push(Vec::new()),
+                _ => runs.last_mut().unwrap().push(i),
+            }
+        }
+        runs
+    }
+
+    /// Returns every state the device could be left in by a power loss
+    /// during the recording, from the oldest.
+    ///
+    /// Writes before a completed flush always reached the device, and each
+    /// write either reached it whole or not at all.
+    pub fn crash_points(&self, options: &CrashOptions) -> Vec<CrashPoint> {
+        let runs = self.runs();
+        let mut points = Vec::new();
+        for (flushes, run) in runs.iter().enumerate() {
+            // A run complete is the next one empty.
+            let last = flushes + 1 == runs.len();
+            let mut push = |writes: Vec<usize>| {
+                if last || writes.len() < run.len() {
+                    points.push(CrashPoint { flushes, writes });
+                }
+            };
+            if run.len() <= options.max_reordered.min(u64::BITS as usize - 1) {
+                for mask in 0..1u64 << run.len() {
+                    push(
+                        (0..run.len())
+                            .filter(|i| mask & 1 << i != 0)
+                            .map(|i| run[i])
+                            .collect(),
+                    );
+                }
+            } else {
+                for len in 0..=run.len() {
+                    push(run[..len].to_vec());
+                }
+            }
+        }
+        points
+    }
+
+    /// Returns the contents of the device at `point`.
+    pub fn image_at(&self, point: &CrashPoint) -> Vec<u8> {
+        let mut image = self.base.clone();
+        let mut flushes = 0;
+        for event in &self.events {
+            if flushes == point.flushes {
+                break;
+            }
+            if *event == BlockEvent::Flush {
+                flushes += 1;
+            }
+            event.apply(&mut image);
+        }
+        for &i in &point.writes {
+            self.events[i].apply(&mut image);
+        }
+        image
+    }
+
+    /// Mounts the filesystem with `mount` in every state returned by
+    /// [`crash_points`](Self::crash_points), and calls `check` on its root
+    /// directory, returning the number of states checked or the first that
+    /// failed.
+    pub fn check(
+        &self,
+        options: &CrashOptions,
+        mut mount: impl FnMut(Arc<dyn BlockDevice>) -> VfsResult<Filesystem>,
+        mut check: impl FnMut(&Location) -> VfsResult<()>,
+    ) -> Result<usize, CrashFailure> {
+        let points = self.crash_points(options);
+        for point in &points {
+            let device = Arc::new(RamDisk::from_vec(self.image_at(point)));
+            mount(device)
+                .and_then(|fs| check(&Mountpoint::new_root(&fs).root_location()))
+                .map_err(|error| CrashFailure {
+                    point: point.clone(),
+                    error,
+                })?;
+        }
+        Ok(points.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{sync::Arc, vec::Vec};
+
+    use super::*;
+    use crate::{
+        NodePermission, NodeType,
+        fs::axfs::{self, AxFs, MkfsOptions},
+        test_util::list,
+    };
+
+    #[test]
+    fn test_crash_points() {
+        let write = |offset| BlockEvent::Write {
+            offset,
+            data: [(offset / 512 + 1) as u8; 512].into(),
+        };
+        let recording = Recording {
+            base: vec![0; 2048],
+            events: vec![write(0), write(512), BlockEvent::Flush, write(1024)],
+        };
+        let points = recording.crash_points(&CrashOptions::default());
+        // {}, {0}, {1}, then {}, {3}.
+        assert_eq!(points.len(), 5);
+        let image = recording.image_at(&points[2]);
+        assert_eq!((image[0], image[512]), (0, 2));
+        let image = recording.image_at(&points[4]);
+        assert_eq!((image[0], image[512], image[1024]), (1, 2, 3));
+
+        let options = CrashOptions { max_reordered: 1 };
+        assert_eq!(recording.crash_points(&options).len(), 4);
+
+        // Runs too long for every subset are tried in order.
+        let recording = Recording {
+            base: vec![0; 2048],
+            events: (0..64).map(|_| write(0)).collect(),
+        };
+        let options = CrashOptions {
+            max_reordered: usize::MAX,
+        };
+        assert_eq!(recording.crash_points(&options).len(), 65);
+    }
+
+    #[test]
+    fn test_axfs_crashes() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(1 << 20));
+        let options = MkfsOptions {
+            block_size: 1024,
+            ..Default::default()
+        };
+        axfs::mkfs(disk.clone(), &options).unwrap();
+
+        let device = Arc::new(RecordingDevice::new(disk).unwrap());
+        let fs = Filesystem::new(AxFs::new(device.clone()).unwrap());
+        let root = Mountpoint::new_root(&fs).root_location();
+        let mode = NodePermission::from_bits_truncate(0o644);
+        let file = root.create("a", NodeType::RegularFile, mode).unwrap();
+        file.entry()
+            .as_file()
+            .unwrap()
+            .write_at(b"hello", 0)
+            .unwrap();
+        root.filesystem().flush().unwrap();
+        root.rename("a", &root, "b").unwrap();
+        root.filesystem().flush().unwrap();
+
+        let recording = device.recording();
+        let count = recording
+            .check(
+                &CrashOptions::default(),
+                |device| Ok(Filesystem::new(AxFs::new(device)?)),
+                |root| {
+                    let names = list(root);
+                    let names: Vec<_> = names.iter().filter(|it| !it.starts_with('.')).collect();
+                    match names[..] {
+                        [] => Ok(()),
+                        [name] if name == "a" || name == "b" => {
+                            let file = root.lookup_no_follow(name)?;
+                            let mut buf = [0; 8];
+                            let len = file.entry().as_file()?.read_at(&mut buf, 0)?;
+                            if buf[..len] == *b"hello" {
+                                Ok(())
+                            } else {
+                                Err(VfsError::InvalidData)
+                            }
+                        }
+                        _ => Err(VfsError::InvalidData),
+                    }
+                },
+            )
+            .unwrap();
+        assert!(count > 3);
+
+        for point in recording.crash_points(&CrashOptions::default()) {
+            let device = Arc::new(RamDisk::from_vec(recording.image_at(&point)));
+            let report = axfs::fsck(device).unwrap();
+            assert!(report.is_clean(), "{point:?}: {:?}", report.problems);
+        }
+    }
+}
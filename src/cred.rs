@@ -0,0 +1,315 @@
+//! Credentials of the callers of filesystem operations.
+//!
+//! A [`Location`](crate::Location) carrying credentials, as made by
+//! [`Location::with_credentials`](crate::Location::with_credentials), checks
+//! them in lookups and in the operations changing the filesystem, and hands
+//! them down to the locations it leads to. Locations without credentials
+//! are not checked.
+//!
+//! Checks with credentials also enforce the rules of sticky directories,
+//! and the restrictions of [`Protection`] set for the whole VFS with
+//! [`Mountpoint::set_protection`](crate::Mountpoint::set_protection).
+
+use alloc::vec::Vec;
+
+bitflags::bitflags! {
+    /// Capabilities overriding the checks of the filesystem, as in Linux.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Capabilities: u64 {
+        /// Changing the owner and group of nodes at will.
+        const CHOWN = 1 << 0;
+        /// Bypassing read, write and execute permission checks.
+        const DAC_OVERRIDE = 1 << 1;
+        /// Bypassing read permission checks, and search permission checks
+        /// on directories.
+        const DAC_READ_SEARCH = 1 << 2;
+        /// Bypassing the checks that the caller owns the node.
+        const FOWNER = 1 << 3;
+        /// Keeping the set-group-ID bit when changing the mode of nodes of
+        /// other groups.
+        const FSETID = 1 << 4;
+        /// Mounting filesystems, and using `trusted.` extended attributes.
+        const SYS_ADMIN = 1 << 21;
+    }
+}
+
+/// Identity of a caller for filesystem access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// User ID for filesystem access.
+    pub fsuid: u32,
+    /// Group ID for filesystem access.
+    pub fsgid: u32,
+    /// Supplementary groups.
+    pub groups: Vec<u32>,
+    pub caps: Capabilities,
+}
+
+impl Credentials {
+    /// Makes the credentials of an unprivileged user.
+    pub fn new(fsuid: u32, fsgid: u32, groups: Vec<u32>) -> Self {
+        Self {
+            fsuid,
+            fsgid,
+            groups,
+            caps: Capabilities::empty(),
+        }
+    }
+
+    /// Makes the credentials of the superuser, with every capability.
+    pub fn root() -> Self {
+        Self {
+            fsuid: 0,
+            fsgid: 0,
+            groups: Vec::new(),
+            caps: Capabilities::all(),
+        }
+    }
+
+    /// Returns whether the caller belongs to group `gid`.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.fsgid == gid || self.groups.contains(&gid)
+    }
+
+    /// Returns whether the caller may act as the owner `uid` of a node.
+    pub fn owns(&self, uid: u32) -> bool {
+        self.fsuid == uid || self.caps.contains(Capabilities::FOWNER)
+    }
+}
+
+/// Restrictions protecting users of shared directories, such as `/tmp`,
+/// from each other, as the `fs.protected_*` settings of Linux.
+///
+/// The levels of `fifos` and `regular` are 0 for none, 1 for sticky
+/// directories writable by others, and 2 for sticky directories writable by
+/// their group too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    /// Following symlinks in sticky directories writable by others is
+    /// limited to their owner, unless they belong to the owner of the
+    /// directory.
+    pub symlinks: bool,
+    /// Hard links are limited to callers owning the node, or having read
+    /// and write access to a regular file which is neither set-user-ID nor
+    /// executable set-group-ID.
+    pub hardlinks: bool,
+    /// Opening FIFOs with `create` in sticky directories is limited to
+    /// their owner, unless they belong to the owner of the directory.
+    pub fifos: u8,
+    /// As `fifos`, for regular files.
+    pub regular: u8,
+}
+
+impl Default for Protection {
+    /// Returns the defaults of Linux.
+    fn default() -> Self {
+        Self {
+            symlinks: true,
+            hardlinks: true,
+            fifos: 0,
+            regular: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{sync::Arc, vec};
+
+    use super::*;
+    use crate::{
+        Access, MetadataUpdate, NodePermission, NodeType, OpenOptions, VfsError, XattrFlags,
+        test_util::memory_fs,
+    };
+
+    #[test]
+    fn test_credentials() {
+        let root = memory_fs();
+        let mode = |bits| NodePermission::from_bits_truncate(bits);
+        let dir = root
+            .create("dir", NodeType::Directory, mode(0o750))
+            .unwrap();
+        dir.update_metadata(MetadataUpdate {
+            owner: Some((1000, 100)),
+            ..Default::default()
+        })
+        .unwrap();
+        dir.create("file", NodeType::RegularFile, mode(0o644))
+            .unwrap();
+
+        let alice = Arc::new(Credentials::new(1000, 1000, vec![]));
+        let bob = Arc::new(Credentials::new(1001, 1001, vec![100]));
+        let eve = Arc::new(Credentials::new(1002, 1002, vec![]));
+        let denied = Some(VfsError::PermissionDenied);
+        let not_permitted = Some(VfsError::OperationNotPermitted);
+
+        // Search permission is checked along the way.
+        let as_eve = root.clone().with_credentials(eve.clone());
+        let eve_dir = as_eve.lookup_no_follow("dir").unwrap();
+        assert_eq!(eve_dir.lookup_no_follow("file").err(), denied);
+        let bob_file = root
+            .clone()
+            .with_credentials(bob.clone())
+            .lookup_no_follow("dir")
+            .and_then(|it| it.lookup_no_follow("file"))
+            .unwrap();
+        assert!(bob_file.credentials().is_some());
+        assert_eq!(bob_file.check_access(&bob, Access::WRITE).err(), denied);
+        let bob_dir = bob_file.parent().unwrap();
+        assert_eq!(
+            bob_dir
+                .create("new", NodeType::RegularFile, mode(0o644))
+                .err(),
+            denied
+        );
+        assert_eq!(bob_dir.unlink("file", false).err(), denied);
+
+        // Created nodes belong to the caller.
+        let alice_dir = root
+            .clone()
+            .with_credentials(alice.clone())
+            .lookup_no_follow("dir")
+            .unwrap();
+        let options = OpenOptions {
+            create: true,
+            ..Default::default()
+        };
+        let new = alice_dir.open_file("new", &options).unwrap();
+        let metadata = new.metadata().unwrap();
+        assert_eq!((metadata.uid, metadata.gid), (1000, 1000));
+        assert_eq!(
+            bob_dir.open_file("new", &options).unwrap().inode(),
+            new.inode()
+        );
+
+        // Only the owner changes the mode, and groups it belongs to.
+        let chmod = MetadataUpdate {
+            mode: Some(mode(0o2640)),
+            ..Default::default()
+        };
+        assert_eq!(bob_dir.update_metadata(chmod.clone()).err(), not_permitted);
+        alice_dir.update_metadata(chmod).unwrap();
+        // Alice is not in group 100.
+        assert_eq!(alice_dir.metadata().unwrap().mode.bits(), 0o640);
+        assert_eq!(
+            new.update_metadata(MetadataUpdate {
+                owner: Some((1000, 100)),
+                ..Default::default()
+            })
+            .err(),
+            not_permitted
+        );
+        assert_eq!(
+            new.set_xattr("trusted.a", b"", XattrFlags::empty()).err(),
+            not_permitted
+        );
+        new.set_xattr("user.a", b"", XattrFlags::empty()).unwrap();
+
+        // Capabilities override the permission bits.
+        let reader = Credentials {
+            caps: Capabilities::DAC_READ_SEARCH,
+            ..(*eve).clone()
+        };
+        assert!(
+            dir.check_access(&reader, Access::READ | Access::EXECUTE)
+                .is_ok()
+        );
+        assert_eq!(dir.check_access(&reader, Access::WRITE).err(), denied);
+        let root_cred = Credentials::root();
+        assert!(dir.check_access(&root_cred, Access::all()).is_ok());
+        assert_eq!(new.check_access(&root_cred, Access::EXECUTE).err(), denied);
+    }
+
+    #[test]
+    fn test_protection() {
+        let root = memory_fs();
+        let tmp = root
+            .create(
+                "tmp",
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o1777),
+            )
+            .unwrap();
+        let alice = Arc::new(Credentials::new(1000, 1000, vec![]));
+        let bob = Arc::new(Credentials::new(1001, 1001, vec![]));
+        let as_alice = tmp.clone().with_credentials(alice);
+        let as_bob = tmp.clone().with_credentials(bob);
+        let perm = NodePermission::from_bits_truncate(0o666);
+        let denied = Some(VfsError::PermissionDenied);
+        let not_permitted = Some(VfsError::OperationNotPermitted);
+
+        let file = as_alice
+            .create("file", NodeType::RegularFile, perm)
+            .unwrap();
+        as_alice.create("fifo", NodeType::Fifo, perm).unwrap();
+        let link = as_alice.create("link", NodeType::Symlink, perm).unwrap();
+        link.entry().as_file().unwrap().set_symlink("file").unwrap();
+
+        // Only owners remove entries of sticky directories.
+        assert_eq!(as_bob.unlink("file", false).err(), not_permitted);
+        assert_eq!(as_bob.rename("file", &as_bob, "moved").err(), not_permitted);
+        as_bob.create("mine", NodeType::RegularFile, perm).unwrap();
+        assert_eq!(as_bob.rename("mine", &as_bob, "file").err(), not_permitted);
+        as_bob.unlink("mine", false).unwrap();
+        as_alice.rename("file", &as_alice, "file2").unwrap();
+        as_alice.rename("file2", &as_alice, "file").unwrap();
+
+        // Symlinks of others are not followed, unlike one's own.
+        let bob_link = as_bob.lookup_no_follow("link").unwrap();
+        assert_eq!(bob_link.follow_link().err(), denied);
+        assert_eq!(bob_link.read_link().unwrap(), "file");
+        assert_eq!(
+            as_alice
+                .lookup_no_follow("link")
+                .unwrap()
+                .follow_link()
+                .unwrap(),
+            "file"
+        );
+        tmp.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o1775)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(bob_link.follow_link().unwrap(), "file");
+
+        // Hard links take owning the node or being able to read and write
+        // it.
+        file.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o644)),
+            ..Default::default()
+        })
+        .unwrap();
+        let home = root
+            .create(
+                "home",
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o777),
+            )
+            .unwrap()
+            .with_credentials(as_bob.credentials().unwrap().clone());
+        assert_eq!(home.link("hard", &file).err(), not_permitted);
+        let mut protection = root.mountpoint().protection();
+        protection.hardlinks = false;
+        root.mountpoint().set_protection(protection);
+        home.link("hard", &file).unwrap();
+
+        // Opening the FIFO of another user with `create` in a sticky
+        // directory writable by others.
+        tmp.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o1777)),
+            ..Default::default()
+        })
+        .unwrap();
+        let options = OpenOptions {
+            create: true,
+            ..Default::default()
+        };
+        as_bob.open_file("fifo", &options).unwrap();
+        protection.fifos = 1;
+        root.mountpoint().set_protection(protection);
+        assert_eq!(as_bob.open_file("fifo", &options).err(), denied);
+        as_bob.open_file("file", &options).unwrap();
+        as_alice.open_file("fifo", &options).unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use super::{AxFs, Handle, MAX_NAME_LEN, Op, ROOT_INO, inode::Inode, le32};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps,
+    NodePermission, NodeType, Reference, VfsError, VfsResult, WeakDirEntry, XattrFlags,
+    path::{DOT, DOTDOT},
+};
+
+/// Size of the header of records: the inode number, the length of the
+/// record, the length of the name and the node type.
+const HEADER_SIZE: usize = 10;
+
+/// Returns the space a record for a name of `len` bytes takes.
+fn record_size(len: usize) -> usize {
+    (HEADER_SIZE + len).next_multiple_of(4)
+}
+
+/// A record of a directory block.
+///
+/// Records never span blocks. Removed records are merged into the previous
+/// one, or marked free with an inode number of zero at the start of blocks.
+pub(super) struct Record {
+    /// Offset of the record in the block.
+    pub offset: usize,
+    pub len: usize,
+    pub ino: u32,
+    pub node_type: NodeType,
+    pub name: Vec<u8>,
+}
+
+impl Record {
+    fn used(&self) -> usize {
+        if self.ino == 0 {
+            0
+        } else {
+            record_size(self.name.len())
+        }
+    }
+}
+
+/// Parses the records of a directory block.
+pub(super) fn parse(data: &[u8]) -> VfsResult<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        if offset + HEADER_SIZE > data.len() {
+            return Err(VfsError::InvalidData);
+        }
+        let raw = &data[offset..];
+        let ino = le32(raw, 0);
+        let len = le32(raw, 4) as usize;
+        let name_len = raw[8] as usize;
+        if len < HEADER_SIZE
+            || !len.is_multiple_of(4)
+            || offset + len > data.len()
+            || (ino != 0 && (name_len == 0 || record_size(name_len) > len))
+        {
+            return Err(VfsError::InvalidData);
+        }
+        records.push(Record {
+            offset,
+            len,
+            ino,
+            node_type: NodeType::from(raw[9]),
+            name: if ino == 0 {
+                Vec::new()
+            } else {
+                raw[HEADER_SIZE..HEADER_SIZE + name_len].to_vec()
+            },
+        });
+        offset += len;
+    }
+    Ok(records)
+}
+
+fn encode(ino: u32, len: usize, node_type: NodeType, name: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; record_size(name.len())];
+    raw[0..4].copy_from_slice(&ino.to_le_bytes());
+    raw[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+    raw[8] = name.len() as u8;
+    raw[9] = node_type as u8;
+    raw[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+    raw
+}
+
+/// A record found in a directory, along with the block holding it.
+pub(super) struct Found {
+    pub block: u32,
+    pub record: Record,
+}
+
+impl Op<'_> {
+    /// Returns the records of data block `index` of directory `dir`.
+    pub(super) fn dir_block(&self, dir: &Inode, index: u64) -> VfsResult<(u32, Vec<Record>)> {
+        let block = self.map(dir, index)?.ok_or(VfsError::InvalidData)?;
+        let data = self.read_block(block as u64)?;
+        Ok((block, parse(&data)?))
+    }
+
+    fn find(&self, dir: &Inode, name: &str) -> VfsResult<Option<Found>> {
+        for index in 0..dir.size / self.block_size() as u64 {
+            let (block, records) = self.dir_block(dir, index)?;
+            if let Some(record) = records
+                .into_iter()
+                .find(|it| it.ino != 0 && it.name == name.as_bytes())
+            {
+                return Ok(Some(Found { block, record }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns whether directory `dir` has no entries.
+    fn is_empty(&self, dir: &Inode) -> VfsResult<bool> {
+        for index in 0..dir.size / self.block_size() as u64 {
+            if self.dir_block(dir, index)?.1.iter().any(|it| it.ino != 0) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Adds an entry to directory `dir`, growing it if there is no room.
+    fn insert(&mut self, dir: u32, name: &str, ino: u32, node_type: NodeType) -> VfsResult<()> {
+        let mut inode = self.inode(dir)?;
+        let name = name.as_bytes();
+        let size = record_size(name.len());
+        let block_size = self.block_size();
+        for index in 0..inode.size / block_size as u64 {
+            let (block, records) = self.dir_block(&inode, index)?;
+            let Some(record) = records.iter().find(|it| it.len - it.used() >= size) else {
+                continue;
+            };
+            let block = block as u64;
+            if record.ino == 0 {
+                return self.write(
+                    block,
+                    record.offset,
+                    &encode(ino, record.len, node_type, name),
+                );
+            }
+            // Split the record, giving the room it does not use to the new
+            // one.
+            let used = record.used();
+            self.write(block, record.offset + 4, &(used as u32).to_le_bytes())?;
+            let raw = encode(ino, record.len - used, node_type, name);
+            return self.write(block, record.offset + used, &raw);
+        }
+
+        let index = inode.size / block_size as u64;
+        let block = self.map_alloc(&mut inode, index)?;
+        self.write(block as u64, 0, &encode(ino, block_size, node_type, name))?;
+        inode.size += block_size as u64;
+        self.store(&inode)
+    }
+
+    /// Removes the record `found` from its directory.
+    fn remove(&mut self, found: &Found) -> VfsResult<()> {
+        let block = found.block as u64;
+        let record = &found.record;
+        if record.offset == 0 {
+            return self.write(block, 0, &0u32.to_le_bytes());
+        }
+        let data = self.read_block(block)?;
+        let previous = parse(&data)?
+            .into_iter()
+            .take_while(|it| it.offset < record.offset)
+            .last()
+            .ok_or(VfsError::InvalidData)?;
+        let len = (previous.len + record.len) as u32;
+        self.write(block, previous.offset + 4, &len.to_le_bytes())
+    }
+
+    /// Points the record `found` to inode `ino`.
+    fn replace(&mut self, found: &Found, ino: u32, node_type: NodeType) -> VfsResult<()> {
+        let offset = found.record.offset;
+        self.write(found.block as u64, offset, &ino.to_le_bytes())?;
+        self.write(found.block as u64, offset + 9, &[node_type as u8])
+    }
+
+    /// Adds `delta` to the link count of inode `ino`, and releases it once it
+    /// has no links.
+    fn add_links(&mut self, ino: u32, delta: i32) -> VfsResult<()> {
+        let mut inode = self.inode(ino)?;
+        inode.nlink = inode
+            .nlink
+            .checked_add_signed(delta)
+            .ok_or(VfsError::InvalidData)?;
+        inode.ctime = self.fs.now();
+        self.store(&inode)?;
+        if inode.nlink == 0 {
+            self.fs.released.lock().push(ino);
+        }
+        Ok(())
+    }
+
+    /// Updates the modification time of directory `dir`.
+    fn touch(&mut self, dir: u32) -> VfsResult<()> {
+        let mut inode = self.inode(dir)?;
+        let now = self.fs.now();
+        inode.mtime = now;
+        inode.ctime = now;
+        self.store(&inode)
+    }
+
+    /// Returns whether directory `dir` is `ancestor` or lies below it.
+    fn is_within(&self, mut dir: u32, ancestor: u32) -> VfsResult<bool> {
+        for _ in 0..self.sb().inode_count {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            if dir == ROOT_INO {
+                return Ok(false);
+            }
+            dir = self.inode(dir)?.parent;
+        }
+        Err(VfsError::InvalidData)
+    }
+}
+
+/// Directory node of an [`AxFs`].
+pub struct AxDir {
+    fs: Arc<AxFs>,
+    handle: Arc<Handle>,
+    this: WeakDirEntry,
+}
+
+impl AxDir {
+    pub(super) fn new(fs: Arc<AxFs>, handle: Arc<Handle>, this: WeakDirEntry) -> Self {
+        Self { fs, handle, this }
+    }
+
+    fn ino(&self) -> u32 {
+        self.handle.ino
+    }
+
+    fn open(&self, ino: u32, name: &str) -> VfsResult<DirEntry> {
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        self.fs.open(ino, reference)
+    }
+}
+
+impl NodeOps for AxDir {
+    fn inode(&self) -> u64 {
+        self.ino() as u64
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let inode = self.fs.inspect(|op| op.inode(self.ino()))?;
+        Ok(inode.metadata(self.fs.sb.block_size))
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.fs.update_metadata(self.ino(), update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.fs.flush()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.fs.get_xattr(self.ino(), name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.fs.set_xattr(self.ino(), name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.fs.list_xattr(self.ino())
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.fs.remove_xattr(self.ino(), name)
+    }
+}
+
+impl DirNodeOps for AxDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        // `.` and `..` are at offsets 0 and 1, and the other entries are
+        // addressed by their position in the directory plus 3, which stays
+        // valid as entries come and go.
+        let ino = self.ino();
+        let dir = self.fs.inspect(|op| op.inode(ino))?;
+        let mut count = 0;
+        for (name, ino, next) in [(DOT, ino, 1), (DOTDOT, dir.parent, 2)] {
+            if offset < next {
+                if !sink.accept(name, ino as u64, NodeType::Directory, next) {
+                    return Ok(count);
+                }
+                count += 1;
+            }
+        }
+
+        let block_size = self.fs.block_size() as u64;
+        let start = offset.saturating_sub(3);
+        for index in start / block_size..dir.size / block_size {
+            // Blocks are read one at a time, so that the sink is not called
+            // with the filesystem locked.
+            let records = self.fs.inspect(|op| {
+                let dir = op.inode(ino)?;
+                if index >= dir.size / block_size {
+                    return Ok(Vec::new());
+                }
+                Ok(op.dir_block(&dir, index)?.1)
+            })?;
+            for record in records {
+                let pos = index * block_size + record.offset as u64;
+                if record.ino == 0 || pos + 3 <= offset {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(&record.name);
+                if !sink.accept(&name, record.ino as u64, record.node_type, pos + 3) {
+                    return Ok(count);
+                }
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let found = self.fs.inspect(|op| {
+            let dir = op.inode(self.ino())?;
+            op.find(&dir, name)
+        })?;
+        let found = found.ok_or(VfsError::NotFound)?;
+        self.open(found.record.ino, name)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(VfsError::NameTooLong);
+        }
+        if node_type == NodeType::Unknown {
+            return Err(VfsError::InvalidInput);
+        }
+        let dir = self.ino();
+        let ino = self.fs.modify(|op| {
+            let parent = op.inode(dir)?;
+            if parent.nlink == 0 {
+                return Err(VfsError::NotFound);
+            }
+            if op.find(&parent, name)?.is_some() {
+                return Err(VfsError::AlreadyExists);
+            }
+            let mode = (node_type as u16) << 12 | (permission.bits() & 0o7777);
+            let mut inode = op.alloc_inode(mode)?;
+            if node_type == NodeType::Directory {
+                inode.nlink = 2;
+                inode.parent = dir;
+                op.add_links(dir, 1)?;
+            } else {
+                inode.nlink = 1;
+            }
+            op.store(&inode)?;
+            op.insert(dir, name, inode.ino, node_type)?;
+            op.touch(dir)?;
+            Ok(inode.ino)
+        })?;
+        self.open(ino, name)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        if node.is_dir() {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        let file = node
+            .downcast::<super::AxFile>()
+            .map_err(|_| VfsError::CrossesDevices)?;
+        if !Arc::ptr_eq(&file.fs, &self.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        let dir = self.ino();
+        let ino = file.handle.ino;
+        self.fs.modify(|op| {
+            let parent = op.inode(dir)?;
+            if op.find(&parent, name)?.is_some() {
+                return Err(VfsError::AlreadyExists);
+            }
+            let inode = op.inode(ino)?;
+            if inode.nlink == 0 {
+                return Err(VfsError::NotFound);
+            }
+            op.add_links(ino, 1)?;
+            op.insert(dir, name, ino, inode.node_type())?;
+            op.touch(dir)
+        })?;
+        self.open(ino, name)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let dir = self.ino();
+        self.fs.modify(|op| {
+            let parent = op.inode(dir)?;
+            let found = op.find(&parent, name)?.ok_or(VfsError::NotFound)?;
+            let ino = found.record.ino;
+            let inode = op.inode(ino)?;
+            if inode.is_dir() {
+                if !op.is_empty(&inode)? {
+                    return Err(VfsError::DirectoryNotEmpty);
+                }
+                op.add_links(ino, -(inode.nlink as i32))?;
+                op.add_links(dir, -1)?;
+            } else {
+                op.add_links(ino, -1)?;
+            }
+            op.remove(&found)?;
+            op.touch(dir)
+        })
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir = dst_dir.downcast::<AxDir>()?;
+        if !Arc::ptr_eq(&dst_dir.fs, &self.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        let (src_dir, dst_dir) = (self.ino(), dst_dir.ino());
+        self.fs.modify(|op| {
+            let src_parent = op.inode(src_dir)?;
+            let src = op.find(&src_parent, src_name)?.ok_or(VfsError::NotFound)?;
+            let ino = src.record.ino;
+            let inode = op.inode(ino)?;
+            let dst_parent = op.inode(dst_dir)?;
+            if dst_parent.nlink == 0 {
+                return Err(VfsError::NotFound);
+            }
+            let dst = op.find(&dst_parent, dst_name)?;
+            if dst.as_ref().is_some_and(|it| it.record.ino == ino) {
+                return Ok(());
+            }
+            if inode.is_dir() && src_dir != dst_dir && op.is_within(dst_dir, ino)? {
+                return Err(VfsError::InvalidInput);
+            }
+
+            match dst {
+                Some(dst) => {
+                    let old = op.inode(dst.record.ino)?;
+                    match (inode.is_dir(), old.is_dir()) {
+                        (true, false) => return Err(VfsError::NotADirectory),
+                        (false, true) => return Err(VfsError::IsADirectory),
+                        (true, true) => {
+                            if !op.is_empty(&old)? {
+                                return Err(VfsError::DirectoryNotEmpty);
+                            }
+                            op.add_links(old.ino, -(old.nlink as i32))?;
+                            op.add_links(dst_dir, -1)?;
+                        }
+                        (false, false) => op.add_links(old.ino, -1)?,
+                    }
+                    op.replace(&dst, ino, inode.node_type())?;
+                }
+                None => op.insert(dst_dir, dst_name, ino, inode.node_type())?,
+            }
+            // Inserting may have split the record of the source.
+            let src_parent = op.inode(src_dir)?;
+            let src = op
+                .find(&src_parent, src_name)?
+                .ok_or(VfsError::InvalidData)?;
+            op.remove(&src)?;
+
+            let mut inode = op.inode(ino)?;
+            inode.ctime = op.fs.now();
+            if inode.is_dir() && src_dir != dst_dir {
+                inode.parent = dst_dir;
+                op.store(&inode)?;
+                op.add_links(src_dir, -1)?;
+                op.add_links(dst_dir, 1)?;
+            } else {
+                op.store(&inode)?;
+            }
+            op.touch(src_dir)?;
+            op.touch(dst_dir)
+        })
+    }
+}
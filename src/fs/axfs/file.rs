@@ -0,0 +1,174 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::{AxFs, Handle, STEP_BLOCKS};
+use crate::{
+    FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps, VfsError, VfsResult, XattrFlags,
+};
+
+/// Non-directory node of an [`AxFs`].
+///
+/// The target of a symlink is stored as its data.
+pub struct AxFile {
+    pub(super) fs: Arc<AxFs>,
+    pub(super) handle: Arc<Handle>,
+}
+
+impl AxFile {
+    pub(super) fn new(fs: Arc<AxFs>, handle: Arc<Handle>) -> Self {
+        Self { fs, handle }
+    }
+
+    fn ino(&self) -> u32 {
+        self.handle.ino
+    }
+
+    /// Writes `buf` at `offset`, or at the end of the file if it is `None`,
+    /// returning the offset written at.
+    ///
+    /// Long writes are split into several operations.
+    fn write(&self, buf: &[u8], offset: Option<u64>) -> VfsResult<u64> {
+        if buf.is_empty() {
+            return offset.map_or_else(|| self.len(), Ok);
+        }
+        let ino = self.ino();
+        let step = STEP_BLOCKS as usize * self.fs.block_size();
+        let mut start = offset;
+        let mut done = 0;
+        loop {
+            let chunk = &buf[done..(done + step).min(buf.len())];
+            start = Some(self.fs.modify(|op| {
+                let mut inode = op.inode(ino)?;
+                let start = start.unwrap_or(inode.size);
+                let offset = start + done as u64;
+                let end = offset
+                    .checked_add(chunk.len() as u64)
+                    .filter(|&it| it <= op.max_file_size())
+                    .ok_or(VfsError::from(axerrno::LinuxError::EFBIG))?;
+                op.write_data(&mut inode, offset, chunk)?;
+                inode.size = inode.size.max(end);
+                let now = op.fs.now();
+                inode.mtime = now;
+                inode.ctime = now;
+                op.store(&inode)?;
+                Ok(start)
+            })?);
+            done += chunk.len();
+            if done == buf.len() {
+                return Ok(start.unwrap());
+            }
+        }
+    }
+
+    fn truncate(&self, len: u64) -> VfsResult<()> {
+        let ino = self.ino();
+        loop {
+            let done = self.fs.modify(|op| {
+                let mut inode = op.inode(ino)?;
+                if len > op.max_file_size() {
+                    return Err(axerrno::LinuxError::EFBIG.into());
+                }
+                let now = op.fs.now();
+                inode.mtime = now;
+                inode.ctime = now;
+                if len >= inode.size {
+                    inode.size = len;
+                    op.store(&inode)?;
+                    return Ok(true);
+                }
+                op.truncate_step(&mut inode, len)
+            })?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl NodeOps for AxFile {
+    fn inode(&self) -> u64 {
+        self.ino() as u64
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let inode = self.fs.inspect(|op| op.inode(self.ino()))?;
+        Ok(inode.metadata(self.fs.sb.block_size))
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.fs.update_metadata(self.ino(), update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.fs.inspect(|op| op.inode(self.ino()))?.size)
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.fs.flush()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.fs.get_xattr(self.ino(), name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.fs.set_xattr(self.ino(), name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.fs.list_xattr(self.ino())
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.fs.remove_xattr(self.ino(), name)
+    }
+}
+
+impl FileNodeOps for AxFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.fs.inspect(|op| {
+            let inode = op.inode(self.ino())?;
+            let len = buf.len().min(inode.size.saturating_sub(offset) as usize);
+            op.read_data(&inode, offset, &mut buf[..len])?;
+            Ok(len)
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.write(buf, Some(offset))?;
+        Ok(buf.len())
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let offset = self.write(buf, None)?;
+        Ok((buf.len(), offset + buf.len() as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.truncate(len)
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        self.truncate(0)?;
+        self.write(target.as_bytes(), Some(0))?;
+        Ok(())
+    }
+}
+
+impl Pollable for AxFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
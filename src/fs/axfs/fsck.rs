@@ -0,0 +1,195 @@
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+
+use hashbrown::{HashMap, HashSet};
+
+use super::{
+    AxFs, AxFsOptions, Op, ROOT_INO,
+    inode::{INODE_SIZE, Inode},
+};
+use crate::{NodeType, VfsResult, block::BlockDevice};
+
+/// Result of [`fsck`].
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of inodes in use.
+    pub inodes: u32,
+    /// Number of blocks in use, metadata included.
+    pub blocks: u64,
+    /// Inodes in use without links, which are freed at the next mount.
+    pub orphans: Vec<u32>,
+    /// Descriptions of the inconsistencies found.
+    pub problems: Vec<String>,
+}
+
+impl FsckReport {
+    /// Returns whether the filesystem is consistent.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the consistency of the filesystem on `device`, as it would be
+/// mounted, without writing to the device.
+pub fn fsck(device: Arc<dyn BlockDevice>) -> VfsResult<FsckReport> {
+    let fs = AxFs::load(device, AxFsOptions::default())?;
+    fs.inspect(check)
+}
+
+fn check(op: &Op) -> VfsResult<FsckReport> {
+    let sb = op.sb().clone();
+    let block_size = sb.block_size as u64;
+    let mut report = FsckReport::default();
+    let problems = &mut report.problems;
+
+    let mut inodes = BTreeMap::new();
+    for ino in 1..=sb.inode_count {
+        let (block, offset) = sb.inode_pos(ino);
+        let mut raw = [0; INODE_SIZE];
+        op.read(block, offset, &mut raw)?;
+        let inode = Inode::parse(ino, &raw);
+        match (op.bit(sb.inode_bitmap, (ino - 1) as u64)?, inode.mode != 0) {
+            (true, false) => problems.push(format!("inode {ino} is free but marked in use")),
+            (false, true) => problems.push(format!("inode {ino} is in use but not marked")),
+            (true, true) if inode.node_type() == NodeType::Unknown => {
+                problems.push(format!("inode {ino} has an invalid type"));
+            }
+            (true, true) => {
+                inodes.insert(ino, inode);
+            }
+            (false, false) => {}
+        }
+    }
+
+    // Every block is owned by the metadata or a single inode, and marked.
+    let mut owners = HashMap::new();
+    let mut bad = HashSet::new();
+    for (&ino, inode) in &inodes {
+        let data_blocks = inode.size.div_ceil(block_size);
+        let mut count = 0;
+        let mut visit = |block: u32, index: Option<u64>| {
+            count += 1;
+            if index.is_some_and(|it| it >= data_blocks) {
+                problems.push(format!("inode {ino} has block {block} past its size"));
+            }
+            if let Some(owner) = owners.insert(block, ino) {
+                problems.push(format!(
+                    "block {block} is owned by inodes {owner} and {ino}"
+                ));
+            }
+        };
+        let valid = inode
+            .pointers
+            .iter()
+            .chain([&inode.xattr])
+            .all(|&it| it == 0 || (sb.data_start..sb.block_count).contains(&(it as u64)));
+        if !valid || op.for_each_block(inode, &mut visit).is_err() {
+            problems.push(format!("inode {ino} has invalid block pointers"));
+            bad.insert(ino);
+            continue;
+        }
+        if inode.xattr != 0 {
+            visit(inode.xattr, None);
+        }
+        if count != inode.blocks {
+            problems.push(format!(
+                "inode {ino} has {count} blocks but counts {}",
+                inode.blocks
+            ));
+        }
+    }
+    for block in 0..sb.block_count {
+        let marked = op.bit(sb.block_bitmap, block)?;
+        let used = block < sb.data_start || owners.contains_key(&(block as u32));
+        if marked {
+            report.blocks += 1;
+        }
+        if marked && !used {
+            problems.push(format!("block {block} is marked in use but unowned"));
+        } else if !marked && used {
+            problems.push(format!("block {block} is in use but not marked"));
+        }
+    }
+
+    // Walk the tree, counting the links to each inode.
+    let mut links: HashMap<u32, u32> = HashMap::new();
+    let mut subdirs: HashMap<u32, u32> = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut queue = vec![ROOT_INO];
+    match inodes.get(&ROOT_INO) {
+        Some(root) if root.is_dir() => {
+            visited.insert(ROOT_INO);
+        }
+        _ => {
+            problems.push(String::from("the root directory is missing"));
+            queue.clear();
+        }
+    }
+    while let Some(ino) = queue.pop() {
+        let dir = &inodes[&ino];
+        if bad.contains(&ino) {
+            continue;
+        }
+        if dir.size % block_size != 0 {
+            problems.push(format!("directory {ino} has a partial block"));
+        }
+        for index in 0..dir.size / block_size {
+            let Ok((_, records)) = op.dir_block(dir, index) else {
+                problems.push(format!("directory {ino} has a corrupted block {index}"));
+                continue;
+            };
+            for record in records.iter().filter(|it| it.ino != 0) {
+                let name = String::from_utf8_lossy(&record.name);
+                let Some(child) = inodes.get(&record.ino) else {
+                    problems.push(format!(
+                        "entry {name:?} of directory {ino} refers to a free inode"
+                    ));
+                    continue;
+                };
+                if record.name.contains(&b'/') || matches!(&*name, "." | "..") {
+                    problems.push(format!("directory {ino} has an invalid name {name:?}"));
+                }
+                if child.node_type() != record.node_type {
+                    problems.push(format!(
+                        "entry {name:?} of directory {ino} has a wrong type"
+                    ));
+                }
+                *links.entry(child.ino).or_default() += 1;
+                if child.is_dir() {
+                    *subdirs.entry(ino).or_default() += 1;
+                    if child.parent != ino {
+                        problems.push(format!("directory {} has a wrong parent", child.ino));
+                    }
+                    if visited.insert(child.ino) {
+                        queue.push(child.ino);
+                    } else {
+                        problems.push(format!("directory {} has several links", child.ino));
+                    }
+                }
+            }
+        }
+    }
+
+    for (&ino, inode) in &inodes {
+        if !visited.contains(&ino) && !links.contains_key(&ino) {
+            if inode.nlink == 0 {
+                report.orphans.push(ino);
+            } else {
+                problems.push(format!("inode {ino} is unreachable"));
+            }
+            continue;
+        }
+        let expected = if inode.is_dir() {
+            2 + subdirs.get(&ino).copied().unwrap_or(0)
+        } else {
+            links[&ino]
+        };
+        if inode.nlink != expected {
+            problems.push(format!(
+                "inode {ino} has {} links but {expected} were found",
+                inode.nlink
+            ));
+        }
+    }
+    report.inodes = inodes.len() as u32;
+    Ok(report)
+}
@@ -0,0 +1,426 @@
+//! On-disk inodes and the mapping of their data blocks.
+
+use core::time::Duration;
+
+use super::{Op, STEP_BLOCKS, le16, le32, le64};
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+pub const INODE_SIZE: usize = 256;
+/// Number of block pointers of an inode: direct ones, then a single, a
+/// double and a triple indirect one.
+const POINTERS: usize = 15;
+const DIRECT: usize = 12;
+
+fn time(raw: &[u8], secs: usize, nanos: usize) -> Duration {
+    Duration::new(le64(raw, secs), le32(raw, nanos).min(999_999_999))
+}
+
+fn put_time(raw: &mut [u8], secs: usize, nanos: usize, time: Duration) {
+    raw[secs..secs + 8].copy_from_slice(&time.as_secs().to_le_bytes());
+    raw[nanos..nanos + 4].copy_from_slice(&time.subsec_nanos().to_le_bytes());
+}
+
+/// An inode read from the inode table.
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub ino: u32,
+    /// File type and permission bits, as in `st_mode`. Free inodes have a
+    /// mode of zero.
+    pub mode: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub atime: Duration,
+    pub mtime: Duration,
+    pub ctime: Duration,
+    pub rdev: u64,
+    /// Number of blocks allocated to the inode, indirect blocks included.
+    pub blocks: u64,
+    pub pointers: [u32; POINTERS],
+    /// Block of the extended attributes, if any.
+    pub xattr: u32,
+    /// Parent of directories.
+    pub parent: u32,
+}
+
+impl Inode {
+    pub fn new(ino: u32, mode: u16, now: Duration) -> Self {
+        Self {
+            ino,
+            mode,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            rdev: 0,
+            blocks: 0,
+            pointers: [0; POINTERS],
+            xattr: 0,
+            parent: 0,
+        }
+    }
+
+    pub fn parse(ino: u32, raw: &[u8]) -> Self {
+        Self {
+            ino,
+            mode: le16(raw, 0),
+            nlink: le32(raw, 4),
+            uid: le32(raw, 8),
+            gid: le32(raw, 12),
+            size: le64(raw, 16),
+            atime: time(raw, 24, 48),
+            mtime: time(raw, 32, 52),
+            ctime: time(raw, 40, 56),
+            rdev: le64(raw, 64),
+            blocks: le64(raw, 72),
+            pointers: core::array::from_fn(|i| le32(raw, 80 + i * 4)),
+            xattr: le32(raw, 140),
+            parent: le32(raw, 144),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; INODE_SIZE] {
+        let mut raw = [0; INODE_SIZE];
+        raw[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        raw[4..8].copy_from_slice(&self.nlink.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.uid.to_le_bytes());
+        raw[12..16].copy_from_slice(&self.gid.to_le_bytes());
+        raw[16..24].copy_from_slice(&self.size.to_le_bytes());
+        put_time(&mut raw, 24, 48, self.atime);
+        put_time(&mut raw, 32, 52, self.mtime);
+        put_time(&mut raw, 40, 56, self.ctime);
+        raw[64..72].copy_from_slice(&self.rdev.to_le_bytes());
+        raw[72..80].copy_from_slice(&self.blocks.to_le_bytes());
+        for (i, pointer) in self.pointers.iter().enumerate() {
+            raw[80 + i * 4..84 + i * 4].copy_from_slice(&pointer.to_le_bytes());
+        }
+        raw[140..144].copy_from_slice(&self.xattr.to_le_bytes());
+        raw[144..148].copy_from_slice(&self.parent.to_le_bytes());
+        raw
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        NodeType::from((self.mode >> 12) as u8)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.node_type() == NodeType::Directory
+    }
+
+    pub fn metadata(&self, block_size: u32) -> Metadata {
+        Metadata {
+            device: 0,
+            inode: self.ino as u64,
+            nlink: self.nlink as u64,
+            mode: NodePermission::from_bits_truncate(self.mode & 0o7777),
+            node_type: self.node_type(),
+            uid: self.uid,
+            gid: self.gid,
+            size: self.size,
+            block_size: block_size as u64,
+            blocks: self.blocks * (block_size / 512) as u64,
+            rdev: DeviceId(self.rdev),
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+        }
+    }
+}
+
+/// Where the pointer to a data block is found: a pointer of the inode, and
+/// the indices in the indirect blocks below it.
+struct Path {
+    pointer: usize,
+    depth: usize,
+    indices: [usize; 3],
+}
+
+impl Op<'_> {
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size() as u64 / 4
+    }
+
+    /// Returns the number of data blocks covered by inode pointer `pointer`,
+    /// and the index of the first one.
+    fn span(&self, pointer: usize) -> (u64, u64) {
+        let per = self.pointers_per_block();
+        if pointer < DIRECT {
+            return (1, pointer as u64);
+        }
+        let mut base = DIRECT as u64;
+        let mut span = per;
+        for _ in DIRECT..pointer {
+            base += span;
+            span *= per;
+        }
+        (span, base)
+    }
+
+    fn path(&self, index: u64) -> VfsResult<Path> {
+        let per = self.pointers_per_block();
+        for pointer in 0..POINTERS {
+            let (span, base) = self.span(pointer);
+            if index < base + span {
+                let mut rest = index - base;
+                let depth = pointer.saturating_sub(DIRECT - 1);
+                let mut indices = [0; 3];
+                for level in (0..depth).rev() {
+                    indices[level] = (rest % per) as usize;
+                    rest /= per;
+                }
+                return Ok(Path {
+                    pointer,
+                    depth,
+                    indices,
+                });
+            }
+        }
+        Err(axerrno::LinuxError::EFBIG.into())
+    }
+
+    /// Returns the largest size of a file.
+    pub(super) fn max_file_size(&self) -> u64 {
+        let (span, base) = self.span(POINTERS - 1);
+        (base + span).saturating_mul(self.block_size() as u64)
+    }
+
+    fn pointer(&self, block: u32, index: usize) -> VfsResult<u32> {
+        let mut raw = [0; 4];
+        self.read(block as u64, index * 4, &mut raw)?;
+        Ok(u32::from_le_bytes(raw))
+    }
+
+    fn set_pointer(&mut self, block: u32, index: usize, value: u32) -> VfsResult<()> {
+        self.write(block as u64, index * 4, &value.to_le_bytes())
+    }
+
+    /// Returns the block holding data block `index` of `inode`, or `None`
+    /// for holes.
+    pub(super) fn map(&self, inode: &Inode, index: u64) -> VfsResult<Option<u32>> {
+        let path = self.path(index)?;
+        let mut block = inode.pointers[path.pointer];
+        for &index in &path.indices[..path.depth] {
+            if block == 0 {
+                return Ok(None);
+            }
+            block = self.pointer(block, index)?;
+        }
+        Ok((block != 0).then_some(block))
+    }
+
+    /// Returns the block holding data block `index` of `inode`, allocating
+    /// it and the indirect blocks leading to it if needed.
+    pub(super) fn map_alloc(&mut self, inode: &mut Inode, index: u64) -> VfsResult<u32> {
+        let path = self.path(index)?;
+        if inode.pointers[path.pointer] == 0 {
+            inode.pointers[path.pointer] = self.alloc_block()?;
+            inode.blocks += 1;
+        }
+        let mut block = inode.pointers[path.pointer];
+        for &index in &path.indices[..path.depth] {
+            let mut next = self.pointer(block, index)?;
+            if next == 0 {
+                next = self.alloc_block()?;
+                inode.blocks += 1;
+                self.set_pointer(block, index, next)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Reads data of `inode` at `offset`, within its size.
+    pub(super) fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (buf.len() - done).min(block_size as usize - start);
+            let dst = &mut buf[done..done + len];
+            match self.map(inode, pos / block_size)? {
+                Some(block) => self.read(block as u64, start, dst)?,
+                None => dst.fill(0),
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes data of `inode` at `offset`, allocating blocks as needed but
+    /// leaving its size alone.
+    pub(super) fn write_data(
+        &mut self,
+        inode: &mut Inode,
+        offset: u64,
+        buf: &[u8],
+    ) -> VfsResult<()> {
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (buf.len() - done).min(block_size as usize - start);
+            let block = self.map_alloc(inode, pos / block_size)?;
+            if len == block_size as usize {
+                self.overwrite(block as u64, &buf[done..done + len])?;
+            } else {
+                self.write(block as u64, start, &buf[done..done + len])?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Shrinks `inode` towards `len`, freeing at most [`STEP_BLOCKS`] data
+    /// blocks from the end, and stores it.
+    ///
+    /// Returns whether the size of the inode reached `len`. The bytes of
+    /// the last block past the size are kept zeroed, so that growing the
+    /// inode again reveals zeros.
+    pub(super) fn truncate_step(&mut self, inode: &mut Inode, len: u64) -> VfsResult<bool> {
+        let block_size = self.block_size() as u64;
+        let keep = len.div_ceil(block_size);
+        let mut budget = STEP_BLOCKS;
+        let mut lowest = None;
+        for pointer in (0..POINTERS).rev() {
+            let (span, base) = self.span(pointer);
+            if base + span <= keep || budget == 0 {
+                break;
+            }
+            let block = inode.pointers[pointer];
+            if block == 0 {
+                continue;
+            }
+            let depth = pointer.saturating_sub(DIRECT - 1);
+            let mut prune = Prune {
+                keep,
+                budget: &mut budget,
+                lowest: &mut lowest,
+                blocks: &mut inode.blocks,
+            };
+            if self.prune(block, depth, base, &mut prune)? {
+                inode.pointers[pointer] = 0;
+            }
+        }
+
+        let done = budget > 0;
+        let target = if done {
+            len
+        } else {
+            // Blocks are freed from the end, so everything from the lowest
+            // one freed on is gone.
+            len.max(lowest.unwrap() * block_size)
+        };
+        if target < inode.size {
+            if target % block_size != 0
+                && let Some(block) = self.map(inode, target / block_size)?
+            {
+                let start = (target % block_size) as usize;
+                let zeros = alloc::vec![0; block_size as usize - start];
+                self.write(block as u64, start, &zeros)?;
+            }
+            inode.size = target;
+        }
+        self.store(inode)?;
+        Ok(done)
+    }
+
+    /// Frees the data blocks from `prune.keep` on under `block`, an
+    /// indirect block of `depth` levels whose first data block is `base`,
+    /// from the end.
+    ///
+    /// Returns whether `block` was freed, which it is once it has no
+    /// pointers left.
+    fn prune(&mut self, block: u32, depth: usize, base: u64, prune: &mut Prune) -> VfsResult<bool> {
+        if depth == 0 {
+            if base < prune.keep || *prune.budget == 0 {
+                return Ok(false);
+            }
+            self.free_block(block)?;
+            *prune.budget -= 1;
+            *prune.blocks -= 1;
+            *prune.lowest = Some(base);
+            return Ok(true);
+        }
+        let per = self.pointers_per_block();
+        let span = per.pow(depth as u32 - 1);
+        for index in (0..per as usize).rev() {
+            let child_base = base + index as u64 * span;
+            if child_base + span <= prune.keep || *prune.budget == 0 {
+                break;
+            }
+            let child = self.pointer(block, index)?;
+            if child != 0 && self.prune(child, depth - 1, child_base, prune)? {
+                self.set_pointer(block, index, 0)?;
+            }
+        }
+        let mut data = alloc::vec![0; self.block_size()];
+        self.read(block as u64, 0, &mut data)?;
+        if data.iter().any(|&it| it != 0) {
+            return Ok(false);
+        }
+        self.free_block(block)?;
+        *prune.blocks -= 1;
+        Ok(true)
+    }
+
+    /// Calls `f` with each block of `inode`, indirect blocks included, and
+    /// the index of the data block, or `None` for indirect blocks.
+    pub(super) fn for_each_block(
+        &self,
+        inode: &Inode,
+        f: &mut dyn FnMut(u32, Option<u64>),
+    ) -> VfsResult<()> {
+        for pointer in 0..POINTERS {
+            let (_, base) = self.span(pointer);
+            let depth = pointer.saturating_sub(DIRECT - 1);
+            if inode.pointers[pointer] != 0 {
+                self.walk(inode.pointers[pointer], depth, base, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk(
+        &self,
+        block: u32,
+        depth: usize,
+        base: u64,
+        f: &mut dyn FnMut(u32, Option<u64>),
+    ) -> VfsResult<()> {
+        if depth == 0 {
+            f(block, Some(base));
+            return Ok(());
+        }
+        f(block, None);
+        let per = self.pointers_per_block();
+        let span = per.pow(depth as u32 - 1);
+        for index in 0..per as usize {
+            let child = self.pointer(block, index)?;
+            if child != 0 {
+                if (child as u64) < self.sb().data_start || child as u64 >= self.sb().block_count {
+                    return Err(VfsError::InvalidData);
+                }
+                self.walk(child, depth - 1, base + index as u64 * span, f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State of a truncation step.
+struct Prune<'a> {
+    /// Number of data blocks to keep.
+    keep: u64,
+    /// Number of data blocks left to free in this step.
+    budget: &'a mut u64,
+    /// Index of the lowest data block freed.
+    lowest: &'a mut Option<u64>,
+    /// Number of blocks of the inode.
+    blocks: &'a mut u64,
+}
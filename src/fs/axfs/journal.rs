@@ -0,0 +1,151 @@
+//! The journal, which holds the last committed transaction.
+//!
+//! A transaction is stored at the start of the journal as a descriptor
+//! block listing the home locations of the blocks following it, and a
+//! commit block after them, which is valid only if it matches the
+//! descriptor and the checksum of the blocks.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use super::{AxFs, Blocks, MAX_OP_BLOCKS, State, Superblock, le32, le64};
+use crate::{VfsError, VfsResult, block::BufferCache};
+
+const DESCRIPTOR_MAGIC: u32 = 0x4a58_4144;
+const COMMIT_MAGIC: u32 = 0x4358_4144;
+/// Offset of the home locations in descriptor blocks.
+const DESCRIPTOR_HEADER: usize = 32;
+
+/// Returns the number of blocks a descriptor block can list.
+fn descriptor_capacity(block_size: u32) -> usize {
+    (block_size as usize - DESCRIPTOR_HEADER) / 4
+}
+
+/// Returns the smallest size of a journal, in blocks, which leaves room
+/// for the descriptor and commit blocks and for several operations.
+pub fn min_blocks() -> usize {
+    MAX_OP_BLOCKS * 3 / 2 + 2
+}
+
+/// Returns the largest size of a journal with blocks of `block_size`
+/// bytes, past which journal blocks would never be used.
+pub fn max_blocks(block_size: u32) -> usize {
+    descriptor_capacity(block_size) + 2
+}
+
+/// Returns the number of blocks a transaction can hold.
+pub fn capacity(sb: &Superblock) -> usize {
+    descriptor_capacity(sb.block_size).min(sb.journal_blocks as usize - 2)
+}
+
+/// 64-bit FNV-1a.
+fn checksum<'a>(blocks: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for block in blocks {
+        for &byte in block {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Reads the journal, returning the sequence number of the next transaction
+/// and the blocks of the last committed one that have not reached their
+/// home locations.
+pub fn replay(cache: &BufferCache, sb: &Superblock) -> VfsResult<(u64, Blocks)> {
+    let block_size = sb.block_size as usize;
+    let read = |block: u64| -> VfsResult<Vec<u8>> {
+        let mut data = vec![0; block_size];
+        cache.read_at(block * block_size as u64, &mut data)?;
+        Ok(data)
+    };
+    let descriptor = read(sb.journal_start)?;
+    if le32(&descriptor, 0) != DESCRIPTOR_MAGIC {
+        return Ok((1, BTreeMap::new()));
+    }
+    let seq = le64(&descriptor, 8);
+    let count = le32(&descriptor, 4) as usize;
+    if count == 0 || count > capacity(sb) {
+        return Ok((seq + 1, BTreeMap::new()));
+    }
+    let commit = read(sb.journal_start + 1 + count as u64)?;
+    if le32(&commit, 0) != COMMIT_MAGIC
+        || le32(&commit, 4) as usize != count
+        || le64(&commit, 8) != seq
+    {
+        return Ok((seq + 1, BTreeMap::new()));
+    }
+
+    let mut blocks = Vec::with_capacity(count);
+    for i in 0..count {
+        blocks.push(read(sb.journal_start + 1 + i as u64)?);
+    }
+    let sum =
+        checksum(core::iter::once(descriptor.as_slice()).chain(blocks.iter().map(Vec::as_slice)));
+    if le64(&commit, 16) != sum {
+        return Ok((seq + 1, BTreeMap::new()));
+    }
+    let mut dirty = BTreeMap::new();
+    for (i, data) in blocks.into_iter().enumerate() {
+        let home = le32(&descriptor, DESCRIPTOR_HEADER + i * 4) as u64;
+        if home == 0
+            || home >= sb.block_count
+            || (sb.journal_start..sb.block_bitmap).contains(&home)
+        {
+            return Err(VfsError::InvalidData);
+        }
+        if read(home)? != data {
+            dirty.insert(home, data.into_boxed_slice());
+        }
+    }
+    Ok((seq + 1, dirty))
+}
+
+/// Commits the transaction of `state`.
+///
+/// The blocks are written to the journal, then the commit block, then the
+/// blocks to their home locations, flushing the device after each step. A
+/// power loss before the commit block reaches the device leaves the
+/// previous state, and one after it is recovered by replaying the journal.
+pub fn commit(fs: &AxFs, state: &mut State) -> VfsResult<()> {
+    if state.dirty.is_empty() {
+        return Ok(());
+    }
+    let sb = &fs.sb;
+    let cache = &fs.cache;
+    let block_size = sb.block_size as usize;
+    let position = |block: u64| block * block_size as u64;
+    let count = state.dirty.len();
+
+    let mut descriptor = vec![0; block_size];
+    descriptor[0..4].copy_from_slice(&DESCRIPTOR_MAGIC.to_le_bytes());
+    descriptor[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+    descriptor[8..16].copy_from_slice(&state.seq.to_le_bytes());
+    for (i, &home) in state.dirty.keys().enumerate() {
+        let offset = DESCRIPTOR_HEADER + i * 4;
+        descriptor[offset..offset + 4].copy_from_slice(&(home as u32).to_le_bytes());
+    }
+    cache.write_at(position(sb.journal_start), &descriptor)?;
+    for (i, data) in state.dirty.values().enumerate() {
+        cache.write_at(position(sb.journal_start + 1 + i as u64), data)?;
+    }
+    cache.sync()?;
+
+    let mut commit = vec![0; block_size];
+    commit[0..4].copy_from_slice(&COMMIT_MAGIC.to_le_bytes());
+    commit[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+    commit[8..16].copy_from_slice(&state.seq.to_le_bytes());
+    let sum = checksum(
+        core::iter::once(descriptor.as_slice()).chain(state.dirty.values().map(|it| &**it)),
+    );
+    commit[16..24].copy_from_slice(&sum.to_le_bytes());
+    cache.write_at(position(sb.journal_start + 1 + count as u64), &commit)?;
+    cache.sync()?;
+
+    for (&home, data) in &state.dirty {
+        cache.write_at(position(home), data)?;
+    }
+    cache.sync()?;
+    state.dirty.clear();
+    state.seq += 1;
+    Ok(())
+}
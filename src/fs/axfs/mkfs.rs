@@ -0,0 +1,107 @@
+use alloc::{string::String, sync::Arc, vec};
+
+use super::{ROOT_INO, Superblock, inode::Inode, journal};
+use crate::{
+    NodeType, VfsError, VfsResult,
+    block::{BlockDevice, BufferCache},
+};
+
+/// Options for making an [`AxFs`](super::AxFs) with [`mkfs`].
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    /// Size of blocks in bytes, a power of two from 1024 to 65536.
+    pub block_size: u32,
+    /// Number of inodes, by default one for every 16 KiB.
+    pub inode_count: Option<u32>,
+    /// Size of the journal in blocks, by default as large as a transaction
+    /// can use, within a quarter of the device.
+    pub journal_blocks: Option<u32>,
+    pub uuid: [u8; 16],
+    /// Label of at most 32 bytes.
+    pub label: String,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            inode_count: None,
+            journal_blocks: None,
+            uuid: [0; 16],
+            label: String::new(),
+        }
+    }
+}
+
+/// Makes an empty filesystem on `device`.
+pub fn mkfs(device: Arc<dyn BlockDevice>, options: &MkfsOptions) -> VfsResult<()> {
+    if device.is_read_only() {
+        return Err(VfsError::ReadOnlyFilesystem);
+    }
+    let block_size = options.block_size;
+    if options.label.len() > 32 || !block_size.is_power_of_two() {
+        return Err(VfsError::InvalidInput);
+    }
+    let block_count = (device.len() / block_size as u64).min(u32::MAX as u64);
+    let inode_count = options
+        .inode_count
+        .unwrap_or((block_count * block_size as u64 / 16384).clamp(64, u32::MAX as u64) as u32);
+    let journal_blocks = options.journal_blocks.unwrap_or(
+        (journal::max_blocks(block_size) as u64)
+            .min(block_count / 4)
+            .max(journal::min_blocks() as u64) as u32,
+    );
+    let mut label = [0; 32];
+    label[..options.label.len()].copy_from_slice(options.label.as_bytes());
+    let sb = Superblock {
+        block_size,
+        inode_count,
+        block_count,
+        journal_start: 0,
+        journal_blocks,
+        block_bitmap: 0,
+        inode_bitmap: 0,
+        inode_table: 0,
+        data_start: 0,
+        uuid: options.uuid,
+        label,
+    }
+    .layout()
+    .ok_or(VfsError::InvalidInput)?;
+
+    let cache = BufferCache::new(device, block_size as usize, 64)?;
+    let zero = |block: u64| cache.get_zeroed(block).map(drop);
+    // Clear the superblock first, so that the device is not taken for a
+    // filesystem until it is complete.
+    zero(0)?;
+    cache.sync()?;
+    for block in sb.journal_start..sb.data_start {
+        zero(block)?;
+    }
+
+    // Mark the metadata blocks and the root directory used.
+    for block in 0..sb.data_start {
+        let bits = block_size as u64 * 8;
+        let pos = (sb.block_bitmap + block / bits) * block_size as u64 + block % bits / 8;
+        let mut byte = [0];
+        cache.read_at(pos, &mut byte)?;
+        byte[0] |= 1 << (block % 8);
+        cache.write_at(pos, &byte)?;
+    }
+    cache.write_at(sb.inode_bitmap * block_size as u64, &[1])?;
+    let mut root = Inode::new(
+        ROOT_INO,
+        (NodeType::Directory as u16) << 12 | 0o755,
+        Default::default(),
+    );
+    root.nlink = 2;
+    root.parent = ROOT_INO;
+    let (block, offset) = sb.inode_pos(ROOT_INO);
+    cache.write_at(block * block_size as u64 + offset as u64, &root.to_bytes())?;
+    cache.sync()?;
+
+    let mut superblock = vec![0; block_size as usize];
+    superblock[..sb.to_bytes().len()].copy_from_slice(&sb.to_bytes());
+    cache.write_at(0, &superblock)?;
+    cache.sync()
+}
@@ -0,0 +1,1086 @@
+//! A small journaling filesystem designed around the traits of this crate.
+//!
+//! Every change, data included, is made to an in-memory transaction of
+//! whole blocks. A transaction is committed by writing its blocks to the
+//! journal followed by a commit record, then to their home locations, with
+//! flushes of the device in between, so that after a power loss the
+//! filesystem is found in the state of the last committed transaction once
+//! the journal is replayed at mount time. Transactions are committed when
+//! the filesystem is flushed, when a node is synced, and when they grow
+//! too large for the journal. Each operation, such as a rename, lands in a
+//! single transaction, and is undone in memory if it fails.
+//!
+//! All node types, hard links and extended attributes are supported. Nodes
+//! unlinked while still in use are freed once their last reference is
+//! dropped, or at the next mount if the filesystem was not cleanly unmounted.
+//!
+//! Filesystems are made with [`mkfs`] and checked offline with [`fsck`].
+
+mod dir;
+mod file;
+mod fsck;
+mod inode;
+mod journal;
+mod mkfs;
+mod xattr;
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, sync::Weak, vec, vec::Vec};
+use core::time::Duration;
+
+use hashbrown::{HashMap, HashSet};
+
+use self::inode::Inode;
+pub use self::{
+    dir::AxDir,
+    file::AxFile,
+    fsck::{FsckReport, fsck},
+    mkfs::{MkfsOptions, mkfs},
+};
+use super::{RootDir, le16, le32, le64};
+use crate::{
+    DirEntry, DirNode, FileNode, FilesystemOps, MetadataUpdate, Mutex, NodeType, Reference, StatFs,
+    VfsError, VfsResult,
+    block::{BlockDevice, BufferCache},
+};
+
+const MAGIC: &[u8; 4] = b"AXFS";
+const VERSION: u32 = 1;
+const AXFS_SUPER_MAGIC: u32 = 0x4158_4653;
+const SUPERBLOCK_SIZE: usize = 128;
+const CACHE_CAPACITY: usize = 1024;
+const ROOT_INO: u32 = 1;
+const MAX_NAME_LEN: usize = 255;
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 65536;
+
+/// Number of blocks an operation may change at most, which the journal
+/// must leave room for.
+const MAX_OP_BLOCKS: usize = 128;
+/// Number of data blocks written or freed by each step of long writes and
+/// truncations, which land in separate transactions.
+const STEP_BLOCKS: u64 = 16;
+
+/// Layout of the filesystem, as recorded in the superblock.
+#[derive(Debug, Clone, PartialEq)]
+struct Superblock {
+    block_size: u32,
+    inode_count: u32,
+    block_count: u64,
+    journal_start: u64,
+    journal_blocks: u32,
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    data_start: u64,
+    uuid: [u8; 16],
+    label: [u8; 32],
+}
+
+impl Superblock {
+    fn parse(sb: &[u8]) -> VfsResult<Self> {
+        if &sb[..4] != MAGIC {
+            return Err(VfsError::InvalidData);
+        }
+        if le32(sb, 4) != VERSION {
+            return Err(VfsError::Unsupported);
+        }
+        let superblock = Self {
+            block_size: le32(sb, 8),
+            inode_count: le32(sb, 12),
+            block_count: le64(sb, 16),
+            journal_start: le64(sb, 24),
+            journal_blocks: le32(sb, 32),
+            block_bitmap: le64(sb, 40),
+            inode_bitmap: le64(sb, 48),
+            inode_table: le64(sb, 56),
+            data_start: le64(sb, 64),
+            uuid: sb[72..88].try_into().unwrap(),
+            label: sb[88..120].try_into().unwrap(),
+        };
+        if superblock.layout() != Some(superblock.clone()) {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(superblock)
+    }
+
+    fn to_bytes(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        sb[..4].copy_from_slice(MAGIC);
+        sb[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        sb[8..12].copy_from_slice(&self.block_size.to_le_bytes());
+        sb[12..16].copy_from_slice(&self.inode_count.to_le_bytes());
+        sb[16..24].copy_from_slice(&self.block_count.to_le_bytes());
+        sb[24..32].copy_from_slice(&self.journal_start.to_le_bytes());
+        sb[32..36].copy_from_slice(&self.journal_blocks.to_le_bytes());
+        sb[40..48].copy_from_slice(&self.block_bitmap.to_le_bytes());
+        sb[48..56].copy_from_slice(&self.inode_bitmap.to_le_bytes());
+        sb[56..64].copy_from_slice(&self.inode_table.to_le_bytes());
+        sb[64..72].copy_from_slice(&self.data_start.to_le_bytes());
+        sb[72..88].copy_from_slice(&self.uuid);
+        sb[88..120].copy_from_slice(&self.label);
+        sb
+    }
+
+    /// Computes the layout following the superblock from the block size,
+    /// the number of blocks and inodes and the size of the journal, or
+    /// returns `None` if they do not make a usable filesystem.
+    fn layout(&self) -> Option<Self> {
+        let block_size = self.block_size;
+        if !block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+            || self.inode_count == 0
+            || (self.journal_blocks as usize) < journal::min_blocks()
+            || journal::max_blocks(block_size) < self.journal_blocks as usize
+            || self.block_count > u32::MAX as u64
+        {
+            return None;
+        }
+        let bits = block_size as u64 * 8;
+        let journal_start = 1;
+        let block_bitmap = journal_start + self.journal_blocks as u64;
+        let inode_bitmap = block_bitmap + self.block_count.div_ceil(bits);
+        let inode_table = inode_bitmap + (self.inode_count as u64).div_ceil(bits);
+        let data_start = inode_table
+            + (self.inode_count as u64 * inode::INODE_SIZE as u64).div_ceil(block_size as u64);
+        if data_start >= self.block_count {
+            return None;
+        }
+        Some(Self {
+            journal_start,
+            block_bitmap,
+            inode_bitmap,
+            inode_table,
+            data_start,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the position of the inode `ino` in the inode table, as a
+    /// block and an offset.
+    fn inode_pos(&self, ino: u32) -> (u64, usize) {
+        let pos = (ino - 1) as u64 * inode::INODE_SIZE as u64;
+        (
+            self.inode_table + pos / self.block_size as u64,
+            (pos % self.block_size as u64) as usize,
+        )
+    }
+}
+
+/// Options for mounting an [`AxFs`].
+#[derive(Debug, Clone, Default)]
+pub struct AxFsOptions {
+    /// Source of the current time, used to timestamp created and modified
+    /// nodes.
+    pub clock: Option<fn() -> Duration>,
+}
+
+/// Allocation state, counted from the bitmaps at mount time.
+#[derive(Debug, Clone, Copy)]
+struct Counters {
+    free_blocks: u64,
+    free_inodes: u32,
+    /// Where to start looking for free blocks and inodes.
+    next_block: u64,
+    next_inode: u32,
+}
+
+/// Contents of blocks, keyed by their position.
+type Blocks = BTreeMap<u64, Box<[u8]>>;
+
+struct State {
+    /// Blocks changed since the last commit.
+    dirty: Blocks,
+    /// Sequence number of the next transaction.
+    seq: u64,
+    counters: Counters,
+}
+
+/// A live inode. Inodes without links are freed once their last handle
+/// is dropped.
+struct Handle {
+    fs: Weak<AxFs>,
+    ino: u32,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if let Some(fs) = self.fs.upgrade()
+            && !fs.cache.device().is_read_only()
+        {
+            fs.released.lock().push(self.ino);
+        }
+    }
+}
+
+/// A filesystem made by [`mkfs`] on a block device.
+pub struct AxFs {
+    cache: BufferCache,
+    sb: Superblock,
+    options: AxFsOptions,
+    state: Mutex<State>,
+    handles: Mutex<HashMap<u32, Weak<Handle>>>,
+    /// Inodes which may have lost their last link or handle.
+    released: Mutex<Vec<u32>>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl AxFs {
+    /// Mounts the filesystem on `device` with default options.
+    pub fn new(device: Arc<dyn BlockDevice>) -> VfsResult<Arc<Self>> {
+        Self::with_options(device, AxFsOptions::default())
+    }
+
+    /// Mounts the filesystem on `device`, replaying its journal and freeing
+    /// the nodes left without links.
+    pub fn with_options(
+        device: Arc<dyn BlockDevice>,
+        options: AxFsOptions,
+    ) -> VfsResult<Arc<Self>> {
+        let fs = Self::load(device, options)?;
+        let writable = !fs.cache.device().is_read_only();
+        if writable {
+            // Get the replayed transaction to its home locations.
+            fs.commit()?;
+        }
+
+        if !fs.inspect(|op| op.inode(ROOT_INO))?.is_dir() {
+            return Err(VfsError::InvalidData);
+        }
+        let fs = Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            ..fs
+        });
+        if writable {
+            let orphans = fs.inspect(|op| op.orphans())?;
+            fs.released.lock().extend(orphans);
+            fs.reap()?;
+            fs.commit()?;
+        }
+        Ok(fs)
+    }
+
+    /// Reads the filesystem on `device`, with the last committed
+    /// transaction of the journal pending in memory.
+    fn load(device: Arc<dyn BlockDevice>, options: AxFsOptions) -> VfsResult<Self> {
+        let mut sb = vec![0; device.sector_size().max(SUPERBLOCK_SIZE)];
+        if device.len() < sb.len() as u64 {
+            return Err(VfsError::InvalidData);
+        }
+        device.read_sectors(0, &mut sb)?;
+        let sb = Superblock::parse(&sb)?;
+        if sb.block_count * sb.block_size as u64 > device.len() {
+            return Err(VfsError::InvalidData);
+        }
+        let cache = BufferCache::new(device, sb.block_size as usize, CACHE_CAPACITY)
+            .map_err(|_| VfsError::InvalidData)?;
+        let (seq, dirty) = journal::replay(&cache, &sb)?;
+        let fs = Self {
+            cache,
+            sb,
+            options,
+            state: Mutex::new(State {
+                dirty,
+                seq,
+                counters: Counters {
+                    free_blocks: 0,
+                    free_inodes: 0,
+                    next_block: 0,
+                    next_inode: 0,
+                },
+            }),
+            handles: Mutex::default(),
+            released: Mutex::default(),
+            this: Weak::new(),
+            root: RootDir::default(),
+        };
+        let (free_blocks, free_inodes) = fs.inspect(|op| {
+            Ok((
+                op.count_free(fs.sb.block_bitmap, fs.sb.block_count)?,
+                op.count_free(fs.sb.inode_bitmap, fs.sb.inode_count as u64)? as u32,
+            ))
+        })?;
+        let mut state = fs.state.lock();
+        state.counters.free_blocks = free_blocks;
+        state.counters.free_inodes = free_inodes;
+        state.counters.next_block = fs.sb.data_start;
+        drop(state);
+        Ok(fs)
+    }
+
+    fn block_size(&self) -> usize {
+        self.sb.block_size as usize
+    }
+
+    fn check_writable(&self) -> VfsResult<()> {
+        if self.cache.device().is_read_only() {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> Duration {
+        self.options.clock.map_or(Duration::ZERO, |clock| clock())
+    }
+
+    fn update_metadata(&self, ino: u32, update: MetadataUpdate) -> VfsResult<()> {
+        self.modify(|op| {
+            let mut inode = op.inode(ino)?;
+            if let Some(mode) = update.mode {
+                inode.mode = (inode.mode & !0o7777) | (mode.bits() & 0o7777);
+            }
+            if let Some((uid, gid)) = update.owner {
+                inode.uid = uid;
+                inode.gid = gid;
+            }
+            if let Some(rdev) = update.rdev {
+                inode.rdev = rdev.0;
+            }
+            if let Some(atime) = update.atime {
+                inode.atime = atime;
+            }
+            if let Some(mtime) = update.mtime {
+                inode.mtime = mtime;
+            }
+            inode.ctime = self.now();
+            op.store(&inode)
+        })
+    }
+
+    /// Commits the pending transaction.
+    fn commit(&self) -> VfsResult<()> {
+        journal::commit(self, &mut self.state.lock())
+    }
+
+    /// Runs `f`, which only reads the filesystem.
+    fn inspect<R>(&self, f: impl FnOnce(&Op) -> VfsResult<R>) -> VfsResult<R> {
+        let mut state = self.state.lock();
+        f(&Op::new(self, &mut state))
+    }
+
+    /// Runs `f` as a single operation, whose changes are undone if it fails.
+    fn run<R>(&self, f: impl FnOnce(&mut Op) -> VfsResult<R>) -> VfsResult<R> {
+        let mut state = self.state.lock();
+        if state.dirty.len() + MAX_OP_BLOCKS > journal::capacity(&self.sb) {
+            journal::commit(self, &mut state)?;
+        }
+        let counters = state.counters;
+        let mut op = Op::new(self, &mut state);
+        let result = f(&mut op);
+        if result.is_err() {
+            let undo = core::mem::take(&mut op.undo);
+            for (block, data) in undo.into_iter().rev() {
+                match data {
+                    Some(data) => state.dirty.insert(block, data),
+                    None => state.dirty.remove(&block),
+                };
+            }
+            state.counters = counters;
+        }
+        result
+    }
+
+    /// Runs `f` as a single operation changing the filesystem, and frees
+    /// the nodes it left without links.
+    fn modify<R>(&self, f: impl FnOnce(&mut Op) -> VfsResult<R>) -> VfsResult<R> {
+        self.check_writable()?;
+        let result = self.run(f)?;
+        self.reap()?;
+        Ok(result)
+    }
+
+    /// Frees the released inodes that have neither links nor handles.
+    fn reap(&self) -> VfsResult<()> {
+        loop {
+            let Some(ino) = self.released.lock().pop() else {
+                return Ok(());
+            };
+            {
+                let mut handles = self.handles.lock();
+                if handles.get(&ino).is_some_and(|it| it.strong_count() > 0) {
+                    continue;
+                }
+                handles.remove(&ino);
+            }
+            // Large files are truncated in several transactions, each of
+            // which leaves the inode consistent.
+            while !self.run(|op| op.evict_step(ino))? {}
+        }
+    }
+
+    /// Returns the handle of inode `ino`.
+    fn handle(self: &Arc<Self>, ino: u32) -> Arc<Handle> {
+        let mut handles = self.handles.lock();
+        if let Some(handle) = handles.get(&ino).and_then(Weak::upgrade) {
+            return handle;
+        }
+        let handle = Arc::new(Handle {
+            fs: Arc::downgrade(self),
+            ino,
+        });
+        handles.insert(ino, Arc::downgrade(&handle));
+        handle
+    }
+
+    /// Opens inode `ino` as the entry `reference`.
+    fn new_root(&self) -> DirEntry {
+        let fs = self.this.upgrade().unwrap();
+        let handle = fs.handle(ROOT_INO);
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(AxDir::new(fs, handle, this))),
+            Reference::root(),
+        )
+    }
+
+    fn open(self: &Arc<Self>, ino: u32, reference: Reference) -> VfsResult<DirEntry> {
+        let node_type = self.inspect(|op| op.inode(ino))?.node_type();
+        let handle = self.handle(ino);
+        let fs = self.clone();
+        Ok(match node_type {
+            NodeType::Directory => DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(AxDir::new(fs, handle, this))),
+                reference,
+            ),
+            _ => DirEntry::new_file(
+                FileNode::new(Arc::new(AxFile::new(fs, handle))),
+                node_type,
+                reference,
+            ),
+        })
+    }
+}
+
+impl FilesystemOps for AxFs {
+    fn name(&self) -> &str {
+        "axfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let counters = self.state.lock().counters;
+        Ok(StatFs {
+            fs_type: AXFS_SUPER_MAGIC,
+            block_size: self.sb.block_size,
+            blocks: self.sb.block_count,
+            blocks_free: counters.free_blocks,
+            blocks_available: counters.free_blocks,
+            file_count: self.sb.inode_count as u64,
+            free_file_count: counters.free_inodes as u64,
+            name_length: MAX_NAME_LEN as u32,
+            fragment_size: self.sb.block_size,
+            mount_flags: 0,
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        if self.cache.device().is_read_only() {
+            return Ok(());
+        }
+        self.reap()?;
+        self.commit()
+    }
+}
+
+/// An operation in progress, which has the filesystem locked.
+struct Op<'a> {
+    fs: &'a AxFs,
+    state: &'a mut State,
+    /// Previous states of the blocks changed by the operation, with `None`
+    /// for blocks that were not dirty.
+    undo: Vec<(u64, Option<Box<[u8]>>)>,
+    touched: HashSet<u64>,
+}
+
+impl<'a> Op<'a> {
+    fn new(fs: &'a AxFs, state: &'a mut State) -> Self {
+        Self {
+            fs,
+            state,
+            undo: Vec::new(),
+            touched: HashSet::new(),
+        }
+    }
+
+    fn sb(&self) -> &Superblock {
+        &self.fs.sb
+    }
+
+    fn block_size(&self) -> usize {
+        self.fs.block_size()
+    }
+
+    /// Reads `buf.len()` bytes at `offset` in block `block`.
+    fn read(&self, block: u64, offset: usize, buf: &mut [u8]) -> VfsResult<()> {
+        if block >= self.sb().block_count || offset + buf.len() > self.block_size() {
+            return Err(VfsError::InvalidData);
+        }
+        match self.state.dirty.get(&block) {
+            Some(data) => {
+                buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                Ok(())
+            }
+            None => self
+                .fs
+                .cache
+                .read_at(block * self.block_size() as u64 + offset as u64, buf),
+        }
+    }
+
+    fn read_block(&self, block: u64) -> VfsResult<Vec<u8>> {
+        let mut data = vec![0; self.block_size()];
+        self.read(block, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Changes block `block` with `f`.
+    fn update<R>(&mut self, block: u64, f: impl FnOnce(&mut [u8]) -> R) -> VfsResult<R> {
+        self.dirty(block, false)?;
+        Ok(f(self.state.dirty.get_mut(&block).unwrap()))
+    }
+
+    /// Writes `data` at `offset` in block `block`.
+    fn write(&mut self, block: u64, offset: usize, data: &[u8]) -> VfsResult<()> {
+        if offset + data.len() > self.block_size() {
+            return Err(VfsError::InvalidData);
+        }
+        self.update(block, |it| {
+            it[offset..offset + data.len()].copy_from_slice(data)
+        })
+    }
+
+    /// Replaces the contents of block `block` with `data`.
+    fn overwrite(&mut self, block: u64, data: &[u8]) -> VfsResult<()> {
+        self.dirty(block, true)?;
+        self.state
+            .dirty
+            .get_mut(&block)
+            .unwrap()
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Fills block `block` with zeros.
+    fn zero(&mut self, block: u64) -> VfsResult<()> {
+        self.dirty(block, true)?;
+        self.state.dirty.get_mut(&block).unwrap().fill(0);
+        Ok(())
+    }
+
+    /// Adds block `block` to the transaction, reading it unless it is about
+    /// to be overwritten.
+    fn dirty(&mut self, block: u64, overwrite: bool) -> VfsResult<()> {
+        if block >= self.sb().block_count {
+            return Err(VfsError::InvalidData);
+        }
+        if self.touched.insert(block) {
+            let previous = self.state.dirty.get(&block).cloned();
+            if previous.is_none() {
+                if self.state.dirty.len() >= journal::capacity(self.sb()) {
+                    self.touched.remove(&block);
+                    return Err(VfsError::StorageFull);
+                }
+                let mut data = vec![0; self.block_size()].into_boxed_slice();
+                if !overwrite {
+                    self.read(block, 0, &mut data)?;
+                }
+                self.state.dirty.insert(block, data);
+            }
+            self.undo.push((block, previous));
+        }
+        Ok(())
+    }
+
+    /// Counts the clear bits among the first `count` bits of the bitmap
+    /// starting at block `start`.
+    fn count_free(&self, start: u64, count: u64) -> VfsResult<u64> {
+        let bits = self.block_size() as u64 * 8;
+        let mut free = 0;
+        for index in 0..count.div_ceil(bits) {
+            let data = self.read_block(start + index)?;
+            let len = (count - index * bits).min(bits);
+            free += (0..len)
+                .filter(|&bit| data[(bit / 8) as usize] & (1 << (bit % 8)) == 0)
+                .count() as u64;
+        }
+        Ok(free)
+    }
+
+    fn bit(&self, start: u64, index: u64) -> VfsResult<bool> {
+        let bits = self.block_size() as u64 * 8;
+        let mut byte = [0];
+        self.read(start + index / bits, (index % bits / 8) as usize, &mut byte)?;
+        Ok(byte[0] & (1 << (index % 8)) != 0)
+    }
+
+    fn set_bit(&mut self, start: u64, index: u64, value: bool) -> VfsResult<()> {
+        let bits = self.block_size() as u64 * 8;
+        let offset = (index % bits / 8) as usize;
+        self.update(start + index / bits, |data| {
+            if value {
+                data[offset] |= 1 << (index % 8);
+            } else {
+                data[offset] &= !(1 << (index % 8));
+            }
+        })
+    }
+
+    /// Finds a clear bit among the first `count` bits of the bitmap at
+    /// `start`, looking from `hint` onwards first.
+    fn find_clear(&self, start: u64, count: u64, hint: u64) -> VfsResult<Option<u64>> {
+        let bits = self.block_size() as u64 * 8;
+        let hint = if hint < count { hint } else { 0 };
+        for index in (hint..count).chain(0..hint) {
+            // Whole bytes of set bits are skipped.
+            if index % 8 == 0 && index + 8 <= count {
+                let mut byte = [0];
+                self.read(start + index / bits, (index % bits / 8) as usize, &mut byte)?;
+                if byte[0] == 0xff {
+                    continue;
+                }
+            }
+            if !self.bit(start, index)? {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Allocates a block filled with zeros.
+    fn alloc_block(&mut self) -> VfsResult<u32> {
+        let sb = self.sb().clone();
+        if self.state.counters.free_blocks == 0 {
+            return Err(VfsError::StorageFull);
+        }
+        let block = self
+            .find_clear(
+                sb.block_bitmap,
+                sb.block_count,
+                self.state.counters.next_block,
+            )?
+            .filter(|&it| it >= sb.data_start)
+            .ok_or(VfsError::StorageFull)?;
+        self.set_bit(sb.block_bitmap, block, true)?;
+        self.zero(block)?;
+        self.state.counters.free_blocks -= 1;
+        self.state.counters.next_block = block + 1;
+        Ok(block as u32)
+    }
+
+    fn free_block(&mut self, block: u32) -> VfsResult<()> {
+        let sb = self.sb().clone();
+        let block = block as u64;
+        if !(sb.data_start..sb.block_count).contains(&block) || !self.bit(sb.block_bitmap, block)? {
+            return Err(VfsError::InvalidData);
+        }
+        self.set_bit(sb.block_bitmap, block, false)?;
+        self.state.counters.free_blocks += 1;
+        Ok(())
+    }
+
+    fn inode(&self, ino: u32) -> VfsResult<Inode> {
+        if ino == 0 || ino > self.sb().inode_count {
+            return Err(VfsError::InvalidData);
+        }
+        let (block, offset) = self.sb().inode_pos(ino);
+        let mut raw = [0; inode::INODE_SIZE];
+        self.read(block, offset, &mut raw)?;
+        let inode = Inode::parse(ino, &raw);
+        if inode.mode == 0 {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(inode)
+    }
+
+    fn store(&mut self, inode: &Inode) -> VfsResult<()> {
+        let (block, offset) = self.sb().inode_pos(inode.ino);
+        self.write(block, offset, &inode.to_bytes())
+    }
+
+    /// Allocates an inode of type `mode`.
+    fn alloc_inode(&mut self, mode: u16) -> VfsResult<Inode> {
+        let sb = self.sb().clone();
+        if self.state.counters.free_inodes == 0 {
+            return Err(VfsError::StorageFull);
+        }
+        let index = self
+            .find_clear(
+                sb.inode_bitmap,
+                sb.inode_count as u64,
+                self.state.counters.next_inode as u64,
+            )?
+            .ok_or(VfsError::StorageFull)?;
+        self.set_bit(sb.inode_bitmap, index, true)?;
+        self.state.counters.free_inodes -= 1;
+        self.state.counters.next_inode = index as u32 + 1;
+        let now = self.fs.now();
+        let inode = Inode::new(index as u32 + 1, mode, now);
+        self.store(&inode)?;
+        Ok(inode)
+    }
+
+    fn free_inode(&mut self, ino: u32) -> VfsResult<()> {
+        let sb = self.sb().clone();
+        let (block, offset) = sb.inode_pos(ino);
+        self.write(block, offset, &[0; inode::INODE_SIZE])?;
+        self.set_bit(sb.inode_bitmap, (ino - 1) as u64, false)?;
+        self.state.counters.free_inodes += 1;
+        Ok(())
+    }
+
+    /// Returns the allocated inodes without links.
+    fn orphans(&self) -> VfsResult<Vec<u32>> {
+        let mut orphans = Vec::new();
+        for ino in 1..=self.sb().inode_count {
+            if self.bit(self.sb().inode_bitmap, (ino - 1) as u64)? {
+                let inode = self.inode(ino)?;
+                if inode.nlink == 0 {
+                    orphans.push(ino);
+                }
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Frees part of inode `ino` if it has no links, returning whether it
+    /// is done.
+    fn evict_step(&mut self, ino: u32) -> VfsResult<bool> {
+        let Ok(mut inode) = self.inode(ino) else {
+            return Ok(true);
+        };
+        if inode.nlink != 0 {
+            return Ok(true);
+        }
+        if !self.truncate_step(&mut inode, 0)? {
+            return Ok(false);
+        }
+        self.free_xattrs(&mut inode)?;
+        self.free_inode(ino)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{string::String, vec::Vec};
+    use core::{
+        any::Any,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        Filesystem, Location, Mountpoint, NodePermission, XattrFlags, block::RamDisk,
+        test_util::list,
+    };
+
+    fn format(size: usize, block_size: u32) -> Arc<RamDisk> {
+        let disk = Arc::new(RamDisk::new(size));
+        let options = MkfsOptions {
+            block_size,
+            label: String::from("data"),
+            ..Default::default()
+        };
+        mkfs(disk.clone(), &options).unwrap();
+        disk
+    }
+
+    fn mount(device: Arc<dyn BlockDevice>) -> (Arc<AxFs>, Location) {
+        let fs = AxFs::new(device).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+        (fs, root)
+    }
+
+    fn read_all(file: &Location) -> Vec<u8> {
+        let file = file.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    fn check(disk: &RamDisk) -> FsckReport {
+        let report = fsck(Arc::new(RamDisk::from_vec(disk.to_vec()))).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        report
+    }
+
+    /// A device losing the writes made after a number of flushes, as on a
+    /// power loss.
+    struct Crashing {
+        disk: Arc<RamDisk>,
+        flushes: AtomicUsize,
+    }
+
+    impl BlockDevice for Crashing {
+        fn num_sectors(&self) -> u64 {
+            self.disk.num_sectors()
+        }
+
+        fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> VfsResult<()> {
+            self.disk.read_sectors(sector, buf)
+        }
+
+        fn write_sectors(&self, sector: u64, buf: &[u8]) -> VfsResult<()> {
+            if self.flushes.load(Ordering::Relaxed) > 0 {
+                self.disk.write_sectors(sector, buf)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&self) -> VfsResult<()> {
+            let _ = self
+                .flushes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |it| it.checked_sub(1));
+            Ok(())
+        }
+
+        fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+            self
+        }
+    }
+
+    #[test]
+    fn test_axfs() {
+        let disk = format(8 << 20, 1024);
+        let info = crate::block::probe(&*disk).unwrap().unwrap();
+        assert_eq!(
+            (info.fs_type, info.label.as_deref()),
+            ("axfs", Some("data"))
+        );
+        let (fs, root) = mount(disk.clone());
+        let free = fs.stat().unwrap().blocks_free;
+        let perm = NodePermission::from_bits_truncate(0o755);
+
+        let dir = root.create("dir", NodeType::Directory, perm).unwrap();
+        let sub = dir.create("sub", NodeType::Directory, perm).unwrap();
+        let file = dir.create("file", NodeType::RegularFile, perm).unwrap();
+        // Spans the double indirect block.
+        let data: Vec<u8> = (0..400_000u32).map(|it| (it % 251) as u8).collect();
+        let handle = file.entry().as_file().unwrap();
+        handle.write_at(&data, 1000).unwrap();
+        assert_eq!(handle.append(b"tail").unwrap(), (4, 401_004));
+        let mut expected = vec![0; 1000];
+        expected.extend_from_slice(&data);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(read_all(&file), expected);
+
+        for node_type in [
+            NodeType::Symlink,
+            NodeType::Fifo,
+            NodeType::Socket,
+            NodeType::CharacterDevice,
+            NodeType::BlockDevice,
+        ] {
+            let name = alloc::format!("{node_type:?}");
+            let node = root.create(&name, node_type, perm).unwrap();
+            assert_eq!(node.node_type(), node_type);
+        }
+        let link = root.lookup_no_follow("Symlink").unwrap();
+        link.entry()
+            .as_file()
+            .unwrap()
+            .set_symlink("dir/file")
+            .unwrap();
+        assert_eq!(link.read_link().unwrap(), "dir/file");
+
+        let hard = root.link("hard", &file).unwrap();
+        assert_eq!(hard.inode(), file.inode());
+        assert_eq!(file.metadata().unwrap().nlink, 2);
+        assert_eq!(
+            root.link("dirlink", &dir).err(),
+            Some(VfsError::OperationNotPermitted)
+        );
+        assert_eq!(
+            root.create("dir", NodeType::RegularFile, perm).err(),
+            Some(VfsError::AlreadyExists)
+        );
+
+        // Replacing an entry drops a link from the node it named.
+        dir.rename("file", &root, "Fifo").unwrap();
+        assert_eq!(root.lookup_no_follow("Fifo").unwrap().inode(), file.inode());
+        assert_eq!(
+            root.rename("dir", &sub, "loop").err(),
+            Some(VfsError::InvalidInput)
+        );
+        dir.rename("sub", &root, "sub").unwrap();
+        assert_eq!(dir.metadata().unwrap().nlink, 2);
+        assert_eq!(root.metadata().unwrap().nlink, 4);
+        assert_eq!(
+            root.unlink("sub", false).err(),
+            Some(VfsError::IsADirectory)
+        );
+        root.unlink("sub", true).unwrap();
+        root.unlink("hard", false).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink, 1);
+
+        let mut names = list(&root);
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ".",
+                "..",
+                "BlockDevice",
+                "CharacterDevice",
+                "Fifo",
+                "Socket",
+                "Symlink",
+                "dir"
+            ]
+        );
+        assert!(fs.stat().unwrap().blocks_free < free);
+        fs.flush().unwrap();
+        check(&disk);
+        drop((fs, root, dir, sub, file, hard, link));
+
+        let (fs, root) = mount(disk.clone());
+        assert_eq!(read_all(&root.lookup_no_follow("Fifo").unwrap()), expected);
+        let file = root.lookup_no_follow("Fifo").unwrap();
+        file.entry().as_file().unwrap().set_len(10).unwrap();
+        assert_eq!(read_all(&file), &expected[..10]);
+        file.entry().as_file().unwrap().set_len(20).unwrap();
+        assert_eq!(read_all(&file), [&expected[..10], &[0; 10]].concat());
+        root.unlink("Fifo", false).unwrap();
+        drop(file);
+        fs.flush().unwrap();
+        // Everything but the symlink target and the directory blocks is back.
+        assert_eq!(fs.stat().unwrap().blocks_free, free - 3);
+        let report = check(&disk);
+        assert_eq!(report.inodes, 6);
+    }
+
+    #[test]
+    fn test_xattr() {
+        let disk = format(4 << 20, 1024);
+        let (fs, root) = mount(disk.clone());
+        let perm = NodePermission::default();
+        let file = root.create("file", NodeType::RegularFile, perm).unwrap();
+        let link = root.create("link", NodeType::Symlink, perm).unwrap();
+        let free = fs.stat().unwrap().blocks_free;
+        let no_data = Some(VfsError::from(axerrno::LinuxError::ENODATA));
+
+        file.set_xattr("user.a", b"1", XattrFlags::CREATE).unwrap();
+        assert_eq!(
+            file.set_xattr("user.a", b"2", XattrFlags::CREATE).err(),
+            Some(VfsError::AlreadyExists)
+        );
+        assert_eq!(
+            file.set_xattr("user.b", b"2", XattrFlags::REPLACE).err(),
+            no_data
+        );
+        file.set_xattr("user.a", b"22", XattrFlags::REPLACE)
+            .unwrap();
+        file.set_xattr("security.b", b"", XattrFlags::empty())
+            .unwrap();
+        assert_eq!(file.get_xattr("user.a").unwrap(), b"22");
+        assert_eq!(file.get_xattr("security.b").unwrap(), b"");
+        assert_eq!(file.list_xattr().unwrap(), ["user.a", "security.b"]);
+        assert_eq!(file.get_xattr("user.c").err(), no_data);
+
+        assert_eq!(
+            file.get_xattr("other.a").err(),
+            Some(VfsError::OperationNotSupported)
+        );
+        assert_eq!(file.get_xattr("user.").err(), Some(VfsError::InvalidInput));
+        let long = alloc::format!("user.{}", "a".repeat(251));
+        assert_eq!(file.get_xattr(&long).err(), Some(VfsError::OutOfRange));
+        assert_eq!(
+            file.set_xattr("user.big", &[0; 65537], XattrFlags::empty())
+                .err(),
+            Some(VfsError::ArgumentListTooLong)
+        );
+        assert_eq!(
+            file.set_xattr("user.big", &[0; 1024], XattrFlags::empty())
+                .err(),
+            Some(VfsError::StorageFull)
+        );
+        assert_eq!(
+            link.set_xattr("user.a", b"1", XattrFlags::empty()).err(),
+            Some(VfsError::OperationNotPermitted)
+        );
+        link.set_xattr("trusted.a", b"1", XattrFlags::empty())
+            .unwrap();
+        fs.flush().unwrap();
+        check(&disk);
+
+        file.remove_xattr("user.a").unwrap();
+        file.remove_xattr("security.b").unwrap();
+        assert_eq!(file.remove_xattr("user.a").err(), no_data);
+        assert!(file.list_xattr().unwrap().is_empty());
+        // The block of the symlink goes with it.
+        root.unlink("link", false).unwrap();
+        drop(link);
+        fs.flush().unwrap();
+        assert_eq!(fs.stat().unwrap().blocks_free, free);
+        check(&disk);
+    }
+
+    #[test]
+    fn test_crash() {
+        let disk = format(4 << 20, 4096);
+        let (fs, root) = mount(disk.clone());
+        let perm = NodePermission::default();
+        root.create("old", NodeType::RegularFile, perm).unwrap();
+        fs.flush().unwrap();
+        drop((fs, root));
+
+        // A commit flushes the device three times: after writing to the
+        // journal, after the commit block and after writing back.
+        for (flushes, committed) in [(0, false), (1, false), (2, true), (3, true)] {
+            let copy = Arc::new(RamDisk::from_vec(disk.to_vec()));
+            let device = Arc::new(Crashing {
+                disk: copy.clone(),
+                flushes: AtomicUsize::new(flushes),
+            });
+            let (fs, root) = mount(device);
+            let new = root.create("new", NodeType::RegularFile, perm).unwrap();
+            new.entry().as_file().unwrap().write_at(b"data", 0).unwrap();
+            root.rename("old", &root, "renamed").unwrap();
+            fs.flush().unwrap();
+            drop((fs, root, new));
+
+            check(&copy);
+            let (_fs, root) = mount(copy.clone());
+            let mut names = list(&root);
+            names.sort();
+            if committed {
+                assert_eq!(names, [".", "..", "new", "renamed"]);
+                assert_eq!(read_all(&root.lookup_no_follow("new").unwrap()), b"data");
+            } else {
+                assert_eq!(names, [".", "..", "old"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_orphans() {
+        let disk = format(4 << 20, 4096);
+        let (fs, root) = mount(disk.clone());
+        let free = fs.stat().unwrap().blocks_free;
+        let file = root
+            .create("file", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        file.entry()
+            .as_file()
+            .unwrap()
+            .write_at(&[1; 100_000], 0)
+            .unwrap();
+        root.unlink("file", false).unwrap();
+        // The node lives on while in use.
+        assert_eq!(read_all(&file), [1; 100_000]);
+        fs.flush().unwrap();
+        let report = check(&disk);
+        assert_eq!(report.orphans, [file.inode() as u32]);
+
+        // As if the system crashed, the node is freed at the next mount.
+        let (fs2, _) = mount(Arc::new(RamDisk::from_vec(disk.to_vec())));
+        assert_eq!(fs2.stat().unwrap().blocks_free, free - 1);
+
+        drop(file);
+        fs.flush().unwrap();
+        assert_eq!(fs.stat().unwrap().blocks_free, free - 1);
+        assert!(check(&disk).orphans.is_empty());
+    }
+}
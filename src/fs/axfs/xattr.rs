@@ -0,0 +1,152 @@
+//! Extended attributes, kept in a single block per inode.
+//!
+//! The block holds a sequence of entries made of the length of the name as
+//! a byte, the length of the value as two little-endian bytes, the name
+//! and the value, ended by an entry with an empty name.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{AxFs, Op, inode::Inode, le16};
+use crate::{VfsError, VfsResult, XattrFlags};
+
+/// Size of the header of entries.
+const HEADER_SIZE: usize = 3;
+
+type Xattrs = Vec<(String, Vec<u8>)>;
+
+fn parse(data: &[u8]) -> VfsResult<Xattrs> {
+    let mut xattrs = Vec::new();
+    let mut pos = 0;
+    loop {
+        let header = data
+            .get(pos..pos + HEADER_SIZE)
+            .ok_or(VfsError::InvalidData)?;
+        let name_len = header[0] as usize;
+        if name_len == 0 {
+            return Ok(xattrs);
+        }
+        let value_len = le16(header, 1) as usize;
+        pos += HEADER_SIZE;
+        let name = data.get(pos..pos + name_len).ok_or(VfsError::InvalidData)?;
+        let value = data
+            .get(pos + name_len..pos + name_len + value_len)
+            .ok_or(VfsError::InvalidData)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| VfsError::InvalidData)?;
+        xattrs.push((name, value.to_vec()));
+        pos += name_len + value_len;
+    }
+}
+
+fn encode(xattrs: &Xattrs, block_size: usize) -> VfsResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(block_size);
+    for (name, value) in xattrs {
+        let value_len = u16::try_from(value.len()).map_err(|_| VfsError::StorageFull)?;
+        data.push(name.len() as u8);
+        data.extend_from_slice(&value_len.to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(value);
+    }
+    if data.len() + HEADER_SIZE > block_size {
+        return Err(VfsError::StorageFull);
+    }
+    data.resize(block_size, 0);
+    Ok(data)
+}
+
+fn no_data() -> VfsError {
+    axerrno::LinuxError::ENODATA.into()
+}
+
+impl Op<'_> {
+    fn xattrs(&self, inode: &Inode) -> VfsResult<Xattrs> {
+        if inode.xattr == 0 {
+            return Ok(Vec::new());
+        }
+        if (inode.xattr as u64) < self.sb().data_start {
+            return Err(VfsError::InvalidData);
+        }
+        parse(&self.read_block(inode.xattr as u64)?)
+    }
+
+    /// Replaces the extended attributes of `inode`, allocating or freeing
+    /// its block as needed.
+    fn store_xattrs(&mut self, inode: &mut Inode, xattrs: &Xattrs) -> VfsResult<()> {
+        if xattrs.is_empty() {
+            self.free_xattrs(inode)?;
+        } else {
+            let data = encode(xattrs, self.block_size())?;
+            if inode.xattr == 0 {
+                inode.xattr = self.alloc_block()?;
+                inode.blocks += 1;
+            }
+            self.overwrite(inode.xattr as u64, &data)?;
+        }
+        inode.ctime = self.fs.now();
+        self.store(inode)
+    }
+
+    /// Frees the block of the extended attributes of `inode`, without
+    /// storing it.
+    pub(super) fn free_xattrs(&mut self, inode: &mut Inode) -> VfsResult<()> {
+        if inode.xattr != 0 {
+            self.free_block(inode.xattr)?;
+            inode.xattr = 0;
+            inode.blocks -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl AxFs {
+    pub(super) fn get_xattr(&self, ino: u32, name: &str) -> VfsResult<Vec<u8>> {
+        self.inspect(|op| {
+            let inode = op.inode(ino)?;
+            op.xattrs(&inode)?
+                .into_iter()
+                .find_map(|(key, value)| (key == name).then_some(value))
+                .ok_or_else(no_data)
+        })
+    }
+
+    pub(super) fn set_xattr(
+        &self,
+        ino: u32,
+        name: &str,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> VfsResult<()> {
+        self.modify(|op| {
+            let mut inode = op.inode(ino)?;
+            let mut xattrs = op.xattrs(&inode)?;
+            match xattrs.iter_mut().find(|(key, _)| key == name) {
+                Some(_) if flags.contains(XattrFlags::CREATE) => {
+                    return Err(VfsError::AlreadyExists);
+                }
+                Some((_, old)) => *old = value.to_vec(),
+                None if flags.contains(XattrFlags::REPLACE) => return Err(no_data()),
+                None => xattrs.push((name.into(), value.to_vec())),
+            }
+            op.store_xattrs(&mut inode, &xattrs)
+        })
+    }
+
+    pub(super) fn list_xattr(&self, ino: u32) -> VfsResult<Vec<String>> {
+        self.inspect(|op| {
+            let inode = op.inode(ino)?;
+            Ok(op.xattrs(&inode)?.into_iter().map(|(key, _)| key).collect())
+        })
+    }
+
+    pub(super) fn remove_xattr(&self, ino: u32, name: &str) -> VfsResult<()> {
+        self.modify(|op| {
+            let mut inode = op.inode(ino)?;
+            let mut xattrs = op.xattrs(&inode)?;
+            let index = xattrs
+                .iter()
+                .position(|(key, _)| key == name)
+                .ok_or_else(no_data)?;
+            xattrs.remove(index);
+            op.store_xattrs(&mut inode, &xattrs)
+        })
+    }
+}
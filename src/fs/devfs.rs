@@ -0,0 +1,387 @@
+//! Flat in-memory filesystem holding block device nodes, typically mounted
+//! on `/dev`.
+//!
+//! Devices are registered by drivers through [`DevFs::add_block_device`];
+//! the resulting nodes expose the device through
+//! [`DirEntry::block_device`] for mounting, and can also be read and written
+//! as files.
+
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Context,
+    time::Duration,
+};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::RootDir;
+use crate::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, Mutex, NodeOps, NodePermission, NodeType, Reference, StatFs,
+    VfsError, VfsResult, WeakDirEntry,
+    block::BlockDevice,
+    path::{DOT, DOTDOT, MAX_NAME_LEN, verify_entry_name},
+};
+
+const ROOT_INO: u64 = 1;
+
+/// A registered device.
+struct DevNode {
+    ino: u64,
+    rdev: DeviceId,
+    device: Arc<dyn BlockDevice>,
+    metadata: Mutex<(NodePermission, u32, u32)>,
+}
+
+/// A filesystem of device nodes.
+pub struct DevFs {
+    nodes: Mutex<BTreeMap<String, Arc<DevNode>>>,
+    next_ino: AtomicU64,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl DevFs {
+    /// Creates an empty device filesystem.
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            nodes: Mutex::default(),
+            next_ino: AtomicU64::new(ROOT_INO + 1),
+            this: this.clone(),
+            root: RootDir::default(),
+        })
+    }
+
+    fn new_root(&self) -> DirEntry {
+        DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(DevDir {
+                    fs: self.this.upgrade().unwrap(),
+                    this,
+                }))
+            },
+            Reference::root(),
+        )
+    }
+
+    /// Registers `device` as the block device node `name`.
+    pub fn add_block_device(
+        &self,
+        name: &str,
+        rdev: DeviceId,
+        device: Arc<dyn BlockDevice>,
+    ) -> VfsResult<()> {
+        if name.is_empty() || name.contains('/') {
+            return Err(VfsError::InvalidInput);
+        }
+        verify_entry_name(name)?;
+        let mut nodes = self.nodes.lock();
+        if nodes.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let node = DevNode {
+            ino: self.next_ino.fetch_add(1, Ordering::Relaxed),
+            rdev,
+            device,
+            metadata: Mutex::new((NodePermission::from_bits_truncate(0o660), 0, 0)),
+        };
+        nodes.insert(name.to_owned(), Arc::new(node));
+        Ok(())
+    }
+
+    /// Removes the device node `name`.
+    ///
+    /// Open handles of the node keep working until they are dropped.
+    pub fn remove(&self, name: &str) -> VfsResult<()> {
+        self.nodes
+            .lock()
+            .remove(name)
+            .map(drop)
+            .ok_or(VfsError::NotFound)
+    }
+
+    /// Returns whether a node named `name` exists.
+    pub fn contains(&self, name: &str) -> bool {
+        self.nodes.lock().contains_key(name)
+    }
+}
+
+impl FilesystemOps for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        Ok(StatFs {
+            fs_type: 0x1373,
+            block_size: 4096,
+            blocks: 0,
+            blocks_free: 0,
+            blocks_available: 0,
+            file_count: 0,
+            free_file_count: 0,
+            name_length: MAX_NAME_LEN as u32,
+            fragment_size: 4096,
+            mount_flags: 0,
+        })
+    }
+}
+
+fn metadata(inode: u64, node_type: NodeType, mode: NodePermission) -> Metadata {
+    Metadata {
+        device: 0,
+        inode,
+        nlink: if node_type == NodeType::Directory {
+            2
+        } else {
+            1
+        },
+        mode,
+        node_type,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        block_size: 4096,
+        blocks: 0,
+        rdev: DeviceId::default(),
+        atime: Duration::ZERO,
+        mtime: Duration::ZERO,
+        ctime: Duration::ZERO,
+    }
+}
+
+/// Root directory of a [`DevFs`].
+pub struct DevDir {
+    fs: Arc<DevFs>,
+    this: WeakDirEntry,
+}
+
+impl NodeOps for DevDir {
+    fn inode(&self) -> u64 {
+        ROOT_INO
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(metadata(
+            ROOT_INO,
+            NodeType::Directory,
+            NodePermission::from_bits_truncate(0o755),
+        ))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for DevDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let nodes = self.fs.nodes.lock().clone();
+        let entries = [
+            (DOT, ROOT_INO, NodeType::Directory),
+            (DOTDOT, ROOT_INO, NodeType::Directory),
+        ]
+        .into_iter()
+        .chain(
+            nodes
+                .iter()
+                .map(|(name, node)| (name.as_str(), node.ino, NodeType::BlockDevice)),
+        );
+
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let node = self
+            .fs
+            .nodes
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(VfsError::NotFound)?;
+        Ok(DirEntry::new_file(
+            FileNode::new(Arc::new(BlockDeviceFile {
+                fs: self.fs.clone(),
+                node,
+            })),
+            NodeType::BlockDevice,
+            Reference::new(self.this.upgrade(), name.to_owned()),
+        ))
+    }
+
+    fn is_cacheable(&self) -> bool {
+        // Nodes come and go with drivers.
+        false
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+}
+
+/// A block device node, reading and writing the device directly.
+pub struct BlockDeviceFile {
+    fs: Arc<DevFs>,
+    node: Arc<DevNode>,
+}
+
+impl NodeOps for BlockDeviceFile {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let (mode, uid, gid) = *self.node.metadata.lock();
+        Ok(Metadata {
+            uid,
+            gid,
+            rdev: self.node.rdev,
+            ..metadata(self.node.ino, NodeType::BlockDevice, mode)
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mut metadata = self.node.metadata.lock();
+        if let Some(mode) = update.mode {
+            metadata.0 = mode;
+        }
+        if let Some((uid, gid)) = update.owner {
+            (metadata.1, metadata.2) = (uid, gid);
+        }
+        Ok(())
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.node.device.len())
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.node.device.flush()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for BlockDeviceFile {
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> VfsResult<usize> {
+        let device = &self.node.device;
+        let sector_size = device.sector_size();
+        let len = buf.len().min(device.len().saturating_sub(offset) as usize);
+        buf = &mut buf[..len];
+        let mut sector = vec![0; sector_size];
+        while !buf.is_empty() {
+            let start = offset as usize % sector_size;
+            let chunk = buf.len().min(sector_size - start);
+            device.read_sectors(offset / sector_size as u64, &mut sector)?;
+            buf[..chunk].copy_from_slice(&sector[start..start + chunk]);
+            buf = &mut buf[chunk..];
+            offset += chunk as u64;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, mut buf: &[u8], mut offset: u64) -> VfsResult<usize> {
+        let device = &self.node.device;
+        if device.is_read_only() {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        let sector_size = device.sector_size();
+        let len = buf.len().min(device.len().saturating_sub(offset) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err(VfsError::StorageFull);
+        }
+        buf = &buf[..len];
+        let mut sector = vec![0; sector_size];
+        while !buf.is_empty() {
+            let index = offset / sector_size as u64;
+            let start = offset as usize % sector_size;
+            let chunk = buf.len().min(sector_size - start);
+            if chunk < sector_size {
+                device.read_sectors(index, &mut sector)?;
+            }
+            sector[start..start + chunk].copy_from_slice(&buf[..chunk]);
+            device.write_sectors(index, &sector)?;
+            buf = &buf[chunk..];
+            offset += chunk as u64;
+        }
+        Ok(len)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::StorageFull)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn block_device(&self) -> VfsResult<Arc<dyn BlockDevice>> {
+        Ok(self.node.device.clone())
+    }
+}
+
+impl Pollable for BlockDeviceFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,355 @@
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use super::{
+    COMPAT_DIR_INDEX, Ext4Fs, INCOMPAT_FILETYPE, INCOMPAT_LARGEDIR,
+    hash::{HASH_UNSIGNED_DELTA, dir_hash, is_signed},
+    inode::{BLOCK_MAP_SIZE, INDEX_FL, Inode},
+    le16, le32,
+};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps,
+    NodePermission, NodeType, Reference, VfsError, VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT},
+};
+
+/// Offset of the hash tree information in the first block, after the `.`
+/// and `..` entries.
+const DX_ROOT_INFO: usize = 24;
+/// Offset of the entries of interior hash tree nodes, after an empty
+/// directory entry spanning the block.
+const DX_NODE_ENTRIES: usize = 8;
+/// Size of the parent inode number heading inline directories.
+const INLINE_PARENT_SIZE: usize = 4;
+
+/// A live directory entry.
+struct RawEntry<'a> {
+    /// Position of the entry in the directory.
+    pos: u64,
+    ino: u32,
+    file_type: u8,
+    name: &'a [u8],
+}
+
+/// Decodes a record length, which can reach 65536 in 64 KiB blocks.
+fn rec_len(raw: u16) -> usize {
+    match raw {
+        0 | 0xffff => 1 << 16,
+        _ => (raw as usize & 0xfffc) | (raw as usize & 3) << 16,
+    }
+}
+
+/// Parses the entries of a directory block placed at `base` in the
+/// directory, skipping unused ones.
+fn parse(data: &[u8], base: u64, file_types: bool) -> VfsResult<Vec<RawEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let ino = le32(data, offset);
+        let len = rec_len(le16(data, offset + 4));
+        let (name_len, file_type) = if file_types {
+            (data[offset + 6] as usize, data[offset + 7])
+        } else {
+            (le16(data, offset + 6) as usize, 0)
+        };
+        if len < 8 || offset + len > data.len() || (ino != 0 && 8 + name_len > len) {
+            return Err(VfsError::InvalidData);
+        }
+        if ino != 0 {
+            entries.push(RawEntry {
+                pos: base + offset as u64,
+                ino,
+                file_type,
+                name: &data[offset + 8..offset + 8 + name_len],
+            });
+        }
+        offset += len;
+    }
+    Ok(entries)
+}
+
+fn node_type_of(file_type: u8) -> NodeType {
+    match file_type {
+        1 => NodeType::RegularFile,
+        2 => NodeType::Directory,
+        3 => NodeType::CharacterDevice,
+        4 => NodeType::BlockDevice,
+        5 => NodeType::Fifo,
+        6 => NodeType::Socket,
+        7 => NodeType::Symlink,
+        _ => NodeType::Unknown,
+    }
+}
+
+/// Directory node of an [`Ext4Fs`].
+pub struct Ext4Dir {
+    fs: Arc<Ext4Fs>,
+    inode: Inode,
+    this: WeakDirEntry,
+}
+
+impl Ext4Dir {
+    pub(super) fn new(fs: Arc<Ext4Fs>, inode: Inode, this: WeakDirEntry) -> Self {
+        Self { fs, inode, this }
+    }
+
+    fn has_file_types(&self) -> bool {
+        self.fs.sb.has_incompat(INCOMPAT_FILETYPE)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inode.size().div_ceil(self.fs.block_size())
+    }
+
+    /// Reads block `index` of the directory, or returns `None` for a hole.
+    fn read_block(&self, index: u64) -> VfsResult<Option<Vec<u8>>> {
+        let Some((block, _)) = self.inode.map_block(&self.fs, index)? else {
+            return Ok(None);
+        };
+        let mut data = vec![0; self.fs.block_size() as usize];
+        self.fs.read_block(block, 0, &mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Returns the parent inode number and the data of an inline directory.
+    fn inline_data(&self) -> VfsResult<(u32, Vec<u8>)> {
+        let mut data = self.inode.inline_data()?;
+        data.truncate(self.inode.size() as usize);
+        if data.len() < INLINE_PARENT_SIZE {
+            return Err(VfsError::InvalidData);
+        }
+        Ok((le32(&data, 0), data))
+    }
+
+    /// Parses an inline directory, whose entries are split between the block
+    /// map and the extended attribute holding the rest of the data.
+    fn inline_entries<'a>(&self, data: &'a [u8]) -> VfsResult<Vec<RawEntry<'a>>> {
+        let split = BLOCK_MAP_SIZE.min(data.len());
+        let mut entries = parse(
+            &data[INLINE_PARENT_SIZE..split],
+            INLINE_PARENT_SIZE as u64,
+            self.has_file_types(),
+        )?;
+        entries.extend(parse(&data[split..], split as u64, self.has_file_types())?);
+        Ok(entries)
+    }
+
+    fn node_type(&self, entry: &RawEntry) -> VfsResult<NodeType> {
+        if self.has_file_types() {
+            Ok(node_type_of(entry.file_type))
+        } else {
+            Ok(self.fs.load_inode(entry.ino as u64)?.node_type())
+        }
+    }
+
+    /// Returns the leaf blocks of the hash tree that may hold `name`, or
+    /// `None` if the directory cannot be looked up through its hash tree.
+    fn htree_leaves(&self, name: &[u8]) -> VfsResult<Option<Vec<u64>>> {
+        let fs = &self.fs;
+        if self.inode.flags() & INDEX_FL == 0 || fs.sb.compat & COMPAT_DIR_INDEX == 0 {
+            return Ok(None);
+        }
+        let Some(mut node) = self.read_block(0)? else {
+            return Ok(None);
+        };
+        let info = &node[DX_ROOT_INFO..DX_ROOT_INFO + 8];
+        let max_levels = if fs.sb.has_incompat(INCOMPAT_LARGEDIR) {
+            3
+        } else {
+            2
+        };
+        let (mut version, levels) = (info[4], info[6]);
+        if le32(info, 0) != 0 || levels >= max_levels {
+            return Ok(None);
+        }
+        if is_signed(version) && fs.sb.unsigned_hash {
+            version += HASH_UNSIGNED_DELTA;
+        }
+        let Some(hash) = dir_hash(name, version, &fs.sb.hash_seed) else {
+            return Ok(None);
+        };
+
+        let mut start = DX_ROOT_INFO + info[5] as usize;
+        for level in 0..=levels {
+            let count = le16(&node, start + 2) as usize;
+            if count == 0 || start + count * 8 > node.len() {
+                return Ok(None);
+            }
+            // The first entry holds the count and limit in place of its hash.
+            let entry = |i: usize| {
+                let hash = if i == 0 {
+                    0
+                } else {
+                    le32(&node, start + i * 8)
+                };
+                (hash, (le32(&node, start + i * 8 + 4) & 0x0fff_ffff) as u64)
+            };
+            let index = (1..count)
+                .take_while(|&i| entry(i).0 <= hash)
+                .last()
+                .unwrap_or(0);
+            if level == levels {
+                // Names sharing a hash may continue in the next leaves,
+                // which are then marked by the low bit of their hash.
+                let mut leaves = vec![entry(index).1];
+                leaves.extend(
+                    (index + 1..count)
+                        .map(entry)
+                        .take_while(|&(it, _)| it & 1 != 0 && it & !1 == hash)
+                        .map(|(_, block)| block),
+                );
+                return Ok(Some(leaves));
+            }
+            let Some(child) = self.read_block(entry(index).1)? else {
+                return Ok(None);
+            };
+            node = child;
+            start = DX_NODE_ENTRIES;
+        }
+        Ok(None)
+    }
+
+    /// Finds the inode number of `name`.
+    fn find(&self, name: &[u8]) -> VfsResult<Option<u32>> {
+        let file_types = self.has_file_types();
+        if self.inode.has_inline_data() {
+            let (_, data) = self.inline_data()?;
+            let entries = self.inline_entries(&data)?;
+            return Ok(entries.iter().find(|it| it.name == name).map(|it| it.ino));
+        }
+        let blocks = match self.htree_leaves(name) {
+            Ok(Some(leaves)) => leaves,
+            // Hash tree directories are valid linear ones, and can still be
+            // scanned if the tree itself is damaged.
+            Ok(None) | Err(_) => (0..self.block_count()).collect(),
+        };
+        for index in blocks {
+            let Some(data) = self.read_block(index)? else {
+                continue;
+            };
+            let base = index * self.fs.block_size();
+            if let Some(entry) = parse(&data, base, file_types)?
+                .iter()
+                .find(|it| it.name == name)
+            {
+                return Ok(Some(entry.ino));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl NodeOps for Ext4Dir {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.inode.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for Ext4Dir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        // Entries are addressed by their position in the directory, so that
+        // offsets stay valid whatever the hash tree looks like.
+        let mut count = 0;
+        let mut emit = |entries: &[RawEntry], end: u64| -> VfsResult<bool> {
+            for (i, entry) in entries.iter().enumerate() {
+                let next = entries.get(i + 1).map_or(end, |it| it.pos);
+                if next <= offset {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(entry.name);
+                let node_type = self.node_type(entry)?;
+                if !sink.accept(&name, entry.ino as u64, node_type, next) {
+                    return Ok(false);
+                }
+                count += 1;
+            }
+            Ok(true)
+        };
+
+        if self.inode.has_inline_data() {
+            let (parent, data) = self.inline_data()?;
+            // Inline directories have no `.` and `..` entries, which take
+            // the positions of the parent inode number.
+            let dots = [
+                RawEntry {
+                    pos: 0,
+                    ino: self.inode.ino as u32,
+                    file_type: 2,
+                    name: DOT.as_bytes(),
+                },
+                RawEntry {
+                    pos: 1,
+                    ino: parent,
+                    file_type: 2,
+                    name: DOTDOT.as_bytes(),
+                },
+            ];
+            let entries = self.inline_entries(&data)?;
+            let entries: Vec<_> = dots.into_iter().chain(entries).collect();
+            emit(&entries, data.len() as u64)?;
+            return Ok(count);
+        }
+
+        let block_size = self.fs.block_size();
+        for index in offset / block_size..self.block_count() {
+            let Some(data) = self.read_block(index)? else {
+                continue;
+            };
+            let base = index * block_size;
+            if !emit(
+                &parse(&data, base, self.has_file_types())?,
+                base + block_size,
+            )? {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let ino = self.find(name.as_bytes())?.ok_or(VfsError::NotFound)?;
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        self.fs.open(ino as u64, reference)
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
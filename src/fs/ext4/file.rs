@@ -0,0 +1,89 @@
+use alloc::sync::Arc;
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::{Ext4Fs, inode::Inode};
+use crate::{FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps, VfsError, VfsResult};
+
+/// Non-directory node of an [`Ext4Fs`].
+///
+/// Reading a symlink returns its target.
+pub struct Ext4File {
+    fs: Arc<Ext4Fs>,
+    inode: Inode,
+}
+
+impl Ext4File {
+    pub(super) fn new(fs: Arc<Ext4Fs>, inode: Inode) -> Self {
+        Self { fs, inode }
+    }
+}
+
+impl NodeOps for Ext4File {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.inode.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.inode.size())
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for Ext4File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let inode = &self.inode;
+        if inode.is_fast_symlink() {
+            let target = &inode.block_map()[..inode.size() as usize];
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        inode.read_at(&self.fs, buf, offset)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for Ext4File {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,161 @@
+//! Hashes of names in hash tree directories.
+
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+const HASH_LEGACY: u8 = 0;
+const HASH_HALF_MD4: u8 = 1;
+const HASH_TEA: u8 = 2;
+/// Offset from the signed variants of the hashes to the unsigned ones.
+pub const HASH_UNSIGNED_DELTA: u8 = 3;
+
+/// Returns whether `version` is one of the signed variants of the hashes.
+pub fn is_signed(version: u8) -> bool {
+    version <= HASH_TEA
+}
+
+/// Computes the hash of `name` with hash `version`, or returns `None` if
+/// the version is unknown.
+pub fn dir_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = if seed.iter().any(|&it| it != 0) {
+        *seed
+    } else {
+        DEFAULT_SEED
+    };
+    if version > HASH_TEA + HASH_UNSIGNED_DELTA {
+        return None;
+    }
+    let signed = is_signed(version);
+    let hash = match version % HASH_UNSIGNED_DELTA {
+        HASH_LEGACY => legacy_hash(name, signed),
+        HASH_HALF_MD4 => {
+            for (i, chunk) in name.chunks(32).enumerate() {
+                let input = str_to_hash_buf::<8>(chunk, name.len() - i * 32, signed);
+                half_md4_transform(&mut buf, &input);
+            }
+            buf[1]
+        }
+        _ => {
+            for (i, chunk) in name.chunks(16).enumerate() {
+                let input = str_to_hash_buf::<4>(chunk, name.len() - i * 16, signed);
+                tea_transform(&mut buf, &input);
+            }
+            buf[0]
+        }
+    };
+    let hash = hash & !1;
+    // The largest value marks the end of the directory in readdir cookies.
+    Some(if hash == 0xffff_fffe {
+        0xffff_fffc
+    } else {
+        hash
+    })
+}
+
+fn char_value(c: u8, signed: bool) -> u32 {
+    if signed {
+        c as i8 as i32 as u32
+    } else {
+        c as u32
+    }
+}
+
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2du32, 0x37ab_e8f9u32);
+    for &c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(c, signed).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Packs a chunk of a name into words, padding with the length of the rest
+/// of the name from the chunk on.
+fn str_to_hash_buf<const N: usize>(chunk: &[u8], rest: usize, signed: bool) -> [u32; N] {
+    let rest = rest as u32;
+    let mut pad = rest | (rest << 8);
+    pad |= pad << 16;
+    let mut buf = [pad; N];
+    let mut val = pad;
+    let mut word = 0;
+    for (i, &c) in chunk.iter().enumerate() {
+        val = char_value(c, signed).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[word] = val;
+            val = pad;
+            word += 1;
+        }
+    }
+    if word < N {
+        buf[word] = val;
+    }
+    buf
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5a82_7999;
+    const K3: u32 = 0x6ed9_eba1;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s);
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    for (it, value) in buf.iter_mut().zip([a, b, c, d]) {
+        *it = it.wrapping_add(value);
+    }
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9e37_79b9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ b1.wrapping_add(sum) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ b0.wrapping_add(sum) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
@@ -0,0 +1,358 @@
+//! On-disk inodes and the mapping of their data.
+
+use alloc::{vec, vec::Vec};
+use core::time::Duration;
+
+use super::{
+    Ext4Fs, INCOMPAT_EXTENTS, INCOMPAT_INLINE_DATA, RO_COMPAT_HUGE_FILE, Superblock, le16, le32,
+};
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+/// Size of the block map stored in the inode.
+pub const BLOCK_MAP_SIZE: usize = 60;
+/// Number of direct block pointers of the indirect block map.
+const DIRECT_BLOCKS: u64 = 12;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Maximum depth of an extent tree.
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Lengths above this mark unwritten extents, which read as zeros.
+const MAX_INIT_EXTENT_LEN: u32 = 32768;
+
+const XATTR_MAGIC: u32 = 0xea02_0000;
+const XATTR_INDEX_SYSTEM: u8 = 7;
+
+const HUGE_FILE_FL: u32 = 0x4_0000;
+const EXTENTS_FL: u32 = 0x8_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+pub const INDEX_FL: u32 = 0x1000;
+
+/// Offsets of the fields of an inode.
+mod field {
+    pub const MODE: usize = 0;
+    pub const UID: usize = 2;
+    pub const SIZE: usize = 4;
+    pub const ATIME: usize = 8;
+    pub const CTIME: usize = 12;
+    pub const MTIME: usize = 16;
+    pub const GID: usize = 24;
+    pub const LINKS: usize = 26;
+    pub const BLOCKS: usize = 28;
+    pub const FLAGS: usize = 32;
+    pub const BLOCK: usize = 40;
+    pub const FILE_ACL: usize = 104;
+    pub const SIZE_HIGH: usize = 108;
+    pub const BLOCKS_HIGH: usize = 116;
+    pub const UID_HIGH: usize = 120;
+    pub const GID_HIGH: usize = 122;
+    pub const EXTRA_ISIZE: usize = 128;
+    pub const CTIME_EXTRA: usize = 132;
+    pub const MTIME_EXTRA: usize = 136;
+    pub const ATIME_EXTRA: usize = 140;
+}
+
+/// An inode read from an inode table.
+#[derive(Clone)]
+pub struct Inode {
+    pub ino: u64,
+    raw: Vec<u8>,
+    /// End of the fields in use, including the extra ones.
+    extra_end: usize,
+    block_size: u64,
+    huge_files: bool,
+}
+
+impl Inode {
+    pub(super) fn parse(sb: &Superblock, ino: u64, raw: Vec<u8>) -> VfsResult<Self> {
+        let extra_end = if raw.len() > field::EXTRA_ISIZE {
+            field::EXTRA_ISIZE + le16(&raw, field::EXTRA_ISIZE) as usize
+        } else {
+            field::EXTRA_ISIZE
+        };
+        if extra_end > raw.len() {
+            return Err(VfsError::InvalidData);
+        }
+        let inode = Self {
+            ino,
+            raw,
+            extra_end,
+            block_size: sb.block_size as u64,
+            huge_files: sb.ro_compat & RO_COMPAT_HUGE_FILE != 0,
+        };
+        if inode.flags() & EXTENTS_FL != 0 && !sb.has_incompat(INCOMPAT_EXTENTS)
+            || inode.flags() & INLINE_DATA_FL != 0 && !sb.has_incompat(INCOMPAT_INLINE_DATA)
+        {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(inode)
+    }
+
+    pub fn mode(&self) -> u16 {
+        le16(&self.raw, field::MODE)
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        NodeType::from((self.mode() >> 12) as u8)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.node_type() == NodeType::Directory
+    }
+
+    pub fn flags(&self) -> u32 {
+        le32(&self.raw, field::FLAGS)
+    }
+
+    pub fn size(&self) -> u64 {
+        le32(&self.raw, field::SIZE) as u64 | (le32(&self.raw, field::SIZE_HIGH) as u64) << 32
+    }
+
+    pub fn has_inline_data(&self) -> bool {
+        self.flags() & INLINE_DATA_FL != 0
+    }
+
+    /// Returns the block map, which also holds inline data and the targets
+    /// of fast symlinks.
+    pub fn block_map(&self) -> &[u8] {
+        &self.raw[field::BLOCK..field::BLOCK + BLOCK_MAP_SIZE]
+    }
+
+    /// Returns the number of 512-byte sectors allocated to the inode.
+    fn sectors(&self) -> u64 {
+        let mut blocks = le32(&self.raw, field::BLOCKS) as u64;
+        if self.huge_files {
+            blocks |= (le16(&self.raw, field::BLOCKS_HIGH) as u64) << 32;
+            if self.flags() & HUGE_FILE_FL != 0 {
+                blocks *= self.block_size / 512;
+            }
+        }
+        blocks
+    }
+
+    /// Returns whether the inode is a symlink storing its target in the
+    /// block map.
+    pub fn is_fast_symlink(&self) -> bool {
+        if self.node_type() != NodeType::Symlink || self.has_inline_data() {
+            return false;
+        }
+        let xattr_sectors = if le32(&self.raw, field::FILE_ACL) != 0 {
+            self.block_size / 512
+        } else {
+            0
+        };
+        self.sectors() == xattr_sectors && self.size() < BLOCK_MAP_SIZE as u64
+    }
+
+    /// Decodes a timestamp and its extra field, holding the nanoseconds and
+    /// two more bits of seconds.
+    fn time(&self, offset: usize, extra_offset: usize) -> Duration {
+        let mut secs = le32(&self.raw, offset) as i32 as i64;
+        let mut nanos = 0;
+        if extra_offset + 4 <= self.extra_end {
+            let extra = le32(&self.raw, extra_offset);
+            secs += ((extra & 3) as i64) << 32;
+            nanos = (extra >> 2).min(999_999_999);
+        }
+        if secs < 0 {
+            return Duration::ZERO;
+        }
+        Duration::new(secs as u64, nanos)
+    }
+
+    fn rdev(&self) -> DeviceId {
+        let map = self.block_map();
+        match (le32(map, 0), le32(map, 4)) {
+            (0, new) => DeviceId::new((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00)),
+            (old, _) => DeviceId::new((old >> 8) & 0xff, old & 0xff),
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let raw = &self.raw;
+        let node_type = self.node_type();
+        let rdev = match node_type {
+            NodeType::CharacterDevice | NodeType::BlockDevice => self.rdev(),
+            _ => DeviceId::default(),
+        };
+        Metadata {
+            device: 0,
+            inode: self.ino,
+            nlink: le16(raw, field::LINKS) as u64,
+            mode: NodePermission::from_bits_truncate(self.mode() & 0o7777),
+            node_type,
+            uid: le16(raw, field::UID) as u32 | (le16(raw, field::UID_HIGH) as u32) << 16,
+            gid: le16(raw, field::GID) as u32 | (le16(raw, field::GID_HIGH) as u32) << 16,
+            size: self.size(),
+            block_size: self.block_size,
+            blocks: self.sectors(),
+            rdev,
+            atime: self.time(field::ATIME, field::ATIME_EXTRA),
+            mtime: self.time(field::MTIME, field::MTIME_EXTRA),
+            ctime: self.time(field::CTIME, field::CTIME_EXTRA),
+        }
+    }
+
+    /// Returns the value of the extended attribute `system.<name>` stored
+    /// in the inode, if there is one.
+    fn inline_xattr(&self, name: &[u8]) -> VfsResult<Option<&[u8]>> {
+        let region = &self.raw[self.extra_end..];
+        if region.len() < 4 || le32(region, 0) != XATTR_MAGIC {
+            return Ok(None);
+        }
+        // Values are addressed from the first entry.
+        let entries = &region[4..];
+        let mut offset = 0;
+        while offset + 4 <= entries.len() && le32(entries, offset) != 0 {
+            let header = entries
+                .get(offset..offset + 16)
+                .ok_or(VfsError::InvalidData)?;
+            let name_len = header[0] as usize;
+            let entry_name = entries
+                .get(offset + 16..offset + 16 + name_len)
+                .ok_or(VfsError::InvalidData)?;
+            if header[1] == XATTR_INDEX_SYSTEM && entry_name == name {
+                let value_offset = le16(header, 2) as usize;
+                let value_size = le32(header, 8) as usize;
+                if le32(header, 4) != 0 {
+                    // Values stored in other inodes are not used for data.
+                    return Err(VfsError::InvalidData);
+                }
+                return entries
+                    .get(value_offset..value_offset + value_size)
+                    .map(Some)
+                    .ok_or(VfsError::InvalidData);
+            }
+            offset += (16 + name_len).next_multiple_of(4);
+        }
+        Ok(None)
+    }
+
+    /// Returns the inline data of the inode, which continue from the block
+    /// map into the `system.data` extended attribute.
+    pub fn inline_data(&self) -> VfsResult<Vec<u8>> {
+        let mut data = self.block_map().to_vec();
+        if let Some(more) = self.inline_xattr(b"data")? {
+            data.extend_from_slice(more);
+        }
+        Ok(data)
+    }
+
+    /// Maps logical block `block` to a run of physical blocks, returning the
+    /// first one and the length of the run, or `None` for a hole.
+    pub fn map_block(&self, fs: &Ext4Fs, block: u64) -> VfsResult<Option<(u64, u64)>> {
+        if self.flags() & EXTENTS_FL != 0 {
+            self.map_extent(fs, block)
+        } else {
+            Ok(self.map_indirect(fs, block)?.map(|it| (it, 1)))
+        }
+    }
+
+    fn map_extent(&self, fs: &Ext4Fs, block: u64) -> VfsResult<Option<(u64, u64)>> {
+        let Ok(block) = u32::try_from(block) else {
+            return Ok(None);
+        };
+        let mut node = self.block_map().to_vec();
+        for expected_depth in (0..=MAX_EXTENT_DEPTH).rev() {
+            let entries = le16(&node, 2) as usize;
+            let depth = le16(&node, 6);
+            if le16(&node, 0) != EXTENT_MAGIC
+                || 12 + entries * 12 > node.len()
+                || depth > expected_depth
+            {
+                return Err(VfsError::InvalidData);
+            }
+            // Entries are sorted by their first logical block.
+            let index = (0..entries)
+                .map(|i| 12 + i * 12)
+                .take_while(|&it| le32(&node, it) <= block)
+                .last();
+            let Some(entry) = index else {
+                return Ok(None);
+            };
+            if depth == 0 {
+                let first = le32(&node, entry);
+                let mut len = le16(&node, entry + 4) as u32;
+                let unwritten = len > MAX_INIT_EXTENT_LEN;
+                if unwritten {
+                    len -= MAX_INIT_EXTENT_LEN;
+                }
+                let start = (le16(&node, entry + 6) as u64) << 32 | le32(&node, entry + 8) as u64;
+                let offset = block - first;
+                return Ok((offset < len && !unwritten)
+                    .then(|| (start + offset as u64, (len - offset) as u64)));
+            }
+            let child = le32(&node, entry + 4) as u64 | (le16(&node, entry + 8) as u64) << 32;
+            node = vec![0; fs.block_size() as usize];
+            fs.read_block(child, 0, &mut node)?;
+        }
+        Err(VfsError::InvalidData)
+    }
+
+    fn map_indirect(&self, fs: &Ext4Fs, mut block: u64) -> VfsResult<Option<u64>> {
+        let map = self.block_map();
+        if block < DIRECT_BLOCKS {
+            return Ok(Some(le32(map, block as usize * 4) as u64).filter(|&it| it != 0));
+        }
+        block -= DIRECT_BLOCKS;
+        let per_block = fs.block_size() / 4;
+        let mut span = per_block;
+        let mut level = 1;
+        while block >= span {
+            block -= span;
+            span *= per_block;
+            level += 1;
+            if level > 3 {
+                return Ok(None);
+            }
+        }
+        let mut ptr = le32(map, (DIRECT_BLOCKS as usize + level - 1) * 4) as u64;
+        for _ in 0..level {
+            if ptr == 0 {
+                return Ok(None);
+            }
+            span /= per_block;
+            let mut next = [0; 4];
+            fs.read_block(ptr, (block / span % per_block * 4) as usize, &mut next)?;
+            ptr = u32::from_le_bytes(next) as u64;
+        }
+        Ok(Some(ptr).filter(|&it| it != 0))
+    }
+
+    /// Reads the data of the inode at `offset`, up to its size.
+    pub fn read_at(&self, fs: &Ext4Fs, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        let buf = &mut buf[..len];
+        if self.has_inline_data() {
+            let data = self.inline_data()?;
+            let start = (offset as usize).min(data.len());
+            let end = (start + len).min(data.len());
+            buf[..end - start].copy_from_slice(&data[start..end]);
+            buf[end - start..].fill(0);
+            return Ok(len);
+        }
+
+        let block_size = fs.block_size();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let block_offset = pos % block_size;
+            match self.map_block(fs, pos / block_size)? {
+                Some((start, count)) => {
+                    let run = (count * block_size - block_offset).min((len - done) as u64);
+                    let chunk = &mut buf[done..done + run as usize];
+                    if start + count > fs.sb.blocks_count {
+                        return Err(VfsError::InvalidData);
+                    }
+                    fs.cache.read_at(start * block_size + block_offset, chunk)?;
+                    done += run as usize;
+                }
+                None => {
+                    let run = ((block_size - block_offset) as usize).min(len - done);
+                    buf[done..done + run].fill(0);
+                    done += run;
+                }
+            }
+        }
+        Ok(len)
+    }
+}
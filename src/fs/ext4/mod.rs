@@ -0,0 +1,524 @@
+//! Read-only ext2/ext3/ext4 filesystem.
+//!
+//! Files may be mapped by extent trees or by the indirect blocks of ext2/3,
+//! or stored inline in their inode. Directories are read linearly, and
+//! looked up through their hash tree if they have one.
+//!
+//! Filesystems using incompatible features this driver does not know about
+//! are refused at mount time. So are filesystems whose journal needs to be
+//! replayed, as the journal is not read.
+
+mod dir;
+mod file;
+mod hash;
+mod inode;
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use self::inode::Inode;
+pub use self::{dir::Ext4Dir, file::Ext4File};
+use super::{RootDir, le16, le32};
+use crate::{
+    DirEntry, DirNode, FileNode, FilesystemOps, Reference, StatFs, VfsError, VfsResult,
+    WeakDirEntry,
+    block::{BlockDevice, BufferCache, read_bytes},
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_SUPER_MAGIC: u16 = 0xef53;
+const CACHE_CAPACITY: usize = 1024;
+const ROOT_INO: u64 = 2;
+const MAX_NAME_LEN: u32 = 255;
+
+const COMPAT_DIR_INDEX: u32 = 0x20;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+/// Incompatible features this driver can read.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_META_BG
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_INLINE_DATA;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+
+/// Superblock flag telling that directory hashes treat names as unsigned.
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+/// The fields of the superblock used by the driver.
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u64,
+    reserved_blocks: u64,
+    free_blocks: u64,
+    free_inodes: u32,
+    first_data_block: u32,
+    block_size: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u32,
+    desc_size: u32,
+    first_meta_bg: u32,
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+    hash_seed: [u32; 4],
+    unsigned_hash: bool,
+}
+
+impl Superblock {
+    fn parse(sb: &[u8]) -> VfsResult<Self> {
+        if le16(sb, 56) != EXT4_SUPER_MAGIC {
+            return Err(VfsError::InvalidData);
+        }
+        let incompat = le32(sb, 96);
+        // The journal is not replayed, so the changes recorded in it would
+        // be missing.
+        if incompat & !INCOMPAT_SUPPORTED != 0 || incompat & INCOMPAT_RECOVER != 0 {
+            return Err(VfsError::Unsupported);
+        }
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let hi = |offset| if is_64bit { le32(sb, offset) as u64 } else { 0 };
+
+        let log_block_size = le32(sb, 24);
+        // Revision 0 has fixed inode sizes.
+        let (inode_size, first_meta_bg) = match le32(sb, 76) {
+            0 => (128, 0),
+            _ => (le16(sb, 88) as u32, le32(sb, 260)),
+        };
+        let desc_size = if is_64bit { le16(sb, 254) as u32 } else { 32 };
+        let superblock = Self {
+            inodes_count: le32(sb, 0),
+            blocks_count: le32(sb, 4) as u64 | hi(336) << 32,
+            reserved_blocks: le32(sb, 8) as u64 | hi(340) << 32,
+            free_blocks: le32(sb, 12) as u64 | hi(344) << 32,
+            free_inodes: le32(sb, 16),
+            first_data_block: le32(sb, 20),
+            block_size: 1024u32.checked_shl(log_block_size).unwrap_or(0),
+            blocks_per_group: le32(sb, 32),
+            inodes_per_group: le32(sb, 40),
+            inode_size,
+            desc_size,
+            first_meta_bg,
+            compat: le32(sb, 92),
+            incompat,
+            ro_compat: le32(sb, 100),
+            hash_seed: [le32(sb, 236), le32(sb, 240), le32(sb, 244), le32(sb, 248)],
+            unsigned_hash: le32(sb, 352) & FLAGS_UNSIGNED_HASH != 0,
+        };
+        if !(1024..=65536).contains(&superblock.block_size)
+            || superblock.blocks_per_group == 0
+            || superblock.inodes_per_group == 0
+            || !superblock.inode_size.is_power_of_two()
+            || !(128..=superblock.block_size).contains(&superblock.inode_size)
+            || !(32..=superblock.block_size).contains(&desc_size)
+            || !desc_size.is_power_of_two()
+        {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(superblock)
+    }
+
+    fn has_incompat(&self, feature: u32) -> bool {
+        self.incompat & feature != 0
+    }
+
+    fn group_count(&self) -> u64 {
+        (self.blocks_count - self.first_data_block as u64).div_ceil(self.blocks_per_group as u64)
+    }
+
+    /// Returns whether `group` holds a backup of the superblock.
+    fn has_super(&self, group: u64) -> bool {
+        if group <= 1 || self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        [3, 5, 7].into_iter().any(|base| {
+            let mut power = base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    /// Returns the block holding the descriptor of `group`.
+    fn desc_block(&self, group: u64) -> u64 {
+        let per_block = (self.block_size / self.desc_size) as u64;
+        let meta_group = group / per_block;
+        let first_data_block = self.first_data_block as u64;
+        if !self.has_incompat(INCOMPAT_META_BG) || meta_group < self.first_meta_bg as u64 {
+            return first_data_block + 1 + meta_group;
+        }
+        // With meta_bg, the descriptors of a meta group are stored in its
+        // first group, after the superblock backup if there is one.
+        let first = meta_group * per_block;
+        first_data_block + first * self.blocks_per_group as u64 + self.has_super(first) as u64
+    }
+}
+
+/// Reads inode `ino` from its inode table.
+fn load_inode(
+    cache: &BufferCache,
+    sb: &Superblock,
+    inode_tables: &[u64],
+    ino: u64,
+) -> VfsResult<Inode> {
+    if ino == 0 || ino > sb.inodes_count as u64 {
+        return Err(VfsError::InvalidData);
+    }
+    let inodes_per_group = sb.inodes_per_group as u64;
+    let table = *inode_tables
+        .get(((ino - 1) / inodes_per_group) as usize)
+        .ok_or(VfsError::InvalidData)?;
+    let block_size = sb.block_size as u64;
+    let inode_size = sb.inode_size as u64;
+    let offset = (ino - 1) % inodes_per_group * inode_size;
+    let block = table + offset / block_size;
+    if block >= sb.blocks_count || offset % block_size + inode_size > block_size {
+        return Err(VfsError::InvalidData);
+    }
+    let mut raw = vec![0; inode_size as usize];
+    cache.read_at(block * block_size + offset % block_size, &mut raw)?;
+    Inode::parse(sb, ino, raw)
+}
+
+/// An ext2, ext3 or ext4 filesystem on a block device.
+pub struct Ext4Fs {
+    cache: BufferCache,
+    sb: Superblock,
+    /// First block of the inode table of each group.
+    inode_tables: Vec<u64>,
+    root_inode: Inode,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl Ext4Fs {
+    /// Mounts the filesystem on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> VfsResult<Arc<Self>> {
+        let sb = read_bytes(&*device, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE)?
+            .ok_or(VfsError::InvalidData)?;
+        let sb = Superblock::parse(&sb)?;
+
+        let block_size = sb.block_size as u64;
+        if sb.blocks_count > device.len() / block_size
+            || sb.first_data_block as u64 >= sb.blocks_count
+        {
+            return Err(VfsError::InvalidData);
+        }
+        let cache = BufferCache::new(
+            device.clone(),
+            (sb.block_size as usize).max(device.sector_size()),
+            CACHE_CAPACITY,
+        )
+        .map_err(|_| VfsError::InvalidData)?;
+
+        let mut inode_tables = Vec::new();
+        for group in 0..sb.group_count() {
+            let per_block = (sb.block_size / sb.desc_size) as u64;
+            let pos = sb.desc_block(group) * block_size + (group % per_block) * sb.desc_size as u64;
+            let mut desc = vec![0; sb.desc_size as usize];
+            cache.read_at(pos, &mut desc)?;
+            let mut table = le32(&desc, 8) as u64;
+            if sb.desc_size >= 64 {
+                table |= (le32(&desc, 40) as u64) << 32;
+            }
+            inode_tables.push(table);
+        }
+
+        let root_inode = load_inode(&cache, &sb, &inode_tables, ROOT_INO)?;
+        if !root_inode.is_dir() {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            cache,
+            sb,
+            inode_tables,
+            root_inode,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let fs = self.this.upgrade().unwrap();
+        let inode = self.root_inode.clone();
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(Ext4Dir::new(fs, inode, this))),
+            Reference::root(),
+        )
+    }
+
+    fn block_size(&self) -> u64 {
+        self.sb.block_size as u64
+    }
+
+    /// Reads `buf.len()` bytes from block `block`, at `offset` in the block.
+    fn read_block(&self, block: u64, offset: usize, buf: &mut [u8]) -> VfsResult<()> {
+        if block >= self.sb.blocks_count || offset + buf.len() > self.sb.block_size as usize {
+            return Err(VfsError::InvalidData);
+        }
+        self.cache
+            .read_at(block * self.block_size() + offset as u64, buf)
+    }
+
+    /// Reads inode `ino` from its inode table.
+    fn load_inode(&self, ino: u64) -> VfsResult<Inode> {
+        load_inode(&self.cache, &self.sb, &self.inode_tables, ino)
+    }
+
+    /// Opens inode `ino` as the entry `reference`.
+    fn open(self: &Arc<Self>, ino: u64, reference: Reference) -> VfsResult<DirEntry> {
+        let inode = self.load_inode(ino)?;
+        if inode.is_dir() {
+            let fs = self.clone();
+            return Ok(DirEntry::new_dir(
+                |this: WeakDirEntry| DirNode::new(Arc::new(Ext4Dir::new(fs, inode, this))),
+                reference,
+            ));
+        }
+        let node_type = inode.node_type();
+        Ok(DirEntry::new_file(
+            FileNode::new(Arc::new(Ext4File::new(self.clone(), inode))),
+            node_type,
+            reference,
+        ))
+    }
+}
+
+impl FilesystemOps for Ext4Fs {
+    fn name(&self) -> &str {
+        "ext4"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let sb = &self.sb;
+        Ok(StatFs {
+            fs_type: EXT4_SUPER_MAGIC as u32,
+            block_size: sb.block_size,
+            blocks: sb.blocks_count,
+            blocks_free: sb.free_blocks,
+            blocks_available: sb.free_blocks.saturating_sub(sb.reserved_blocks),
+            file_count: sb.inodes_count as u64,
+            free_file_count: sb.free_inodes as u64,
+            name_length: MAX_NAME_LEN,
+            fragment_size: sb.block_size,
+            mount_flags: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{string::String, vec::Vec};
+    use core::time::Duration;
+
+    use super::*;
+    use crate::{
+        Filesystem, Location, MetadataUpdate, Mountpoint, NodeType, block::RamDisk, test_util::list,
+    };
+
+    // The images were made from the same tree with e2fsprogs 1.47:
+    //
+    //   mke2fs -t ext4 -b 1024 -O inline_data,64bit,metadata_csum -N 512 -d src ext4.img 8M
+    //   e2fsck -fyD ext4.img # builds the hash tree of `big`
+    //   mke2fs -t ext2 -b 1024 -N 512 -d src ext2.img 8M
+    //
+    // after which the times of `small.txt` were set with `debugfs -w` to an
+    // mtime of 0x12345678 with an mtime_extra of 0x77359400, and a ctime of
+    // 1700000000. They are compressed with zlib.
+    const EXT4_IMAGE: &[u8] = include_bytes!("testdata/ext4.img.zz");
+    const EXT2_IMAGE: &[u8] = include_bytes!("testdata/ext2.img.zz");
+
+    fn mount(image: &[u8]) -> (Arc<Ext4Fs>, Location) {
+        let image = miniz_oxide::inflate::decompress_to_vec_zlib(image).unwrap();
+        let fs = Ext4Fs::new(Arc::new(RamDisk::from_vec(image))).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+        (fs, root)
+    }
+
+    fn read_all(file: &Location) -> Vec<u8> {
+        let file = file.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    fn resolve(root: &Location, path: &str) -> Location {
+        path.split('/').fold(root.clone(), |dir, name| {
+            dir.lookup_no_follow(name).unwrap()
+        })
+    }
+
+    #[test]
+    fn test_ext4() {
+        for image in [EXT4_IMAGE, EXT2_IMAGE] {
+            let (_fs, root) = mount(image);
+            let mut names = list(&root);
+            names.sort();
+            assert_eq!(
+                names,
+                [
+                    ".",
+                    "..",
+                    "big",
+                    "data.bin",
+                    "dir",
+                    "fast",
+                    "fifo",
+                    "holes",
+                    "inline.txt",
+                    "lost+found",
+                    "slow",
+                    "small.txt",
+                    "sparse"
+                ]
+            );
+
+            assert_eq!(read_all(&resolve(&root, "small.txt")), b"hello, ext4\n");
+            assert_eq!(read_all(&resolve(&root, "inline.txt")), [b'i'; 90]);
+            assert_eq!(read_all(&resolve(&root, "dir/sub/deep")), b"deep\n");
+            let data: Vec<u8> = (0..300000).map(|i| (i * 7 % 251) as u8).collect();
+            assert_eq!(read_all(&resolve(&root, "data.bin")), data);
+
+            let sparse = read_all(&resolve(&root, "sparse"));
+            assert_eq!(sparse.len(), 5 * 1024 * 1024 + 3);
+            assert!(sparse[..5 * 1024 * 1024].iter().all(|&it| it == 0));
+            assert_eq!(&sparse[5 * 1024 * 1024..], b"end");
+            let holes = read_all(&resolve(&root, "holes"));
+            for (i, block) in holes.chunks(1024).enumerate() {
+                let expected = if i % 2 == 0 { i as u8 } else { 0 };
+                assert!(block.iter().all(|&it| it == expected));
+            }
+
+            assert_eq!(resolve(&root, "fast").read_link().unwrap(), "small.txt");
+            let slow = alloc::format!("/{}/target", "x".repeat(100));
+            assert_eq!(resolve(&root, "slow").read_link().unwrap(), slow);
+            assert_eq!(
+                resolve(&root, "fifo").metadata().unwrap().node_type,
+                NodeType::Fifo
+            );
+
+            let big = resolve(&root, "big");
+            assert_eq!(list(&big).len(), 202);
+            for i in [0, 57, 199] {
+                let name = alloc::format!("file-with-a-long-name-{i:04}");
+                assert_eq!(
+                    read_all(&big.lookup_no_follow(&name).unwrap()),
+                    alloc::format!("{i}").as_bytes()
+                );
+            }
+            assert_eq!(
+                big.lookup_no_follow("file-with-a-long-name-0200").err(),
+                Some(VfsError::NotFound)
+            );
+
+            let dir = resolve(&root, "dir");
+            assert_eq!(resolve(&dir, "sub/..").inode(), dir.inode());
+            assert_eq!(
+                root.create("new", NodeType::RegularFile, Default::default())
+                    .err(),
+                Some(VfsError::ReadOnlyFilesystem)
+            );
+            assert_eq!(
+                dir.update_metadata(MetadataUpdate::default()).err(),
+                Some(VfsError::ReadOnlyFilesystem)
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_dir_offsets() {
+        let (_fs, root) = mount(EXT4_IMAGE);
+        for path in ["big", "dir"] {
+            let dir = resolve(&root, path);
+            let all = list(&dir);
+            // Resuming from every offset yields the remaining entries.
+            let mut offsets = Vec::new();
+            dir.read_dir(0, &mut |_: &str, _, _, next| {
+                offsets.push(next);
+                true
+            })
+            .unwrap();
+            for (i, &offset) in offsets.iter().enumerate() {
+                let mut rest: Vec<String> = Vec::new();
+                dir.read_dir(offset, &mut |name: &str, _, _, _| {
+                    rest.push(name.into());
+                    false
+                })
+                .unwrap();
+                assert_eq!(rest.first(), all.get(i + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let (fs, root) = mount(EXT4_IMAGE);
+        let meta = resolve(&root, "small.txt").metadata().unwrap();
+        assert_eq!(meta.size, 12);
+        assert_eq!(meta.nlink, 1);
+        assert_eq!(meta.mode.bits(), 0o644);
+        assert_eq!(meta.mtime, Duration::new(0x1234_5678, 500_000_000));
+        assert_eq!(meta.ctime, Duration::from_secs(1_700_000_000));
+        let stat = fs.stat().unwrap();
+        assert_eq!(stat.fs_type, 0xef53);
+        assert_eq!(stat.blocks, 8192);
+
+        // Unknown incompatible features are refused.
+        let mut image = miniz_oxide::inflate::decompress_to_vec_zlib(EXT4_IMAGE).unwrap();
+        image[1024 + 96 + 2] |= 0x80;
+        assert_eq!(
+            Ext4Fs::new(Arc::new(RamDisk::from_vec(image))).err(),
+            Some(VfsError::Unsupported)
+        );
+        // So is a journal that needs to be replayed.
+        let mut image = miniz_oxide::inflate::decompress_to_vec_zlib(EXT4_IMAGE).unwrap();
+        image[1024 + 96] |= INCOMPAT_RECOVER as u8;
+        assert_eq!(
+            Ext4Fs::new(Arc::new(RamDisk::from_vec(image))).err(),
+            Some(VfsError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_release() {
+        let image = miniz_oxide::inflate::decompress_to_vec_zlib(EXT4_IMAGE).unwrap();
+        let disk = Arc::new(RamDisk::from_vec(image));
+        let fs = Ext4Fs::new(disk.clone()).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs)).root_location();
+        let file = resolve(&root, "dir/sub/deep");
+        root.unmount().unwrap();
+        drop(root);
+        // Nodes keep the filesystem alive.
+        assert!(!read_all(&file).is_empty());
+        assert_eq!(Arc::strong_count(&disk), 2);
+        drop(file);
+        assert_eq!(Arc::strong_count(&disk), 1);
+    }
+}
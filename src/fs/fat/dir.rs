@@ -0,0 +1,554 @@
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+
+use super::{
+    FatFile, FatFs, ROOT_INO,
+    inode::{
+        ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LONG_NAME, ATTR_READ_ONLY, ATTR_VOLUME_ID, ENTRY_SIZE,
+        FatInode, entry_cluster, field, ino_of, set_entry_cluster, set_entry_times,
+    },
+    le16,
+    name::{
+        CHARS_PER_LFN_ENTRY, DELETED, LFN_CHAR_OFFSETS, ShortName, checksum, decode_short,
+        encode_short, eq_ignore_case, exact_short, generate_short, normalize,
+    },
+};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FilesystemOps, Metadata, MetadataUpdate,
+    NodeOps, NodePermission, NodeType, Reference, VfsError, VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT},
+};
+
+/// Maximum number of entries of a directory.
+const MAX_DIR_ENTRIES: usize = 65536;
+const DOT_NAME: &ShortName = b".          ";
+const DOTDOT_NAME: &ShortName = b"..         ";
+
+/// A directory entry together with its long name.
+struct RawEntry {
+    name: String,
+    short: ShortName,
+    /// Slots of the first long name entry and of the short entry.
+    first_slot: usize,
+    slot: usize,
+    /// Position of the short entry on the device.
+    pos: u64,
+    entry: [u8; ENTRY_SIZE],
+}
+
+impl RawEntry {
+    fn is_dir(&self) -> bool {
+        self.entry[field::ATTR] & ATTR_DIRECTORY != 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        eq_ignore_case(&self.name, name) || eq_ignore_case(&decode_short(&self.short, 0), name)
+    }
+}
+
+/// Long name entries collected so far.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the next entry expected.
+    next: u8,
+    first_slot: usize,
+}
+
+/// Parses the entries of a directory, skipping `.`, `..` and volume labels.
+fn parse(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            0 => break,
+            DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        if raw[field::ATTR] & 0x3f == ATTR_LONG_NAME {
+            let order = raw[0];
+            if order & 0x40 != 0 {
+                let count = (order & 0x1f) as usize;
+                long_name = (1..=20).contains(&count).then(|| LongName {
+                    units: vec![0xffff; count * CHARS_PER_LFN_ENTRY],
+                    checksum: raw[13],
+                    next: count as u8,
+                    first_slot: slot,
+                });
+            }
+            match &mut long_name {
+                Some(lfn) if order & 0x1f == lfn.next && raw[13] == lfn.checksum => {
+                    let start = (lfn.next - 1) as usize * CHARS_PER_LFN_ENTRY;
+                    for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                        lfn.units[start + i] = le16(raw, offset);
+                    }
+                    lfn.next -= 1;
+                }
+                _ => long_name = None,
+            }
+            continue;
+        }
+
+        let long_name = long_name.take();
+        if raw[field::ATTR] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+        let short: ShortName = raw[..11].try_into().unwrap();
+        let (name, first_slot) = match long_name {
+            Some(lfn) if lfn.next == 0 && lfn.checksum == checksum(&short) => {
+                let end = lfn
+                    .units
+                    .iter()
+                    .position(|&it| it == 0)
+                    .unwrap_or(lfn.units.len());
+                let name = char::decode_utf16(lfn.units[..end].iter().copied())
+                    .map(|it| it.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, lfn.first_slot)
+            }
+            _ => (decode_short(&short, raw[field::NT_RESERVED]), slot),
+        };
+        if name.is_empty() || name.contains(['/', '\0']) {
+            continue;
+        }
+        entries.push(RawEntry {
+            name,
+            short,
+            first_slot,
+            slot,
+            pos: 0,
+            entry: raw.try_into().unwrap(),
+        });
+    }
+    entries
+}
+
+/// Reads and parses the entries of the directory `inode`.
+fn load(inode: &FatInode) -> VfsResult<(Vec<u8>, Vec<RawEntry>)> {
+    let mut state = inode.lock();
+    let mut data = vec![0; inode.capacity(&mut state)? as usize];
+    inode.read_data(&mut state, 0, &mut data)?;
+    let mut entries = parse(&data);
+    for entry in &mut entries {
+        entry.pos = inode.data_pos(&mut state, (entry.slot * ENTRY_SIZE) as u64)?;
+    }
+    Ok((data, entries))
+}
+
+fn find<'a>(entries: &'a [RawEntry], name: &str) -> Option<&'a RawEntry> {
+    entries.iter().find(|it| it.matches(name))
+}
+
+/// Adds the entry `entry` named `name` to the directory `inode`, whose
+/// current contents are `data` and `entries`.
+///
+/// Returns the position of the new short entry, and the entry with its
+/// short name filled in.
+fn insert(
+    inode: &FatInode,
+    name: &str,
+    mut entry: [u8; ENTRY_SIZE],
+    data: &[u8],
+    entries: &[RawEntry],
+) -> VfsResult<(u64, [u8; ENTRY_SIZE])> {
+    let exists = |short: &ShortName| entries.iter().any(|it| &it.short == short);
+    let (short, nt, units) = match exact_short(name) {
+        Some((short, nt)) if !exists(&short) => (short, nt, Vec::new()),
+        _ => (
+            generate_short(name, exists)?,
+            0,
+            name.encode_utf16().collect(),
+        ),
+    };
+    let short = encode_short(short);
+    entry[..11].copy_from_slice(&short);
+    entry[field::NT_RESERVED] = nt;
+
+    let lfn_count = units.len().div_ceil(CHARS_PER_LFN_ENTRY);
+    let count = lfn_count + 1;
+    let sum = checksum(&short);
+    let mut buf = vec![0; count * ENTRY_SIZE];
+    for (i, raw) in buf.chunks_exact_mut(ENTRY_SIZE).take(lfn_count).enumerate() {
+        let order = lfn_count - i;
+        raw[0] = order as u8 | if i == 0 { 0x40 } else { 0 };
+        raw[field::ATTR] = ATTR_LONG_NAME;
+        raw[13] = sum;
+        for (k, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            let index = (order - 1) * CHARS_PER_LFN_ENTRY + k;
+            let unit = match index.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[index],
+                core::cmp::Ordering::Equal => 0,
+                core::cmp::Ordering::Greater => 0xffff,
+            };
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    buf[lfn_count * ENTRY_SIZE..].copy_from_slice(&entry);
+
+    // Look for a run of free slots. Everything from the end marker on is
+    // free, and the directory is extended if the run is too short.
+    let mut start = 0;
+    let mut run = 0;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        if raw[0] == 0 {
+            break;
+        }
+        if raw[0] == DELETED {
+            run += 1;
+            if run == count {
+                break;
+            }
+        } else {
+            start = slot + 1;
+            run = 0;
+        }
+    }
+    let end = (start + count) * ENTRY_SIZE;
+    if end > MAX_DIR_ENTRIES * ENTRY_SIZE {
+        return Err(VfsError::StorageFull);
+    }
+
+    let mut state = inode.lock();
+    if end as u64 > inode.capacity(&mut state)? {
+        inode.resize(&mut state, end as u64)?;
+    }
+    inode.write_data(&mut state, (start * ENTRY_SIZE) as u64, &buf)?;
+    let pos = inode.data_pos(&mut state, ((start + lfn_count) * ENTRY_SIZE) as u64)?;
+    Ok((pos, entry))
+}
+
+/// Marks the slots of `entry` in the directory `inode` deleted.
+fn remove(inode: &FatInode, entry: &RawEntry) -> VfsResult<()> {
+    let mut state = inode.lock();
+    for slot in entry.first_slot..=entry.slot {
+        inode.write_data(&mut state, (slot * ENTRY_SIZE) as u64, &[DELETED])?;
+    }
+    Ok(())
+}
+
+/// Releases the clusters of a removed entry, or leaves it to the node if it
+/// is still in use.
+fn release(fs: &Arc<FatFs>, entry: &RawEntry) -> VfsResult<()> {
+    match fs.live_inode(entry.pos) {
+        Some(inode) => {
+            inode.detach();
+            Ok(())
+        }
+        None => fs.free_clusters(&fs.read_chain(entry_cluster(&entry.entry))?),
+    }
+}
+
+/// Marks a directory modified.
+fn touch(inode: &FatInode) -> VfsResult<()> {
+    let mut state = inode.lock();
+    inode.touch(&mut state);
+    inode.write_entry(&state)
+}
+
+/// Directory node of a [`FatFs`].
+pub struct FatDir {
+    inode: Arc<FatInode>,
+    this: WeakDirEntry,
+}
+
+impl FatDir {
+    pub(super) fn new(inode: Arc<FatInode>, this: WeakDirEntry) -> Self {
+        Self { inode, this }
+    }
+
+    fn fs(&self) -> &Arc<FatFs> {
+        &self.inode.fs
+    }
+
+    /// Returns the cluster that `..` entries of subdirectories refer to.
+    fn dotdot_cluster(&self) -> u32 {
+        if self.inode.ino == ROOT_INO {
+            0
+        } else {
+            self.inode.lock().first_cluster()
+        }
+    }
+
+    fn open(&self, pos: u64, entry: [u8; ENTRY_SIZE], name: &str) -> DirEntry {
+        let inode = self.fs().inode(pos, entry);
+        let parent = self.this.upgrade();
+        let mut last = inode.entry.lock();
+        // Names differing only in case lead to the same entry, as the core
+        // tells nodes apart by their entries.
+        if let Some(entry) = last.as_ref().and_then(WeakDirEntry::upgrade)
+            && eq_ignore_case(entry.name(), name)
+            && entry
+                .parent()
+                .zip(parent.as_ref())
+                .is_some_and(|(a, b)| a.ptr_eq(b))
+        {
+            return entry;
+        }
+        let reference = Reference::new(parent, name.to_owned());
+        let entry = if inode.is_dir {
+            let inode = inode.clone();
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(FatDir::new(inode, this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Arc::new(FatFile::new(inode.clone()))),
+                NodeType::RegularFile,
+                reference,
+            )
+        };
+        *last = Some(entry.downgrade());
+        entry
+    }
+
+    /// Allocates and initializes the cluster of a new subdirectory.
+    fn new_dir_cluster(&self, entry: &[u8; ENTRY_SIZE]) -> VfsResult<u32> {
+        let fs = self.fs();
+        let cluster = fs.alloc_clusters(None, 1)?[0];
+        fs.zero_cluster(cluster)?;
+        let mut dots = [*entry; 2];
+        dots[0][..11].copy_from_slice(DOT_NAME);
+        set_entry_cluster(&mut dots[0], cluster);
+        dots[1][..11].copy_from_slice(DOTDOT_NAME);
+        set_entry_cluster(&mut dots[1], self.dotdot_cluster());
+        let pos = fs.geometry.cluster_pos(cluster);
+        fs.cache.write_at(pos, dots.as_flattened())?;
+        Ok(cluster)
+    }
+}
+
+impl NodeOps for FatDir {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &**self.fs()
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.fs().flush()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for FatDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let fs = self.fs();
+        let (_, entries) = {
+            let _guard = fs.dir_lock.lock();
+            load(&self.inode)?
+        };
+        let parent_ino = self
+            .this
+            .upgrade()
+            .and_then(|it| it.parent())
+            .map_or(self.inode.ino, |it| it.inode());
+        // Children are addressed by their slot, so that offsets stay valid
+        // across changes to the directory.
+        let dots = [(DOT, self.inode.ino, 1), (DOTDOT, parent_ino, 2)]
+            .into_iter()
+            .map(|(name, ino, next)| (name, ino, NodeType::Directory, next));
+        let children = entries.iter().map(|it| {
+            let ino = fs.live_inode(it.pos).map_or(ino_of(it.pos), |it| it.ino);
+            let node_type = if it.is_dir() {
+                NodeType::Directory
+            } else {
+                NodeType::RegularFile
+            };
+            (it.name.as_str(), ino, node_type, it.slot as u64 + 3)
+        });
+
+        let mut count = 0;
+        for (name, ino, node_type, next) in dots.chain(children) {
+            if next <= offset {
+                continue;
+            }
+            if !sink.accept(name, ino, node_type, next) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let name = normalize(name)?;
+        let _guard = self.fs().dir_lock.lock();
+        let (_, entries) = load(&self.inode)?;
+        let entry = find(&entries, name).ok_or(VfsError::NotFound)?;
+        Ok(self.open(entry.pos, entry.entry, name))
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let fs = self.fs();
+        fs.check_writable()?;
+        let name = normalize(name)?;
+        let attr = match node_type {
+            NodeType::Directory => ATTR_DIRECTORY,
+            NodeType::RegularFile => ATTR_ARCHIVE,
+            _ => return Err(VfsError::Unsupported),
+        };
+        let _guard = fs.dir_lock.lock();
+        let (data, entries) = load(&self.inode)?;
+        if find(&entries, name).is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let mut entry = [0; ENTRY_SIZE];
+        entry[field::ATTR] = attr;
+        if !permission.contains(NodePermission::OWNER_WRITE) {
+            entry[field::ATTR] |= ATTR_READ_ONLY;
+        }
+        set_entry_times(&mut entry, fs.now().unwrap_or_default());
+        if node_type == NodeType::Directory {
+            let cluster = self.new_dir_cluster(&entry)?;
+            set_entry_cluster(&mut entry, cluster);
+        }
+        let (pos, entry) = match insert(&self.inode, name, entry, &data, &entries) {
+            Ok(it) => it,
+            Err(err) => {
+                if node_type == NodeType::Directory {
+                    fs.free_clusters(&[entry_cluster(&entry)])?;
+                }
+                return Err(err);
+            }
+        };
+        touch(&self.inode)?;
+        Ok(self.open(pos, entry, name))
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let fs = self.fs();
+        fs.check_writable()?;
+        let name = normalize(name)?;
+        let _guard = fs.dir_lock.lock();
+        let (_, entries) = load(&self.inode)?;
+        let entry = find(&entries, name).ok_or(VfsError::NotFound)?;
+        if entry.is_dir() {
+            let inode = fs.inode(entry.pos, entry.entry);
+            if !load(&inode)?.1.is_empty() {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+        }
+        remove(&self.inode, entry)?;
+        release(fs, entry)?;
+        touch(&self.inode)
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let fs = self.fs();
+        fs.check_writable()?;
+        let src_name = normalize(src_name)?;
+        let dst_name = normalize(dst_name)?;
+        let dst_dir = dst_dir.downcast::<FatDir>()?;
+        let same_dir = Arc::ptr_eq(&self.inode, &dst_dir.inode);
+        let _guard = fs.dir_lock.lock();
+
+        let (src_data, src_entries) = load(&self.inode)?;
+        let src = find(&src_entries, src_name).ok_or(VfsError::NotFound)?;
+        let (_, dst_entries) = load(&dst_dir.inode)?;
+        let mut replaced = None;
+        if let Some(dst) = find(&dst_entries, dst_name) {
+            if dst.pos == src.pos {
+                if src.name == dst_name {
+                    return Ok(());
+                }
+                // Only the case of the name changes.
+            } else {
+                if src.is_dir() != dst.is_dir() {
+                    return Err(if dst.is_dir() {
+                        VfsError::IsADirectory
+                    } else {
+                        VfsError::NotADirectory
+                    });
+                }
+                if dst.is_dir() && !load(&fs.inode(dst.pos, dst.entry))?.1.is_empty() {
+                    return Err(VfsError::DirectoryNotEmpty);
+                }
+                replaced = Some(dst);
+            }
+        }
+
+        let live = fs.live_inode(src.pos);
+        let mut state = live.as_ref().map(|it| it.lock());
+        let entry = state.as_ref().map_or(src.entry, |it| it.entry);
+        remove(&self.inode, src)?;
+        let placed = match replaced {
+            // Take over the entry of the replaced node, keeping its name, so
+            // that the destination never goes missing and no room is needed.
+            Some(dst) => {
+                let mut entry = entry;
+                entry[..11].copy_from_slice(&dst.entry[..11]);
+                entry[field::NT_RESERVED] = dst.entry[field::NT_RESERVED];
+                let mut dir_state = dst_dir.inode.lock();
+                dst_dir
+                    .inode
+                    .write_data(&mut dir_state, (dst.slot * ENTRY_SIZE) as u64, &entry)
+                    .map(|()| (dst.pos, entry))
+            }
+            None => load(&dst_dir.inode).and_then(|(dst_data, dst_entries)| {
+                insert(&dst_dir.inode, dst_name, entry, &dst_data, &dst_entries)
+            }),
+        };
+        let (pos, entry) = match placed {
+            Ok(it) => it,
+            Err(err) => {
+                // Bring the source entry back.
+                let range = src.first_slot * ENTRY_SIZE..(src.slot + 1) * ENTRY_SIZE;
+                let mut dir_state = self.inode.lock();
+                self.inode
+                    .write_data(&mut dir_state, range.start as u64, &src_data[range])?;
+                return Err(err);
+            }
+        };
+        if let Some(dst) = replaced {
+            release(fs, dst)?;
+        }
+        if let (Some(inode), Some(state)) = (&live, &mut state) {
+            state.entry = entry;
+            inode.relocate(state, pos);
+        }
+        drop(state);
+
+        if src.is_dir() && !same_dir {
+            let inode = live.unwrap_or_else(|| fs.inode(pos, entry));
+            let mut state = inode.lock();
+            let mut dotdot = [0; ENTRY_SIZE];
+            inode.read_data(&mut state, ENTRY_SIZE as u64, &mut dotdot)?;
+            if dotdot[..11] == *DOTDOT_NAME {
+                set_entry_cluster(&mut dotdot, dst_dir.dotdot_cluster());
+                inode.write_data(&mut state, ENTRY_SIZE as u64, &dotdot)?;
+            }
+        }
+        touch(&self.inode)?;
+        if !same_dir {
+            touch(&dst_dir.inode)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use alloc::{sync::Arc, vec};
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::inode::{FatInode, InodeState};
+use crate::{FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps, VfsError, VfsResult};
+
+/// Largest size of a file, as sizes are stored in 32 bits.
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// Regular file node of a [`FatFs`](super::FatFs).
+pub struct FatFile {
+    inode: Arc<FatInode>,
+}
+
+impl FatFile {
+    pub(super) fn new(inode: Arc<FatInode>) -> Self {
+        Self { inode }
+    }
+
+    /// Fills `start..end` with zeros.
+    fn zero_fill(&self, state: &mut InodeState, mut start: u64, end: u64) -> VfsResult<()> {
+        let zeros = vec![0; self.inode.fs.geometry.cluster_size as usize];
+        while start < end {
+            let len = (end - start).min(zeros.len() as u64) as usize;
+            self.inode.write_data(state, start, &zeros[..len])?;
+            start += len as u64;
+        }
+        Ok(())
+    }
+
+    fn write_locked(&self, state: &mut InodeState, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let inode = &self.inode;
+        inode.fs.check_writable()?;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&it| it <= MAX_FILE_SIZE)
+            .ok_or(VfsError::from(axerrno::LinuxError::EFBIG))?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let size = state.size();
+        if end > inode.capacity(state)? {
+            inode.resize(state, end)?;
+        }
+        if offset > size {
+            self.zero_fill(state, size, offset)?;
+        }
+        inode.write_data(state, offset, buf)?;
+        if end > size {
+            state.set_size(end);
+        }
+        inode.touch(state);
+        inode.write_entry(state)?;
+        Ok(buf.len())
+    }
+}
+
+impl NodeOps for FatFile {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.inode.lock().size())
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.inode.fs.flush()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for FatFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let mut state = self.inode.lock();
+        let len = buf.len().min(state.size().saturating_sub(offset) as usize);
+        self.inode.read_data(&mut state, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.write_locked(&mut self.inode.lock(), buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let mut state = self.inode.lock();
+        let offset = state.size();
+        let written = self.write_locked(&mut state, buf, offset)?;
+        Ok((written, offset + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        let inode = &self.inode;
+        inode.fs.check_writable()?;
+        if len > MAX_FILE_SIZE {
+            return Err(axerrno::LinuxError::EFBIG.into());
+        }
+        let mut state = inode.lock();
+        let size = state.size();
+        if len > size {
+            if len > inode.capacity(&mut state)? {
+                inode.resize(&mut state, len)?;
+            }
+            self.zero_fill(&mut state, size, len)?;
+        } else {
+            inode.resize(&mut state, len)?;
+        }
+        state.set_size(len);
+        inode.touch(&mut state);
+        inode.write_entry(&state)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+impl Pollable for FatFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,437 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::time::Duration;
+
+use hashbrown::HashSet;
+
+use super::{FatFs, FatType, ROOT_INO, from_fat_time, le16, le32, to_fat_time};
+use crate::{
+    DeviceId, Metadata, MetadataUpdate, Mutex, MutexGuard, NodePermission, NodeType, VfsError,
+    VfsResult, WeakDirEntry,
+};
+
+/// Size of a directory entry.
+pub(super) const ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTR_READ_ONLY: u8 = 0x01;
+pub(super) const ATTR_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTR_DIRECTORY: u8 = 0x10;
+pub(super) const ATTR_ARCHIVE: u8 = 0x20;
+pub(super) const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Byte offsets of the fields of a directory entry.
+pub(super) mod field {
+    pub const ATTR: usize = 11;
+    pub const NT_RESERVED: usize = 12;
+    pub const CREATE_TIME: usize = 14;
+    pub const CREATE_DATE: usize = 16;
+    pub const ACCESS_DATE: usize = 18;
+    pub const CLUSTER_HIGH: usize = 20;
+    pub const WRITE_TIME: usize = 22;
+    pub const WRITE_DATE: usize = 24;
+    pub const CLUSTER_LOW: usize = 26;
+    pub const SIZE: usize = 28;
+}
+
+/// Returns the first cluster recorded in a directory entry.
+pub(super) fn entry_cluster(entry: &[u8]) -> u32 {
+    ((le16(entry, field::CLUSTER_HIGH) as u32) << 16) | le16(entry, field::CLUSTER_LOW) as u32
+}
+
+pub(super) fn set_entry_cluster(entry: &mut [u8], cluster: u32) {
+    entry[field::CLUSTER_HIGH..field::CLUSTER_HIGH + 2]
+        .copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[field::CLUSTER_LOW..field::CLUSTER_LOW + 2]
+        .copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Stamps the creation, access and modification times of an entry.
+pub(super) fn set_entry_times(entry: &mut [u8], time: Duration) {
+    let (date, time) = to_fat_time(time);
+    for (offset, value) in [
+        (field::CREATE_TIME, time),
+        (field::CREATE_DATE, date),
+        (field::ACCESS_DATE, date),
+        (field::WRITE_TIME, time),
+        (field::WRITE_DATE, date),
+    ] {
+        entry[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Inode number of the node whose directory entry is at `pos`.
+pub(super) fn ino_of(pos: u64) -> u64 {
+    pos / ENTRY_SIZE as u64
+}
+
+/// Inode numbers of the live nodes.
+///
+/// Nodes are numbered after the position of their directory entry, which a
+/// node moved or unlinked while in use keeps. A new entry at that position
+/// then gets a number past those of any position.
+pub(super) struct Inos {
+    live: HashSet<u64>,
+    next: u64,
+}
+
+impl Inos {
+    pub fn new(device_len: u64) -> Self {
+        Self {
+            live: HashSet::new(),
+            next: ino_of(device_len) + 1,
+        }
+    }
+
+    fn alloc(&mut self, pos: u64) -> u64 {
+        let mut ino = ino_of(pos);
+        while !self.live.insert(ino) {
+            ino = self.next;
+            self.next += 1;
+        }
+        ino
+    }
+}
+
+pub(super) struct InodeState {
+    /// Position of the directory entry on the device, `None` for the root
+    /// and for unlinked nodes.
+    pub entry_pos: Option<u64>,
+    /// The directory entry, kept in sync with the device.
+    pub entry: [u8; ENTRY_SIZE],
+    /// Cluster chain, loaded on first use.
+    clusters: Option<Vec<u32>>,
+}
+
+impl InodeState {
+    pub fn root(cluster: u32) -> Self {
+        let mut entry = [0; ENTRY_SIZE];
+        entry[field::ATTR] = ATTR_DIRECTORY;
+        set_entry_cluster(&mut entry, cluster);
+        Self {
+            entry_pos: None,
+            entry,
+            clusters: None,
+        }
+    }
+
+    pub fn new(pos: u64, entry: [u8; ENTRY_SIZE]) -> Self {
+        Self {
+            entry_pos: Some(pos),
+            entry,
+            clusters: None,
+        }
+    }
+
+    pub fn attr(&self) -> u8 {
+        self.entry[field::ATTR]
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        entry_cluster(&self.entry)
+    }
+
+    pub fn size(&self) -> u64 {
+        le32(&self.entry, field::SIZE) as u64
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.entry[field::SIZE..field::SIZE + 4].copy_from_slice(&(size as u32).to_le_bytes());
+    }
+}
+
+/// A node of a [`FatFs`], shared by all handles to it.
+pub(super) struct FatInode {
+    pub fs: Arc<FatFs>,
+    pub ino: u64,
+    pub is_dir: bool,
+    state: Mutex<InodeState>,
+    /// Entry of the node last opened, handed out again for names differing
+    /// only in case.
+    pub entry: Mutex<Option<WeakDirEntry>>,
+}
+
+impl FatFs {
+    /// Returns the node of the directory entry `entry` at `pos`.
+    pub(super) fn inode(self: &Arc<Self>, pos: u64, entry: [u8; ENTRY_SIZE]) -> Arc<FatInode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&pos).and_then(|it| it.upgrade()) {
+            return inode;
+        }
+        let inode = FatInode::new(
+            self.clone(),
+            self.inos.lock().alloc(pos),
+            entry[field::ATTR] & ATTR_DIRECTORY != 0,
+            InodeState::new(pos, entry),
+        );
+        inodes.retain(|_, it| it.strong_count() > 0);
+        inodes.insert(pos, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Returns the node of the directory entry at `pos`, if it is alive.
+    pub(super) fn live_inode(&self, pos: u64) -> Option<Arc<FatInode>> {
+        self.inodes.lock().get(&pos).and_then(|it| it.upgrade())
+    }
+}
+
+impl FatInode {
+    pub fn new(fs: Arc<FatFs>, ino: u64, is_dir: bool, state: InodeState) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            ino,
+            is_dir,
+            state: Mutex::new(state),
+            entry: Mutex::new(None),
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, InodeState> {
+        self.state.lock()
+    }
+
+    /// Returns whether this is the fixed-size root directory of FAT12/16.
+    fn is_fixed_root(&self) -> bool {
+        self.ino == ROOT_INO && self.fs.geometry.fat_type != FatType::Fat32
+    }
+
+    fn chain<'a>(&self, state: &'a mut InodeState) -> VfsResult<&'a Vec<u32>> {
+        if state.clusters.is_none() {
+            state.clusters = Some(self.fs.read_chain(state.first_cluster())?);
+        }
+        Ok(state.clusters.as_ref().unwrap())
+    }
+
+    /// Returns the number of bytes the node can hold without allocating.
+    pub fn capacity(&self, state: &mut InodeState) -> VfsResult<u64> {
+        if self.is_fixed_root() {
+            return Ok(self.fs.geometry.root_size);
+        }
+        let cluster_size = self.fs.geometry.cluster_size as u64;
+        Ok(self.chain(state)?.len() as u64 * cluster_size)
+    }
+
+    /// Calls `f` with the device position and length of each contiguous
+    /// piece of the node data in `offset..offset + len`, which must lie
+    /// within the capacity.
+    fn for_each_piece(
+        &self,
+        state: &mut InodeState,
+        mut offset: u64,
+        mut len: usize,
+        mut f: impl FnMut(u64, usize) -> VfsResult<()>,
+    ) -> VfsResult<()> {
+        if self.is_fixed_root() {
+            return f(self.fs.geometry.root_start + offset, len);
+        }
+        let geometry = &self.fs.geometry;
+        let cluster_size = geometry.cluster_size as u64;
+        let chain = self.chain(state)?;
+        while len > 0 {
+            let start = offset % cluster_size;
+            let chunk = len.min((cluster_size - start) as usize);
+            let cluster = chain[(offset / cluster_size) as usize];
+            f(geometry.cluster_pos(cluster) + start, chunk)?;
+            offset += chunk as u64;
+            len -= chunk;
+        }
+        Ok(())
+    }
+
+    /// Returns the device position of the byte `offset` of the node data.
+    pub fn data_pos(&self, state: &mut InodeState, offset: u64) -> VfsResult<u64> {
+        let mut result = 0;
+        self.for_each_piece(state, offset, 1, |pos, _| {
+            result = pos;
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    pub fn read_data(&self, state: &mut InodeState, offset: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let mut done = 0;
+        self.for_each_piece(state, offset, buf.len(), |pos, len| {
+            self.fs.cache.read_at(pos, &mut buf[done..done + len])?;
+            done += len;
+            Ok(())
+        })
+    }
+
+    pub fn write_data(&self, state: &mut InodeState, offset: u64, buf: &[u8]) -> VfsResult<()> {
+        let mut done = 0;
+        self.for_each_piece(state, offset, buf.len(), |pos, len| {
+            self.fs.cache.write_at(pos, &buf[done..done + len])?;
+            done += len;
+            Ok(())
+        })
+    }
+
+    /// Grows or shrinks the cluster chain to hold `len` bytes.
+    ///
+    /// Clusters added to directories are zeroed. The directory entry is
+    /// updated in memory only.
+    pub fn resize(&self, state: &mut InodeState, len: u64) -> VfsResult<()> {
+        if self.is_fixed_root() {
+            return if len > self.fs.geometry.root_size {
+                Err(VfsError::StorageFull)
+            } else {
+                Ok(())
+            };
+        }
+        let fs = &self.fs;
+        let needed = len.div_ceil(fs.geometry.cluster_size as u64) as usize;
+        let chain = self.chain(state)?;
+        let current = chain.len();
+        if needed > current {
+            let new = fs.alloc_clusters(chain.last().copied(), needed - current)?;
+            if self.is_dir {
+                for &cluster in &new {
+                    fs.zero_cluster(cluster)?;
+                }
+            }
+            if current == 0 {
+                set_entry_cluster(&mut state.entry, new[0]);
+            }
+            state.clusters.as_mut().unwrap().extend(new);
+        } else if needed < current {
+            let freed = state.clusters.as_mut().unwrap().split_off(needed);
+            match state.clusters.as_ref().unwrap().last() {
+                Some(&last) => fs.set_fat_entry(last, fs.geometry.end_of_chain())?,
+                None => set_entry_cluster(&mut state.entry, 0),
+            }
+            fs.free_clusters(&freed)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the node modified: sets the archive attribute and, if a clock
+    /// is available, the modification time.
+    pub fn touch(&self, state: &mut InodeState) {
+        let entry = &mut state.entry;
+        if !self.is_dir {
+            entry[field::ATTR] |= ATTR_ARCHIVE;
+        }
+        if let Some(now) = self.fs.now() {
+            let (date, time) = to_fat_time(now);
+            entry[field::WRITE_TIME..field::WRITE_TIME + 2].copy_from_slice(&time.to_le_bytes());
+            entry[field::WRITE_DATE..field::WRITE_DATE + 2].copy_from_slice(&date.to_le_bytes());
+            entry[field::ACCESS_DATE..field::ACCESS_DATE + 2].copy_from_slice(&date.to_le_bytes());
+        }
+    }
+
+    /// Writes the in-memory directory entry to the device.
+    pub fn write_entry(&self, state: &InodeState) -> VfsResult<()> {
+        match state.entry_pos {
+            Some(pos) => self.fs.cache.write_at(pos, &state.entry),
+            None => Ok(()),
+        }
+    }
+
+    pub fn metadata(&self) -> VfsResult<Metadata> {
+        let mut state = self.lock();
+        let options = &self.fs.options;
+        let attr = state.attr();
+        let (node_type, mut mode) = if self.is_dir {
+            (NodeType::Directory, 0o777)
+        } else {
+            (NodeType::RegularFile, 0o666)
+        };
+        if attr & ATTR_READ_ONLY != 0 {
+            mode &= !0o222;
+        }
+        let capacity = self.capacity(&mut state)?;
+        let size = if self.is_dir { capacity } else { state.size() };
+        let entry = &state.entry;
+        let mtime = from_fat_time(
+            le16(entry, field::WRITE_DATE),
+            le16(entry, field::WRITE_TIME),
+        );
+        Ok(Metadata {
+            device: 0,
+            inode: self.ino,
+            nlink: if self.is_dir { 2 } else { 1 },
+            mode: NodePermission::from_bits_truncate(mode & !options.umask),
+            node_type,
+            uid: options.uid,
+            gid: options.gid,
+            size,
+            block_size: self.fs.geometry.cluster_size as u64,
+            blocks: capacity / 512,
+            rdev: DeviceId::default(),
+            atime: from_fat_time(le16(entry, field::ACCESS_DATE), 0),
+            mtime,
+            ctime: mtime,
+        })
+    }
+
+    pub fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let options = &self.fs.options;
+        if update
+            .owner
+            .is_some_and(|owner| owner != (options.uid, options.gid))
+            || update.rdev.is_some()
+        {
+            return Err(VfsError::Unsupported);
+        }
+        if self.ino == ROOT_INO {
+            // The root directory has no entry to record anything in.
+            return Ok(());
+        }
+        self.fs.check_writable()?;
+        let mut state = self.lock();
+        let entry = &mut state.entry;
+        if let Some(mode) = update.mode {
+            if mode.contains(NodePermission::OWNER_WRITE) {
+                entry[field::ATTR] &= !ATTR_READ_ONLY;
+            } else {
+                entry[field::ATTR] |= ATTR_READ_ONLY;
+            }
+        }
+        if let Some(atime) = update.atime {
+            let (date, _) = to_fat_time(atime);
+            entry[field::ACCESS_DATE..field::ACCESS_DATE + 2].copy_from_slice(&date.to_le_bytes());
+        }
+        if let Some(mtime) = update.mtime {
+            let (date, time) = to_fat_time(mtime);
+            entry[field::WRITE_TIME..field::WRITE_TIME + 2].copy_from_slice(&time.to_le_bytes());
+            entry[field::WRITE_DATE..field::WRITE_DATE + 2].copy_from_slice(&date.to_le_bytes());
+        }
+        self.write_entry(&state)
+    }
+
+    /// Detaches the node from its directory entry, which the caller is about
+    /// to remove. The clusters are freed once the last handle is dropped.
+    pub fn detach(&self) {
+        let mut state = self.lock();
+        if let Some(pos) = state.entry_pos.take() {
+            self.fs.inodes.lock().remove(&pos);
+        }
+    }
+
+    /// Moves the node to the directory entry at `pos`.
+    pub fn relocate(self: &Arc<Self>, state: &mut InodeState, pos: u64) {
+        let mut inodes = self.fs.inodes.lock();
+        if let Some(old) = state.entry_pos.replace(pos) {
+            inodes.remove(&old);
+        }
+        inodes.insert(pos, Arc::downgrade(self));
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        if self.ino == ROOT_INO {
+            return;
+        }
+        self.fs.inos.lock().live.remove(&self.ino);
+        let state = self.state.get_mut();
+        if state.entry_pos.is_none() {
+            // Unlinked while in use.
+            let chain = match state.clusters.take() {
+                Some(chain) => Ok(chain),
+                None => self.fs.read_chain(state.first_cluster()),
+            };
+            if let Ok(chain) = chain {
+                let _ = self.fs.free_clusters(&chain);
+            }
+        }
+    }
+}
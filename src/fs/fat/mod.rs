@@ -0,0 +1,815 @@
+//! FAT12/16/32 filesystem with VFAT long file names.
+//!
+//! Names are looked up case-insensitively, and new names get a generated
+//! 8.3 short name alongside the long one when needed. FAT has no notion of
+//! ownership, links or special files: ownership is fixed by [`FatOptions`],
+//! and the write permission bits map to the read-only attribute.
+//!
+//! Timestamps have a granularity of 2 seconds for modification times and a
+//! day for access times. As there is no clock in this crate, nodes are only
+//! timestamped on changes if [`FatOptions::clock`] is set.
+
+mod dir;
+mod file;
+mod inode;
+mod name;
+
+use alloc::{sync::Arc, sync::Weak, vec, vec::Vec};
+use core::time::Duration;
+
+use hashbrown::HashMap;
+
+use self::inode::{FatInode, InodeState, Inos};
+pub use self::{dir::FatDir, file::FatFile};
+use super::{RootDir, days_from_civil, le16, le32};
+use crate::{
+    DirEntry, DirNode, FilesystemOps, Mutex, Reference, StatFs, VfsError, VfsResult,
+    block::{BlockDevice, BufferCache},
+};
+
+pub(crate) const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+pub(crate) const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+pub(crate) const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+pub(crate) const FSINFO_TRAIL_SIGNATURE: u32 = 0xaa55_0000;
+/// FSInfo value for an unknown free count or next free cluster.
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+const CACHE_CAPACITY: usize = 1024;
+const ROOT_INO: u64 = 1;
+
+/// Smallest cluster number referring to the data region.
+const FIRST_CLUSTER: u32 = 2;
+
+/// Options for mounting a [`FatFs`].
+#[derive(Debug, Clone)]
+pub struct FatOptions {
+    /// Owner reported for all nodes.
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits masked out of the reported modes.
+    pub umask: u16,
+    /// Source of the current time, used to timestamp created and modified
+    /// nodes.
+    pub clock: Option<fn() -> Duration>,
+}
+
+impl Default for FatOptions {
+    fn default() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            umask: 0o022,
+            clock: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Layout of the volume, from the BIOS parameter block.
+struct Geometry {
+    fat_type: FatType,
+    cluster_size: u32,
+    /// Byte offset of the first FAT.
+    fat_start: u64,
+    /// Size of a FAT in bytes.
+    fat_size: u64,
+    num_fats: u32,
+    /// The only FAT in use, if mirroring is disabled.
+    active_fat: Option<u32>,
+    /// Byte offset and size of the fixed root directory of FAT12/16.
+    root_start: u64,
+    root_size: u64,
+    /// First cluster of the root directory of FAT32.
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    /// Byte offset of the FSInfo sector of FAT32.
+    fsinfo: Option<u64>,
+}
+
+impl Geometry {
+    fn parse(bs: &[u8], device_len: u64) -> VfsResult<Self> {
+        let bytes_per_sector = le16(bs, 11) as u64;
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved = le16(bs, 14) as u64;
+        let num_fats = bs[16] as u32;
+        let root_entries = le16(bs, 17) as u64;
+        let total_sectors = match le16(bs, 19) {
+            0 => le32(bs, 32) as u64,
+            it => it as u64,
+        };
+        let fat16_size = le16(bs, 22) as u64;
+        if bs[510..512] != BOOT_SIGNATURE
+            || !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || num_fats == 0
+        {
+            return Err(VfsError::InvalidData);
+        }
+        let is_fat32 = fat16_size == 0;
+        let fat_sectors = if is_fat32 {
+            le32(bs, 36) as u64
+        } else {
+            fat16_size
+        };
+        if fat_sectors == 0 || (is_fat32 && root_entries != 0) {
+            return Err(VfsError::InvalidData);
+        }
+
+        let root_start = (reserved + num_fats as u64 * fat_sectors) * bytes_per_sector;
+        let root_size = (root_entries * 32).next_multiple_of(bytes_per_sector);
+        let data_start = root_start + root_size;
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+        let cluster_count = (total_sectors * bytes_per_sector)
+            .checked_sub(data_start)
+            .ok_or(VfsError::InvalidData)?
+            / cluster_size;
+        let fat_type = if is_fat32 {
+            FatType::Fat32
+        } else if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            return Err(VfsError::InvalidData);
+        };
+        let fat_size = fat_sectors * bytes_per_sector;
+        let geometry = Self {
+            fat_type,
+            cluster_size: cluster_size as u32,
+            fat_start: reserved * bytes_per_sector,
+            fat_size,
+            num_fats,
+            active_fat: None,
+            root_start,
+            root_size,
+            root_cluster: 0,
+            data_start,
+            cluster_count: cluster_count.min(0x0fff_fff5) as u32,
+            fsinfo: None,
+        };
+        let max_entries = match fat_type {
+            FatType::Fat12 => fat_size * 2 / 3,
+            FatType::Fat16 => fat_size / 2,
+            FatType::Fat32 => fat_size / 4,
+        };
+        if max_entries < geometry.cluster_count as u64 + 2
+            || data_start + cluster_count * cluster_size > device_len
+        {
+            return Err(VfsError::InvalidData);
+        }
+        if fat_type != FatType::Fat32 {
+            return Ok(geometry);
+        }
+
+        let ext_flags = le16(bs, 40);
+        let fsinfo = le16(bs, 48) as u64;
+        Ok(Self {
+            active_fat: (ext_flags & 0x80 != 0).then_some(ext_flags as u32 & 0xf),
+            root_cluster: le32(bs, 44),
+            fsinfo: (fsinfo != 0 && fsinfo < reserved).then_some(fsinfo * bytes_per_sector),
+            ..geometry
+        })
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size as u64
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+/// Allocation state of the FAT, mirrored in the FSInfo sector of FAT32.
+struct TableState {
+    free_count: u32,
+    next_free: u32,
+    dirty: bool,
+}
+
+/// A FAT filesystem on a block device.
+pub struct FatFs {
+    cache: BufferCache,
+    geometry: Geometry,
+    options: FatOptions,
+    table: Mutex<TableState>,
+    /// Live nodes, keyed by the position of their directory entry.
+    inodes: Mutex<HashMap<u64, Weak<FatInode>>>,
+    inos: Mutex<Inos>,
+    /// Serializes changes to directories.
+    dir_lock: Mutex<()>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl FatFs {
+    /// Mounts the FAT filesystem on `device` with default options.
+    pub fn new(device: Arc<dyn BlockDevice>) -> VfsResult<Arc<Self>> {
+        Self::with_options(device, FatOptions::default())
+    }
+
+    /// Mounts the FAT filesystem on `device`.
+    pub fn with_options(device: Arc<dyn BlockDevice>, options: FatOptions) -> VfsResult<Arc<Self>> {
+        if device.len() < 512 {
+            return Err(VfsError::InvalidData);
+        }
+        let mut bs = vec![0; device.sector_size().max(512)];
+        device.read_sectors(0, &mut bs)?;
+        let geometry = Geometry::parse(&bs, device.len())?;
+        let inos = Inos::new(device.len());
+        let bytes_per_sector = le16(&bs, 11) as usize;
+        let cache = BufferCache::new(device, bytes_per_sector, CACHE_CAPACITY)
+            .map_err(|_| VfsError::InvalidData)?;
+        if geometry.fat_type == FatType::Fat32 && !geometry.is_valid_cluster(geometry.root_cluster)
+        {
+            return Err(VfsError::InvalidData);
+        }
+
+        let fs = Arc::new_cyclic(|this| Self {
+            cache,
+            geometry,
+            options,
+            table: Mutex::new(TableState {
+                free_count: 0,
+                next_free: FIRST_CLUSTER,
+                dirty: false,
+            }),
+            inodes: Mutex::default(),
+            inos: Mutex::new(inos),
+            dir_lock: Mutex::default(),
+            this: this.clone(),
+            root: RootDir::default(),
+        });
+        fs.load_table_state()?;
+        Ok(fs)
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let root = FatInode::new(
+            self.this.upgrade().unwrap(),
+            ROOT_INO,
+            true,
+            InodeState::root(self.geometry.root_cluster),
+        );
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(FatDir::new(root, this))),
+            Reference::root(),
+        )
+    }
+
+    /// Loads the free cluster count from FSInfo, or counts free clusters if
+    /// it is missing or implausible.
+    fn load_table_state(&self) -> VfsResult<()> {
+        let geometry = &self.geometry;
+        if let Some(pos) = geometry.fsinfo {
+            let mut fsinfo = [0; 512];
+            self.cache.read_at(pos, &mut fsinfo)?;
+            let free_count = le32(&fsinfo, 488);
+            let next_free = le32(&fsinfo, 492);
+            if le32(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE
+                && le32(&fsinfo, 484) == FSINFO_STRUCT_SIGNATURE
+                && free_count <= geometry.cluster_count
+            {
+                let mut table = self.table.lock();
+                table.free_count = free_count;
+                if geometry.is_valid_cluster(next_free) {
+                    table.next_free = next_free;
+                }
+                return Ok(());
+            }
+        }
+        let mut free_count = 0;
+        for cluster in FIRST_CLUSTER..geometry.cluster_count + FIRST_CLUSTER {
+            if self.fat_entry(cluster)? == 0 {
+                free_count += 1;
+            }
+        }
+        let mut table = self.table.lock();
+        table.free_count = free_count;
+        // Get the FSInfo sector fixed on the next flush.
+        table.dirty = geometry.fsinfo.is_some();
+        Ok(())
+    }
+
+    fn check_writable(&self) -> VfsResult<()> {
+        if self.cache.device().is_read_only() {
+            return Err(VfsError::ReadOnlyFilesystem);
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> Option<Duration> {
+        self.options.clock.map(|clock| clock())
+    }
+
+    /// Returns the byte offset of the FAT entry of `cluster` in FAT `index`.
+    fn fat_entry_pos(&self, index: u32, cluster: u32) -> u64 {
+        let geometry = &self.geometry;
+        let offset = match geometry.fat_type {
+            FatType::Fat12 => cluster as u64 * 3 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        geometry.fat_start + index as u64 * geometry.fat_size + offset
+    }
+
+    fn fat_entry(&self, cluster: u32) -> VfsResult<u32> {
+        let pos = self.fat_entry_pos(self.geometry.active_fat.unwrap_or(0), cluster);
+        Ok(match self.geometry.fat_type {
+            FatType::Fat12 => {
+                let mut buf = [0; 2];
+                self.cache.read_at(pos, &mut buf)?;
+                let value = u16::from_le_bytes(buf) as u32;
+                if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FatType::Fat16 => {
+                let mut buf = [0; 2];
+                self.cache.read_at(pos, &mut buf)?;
+                u16::from_le_bytes(buf) as u32
+            }
+            FatType::Fat32 => {
+                let mut buf = [0; 4];
+                self.cache.read_at(pos, &mut buf)?;
+                u32::from_le_bytes(buf) & 0x0fff_ffff
+            }
+        })
+    }
+
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> VfsResult<()> {
+        let geometry = &self.geometry;
+        let fats = match geometry.active_fat {
+            Some(index) => index..index + 1,
+            None => 0..geometry.num_fats,
+        };
+        for index in fats {
+            let pos = self.fat_entry_pos(index, cluster);
+            match geometry.fat_type {
+                FatType::Fat12 => {
+                    let mut buf = [0; 2];
+                    self.cache.read_at(pos, &mut buf)?;
+                    let old = u16::from_le_bytes(buf);
+                    let new = if cluster % 2 == 1 {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    } else {
+                        (old & 0xf000) | (value as u16 & 0xfff)
+                    };
+                    self.cache.write_at(pos, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.cache.write_at(pos, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    let mut buf = [0; 4];
+                    self.cache.read_at(pos, &mut buf)?;
+                    let new = (u32::from_le_bytes(buf) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.cache.write_at(pos, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next cluster of a chain, or `None` at its end.
+    fn next_cluster(&self, cluster: u32) -> VfsResult<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+        let end_of_chain = match self.geometry.fat_type {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        };
+        if next >= end_of_chain {
+            Ok(None)
+        } else if self.geometry.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(VfsError::InvalidData)
+        }
+    }
+
+    /// Returns the clusters of the chain starting from `first`.
+    fn read_chain(&self, first: u32) -> VfsResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first == 0 {
+            return Ok(chain);
+        }
+        if !self.geometry.is_valid_cluster(first) {
+            return Err(VfsError::InvalidData);
+        }
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            if chain.len() >= self.geometry.cluster_count as usize {
+                // A cycle.
+                return Err(VfsError::InvalidData);
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    /// Allocates `count` clusters and links them after `last`, if any.
+    fn alloc_clusters(&self, last: Option<u32>, count: usize) -> VfsResult<Vec<u32>> {
+        let geometry = &self.geometry;
+        let mut table = self.table.lock();
+        if (table.free_count as usize) < count {
+            return Err(VfsError::StorageFull);
+        }
+        let mut clusters = Vec::with_capacity(count);
+        let mut cluster = table.next_free;
+        for _ in 0..geometry.cluster_count {
+            if clusters.len() == count {
+                break;
+            }
+            if !geometry.is_valid_cluster(cluster) {
+                cluster = FIRST_CLUSTER;
+            }
+            if self.fat_entry(cluster)? == 0 {
+                clusters.push(cluster);
+            }
+            cluster += 1;
+        }
+        if clusters.len() < count {
+            // The free count was off.
+            table.free_count = clusters.len() as u32;
+            table.dirty = true;
+            return Err(VfsError::StorageFull);
+        }
+
+        for pair in clusters.windows(2) {
+            self.set_fat_entry(pair[0], pair[1])?;
+        }
+        if let Some(&tail) = clusters.last() {
+            self.set_fat_entry(tail, geometry.end_of_chain())?;
+        }
+        if let (Some(last), Some(&first)) = (last, clusters.first()) {
+            self.set_fat_entry(last, first)?;
+        }
+        table.free_count -= count as u32;
+        table.next_free = cluster;
+        table.dirty = true;
+        Ok(clusters)
+    }
+
+    /// Frees the given clusters.
+    fn free_clusters(&self, clusters: &[u32]) -> VfsResult<()> {
+        let mut table = self.table.lock();
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        table.free_count += clusters.len() as u32;
+        table.dirty = true;
+        Ok(())
+    }
+
+    /// Fills a cluster with zeros.
+    fn zero_cluster(&self, cluster: u32) -> VfsResult<()> {
+        let block_size = self.cache.block_size() as u64;
+        let start = self.geometry.cluster_pos(cluster) / block_size;
+        for block in start..start + self.geometry.cluster_size as u64 / block_size {
+            self.cache.get_zeroed(block)?;
+        }
+        Ok(())
+    }
+
+    fn write_fsinfo(&self) -> VfsResult<()> {
+        let mut table = self.table.lock();
+        if !table.dirty {
+            return Ok(());
+        }
+        if let Some(pos) = self.geometry.fsinfo {
+            let mut fsinfo = [0; 512];
+            self.cache.read_at(pos, &mut fsinfo)?;
+            fsinfo[0..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+            fsinfo[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+            fsinfo[488..492].copy_from_slice(&table.free_count.to_le_bytes());
+            let next_free = if self.geometry.is_valid_cluster(table.next_free) {
+                table.next_free
+            } else {
+                FSINFO_UNKNOWN
+            };
+            fsinfo[492..496].copy_from_slice(&next_free.to_le_bytes());
+            fsinfo[508..512].copy_from_slice(&FSINFO_TRAIL_SIGNATURE.to_le_bytes());
+            self.cache.write_at(pos, &fsinfo)?;
+        }
+        table.dirty = false;
+        Ok(())
+    }
+}
+
+impl FilesystemOps for FatFs {
+    fn name(&self) -> &str {
+        "vfat"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let free = self.table.lock().free_count as u64;
+        let cluster_size = self.geometry.cluster_size;
+        Ok(StatFs {
+            fs_type: MSDOS_SUPER_MAGIC,
+            block_size: cluster_size,
+            blocks: self.geometry.cluster_count as u64,
+            blocks_free: free,
+            blocks_available: free,
+            file_count: 0,
+            free_file_count: 0,
+            name_length: name::MAX_LONG_NAME_LEN as u32,
+            fragment_size: cluster_size,
+            mount_flags: 0,
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        if !self.cache.device().is_read_only() {
+            self.write_fsinfo()?;
+        }
+        self.cache.sync()
+    }
+}
+
+const UNIX_EPOCH_DAYS_TO_1980: i64 = 3652;
+
+/// Converts days since the Unix epoch to a civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Converts a FAT date and time to a timestamp.
+fn from_fat_time(date: u16, time: u16) -> Duration {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as u32;
+    let day = (date & 0x1f).max(1) as u32;
+    let days = days_from_civil(year, month, day);
+    let secs =
+        (time >> 11) as u64 * 3600 + ((time >> 5) & 0x3f) as u64 * 60 + (time & 0x1f) as u64 * 2;
+    Duration::from_secs(days as u64 * 86400 + secs)
+}
+
+/// Converts a timestamp to a FAT date and time, clamped to the years FAT
+/// can represent.
+fn to_fat_time(time: Duration) -> (u16, u16) {
+    let secs = time.as_secs() as i64;
+    let days = secs / 86400;
+    if days < UNIX_EPOCH_DAYS_TO_1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let (year, month, day) = civil_from_days(days);
+    if year > 2107 {
+        return ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29);
+    }
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let secs = secs % 86400;
+    let time = (((secs / 3600) as u16) << 11)
+        | ((((secs / 60) % 60) as u16) << 5)
+        | ((secs % 60) / 2) as u16;
+    (date, time)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String, vec::Vec};
+
+    use super::*;
+    use crate::{
+        Filesystem, Location, MetadataUpdate, Mountpoint, NodePermission, NodeType,
+        block::RamDisk,
+        test_util::{fat_image, list},
+    };
+
+    fn mount(disk: &Arc<RamDisk>, options: FatOptions) -> (Arc<FatFs>, Location) {
+        let fs = FatFs::with_options(disk.clone(), options).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+        (fs, root)
+    }
+
+    fn read_all(file: &Location) -> Vec<u8> {
+        let file = file.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    #[test]
+    fn test_fat() {
+        let cases = [
+            (8192, 4, false, FatType::Fat12),
+            (65536, 4, false, FatType::Fat16),
+            (16384, 1, true, FatType::Fat32),
+        ];
+        for (sectors, sectors_per_cluster, fat32, fat_type) in cases {
+            let disk = Arc::new(RamDisk::from_vec(fat_image(
+                sectors,
+                sectors_per_cluster,
+                fat32,
+            )));
+            let (fs, root) = mount(&disk, FatOptions::default());
+            assert_eq!(fs.geometry.fat_type, fat_type);
+            let free = fs.stat().unwrap().blocks_free;
+            let perm = NodePermission::from_bits_truncate(0o755);
+
+            let efi = root.create("EFI", NodeType::Directory, perm).unwrap();
+            let boot = efi.create("boot", NodeType::Directory, perm).unwrap();
+            let file = boot
+                .create("Long File Name.efi", NodeType::RegularFile, perm)
+                .unwrap();
+            let data: Vec<u8> = (0..10000u32).map(|it| it as u8).collect();
+            let handle = file.entry().as_file().unwrap();
+            handle.write_at(&data, 100).unwrap();
+            assert_eq!(handle.append(b"tail").unwrap(), (4, 10104));
+            for name in ["short.txt", "MiXeD.TxT", "a+b"] {
+                boot.create(name, NodeType::RegularFile, perm).unwrap();
+            }
+            assert_eq!(
+                boot.create("SHORT.TXT", NodeType::RegularFile, perm).err(),
+                Some(VfsError::AlreadyExists)
+            );
+            assert_eq!(
+                boot.create("link", NodeType::Symlink, perm).err(),
+                Some(VfsError::Unsupported)
+            );
+            assert_eq!(boot.link("hard", &file).err(), Some(VfsError::Unsupported));
+            assert_eq!(
+                file.update_metadata(MetadataUpdate {
+                    owner: Some((1, 1)),
+                    ..Default::default()
+                })
+                .err(),
+                Some(VfsError::Unsupported)
+            );
+            root.entry().filesystem().flush().unwrap();
+
+            // Remount and look around.
+            let (fs, root) = mount(&disk, FatOptions::default());
+            let boot = root
+                .lookup_no_follow("efi")
+                .unwrap()
+                .lookup_no_follow("BOOT")
+                .unwrap();
+            assert_eq!(
+                list(&boot),
+                [
+                    ".",
+                    "..",
+                    "Long File Name.efi",
+                    "short.txt",
+                    "MiXeD.TxT",
+                    "a+b"
+                ]
+            );
+            let file = boot.lookup_no_follow("long file name.EFI").unwrap();
+            let contents = read_all(&file);
+            assert_eq!(contents.len(), 10104);
+            assert!(contents[..100].iter().all(|&it| it == 0));
+            assert_eq!(&contents[100..10100], &data);
+            // The generated short name works too.
+            let short = boot.lookup_no_follow("LONGFI~1.EFI").unwrap();
+            assert_eq!(short.inode(), file.inode());
+
+            root.rename("EFI", &root, "efi").unwrap();
+            boot.rename("Long File Name.efi", &root, "moved.efi")
+                .unwrap();
+            let efi = root.lookup_no_follow("efi").unwrap();
+            efi.rename("boot", &root, "boot2").unwrap();
+            let boot2 = root.lookup_no_follow("boot2").unwrap();
+            assert_eq!(boot2.lookup_no_follow("..").unwrap().inode(), root.inode());
+            assert_eq!(list(&root), [".", "..", "efi", "moved.efi", "boot2"]);
+            assert_eq!(
+                read_all(&root.lookup_no_follow("moved.efi").unwrap()),
+                contents
+            );
+
+            file.entry().as_file().unwrap().set_len(10).unwrap();
+            assert_eq!(
+                read_all(&root.lookup_no_follow("moved.efi").unwrap()).len(),
+                10
+            );
+            assert_eq!(
+                root.unlink("boot2", true).err(),
+                Some(VfsError::DirectoryNotEmpty)
+            );
+            for name in ["short.txt", "MiXeD.TxT", "a+b"] {
+                boot2.unlink(name, false).unwrap();
+            }
+            root.unlink("boot2", true).unwrap();
+            root.unlink("efi", true).unwrap();
+            root.unlink("moved.efi", false).unwrap();
+            drop((file, short, boot, boot2, efi));
+            assert_eq!(list(&root), [".", ".."]);
+            assert_eq!(fs.stat().unwrap().blocks_free, free);
+            root.entry().filesystem().flush().unwrap();
+            let (fs, _) = mount(&disk, FatOptions::default());
+            assert_eq!(fs.stat().unwrap().blocks_free, free);
+        }
+    }
+
+    #[test]
+    fn test_times() {
+        let disk = Arc::new(RamDisk::from_vec(fat_image(8192, 4, false)));
+        let options = FatOptions {
+            umask: 0o027,
+            clock: Some(|| Duration::from_secs(1_700_000_001)),
+            ..Default::default()
+        };
+        let (_, root) = mount(&disk, options);
+        let file = root
+            .create("file", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.mtime, Duration::from_secs(1_700_000_000));
+        assert_eq!(metadata.atime, Duration::from_secs(1_699_920_000));
+        assert_eq!(metadata.mode.bits(), 0o640);
+
+        file.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o444)),
+            mtime: Some(Duration::from_secs(315_532_800 + 3)),
+            ..Default::default()
+        })
+        .unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.mtime, Duration::from_secs(315_532_802));
+        assert_eq!(metadata.mode.bits(), 0o440);
+        let name: String = "x".repeat(256);
+        assert_eq!(
+            root.create(&name, NodeType::RegularFile, NodePermission::default())
+                .err(),
+            Some(VfsError::NameTooLong)
+        );
+    }
+
+    #[test]
+    fn test_moved_inode() {
+        let disk = Arc::new(RamDisk::from_vec(fat_image(8192, 4, false)));
+        let (_, root) = mount(&disk, FatOptions::default());
+        let perm = NodePermission::default();
+        let x = root.create("x", NodeType::Directory, perm).unwrap();
+        let y = root.create("y", NodeType::Directory, perm).unwrap();
+        let a = x.create("a", NodeType::RegularFile, perm).unwrap();
+        x.rename("a", &y, "a").unwrap();
+        // The new entry takes the slot the open file was moved out of.
+        let c = x.create("c", NodeType::Directory, perm).unwrap();
+        c.create("d", NodeType::RegularFile, perm).unwrap();
+        assert_ne!(c.inode(), a.inode());
+        assert_eq!(y.lookup_no_follow("a").unwrap().inode(), a.inode());
+        assert_eq!(y.rename("a", &x, "c").err(), Some(VfsError::IsADirectory));
+        assert_eq!(list(&c), [".", "..", "d"]);
+
+        // Names differing in case still lead to the same node.
+        x.rename("c", &x, "C").unwrap();
+        assert_eq!(list(&x), [".", "..", "C"]);
+    }
+
+    #[test]
+    fn test_rename_full() {
+        let disk = Arc::new(RamDisk::from_vec(fat_image(8192, 4, false)));
+        let (_, root) = mount(&disk, FatOptions::default());
+        let perm = NodePermission::default();
+        let sub = root.create("sub", NodeType::Directory, perm).unwrap();
+        sub.create("long source name", NodeType::RegularFile, perm)
+            .unwrap();
+        let dst = root.create("dst", NodeType::RegularFile, perm).unwrap();
+        dst.entry().as_file().unwrap().write_at(b"dst", 0).unwrap();
+        // Fill the fixed root directory.
+        for i in 2..512 {
+            root.create(&format!("f{i}"), NodeType::RegularFile, perm)
+                .unwrap();
+        }
+
+        // Replacing an entry takes no room.
+        sub.rename("long source name", &root, "dst").unwrap();
+        assert!(read_all(&root.lookup_no_follow("dst").unwrap()).is_empty());
+        assert_eq!(read_all(&dst), b"dst");
+        assert_eq!(list(&sub), [".", ".."]);
+    }
+}
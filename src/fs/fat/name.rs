@@ -0,0 +1,200 @@
+//! Long and short (8.3) names.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{VfsError, VfsResult};
+
+/// Maximum length of a long name in UTF-16 code units.
+pub const MAX_LONG_NAME_LEN: usize = 255;
+/// Number of UTF-16 code units held by a long name entry.
+pub const CHARS_PER_LFN_ENTRY: usize = 13;
+/// Offsets of the characters in a long name entry.
+pub const LFN_CHAR_OFFSETS: [usize; CHARS_PER_LFN_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Flags of the NT reserved byte marking lowercase short names.
+pub const NT_LOWER_BASE: u8 = 0x08;
+pub const NT_LOWER_EXT: u8 = 0x10;
+
+/// Marker of a deleted entry.
+pub const DELETED: u8 = 0xe5;
+/// Stands for a leading 0xe5 byte in a short name.
+const KANJI_E5: u8 = 0x05;
+
+pub type ShortName = [u8; 11];
+
+/// Checks a long name, stripping the trailing dots that FAT ignores.
+pub fn normalize(name: &str) -> VfsResult<&str> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return Err(VfsError::InvalidInput);
+    }
+    if name.encode_utf16().count() > MAX_LONG_NAME_LEN {
+        return Err(VfsError::NameTooLong);
+    }
+    if name
+        .chars()
+        .any(|it| it < ' ' || matches!(it, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|'))
+    {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok(name)
+}
+
+/// Compares names case-insensitively.
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// Computes the checksum of a short name stored in long name entries.
+pub fn checksum(short: &ShortName) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &it| sum.rotate_right(1).wrapping_add(it))
+}
+
+/// Decodes a short name, applying the lowercase flags.
+pub fn decode_short(short: &ShortName, nt: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut part: String = bytes
+            .iter()
+            .map(|&it| char::from(it))
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .into();
+        if lower {
+            part.make_ascii_lowercase();
+        }
+        part
+    };
+    let mut name = part(&short[..8], nt & NT_LOWER_BASE != 0);
+    if name.starts_with(char::from(KANJI_E5)) {
+        name.replace_range(..1, "\u{e5}");
+    }
+    let ext = part(&short[8..], nt & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn is_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Returns the short name and lowercase flags that represent `name`
+/// exactly, if there are any.
+pub fn exact_short(name: &str) -> Option<(ShortName, u8)> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let mut nt = 0;
+    for (part, flag) in [(base, NT_LOWER_BASE), (ext, NT_LOWER_EXT)] {
+        let has_upper = part.bytes().any(|it| it.is_ascii_uppercase());
+        let has_lower = part.bytes().any(|it| it.is_ascii_lowercase());
+        if has_upper && has_lower {
+            return None;
+        }
+        if has_lower {
+            nt |= flag;
+        }
+        if !part
+            .bytes()
+            .all(|it| is_short_char(it.to_ascii_uppercase()))
+        {
+            return None;
+        }
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short.make_ascii_uppercase();
+    Some((short, nt))
+}
+
+/// Generates a short name for a long name that has no exact one, as
+/// `BASIS~N.EXT`, avoiding the names for which `exists` returns true.
+pub fn generate_short(name: &str, exists: impl Fn(&ShortName) -> bool) -> VfsResult<ShortName> {
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&it| it != ' ' && it != '.')
+            .map(|it| {
+                let it = it.to_ascii_uppercase();
+                if it.is_ascii() && is_short_char(it as u8) {
+                    it as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(len)
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (convert(base, 8), convert(ext, 3)),
+        None => (convert(name, 8), Vec::new()),
+    };
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+
+    let mut short = [b' '; 11];
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1..1_000_000u32 {
+        let mut tail = [0; 7];
+        let tail_len = {
+            let digits = alloc::format!("~{n}");
+            tail[..digits.len()].copy_from_slice(digits.as_bytes());
+            digits.len()
+        };
+        let keep = base.len().min(8 - tail_len);
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail_len].copy_from_slice(&tail[..tail_len]);
+        if !exists(&short) {
+            return Ok(short);
+        }
+    }
+    Err(VfsError::AlreadyExists)
+}
+
+/// Encodes `short` for storing in a directory entry.
+pub fn encode_short(mut short: ShortName) -> ShortName {
+    if short[0] == DELETED {
+        short[0] = KANJI_E5;
+    }
+    short
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_short_names() {
+        assert_eq!(exact_short("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(
+            exact_short("readme.txt"),
+            Some((*b"README  TXT", NT_LOWER_BASE | NT_LOWER_EXT))
+        );
+        assert_eq!(exact_short("ReadMe.txt"), None);
+        assert_eq!(exact_short("a.b.c"), None);
+        assert_eq!(exact_short("longername.txt"), None);
+        assert_eq!(decode_short(b"README  TXT", NT_LOWER_EXT), "README.txt");
+
+        let short = generate_short("Long File Name.text", |_| false).unwrap();
+        assert_eq!(&short, b"LONGFI~1TEX");
+        let short = generate_short("Long File Name.text", |it| it == b"LONGFI~1TEX").unwrap();
+        assert_eq!(&short, b"LONGFI~2TEX");
+        assert_eq!(
+            &generate_short(".bashrc", |_| false).unwrap(),
+            b"BASHRC~1   "
+        );
+        assert_eq!(&generate_short("a+b", |_| false).unwrap(), b"A_B~1      ");
+    }
+}
@@ -0,0 +1,170 @@
+//! Messages of the FUSE kernel protocol, version 7.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+pub const KERNEL_VERSION: u32 = 7;
+/// Latest minor version of the protocol spoken.
+pub const KERNEL_MINOR_VERSION: u32 = 31;
+/// Oldest minor version accepted from daemons.
+pub const MIN_MINOR_VERSION: u32 = 12;
+pub const ROOT_ID: u64 = 1;
+/// Type of filesystem reported by `statfs`.
+pub const SUPER_MAGIC: u32 = 0x65735546;
+
+pub const LOOKUP: u32 = 1;
+pub const FORGET: u32 = 2;
+pub const GETATTR: u32 = 3;
+pub const SETATTR: u32 = 4;
+pub const READLINK: u32 = 5;
+pub const SYMLINK: u32 = 6;
+pub const MKNOD: u32 = 8;
+pub const MKDIR: u32 = 9;
+pub const UNLINK: u32 = 10;
+pub const RMDIR: u32 = 11;
+pub const RENAME: u32 = 12;
+pub const LINK: u32 = 13;
+pub const OPEN: u32 = 14;
+pub const READ: u32 = 15;
+pub const WRITE: u32 = 16;
+pub const STATFS: u32 = 17;
+pub const RELEASE: u32 = 18;
+pub const FSYNC: u32 = 20;
+pub const INIT: u32 = 26;
+pub const OPENDIR: u32 = 27;
+pub const READDIR: u32 = 28;
+pub const RELEASEDIR: u32 = 29;
+pub const CREATE: u32 = 35;
+
+pub const IN_HEADER_SIZE: usize = 40;
+pub const OUT_HEADER_SIZE: usize = 16;
+/// Size of `fuse_entry_out`.
+pub const ENTRY_OUT_SIZE: usize = 128;
+/// Size of `fuse_dirent` without its name.
+pub const DIRENT_SIZE: usize = 24;
+
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+
+pub const O_RDONLY: u32 = 0;
+pub const O_RDWR: u32 = 2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+
+pub const FSYNC_FDATASYNC: u32 = 1;
+
+/// Builder of the arguments of a request or the fields of a reply.
+#[derive(Default)]
+pub struct Args(pub Vec<u8>);
+
+impl Args {
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Appends a NUL-terminated name.
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        self
+    }
+
+    pub fn raw(&mut self, data: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(data);
+        self
+    }
+}
+
+/// Parser of the fields of a message.
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl<'a> Fields<'a> {
+    pub fn take(&mut self, len: usize) -> VfsResult<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(VfsError::InvalidData);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> VfsResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> VfsResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a `fuse_attr`.
+    pub fn attr(&mut self) -> VfsResult<Metadata> {
+        let ino = self.u64()?;
+        let size = self.u64()?;
+        let blocks = self.u64()?;
+        let (atime, mtime, ctime) = (self.u64()?, self.u64()?, self.u64()?);
+        let (atime_nsec, mtime_nsec, ctime_nsec) = (self.u32()?, self.u32()?, self.u32()?);
+        let mode = self.u32()?;
+        let nlink = self.u32()?;
+        let uid = self.u32()?;
+        let gid = self.u32()?;
+        let rdev = self.u32()?;
+        let block_size = self.u32()?;
+        let _flags = self.u32()?;
+        Ok(Metadata {
+            device: 0,
+            inode: ino,
+            nlink: nlink as u64,
+            mode: NodePermission::from_bits_truncate(mode as u16),
+            node_type: NodeType::from((mode >> 12 & 0o17) as u8),
+            uid,
+            gid,
+            size,
+            block_size: block_size as u64,
+            blocks,
+            rdev: decode_dev(rdev),
+            atime: timeout(atime, atime_nsec),
+            mtime: timeout(mtime, mtime_nsec),
+            ctime: timeout(ctime, ctime_nsec),
+        })
+    }
+}
+
+/// Combines the seconds and nanoseconds of a time or timeout, which are
+/// apart in messages.
+pub fn timeout(secs: u64, nsecs: u32) -> Duration {
+    Duration::new(secs, nsecs.min(999_999_999))
+}
+
+/// Decodes a device number in the 32-bit format of Linux.
+pub fn decode_dev(rdev: u32) -> DeviceId {
+    DeviceId::new(rdev >> 8 & 0xfff, (rdev & 0xff) | (rdev >> 12 & 0xfff00))
+}
+
+/// Builds a request.
+pub fn request(opcode: u32, unique: u64, nodeid: u64, ids: (u32, u32), args: &[u8]) -> Vec<u8> {
+    let mut message = Args::default();
+    message
+        .u32((IN_HEADER_SIZE + args.len()) as u32)
+        .u32(opcode)
+        .u64(unique)
+        .u64(nodeid)
+        .u32(ids.0)
+        .u32(ids.1)
+        // Process ID, and the length of extensions and padding.
+        .u32(0)
+        .u32(0)
+        .raw(args);
+    message.0
+}
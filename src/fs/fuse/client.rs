@@ -0,0 +1,811 @@
+use alloc::{
+    borrow::ToOwned,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{any::Any, task::Context, time::Duration};
+
+use axpoll::{IoEvents, Pollable};
+use hashbrown::HashMap;
+
+use super::{FuseChannel, abi::*, from_errno};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps, Metadata,
+    MetadataUpdate, Mutex, NodeOps, NodePermission, NodeType, Reference, StatFs, VfsError,
+    VfsResult, WeakDirEntry, fs::RootDir,
+};
+
+/// Options for mounting a [`FuseFs`].
+#[derive(Debug, Clone)]
+pub struct FuseOptions {
+    /// ID of the user making requests.
+    pub uid: u32,
+    /// ID of the group making requests.
+    pub gid: u32,
+    /// Largest number of bytes read by a single request.
+    pub max_read: u32,
+    /// Source of the current time, against which the timeouts of entries
+    /// and attributes given by the daemon are checked. Without it, nothing
+    /// is cached.
+    pub clock: Option<fn() -> Duration>,
+}
+
+impl Default for FuseOptions {
+    fn default() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            max_read: 128 << 10,
+            clock: None,
+        }
+    }
+}
+
+/// End of a channel, with the unique ID of the next request.
+///
+/// Requests are sent one at a time: each holds the session until its reply
+/// has been received.
+struct Session {
+    channel: Arc<dyn FuseChannel>,
+    unique: u64,
+}
+
+impl Session {
+    fn send(&mut self, opcode: u32, nodeid: u64, ids: (u32, u32), args: &[u8]) -> VfsResult<u64> {
+        self.unique += 1;
+        let message = request(opcode, self.unique, nodeid, ids, args);
+        self.channel.send(&message)?;
+        Ok(self.unique)
+    }
+
+    fn call(
+        &mut self,
+        opcode: u32,
+        nodeid: u64,
+        ids: (u32, u32),
+        args: &[u8],
+    ) -> VfsResult<Vec<u8>> {
+        let unique = self.send(opcode, nodeid, ids, args)?;
+        loop {
+            let mut reply = self.channel.recv()?;
+            let mut fields = Fields(&reply);
+            let len = fields.u32()?;
+            let error = fields.u32()? as i32;
+            let reply_unique = fields.u64()?;
+            if len as usize != reply.len() {
+                return Err(VfsError::InvalidData);
+            }
+            // Notifications are not asked for, and are ignored.
+            if reply_unique == 0 {
+                continue;
+            }
+            if reply_unique != unique {
+                return Err(VfsError::InvalidData);
+            }
+            if error < 0 {
+                return Err(from_errno(error.unsigned_abs()));
+            }
+            reply.drain(..OUT_HEADER_SIZE);
+            return Ok(reply);
+        }
+    }
+}
+
+/// Filesystem whose nodes are served by a FUSE daemon.
+///
+/// Every node holds one lookup of its node ID, forgotten when it is
+/// dropped. Entries and attributes are cached for as long as the daemon
+/// allows, given a [`FuseOptions::clock`].
+pub struct FuseFs {
+    session: Mutex<Session>,
+    options: FuseOptions,
+    /// Largest number of bytes written by a single request.
+    max_write: u32,
+    /// Attributes of the root directory when mounting, refreshed by the
+    /// root before use.
+    root_metadata: Metadata,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl FuseFs {
+    /// Negotiates the protocol with the daemon at the other end of
+    /// `channel`.
+    pub fn new(channel: Arc<dyn FuseChannel>, options: FuseOptions) -> VfsResult<Arc<Self>> {
+        let mut session = Session { channel, unique: 0 };
+        let mut args = Args::default();
+        args.u32(KERNEL_VERSION)
+            .u32(KERNEL_MINOR_VERSION)
+            .u32(options.max_read)
+            // No optional feature is asked for.
+            .u32(0);
+        let reply = session.call(INIT, 0, (options.uid, options.gid), &args.0)?;
+        let mut fields = Fields(&reply);
+        let major = fields.u32()?;
+        let minor = fields.u32()?;
+        let _max_readahead = fields.u32()?;
+        let _flags = fields.u32()?;
+        let _max_background = fields.take(4)?;
+        let max_write = fields.u32()?;
+        if major != KERNEL_VERSION || minor < MIN_MINOR_VERSION {
+            return Err(VfsError::Unsupported);
+        }
+        let ids = (options.uid, options.gid);
+        let reply = session.call(GETATTR, ROOT_ID, ids, &getattr_args().0)?;
+        let (metadata, _) = parse_attr_out(&reply)?;
+        if metadata.node_type != NodeType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            session: Mutex::new(session),
+            options,
+            max_write: max_write.max(4096),
+            root_metadata: metadata,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let fs = self.this.upgrade().unwrap();
+        let inode = FuseInode::new(&fs, ROOT_ID, self.root_metadata.clone(), Duration::ZERO);
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(FuseDir::new(inode, this))),
+            Reference::root(),
+        )
+    }
+
+    /// Sends a request about `nodeid`, and returns the fields of its reply.
+    fn call(&self, opcode: u32, nodeid: u64, args: &Args) -> VfsResult<Vec<u8>> {
+        let ids = (self.options.uid, self.options.gid);
+        self.session.lock().call(opcode, nodeid, ids, &args.0)
+    }
+
+    /// Returns the current time, if known.
+    fn now(&self) -> Option<Duration> {
+        self.options.clock.map(|clock| clock())
+    }
+
+    /// Returns when something valid for `timeout` from now expires, or
+    /// `None` if it must not be cached.
+    fn deadline(&self, timeout: Duration) -> Option<Duration> {
+        self.now()
+            .filter(|_| !timeout.is_zero())
+            .map(|now| now.saturating_add(timeout))
+    }
+
+    fn is_valid(&self, deadline: Duration) -> bool {
+        self.now().is_some_and(|now| now < deadline)
+    }
+}
+
+impl FilesystemOps for FuseFs {
+    fn name(&self) -> &str {
+        "fuse"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let reply = self.call(STATFS, ROOT_ID, &Args::default())?;
+        let mut fields = Fields(&reply);
+        let blocks = fields.u64()?;
+        let blocks_free = fields.u64()?;
+        let blocks_available = fields.u64()?;
+        let file_count = fields.u64()?;
+        let free_file_count = fields.u64()?;
+        let block_size = fields.u32()?;
+        let name_length = fields.u32()?;
+        let fragment_size = fields.u32()?;
+        Ok(StatFs {
+            fs_type: SUPER_MAGIC,
+            block_size,
+            blocks,
+            blocks_free,
+            blocks_available,
+            file_count,
+            free_file_count,
+            name_length,
+            fragment_size,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// A node ID looked up once, with its cached attributes and when they
+/// expire.
+struct FuseInode {
+    fs: Arc<FuseFs>,
+    nodeid: u64,
+    attr: Mutex<(Metadata, Duration)>,
+}
+
+impl Drop for FuseInode {
+    fn drop(&mut self) {
+        if self.nodeid == ROOT_ID {
+            return;
+        }
+        let mut args = Args::default();
+        args.u64(1);
+        let ids = (self.fs.options.uid, self.fs.options.gid);
+        // `FORGET` has no reply.
+        let _ = self
+            .fs
+            .session
+            .lock()
+            .send(FORGET, self.nodeid, ids, &args.0);
+    }
+}
+
+impl FuseInode {
+    fn new(fs: &Arc<FuseFs>, nodeid: u64, metadata: Metadata, timeout: Duration) -> Arc<Self> {
+        let deadline = fs.deadline(timeout).unwrap_or_default();
+        Arc::new(Self {
+            fs: fs.clone(),
+            nodeid,
+            attr: Mutex::new((metadata, deadline)),
+        })
+    }
+
+    fn call(&self, opcode: u32, args: &Args) -> VfsResult<Vec<u8>> {
+        self.fs.call(opcode, self.nodeid, args)
+    }
+
+    fn set_attr(&self, metadata: Metadata, timeout: Duration) {
+        let deadline = self.fs.deadline(timeout).unwrap_or_default();
+        *self.attr.lock() = (metadata, deadline);
+    }
+
+    /// Drops the cached attributes, after the node was changed.
+    fn invalidate(&self) {
+        self.attr.lock().1 = Duration::ZERO;
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        {
+            let attr = self.attr.lock();
+            if self.fs.is_valid(attr.1) {
+                return Ok(attr.0.clone());
+            }
+        }
+        self.set_attr_out(&self.call(GETATTR, &getattr_args())?)
+    }
+
+    /// Caches the attributes of a `fuse_attr_out`.
+    fn set_attr_out(&self, reply: &[u8]) -> VfsResult<Metadata> {
+        let (metadata, timeout) = parse_attr_out(reply)?;
+        self.set_attr(metadata.clone(), timeout);
+        Ok(metadata)
+    }
+
+    fn setattr(&self, update: &MetadataUpdate, size: Option<u64>) -> VfsResult<()> {
+        if update.rdev.is_some() {
+            return Err(VfsError::Unsupported);
+        }
+        let mut valid = 0;
+        if update.mode.is_some() {
+            valid |= FATTR_MODE;
+        }
+        if update.owner.is_some() {
+            valid |= FATTR_UID | FATTR_GID;
+        }
+        if size.is_some() {
+            valid |= FATTR_SIZE;
+        }
+        if update.atime.is_some() {
+            valid |= FATTR_ATIME;
+        }
+        if update.mtime.is_some() {
+            valid |= FATTR_MTIME;
+        }
+        if valid == 0 {
+            return Ok(());
+        }
+        let (uid, gid) = update.owner.unwrap_or_default();
+        let (atime, mtime) = (
+            update.atime.unwrap_or_default(),
+            update.mtime.unwrap_or_default(),
+        );
+        let mut args = Args::default();
+        args.u32(valid)
+            .u32(0)
+            // File handle and size.
+            .u64(0)
+            .u64(size.unwrap_or(0))
+            // Lock owner.
+            .u64(0)
+            .u64(atime.as_secs())
+            .u64(mtime.as_secs())
+            .u64(0)
+            .u32(atime.subsec_nanos())
+            .u32(mtime.subsec_nanos())
+            .u32(0)
+            .u32(update.mode.map_or(0, |it| it.bits() as u32))
+            .u32(0)
+            .u32(uid)
+            .u32(gid)
+            .u32(0);
+        self.invalidate();
+        self.set_attr_out(&self.call(SETATTR, &args)?).map(drop)
+    }
+
+    /// Opens the node with `flags`, returning the file handle.
+    fn open(&self, opcode: u32, flags: u32) -> VfsResult<u64> {
+        let mut args = Args::default();
+        args.u32(flags).u32(0);
+        Fields(&self.call(opcode, &args)?).u64()
+    }
+
+    fn release(&self, opcode: u32, fh: u64) -> VfsResult<()> {
+        let mut args = Args::default();
+        args.u64(fh).u32(0).u32(0).u64(0);
+        self.call(opcode, &args).map(drop)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> VfsResult<Vec<u8>> {
+        let mut args = Args::default();
+        args.name(name).name(target);
+        self.call(SYMLINK, &args)
+    }
+
+    /// Parses a `fuse_entry_out`, returning the node and its attributes.
+    fn parse_entry(&self, reply: &[u8]) -> VfsResult<(Arc<FuseInode>, Metadata, Duration)> {
+        let mut fields = Fields(reply);
+        let nodeid = fields.u64()?;
+        let _generation = fields.u64()?;
+        let (entry_secs, attr_secs) = (fields.u64()?, fields.u64()?);
+        let (entry_nsecs, attr_nsecs) = (fields.u32()?, fields.u32()?);
+        let metadata = fields.attr()?;
+        // A node ID of zero is a negative entry.
+        if nodeid == 0 {
+            return Err(VfsError::NotFound);
+        }
+        let inode = FuseInode::new(
+            &self.fs,
+            nodeid,
+            metadata.clone(),
+            timeout(attr_secs, attr_nsecs),
+        );
+        Ok((inode, metadata, timeout(entry_secs, entry_nsecs)))
+    }
+}
+
+/// Returns the arguments of `GETATTR`, without a file handle.
+fn getattr_args() -> Args {
+    let mut args = Args::default();
+    args.u32(0).u32(0).u64(0);
+    args
+}
+
+/// Parses a `fuse_attr_out`, returning the attributes and their timeout.
+fn parse_attr_out(reply: &[u8]) -> VfsResult<(Metadata, Duration)> {
+    let mut fields = Fields(reply);
+    let secs = fields.u64()?;
+    let nsecs = fields.u32()?;
+    let _dummy = fields.u32()?;
+    Ok((fields.attr()?, timeout(secs, nsecs)))
+}
+
+/// Directory of a [`FuseFs`].
+///
+/// The VFS does not cache its entries: they are cached here instead, each
+/// until the timeout given by the daemon expires.
+pub struct FuseDir {
+    inode: Arc<FuseInode>,
+    this: WeakDirEntry,
+    entries: Mutex<HashMap<String, (DirEntry, Duration)>>,
+}
+
+impl FuseDir {
+    fn new(inode: Arc<FuseInode>, this: WeakDirEntry) -> Self {
+        Self {
+            inode,
+            this,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn fs(&self) -> &Arc<FuseFs> {
+        &self.inode.fs
+    }
+
+    /// Makes an entry for `name` out of a reply starting with a
+    /// `fuse_entry_out`, and caches it.
+    fn new_entry(&self, name: &str, reply: &[u8], fh: Option<u64>) -> VfsResult<DirEntry> {
+        let (inode, metadata, timeout) = self.inode.parse_entry(reply)?;
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        let entry = if metadata.node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(FuseDir::new(inode, this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Arc::new(FuseFile {
+                    inode: Mutex::new(inode),
+                    node_type: metadata.node_type,
+                    location: (self.inode.clone(), name.to_owned()),
+                    open: Mutex::new(fh.map(|it| (it, true))),
+                })),
+                metadata.node_type,
+                reference,
+            )
+        };
+        let mut entries = self.entries.lock();
+        match self.fs().deadline(timeout) {
+            Some(deadline) => entries.insert(name.to_owned(), (entry.clone(), deadline)),
+            None => entries.remove(name),
+        };
+        Ok(entry)
+    }
+
+    /// Drops the cached entry of `name`, after it was changed.
+    fn invalidate(&self, name: &str) {
+        self.entries.lock().remove(name);
+    }
+
+    fn call_named(&self, opcode: u32, args: &mut Args, name: &str) -> VfsResult<Vec<u8>> {
+        args.name(name);
+        self.inode.call(opcode, args)
+    }
+}
+
+impl NodeOps for FuseDir {
+    fn inode(&self) -> u64 {
+        self.inode.attr.lock().0.inode
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.setattr(&update, None)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &**self.fs()
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for FuseDir {
+    fn read_dir(&self, mut offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let fh = self.inode.open(OPENDIR, O_RDONLY)?;
+        let mut count = 0;
+        let result = (|| loop {
+            let mut args = Args::default();
+            args.u64(fh)
+                .u64(offset)
+                .u32(self.fs().options.max_read)
+                .u32(0)
+                .u64(0)
+                .u32(0)
+                .u32(0);
+            let reply = self.inode.call(READDIR, &args)?;
+            if reply.is_empty() {
+                return Ok(count);
+            }
+            let mut fields = Fields(&reply);
+            while !fields.0.is_empty() {
+                let ino = fields.u64()?;
+                let next = fields.u64()?;
+                let len = fields.u32()? as usize;
+                let node_type = NodeType::from(fields.u32()? as u8);
+                let name = fields.take(len)?;
+                let name = core::str::from_utf8(name).map_err(|_| VfsError::InvalidData)?;
+                // Entries are aligned to 8 bytes.
+                fields.take((DIRENT_SIZE + len).next_multiple_of(8) - DIRENT_SIZE - len)?;
+                if !sink.accept(name, ino, node_type, next) {
+                    return Ok(count);
+                }
+                count += 1;
+                offset = next;
+            }
+        })();
+        self.inode.release(RELEASEDIR, fh)?;
+        result
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        if let Some((entry, deadline)) = self.entries.lock().get(name)
+            && self.fs().is_valid(*deadline)
+        {
+            return Ok(entry.clone());
+        }
+        let reply = self.call_named(LOOKUP, &mut Args::default(), name);
+        match reply {
+            Ok(reply) => self.new_entry(name, &reply, None),
+            Err(err) => {
+                self.invalidate(name);
+                Err(err)
+            }
+        }
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let mode = (node_type as u32) << 12 | permission.bits() as u32;
+        let mut args = Args::default();
+        let reply = match node_type {
+            NodeType::RegularFile => {
+                args.u32(O_RDWR | O_CREAT | O_EXCL).u32(mode).u32(0).u32(0);
+                match self.call_named(CREATE, &mut args, name) {
+                    // The new file is left open.
+                    Ok(reply) => {
+                        let fh = Fields(reply.get(ENTRY_OUT_SIZE..).unwrap_or_default()).u64()?;
+                        self.inode.invalidate();
+                        return self.new_entry(name, &reply, Some(fh));
+                    }
+                    // Daemons without `CREATE` make files with `MKNOD`.
+                    Err(VfsError::Unsupported) => {
+                        let mut args = Args::default();
+                        args.u32(mode).u32(0).u32(0).u32(0);
+                        self.call_named(MKNOD, &mut args, name)?
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            NodeType::Directory => {
+                args.u32(mode).u32(0);
+                self.call_named(MKDIR, &mut args, name)?
+            }
+            // The target is set later through `set_symlink`.
+            NodeType::Symlink => self.inode.symlink(name, ".")?,
+            NodeType::Fifo
+            | NodeType::CharacterDevice
+            | NodeType::BlockDevice
+            | NodeType::Socket => {
+                args.u32(mode).u32(0).u32(0).u32(0);
+                self.call_named(MKNOD, &mut args, name)?
+            }
+            NodeType::Unknown => return Err(VfsError::InvalidInput),
+        };
+        self.inode.invalidate();
+        self.new_entry(name, &reply, None)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let src = node.downcast::<FuseFile>()?;
+        let src = src.inode();
+        if !Arc::ptr_eq(&src.fs, self.fs()) {
+            return Err(VfsError::CrossesDevices);
+        }
+        let mut args = Args::default();
+        args.u64(src.nodeid);
+        let reply = self.call_named(LINK, &mut args, name)?;
+        src.invalidate();
+        self.inode.invalidate();
+        self.new_entry(name, &reply, None)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let entry = self.lookup(name)?;
+        let opcode = if entry.is_dir() { RMDIR } else { UNLINK };
+        self.invalidate(name);
+        self.call_named(opcode, &mut Args::default(), name)?;
+        self.inode.invalidate();
+        if let Ok(file) = entry.downcast::<FuseFile>() {
+            file.inode().invalidate();
+        }
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir = dst_dir.downcast::<FuseDir>()?;
+        if !Arc::ptr_eq(dst_dir.fs(), self.fs()) {
+            return Err(VfsError::CrossesDevices);
+        }
+        self.invalidate(src_name);
+        dst_dir.invalidate(dst_name);
+        let mut args = Args::default();
+        args.u64(dst_dir.inode.nodeid).name(src_name).name(dst_name);
+        self.inode.call(RENAME, &args)?;
+        self.inode.invalidate();
+        dst_dir.inode.invalidate();
+        Ok(())
+    }
+}
+
+/// Non-directory node of a [`FuseFs`].
+pub struct FuseFile {
+    inode: Mutex<Arc<FuseInode>>,
+    node_type: NodeType,
+    /// Directory and name the node was found at, where symlinks are
+    /// recreated by `set_symlink`.
+    location: (Arc<FuseInode>, String),
+    /// Handle opened for I/O and whether it is writable.
+    open: Mutex<Option<(u64, bool)>>,
+}
+
+impl Drop for FuseFile {
+    fn drop(&mut self) {
+        if let Some((fh, _)) = self.open.get_mut().take() {
+            let _ = self.inode.get_mut().release(RELEASE, fh);
+        }
+    }
+}
+
+impl FuseFile {
+    fn inode(&self) -> Arc<FuseInode> {
+        self.inode.lock().clone()
+    }
+
+    /// Returns the handle opened for I/O, reopening it for writing if
+    /// requested.
+    fn open(&self, write: bool) -> VfsResult<u64> {
+        let mut guard = self.open.lock();
+        if let Some((fh, writable)) = *guard
+            && (writable || !write)
+        {
+            return Ok(fh);
+        }
+        let inode = self.inode();
+        let (fh, writable) = match inode.open(OPEN, O_RDWR) {
+            Ok(fh) => (fh, true),
+            Err(VfsError::PermissionDenied | VfsError::ReadOnlyFilesystem) if !write => {
+                (inode.open(OPEN, O_RDONLY)?, false)
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some((old, _)) = guard.replace((fh, writable)) {
+            inode.release(RELEASE, old)?;
+        }
+        Ok(fh)
+    }
+
+    fn read_link(&self) -> VfsResult<String> {
+        let reply = self.inode().call(READLINK, &Args::default())?;
+        String::from_utf8(reply).map_err(|_| VfsError::InvalidData)
+    }
+
+    fn write(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let fh = self.open(true)?;
+        let inode = self.inode();
+        let mut done = 0;
+        for chunk in buf.chunks(inode.fs.max_write as usize) {
+            let mut args = Args::default();
+            args.u64(fh)
+                .u64(offset + done as u64)
+                .u32(chunk.len() as u32)
+                .u32(0)
+                .u64(0)
+                .u32(0)
+                .u32(0)
+                .raw(chunk);
+            let result = inode.call(WRITE, &args);
+            inode.invalidate();
+            let len = (Fields(&result?).u32()? as usize).min(chunk.len());
+            done += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+impl NodeOps for FuseFile {
+    fn inode(&self) -> u64 {
+        self.inode().attr.lock().0.inode
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode().metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode().setattr(&update, None)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.location.0.fs
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        if self.node_type != NodeType::RegularFile {
+            return Ok(());
+        }
+        let fh = self.open(false)?;
+        let mut args = Args::default();
+        args.u64(fh)
+            .u32(if data_only { FSYNC_FDATASYNC } else { 0 })
+            .u32(0);
+        self.inode().call(FSYNC, &args).map(drop)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for FuseFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if self.node_type == NodeType::Symlink {
+            let target = self.read_link()?;
+            let target = target.as_bytes();
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        let fh = self.open(false)?;
+        let inode = self.inode();
+        let max_read = inode.fs.options.max_read as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(max_read);
+            let mut args = Args::default();
+            args.u64(fh)
+                .u64(offset + done as u64)
+                .u32(count as u32)
+                .u32(0)
+                .u64(0)
+                .u32(0)
+                .u32(0);
+            let data = inode.call(READ, &args)?;
+            let len = data.len().min(count);
+            buf[done..done + len].copy_from_slice(&data[..len]);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.write(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let inode = self.inode();
+        inode.invalidate();
+        let offset = inode.metadata()?.size;
+        let written = self.write(buf, offset)?;
+        Ok((written, offset + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.inode().setattr(&MetadataUpdate::default(), Some(len))
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        if self.node_type != NodeType::Symlink {
+            return Err(VfsError::InvalidInput);
+        }
+        let (dir, name) = &self.location;
+        let mut args = Args::default();
+        args.name(name);
+        dir.call(UNLINK, &args)?;
+        let (inode, ..) = dir.parse_entry(&dir.symlink(name, target)?)?;
+        *self.inode.lock() = inode;
+        Ok(())
+    }
+}
+
+impl Pollable for FuseFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,458 @@
+//! Filesystems implemented by userspace daemons, over the FUSE protocol.
+//!
+//! [`FuseFs`] turns node operations into FUSE requests, sent to the daemon
+//! through a [`FuseChannel`], such as the queue of a `/dev/fuse` device or
+//! of a virtio-fs device. Nothing the daemon replies is trusted beyond the
+//! bounds of the messages themselves.
+
+mod abi;
+mod client;
+
+use alloc::vec::Vec;
+
+pub use self::client::{FuseDir, FuseFile, FuseFs, FuseOptions};
+use crate::{VfsError, VfsResult};
+
+/// A channel carrying whole FUSE messages between the VFS and a daemon.
+pub trait FuseChannel: Send + Sync {
+    /// Sends a request to the daemon.
+    fn send(&self, message: &[u8]) -> VfsResult<()>;
+
+    /// Waits for the next message from the daemon.
+    fn recv(&self) -> VfsResult<Vec<u8>>;
+}
+
+/// Translates the errno of a reply into a [`VfsError`].
+fn from_errno(errno: u32) -> VfsError {
+    VfsError::try_from(-(errno as i32)).map_or(VfsError::Io, VfsError::canonicalize)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+    use core::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+    use std::sync::Mutex;
+
+    use axerrno::LinuxError;
+    use hashbrown::HashMap;
+
+    use super::{FuseChannel, FuseFs, FuseOptions, abi::*};
+    use crate::{
+        Filesystem, Location, Metadata, MetadataUpdate, Mountpoint, NodePermission, NodeType,
+        VfsError, VfsResult,
+        test_util::{list, memory_fs},
+    };
+
+    /// Daemon serving a [`Location`] from within the channel, replying to
+    /// each request as soon as it is sent.
+    struct Daemon {
+        root: Location,
+        minor: u32,
+        max_write: u32,
+        /// Timeout of all entries and attributes, in seconds.
+        timeout: u64,
+        /// Nodes by node ID, which is their inode, with their lookup count.
+        nodes: Mutex<HashMap<u64, (Location, u64)>>,
+        replies: Mutex<VecDeque<Vec<u8>>>,
+        /// Number of requests received by opcode.
+        requests: Mutex<HashMap<u32, usize>>,
+        /// Number of handles opened and not released.
+        handles: Mutex<isize>,
+    }
+
+    impl Daemon {
+        fn new(root: Location, timeout: u64) -> Arc<Self> {
+            let mut nodes = HashMap::new();
+            nodes.insert(ROOT_ID, (root.clone(), 0));
+            Arc::new(Self {
+                root,
+                minor: 28,
+                max_write: 4096,
+                timeout,
+                nodes: Mutex::new(nodes),
+                replies: Mutex::default(),
+                requests: Mutex::default(),
+                handles: Mutex::new(0),
+            })
+        }
+
+        fn requests(&self, opcode: u32) -> usize {
+            self.requests
+                .lock()
+                .unwrap()
+                .get(&opcode)
+                .copied()
+                .unwrap_or(0)
+        }
+
+        fn lookups(&self, loc: &Location) -> u64 {
+            self.nodes
+                .lock()
+                .unwrap()
+                .get(&loc.inode())
+                .map_or(0, |it| it.1)
+        }
+
+        fn node(&self, nodeid: u64) -> VfsResult<Location> {
+            let nodes = self.nodes.lock().unwrap();
+            Ok(nodes.get(&nodeid).ok_or(VfsError::NotFound)?.0.clone())
+        }
+
+        fn attr(reply: &mut Args, metadata: &Metadata) {
+            let mode = (metadata.node_type as u32) << 12 | metadata.mode.bits() as u32;
+            reply
+                .u64(metadata.inode)
+                .u64(metadata.size)
+                .u64(metadata.blocks)
+                .u64(metadata.atime.as_secs())
+                .u64(metadata.mtime.as_secs())
+                .u64(metadata.ctime.as_secs())
+                .u32(metadata.atime.subsec_nanos())
+                .u32(metadata.mtime.subsec_nanos())
+                .u32(metadata.ctime.subsec_nanos())
+                .u32(mode)
+                .u32(metadata.nlink as u32)
+                .u32(metadata.uid)
+                .u32(metadata.gid)
+                .u32(0)
+                .u32(metadata.block_size as u32)
+                .u32(0);
+        }
+
+        fn attr_out(&self, loc: &Location) -> VfsResult<Args> {
+            let mut reply = Args::default();
+            reply.u64(self.timeout).u32(0).u32(0);
+            Self::attr(&mut reply, &loc.metadata()?);
+            Ok(reply)
+        }
+
+        /// Replies with a `fuse_entry_out`, counting a lookup of `loc`.
+        fn entry_out(&self, loc: Location) -> VfsResult<Args> {
+            let metadata = loc.metadata()?;
+            let mut reply = Args::default();
+            reply
+                .u64(metadata.inode)
+                .u64(0)
+                .u64(self.timeout)
+                .u64(self.timeout)
+                .u32(0)
+                .u32(0);
+            Self::attr(&mut reply, &metadata);
+            self.nodes
+                .lock()
+                .unwrap()
+                .entry(metadata.inode)
+                .or_insert((loc, 0))
+                .1 += 1;
+            Ok(reply)
+        }
+
+        fn handle(&self, opcode: u32, nodeid: u64, args: &[u8]) -> VfsResult<Args> {
+            let mut fields = Fields(args);
+            let names = |offset: usize| names(&args[offset..]);
+            let node = || self.node(nodeid);
+            let mode = |bits: u32| NodePermission::from_bits_truncate(bits as u16);
+            let mut reply = Args::default();
+            match opcode {
+                INIT => {
+                    assert_eq!(fields.u32()?, KERNEL_VERSION);
+                    reply
+                        .u32(KERNEL_VERSION)
+                        .u32(fields.u32()?.min(self.minor))
+                        .u32(0)
+                        .u32(0)
+                        .u32(0)
+                        .u32(self.max_write)
+                        .raw(&[0; 40]);
+                }
+                LOOKUP => return self.entry_out(node()?.lookup_no_follow(names(0)[0])?),
+                GETATTR => return self.attr_out(&node()?),
+                SETATTR => {
+                    let loc = node()?;
+                    let valid = fields.u32()?;
+                    let size = {
+                        fields.take(12)?;
+                        fields.u64()?
+                    };
+                    fields.take(44)?;
+                    let bits = fields.u32()?;
+                    if valid & FATTR_SIZE != 0 {
+                        loc.entry().as_file()?.set_len(size)?;
+                    }
+                    if valid & FATTR_MODE != 0 {
+                        loc.update_metadata(MetadataUpdate {
+                            mode: Some(mode(bits)),
+                            ..Default::default()
+                        })?;
+                    }
+                    return self.attr_out(&loc);
+                }
+                READLINK => {
+                    reply.raw(node()?.read_link()?.as_bytes());
+                }
+                SYMLINK => {
+                    let names = names(0);
+                    let loc = node()?.create(names[0], NodeType::Symlink, mode(0o777))?;
+                    loc.entry().as_file()?.set_symlink(names[1])?;
+                    return self.entry_out(loc);
+                }
+                MKNOD | MKDIR | CREATE => {
+                    if opcode == CREATE {
+                        fields.u32()?;
+                    }
+                    let bits = fields.u32()?;
+                    let loc = node()?.create(
+                        names(if opcode == MKDIR { 8 } else { 16 })[0],
+                        NodeType::from((bits >> 12) as u8),
+                        mode(bits),
+                    )?;
+                    let mut reply = self.entry_out(loc)?;
+                    if opcode == CREATE {
+                        *self.handles.lock().unwrap() += 1;
+                        reply.u64(nodeid).u32(0).u32(0);
+                    }
+                    return Ok(reply);
+                }
+                UNLINK | RMDIR => node()?.unlink(names(0)[0], opcode == RMDIR)?,
+                RENAME => {
+                    let dst = self.node(fields.u64()?)?;
+                    let names = names(8);
+                    node()?.rename(names[0], &dst, names[1])?;
+                }
+                LINK => {
+                    let src = self.node(fields.u64()?)?;
+                    return self.entry_out(node()?.link(names(8)[0], &src)?);
+                }
+                OPEN | OPENDIR => {
+                    node()?;
+                    *self.handles.lock().unwrap() += 1;
+                    reply.u64(nodeid).u32(0).u32(0);
+                }
+                RELEASE | RELEASEDIR => *self.handles.lock().unwrap() -= 1,
+                READ => {
+                    let (_fh, offset, size) = (fields.u64()?, fields.u64()?, fields.u32()?);
+                    let mut buf = vec![0; size as usize];
+                    let len = node()?.entry().as_file()?.read_at(&mut buf, offset)?;
+                    reply.raw(&buf[..len]);
+                }
+                WRITE => {
+                    let (_fh, offset, size) = (fields.u64()?, fields.u64()?, fields.u32()?);
+                    let data = &args[40..];
+                    assert!(size <= self.max_write && data.len() == size as usize);
+                    let len = node()?.entry().as_file()?.write_at(data, offset)?;
+                    reply.u32(len as u32).u32(0);
+                }
+                READDIR => {
+                    let (_fh, offset, size) = (fields.u64()?, fields.u64()?, fields.u32()?);
+                    node()?.read_dir(offset, &mut |name: &str, ino, node_type, next| {
+                        let len = (DIRENT_SIZE + name.len()).next_multiple_of(8);
+                        if reply.0.len() + len > size as usize {
+                            return false;
+                        }
+                        reply
+                            .u64(ino)
+                            .u64(next)
+                            .u32(name.len() as u32)
+                            .u32(node_type as u32)
+                            .raw(name.as_bytes())
+                            .raw(&[0; 7][..len - DIRENT_SIZE - name.len()]);
+                        true
+                    })?;
+                }
+                STATFS => {
+                    let stat = self.root.filesystem().stat()?;
+                    reply
+                        .u64(stat.blocks)
+                        .u64(stat.blocks_free)
+                        .u64(stat.blocks_available)
+                        .u64(stat.file_count)
+                        .u64(stat.free_file_count)
+                        .u32(stat.block_size)
+                        .u32(stat.name_length)
+                        .u32(stat.fragment_size)
+                        .raw(&[0; 28]);
+                }
+                FSYNC => node()?.sync(false)?,
+                _ => return Err(VfsError::Unsupported),
+            }
+            Ok(reply)
+        }
+    }
+
+    /// Splits NUL-terminated names.
+    fn names(args: &[u8]) -> Vec<&str> {
+        args.split(|&it| it == 0)
+            .filter(|it| !it.is_empty())
+            .map(|it| core::str::from_utf8(it).unwrap())
+            .collect()
+    }
+
+    impl FuseChannel for Daemon {
+        fn send(&self, message: &[u8]) -> VfsResult<()> {
+            let mut header = Fields(message);
+            assert_eq!(header.u32()? as usize, message.len());
+            let opcode = header.u32()?;
+            let unique = header.u64()?;
+            let nodeid = header.u64()?;
+            *self.requests.lock().unwrap().entry(opcode).or_default() += 1;
+            if opcode == FORGET {
+                let count = Fields(&message[IN_HEADER_SIZE..]).u64()?;
+                let mut nodes = self.nodes.lock().unwrap();
+                let lookups = &mut nodes.get_mut(&nodeid).unwrap().1;
+                *lookups = lookups.checked_sub(count).unwrap();
+                return Ok(());
+            }
+            let (error, body) = match self.handle(opcode, nodeid, &message[IN_HEADER_SIZE..]) {
+                Ok(reply) => (0, reply.0),
+                Err(err) => (-LinuxError::from(err).code(), Vec::new()),
+            };
+            let mut reply = Args::default();
+            reply
+                .u32((OUT_HEADER_SIZE + body.len()) as u32)
+                .u32(error as u32)
+                .u64(unique)
+                .raw(&body);
+            self.replies.lock().unwrap().push_back(reply.0);
+            Ok(())
+        }
+
+        fn recv(&self) -> VfsResult<Vec<u8>> {
+            self.replies
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(VfsError::NotConnected)
+        }
+    }
+
+    fn mount(daemon: &Arc<Daemon>, options: FuseOptions) -> Location {
+        let fs = FuseFs::new(daemon.clone(), options).unwrap();
+        Mountpoint::new_root(&Filesystem::new(fs)).root_location()
+    }
+
+    fn read_all(loc: &Location) -> Vec<u8> {
+        let file = loc.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    #[test]
+    fn test_fuse() {
+        let exported = memory_fs();
+        let daemon = Daemon::new(exported.clone(), 0);
+        let root = mount(&daemon, FuseOptions::default());
+        let mode = NodePermission::from_bits_truncate(0o755);
+
+        let dir = root.create("dir", NodeType::Directory, mode).unwrap();
+        let file = dir.create("file", NodeType::RegularFile, mode).unwrap();
+        // Writes are split at the size negotiated by `INIT`.
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        file.entry().as_file().unwrap().write_at(&data, 0).unwrap();
+        assert_eq!(daemon.requests(WRITE), 3);
+        assert_eq!(read_all(&file), data);
+        let (_, end) = file.entry().as_file().unwrap().append(b"!").unwrap();
+        assert_eq!(end, 10001);
+        file.entry().as_file().unwrap().set_len(5).unwrap();
+        assert_eq!(read_all(&file), &data[..5]);
+        file.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o600)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(file.metadata().unwrap().mode.bits(), 0o600);
+
+        let link = root.create("link", NodeType::Symlink, mode).unwrap();
+        link.entry()
+            .as_file()
+            .unwrap()
+            .set_symlink("dir/file")
+            .unwrap();
+        assert_eq!(
+            root.lookup_no_follow("link").unwrap().read_link().unwrap(),
+            "dir/file"
+        );
+        root.link("hard", &file).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink, 2);
+        root.rename("hard", &dir, "moved").unwrap();
+        assert_eq!(
+            list(&dir)[2..],
+            [String::from("file"), String::from("moved")]
+        );
+        assert_eq!(
+            root.unlink("dir", true).unwrap_err(),
+            VfsError::DirectoryNotEmpty
+        );
+        dir.unlink("moved", false).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink, 1);
+        assert_eq!(root.filesystem().stat().unwrap().block_size, 4096);
+
+        // Nodes are forgotten as they are dropped, and handles released.
+        exported
+            .create("other", NodeType::RegularFile, mode)
+            .unwrap();
+        let handles = *daemon.handles.lock().unwrap();
+        let other = root.lookup_no_follow("other").unwrap();
+        read_all(&other);
+        let target = exported.lookup_no_follow("other").unwrap();
+        assert_eq!(daemon.lookups(&target), 1);
+        assert_eq!(*daemon.handles.lock().unwrap(), handles + 1);
+        drop(other);
+        assert_eq!(daemon.lookups(&target), 0);
+        assert_eq!(*daemon.handles.lock().unwrap(), handles);
+    }
+
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn clock() -> Duration {
+        Duration::from_secs(NOW.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let exported = memory_fs();
+        let mode = NodePermission::from_bits_truncate(0o755);
+        exported.create("dir", NodeType::Directory, mode).unwrap();
+        let daemon = Daemon::new(exported.clone(), 10);
+        let options = FuseOptions {
+            clock: Some(clock),
+            ..Default::default()
+        };
+        let root = mount(&daemon, options);
+
+        // Entries and attributes are reused until they expire.
+        let dir = root.lookup_no_follow("dir").unwrap();
+        root.lookup_no_follow("dir").unwrap();
+        dir.metadata().unwrap();
+        assert_eq!((daemon.requests(LOOKUP), daemon.requests(GETATTR)), (1, 1));
+        exported.unlink("dir", true).unwrap();
+        assert!(root.lookup_no_follow("dir").unwrap().is_dir());
+        NOW.fetch_add(11, Ordering::Relaxed);
+        assert_eq!(
+            root.lookup_no_follow("dir").unwrap_err(),
+            VfsError::NotFound
+        );
+
+        // Changes made through the mount drop what they make stale.
+        let file = root.create("file", NodeType::RegularFile, mode).unwrap();
+        assert_eq!(file.metadata().unwrap().size, 0);
+        file.entry()
+            .as_file()
+            .unwrap()
+            .write_at(b"data", 0)
+            .unwrap();
+        assert_eq!(file.metadata().unwrap().size, 4);
+        root.rename("file", &root, "renamed").unwrap();
+        assert_eq!(
+            root.lookup_no_follow("file").unwrap_err(),
+            VfsError::NotFound
+        );
+        assert_eq!(
+            read_all(&root.lookup_no_follow("renamed").unwrap()),
+            b"data"
+        );
+    }
+}
@@ -0,0 +1,765 @@
+//! Host passthrough filesystem, mapping a directory on the host through
+//! `std::fs`.
+//!
+//! This is mainly intended for running VFS-level tests and tools on the build
+//! machine. Each entry keeps the host path it was reached by, and follows its
+//! host file across renames done through this filesystem. Entries whose file
+//! is unlinked or replaced through this filesystem report `NotFound`.
+
+use alloc::{borrow::ToOwned, string::String, sync::Arc, sync::Weak, vec::Vec};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Context,
+    time::Duration,
+};
+use std::{
+    fs::{self, File, FileTimes},
+    io::{self, ErrorKind},
+    os::unix::fs::{
+        DirBuilderExt, DirEntryExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axpoll::{IoEvents, Pollable};
+use hashbrown::HashMap;
+
+use super::RootDir;
+use crate::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, Mutex, NodeOps, NodePermission, NodeType, Reference, StatFs,
+    VfsError, VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT},
+};
+
+/// Translates a host I/O error into a [`VfsError`].
+pub fn map_io_error(err: io::Error) -> VfsError {
+    match err.kind() {
+        ErrorKind::NotFound => VfsError::NotFound,
+        ErrorKind::PermissionDenied => VfsError::PermissionDenied,
+        ErrorKind::AlreadyExists => VfsError::AlreadyExists,
+        ErrorKind::WouldBlock => VfsError::WouldBlock,
+        ErrorKind::InvalidInput => VfsError::InvalidInput,
+        ErrorKind::InvalidData => VfsError::InvalidData,
+        ErrorKind::NotADirectory => VfsError::NotADirectory,
+        ErrorKind::IsADirectory => VfsError::IsADirectory,
+        ErrorKind::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+        ErrorKind::ReadOnlyFilesystem => VfsError::ReadOnlyFilesystem,
+        ErrorKind::StorageFull => VfsError::StorageFull,
+        ErrorKind::CrossesDevices => VfsError::CrossesDevices,
+        ErrorKind::ResourceBusy => VfsError::ResourceBusy,
+        ErrorKind::InvalidFilename => VfsError::NameTooLong,
+        ErrorKind::TimedOut => VfsError::TimedOut,
+        ErrorKind::Interrupted => VfsError::Interrupted,
+        ErrorKind::UnexpectedEof => VfsError::UnexpectedEof,
+        ErrorKind::Unsupported => VfsError::Unsupported,
+        ErrorKind::OutOfMemory => VfsError::NoMemory,
+        _ => err
+            .raw_os_error()
+            .and_then(|errno| VfsError::try_from(-errno).ok())
+            .unwrap_or(VfsError::Io),
+    }
+}
+
+fn node_type_of(file_type: fs::FileType) -> NodeType {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_dir() {
+        NodeType::Directory
+    } else if file_type.is_file() {
+        NodeType::RegularFile
+    } else if file_type.is_symlink() {
+        NodeType::Symlink
+    } else if file_type.is_fifo() {
+        NodeType::Fifo
+    } else if file_type.is_char_device() {
+        NodeType::CharacterDevice
+    } else if file_type.is_block_device() {
+        NodeType::BlockDevice
+    } else if file_type.is_socket() {
+        NodeType::Socket
+    } else {
+        NodeType::Unknown
+    }
+}
+
+fn timestamp(secs: i64, nsecs: i64) -> Duration {
+    Duration::new(secs.max(0) as u64, nsecs as u32)
+}
+
+fn system_time(time: Duration) -> SystemTime {
+    UNIX_EPOCH + time
+}
+
+/// Opens `path` with read access, or write access if reading is not
+/// permitted, for the operations working with either.
+fn open_any(path: &Path) -> io::Result<File> {
+    match File::open(path) {
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|_| err),
+        result => result,
+    }
+}
+
+/// Host passthrough filesystem.
+pub struct HostFs {
+    root_path: PathBuf,
+    root_metadata: fs::Metadata,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl HostFs {
+    /// Creates a filesystem backed by the host directory `root`.
+    pub fn new(root: impl Into<PathBuf>) -> VfsResult<Arc<Self>> {
+        let root_path = root.into();
+        let metadata = fs::metadata(&root_path).map_err(map_io_error)?;
+        if !metadata.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            root_path,
+            root_metadata: metadata,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let inode = HostInode::new(
+            self.this.upgrade().unwrap(),
+            &self.root_metadata,
+            HostLocation::Root,
+        );
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(HostDir { inode, this })),
+            Reference::root(),
+        )
+    }
+}
+
+impl FilesystemOps for HostFs {
+    fn name(&self) -> &str {
+        "hostfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        // `std` has no way to query the host filesystem.
+        Err(VfsError::Unsupported)
+    }
+}
+
+/// Where a [`HostInode`] is found on the host.
+enum HostLocation {
+    Root,
+    /// Parent directory and name.
+    Child(Arc<HostInode>, String),
+    /// Unlinked or replaced, so that its former path names another file if
+    /// any.
+    Detached,
+}
+
+/// Host file reached through an entry, which is not shared with the other
+/// hard links of the file so that each keeps its own path.
+struct HostInode {
+    fs: Arc<HostFs>,
+    /// Host inode number, changed when a symlink is replaced.
+    ino: AtomicU64,
+    location: Mutex<HostLocation>,
+    /// Live entries of a directory by name, moved along on renames.
+    children: Mutex<HashMap<String, Weak<HostInode>>>,
+}
+
+impl HostInode {
+    fn new(fs: Arc<HostFs>, metadata: &fs::Metadata, location: HostLocation) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            ino: AtomicU64::new(metadata.ino()),
+            location: Mutex::new(location),
+            children: Mutex::default(),
+        })
+    }
+
+    fn ino(&self) -> u64 {
+        self.ino.load(Ordering::Relaxed)
+    }
+
+    /// Returns the host path of the file, or `NotFound` once it was detached.
+    fn path(&self) -> VfsResult<PathBuf> {
+        match &*self.location.lock() {
+            HostLocation::Root => Ok(self.fs.root_path.clone()),
+            HostLocation::Child(parent, name) => Ok(parent.path()?.join(name)),
+            HostLocation::Detached => Err(VfsError::NotFound),
+        }
+    }
+
+    fn detach(&self) {
+        *self.location.lock() = HostLocation::Detached;
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let metadata = fs::symlink_metadata(self.path()?).map_err(map_io_error)?;
+        Ok(Metadata {
+            device: metadata.dev(),
+            inode: self.ino(),
+            nlink: metadata.nlink(),
+            mode: NodePermission::from_bits_truncate(metadata.mode() as u16),
+            node_type: node_type_of(metadata.file_type()),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+            block_size: metadata.blksize(),
+            blocks: metadata.blocks(),
+            rdev: DeviceId(metadata.rdev()),
+            atime: timestamp(metadata.atime(), metadata.atime_nsec()),
+            mtime: timestamp(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: timestamp(metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        if update.rdev.is_some() {
+            return Err(VfsError::Unsupported);
+        }
+        let path = self.path()?;
+        let is_symlink = fs::symlink_metadata(&path)
+            .map_err(map_io_error)?
+            .file_type()
+            .is_symlink();
+        if let Some((uid, gid)) = update.owner {
+            std::os::unix::fs::lchown(&path, Some(uid), Some(gid)).map_err(map_io_error)?;
+        }
+        if is_symlink {
+            // Permissions and timestamps of symlinks can not be changed
+            // through `std`.
+            return Ok(());
+        }
+        if let Some(mode) = update.mode {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode.bits() as u32))
+                .map_err(map_io_error)?;
+        }
+        if update.atime.is_some() || update.mtime.is_some() {
+            let mut times = FileTimes::new();
+            if let Some(atime) = update.atime {
+                times = times.set_accessed(system_time(atime));
+            }
+            if let Some(mtime) = update.mtime {
+                times = times.set_modified(system_time(mtime));
+            }
+            open_any(&path)
+                .and_then(|file| file.set_times(times))
+                .map_err(map_io_error)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        let file = open_any(&self.path()?).map_err(map_io_error)?;
+        if data_only {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+        .map_err(map_io_error)
+    }
+}
+
+/// Directory of a [`HostFs`].
+pub struct HostDir {
+    inode: Arc<HostInode>,
+    this: WeakDirEntry,
+}
+
+impl HostDir {
+    fn new_entry(&self, name: &str, metadata: &fs::Metadata) -> VfsResult<DirEntry> {
+        let inode = {
+            let mut children = self.inode.children.lock();
+            match children.get(name).and_then(Weak::upgrade) {
+                Some(inode) if inode.ino() == metadata.ino() => inode,
+                _ => {
+                    let inode = HostInode::new(
+                        self.inode.fs.clone(),
+                        metadata,
+                        HostLocation::Child(self.inode.clone(), name.to_owned()),
+                    );
+                    children.retain(|_, it| it.strong_count() > 0);
+                    children.insert(name.to_owned(), Arc::downgrade(&inode));
+                    inode
+                }
+            }
+        };
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        let node_type = node_type_of(metadata.file_type());
+        Ok(if node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(HostDir { inode, this })),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Arc::new(HostFile {
+                    inode,
+                    file: Mutex::default(),
+                })),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    /// Lists the host directory, sorted by name so that offsets stay stable
+    /// between calls.
+    fn list(&self) -> VfsResult<Vec<(String, u64, NodeType)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.inode.path()?).map_err(map_io_error)? {
+            let entry = entry.map_err(map_io_error)?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| VfsError::InvalidData)?;
+            let node_type = node_type_of(entry.file_type().map_err(map_io_error)?);
+            entries.push((name, entry.ino(), node_type));
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+impl NodeOps for HostDir {
+    fn inode(&self) -> u64 {
+        self.inode.ino()
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        self.inode.sync(data_only)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for HostDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let parent_ino = match &*self.inode.location.lock() {
+            HostLocation::Child(parent, _) => parent.ino(),
+            _ => self.inode.ino(),
+        };
+        let entries = [
+            (DOT.to_owned(), self.inode.ino(), NodeType::Directory),
+            (DOTDOT.to_owned(), parent_ino, NodeType::Directory),
+        ]
+        .into_iter()
+        .chain(self.list()?);
+
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(&name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let metadata = fs::symlink_metadata(self.inode.path()?.join(name)).map_err(map_io_error)?;
+        self.new_entry(name, &metadata)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let path = self.inode.path()?.join(name);
+        let mode = permission.bits() as u32;
+        match node_type {
+            NodeType::RegularFile => fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(mode)
+                .open(&path)
+                .map(drop),
+            NodeType::Directory => fs::DirBuilder::new().mode(mode).create(&path),
+            // The target is set later through `set_symlink`.
+            NodeType::Symlink => std::os::unix::fs::symlink(DOT, &path),
+            _ => return Err(VfsError::Unsupported),
+        }
+        .map_err(map_io_error)?;
+        let metadata = fs::symlink_metadata(&path).map_err(map_io_error)?;
+        self.new_entry(name, &metadata)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let src = node.downcast::<HostFile>()?;
+        if !Arc::ptr_eq(&src.inode.fs, &self.inode.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        let path = self.inode.path()?.join(name);
+        fs::hard_link(src.inode.path()?, &path).map_err(map_io_error)?;
+        let metadata = fs::symlink_metadata(&path).map_err(map_io_error)?;
+        self.new_entry(name, &metadata)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let path = self.inode.path()?.join(name);
+        let metadata = fs::symlink_metadata(&path).map_err(map_io_error)?;
+        if metadata.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .map_err(map_io_error)?;
+        if let Some(inode) = self.inode.children.lock().remove(name)
+            && let Some(inode) = inode.upgrade()
+        {
+            inode.detach();
+        }
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir = dst_dir.downcast::<HostDir>()?;
+        let src_path = self.inode.path()?.join(src_name);
+        let dst_path = dst_dir.inode.path()?.join(dst_name);
+        let metadata = fs::symlink_metadata(&src_path).map_err(map_io_error)?;
+        let same_file = fs::symlink_metadata(&dst_path)
+            .is_ok_and(|it| (it.dev(), it.ino()) == (metadata.dev(), metadata.ino()));
+        fs::rename(&src_path, &dst_path).map_err(map_io_error)?;
+        if same_file {
+            // Renaming a hard link over another one of the same file does
+            // nothing.
+            return Ok(());
+        }
+        let moved = self
+            .inode
+            .children
+            .lock()
+            .remove(src_name)
+            .and_then(|it| it.upgrade());
+        let mut children = dst_dir.inode.children.lock();
+        let replaced = match moved {
+            Some(inode) => {
+                *inode.location.lock() =
+                    HostLocation::Child(dst_dir.inode.clone(), dst_name.to_owned());
+                children.insert(dst_name.to_owned(), Arc::downgrade(&inode))
+            }
+            None => children.remove(dst_name),
+        };
+        if let Some(inode) = replaced.and_then(|it| it.upgrade()) {
+            inode.detach();
+        }
+        Ok(())
+    }
+}
+
+/// Non-directory node of a [`HostFs`].
+pub struct HostFile {
+    inode: Arc<HostInode>,
+    /// Opened host file and whether it is readable and writable.
+    file: Mutex<Option<(Arc<File>, bool, bool)>>,
+}
+
+impl HostFile {
+    /// Returns the opened host file, reopening it with the access requested.
+    ///
+    /// Read and write access are both tried first, then either alone.
+    fn file(&self, read: bool, write: bool) -> VfsResult<Arc<File>> {
+        let mut guard = self.file.lock();
+        if let Some((file, readable, writable)) = guard.as_ref()
+            && (*readable || !read)
+            && (*writable || !write)
+        {
+            return Ok(file.clone());
+        }
+        let path = self.inode.path()?;
+        let mut denied = None;
+        for (readable, writable) in [(true, true), (true, false), (false, true)] {
+            if (read && !readable) || (write && !writable) {
+                continue;
+            }
+            match fs::OpenOptions::new()
+                .read(readable)
+                .write(writable)
+                .open(&path)
+            {
+                Ok(file) => {
+                    let file = Arc::new(file);
+                    *guard = Some((file.clone(), readable, writable));
+                    return Ok(file);
+                }
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                    denied.get_or_insert(err);
+                }
+                Err(err) => return Err(map_io_error(err)),
+            }
+        }
+        Err(denied.map_or(VfsError::PermissionDenied, map_io_error))
+    }
+}
+
+impl NodeOps for HostFile {
+    fn inode(&self) -> u64 {
+        self.inode.ino()
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        let path = self.inode.path()?;
+        let metadata = fs::symlink_metadata(&path).map_err(map_io_error)?;
+        if metadata.is_symlink() {
+            let target = fs::read_link(&path).map_err(map_io_error)?;
+            Ok(target.as_os_str().len() as u64)
+        } else {
+            Ok(metadata.len())
+        }
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        let file = self.file(false, false)?;
+        if data_only {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+        .map_err(map_io_error)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for HostFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let path = self.inode.path()?;
+        if fs::symlink_metadata(&path).is_ok_and(|it| it.is_symlink()) {
+            let target = fs::read_link(&path).map_err(map_io_error)?;
+            let target = target.as_os_str().as_encoded_bytes();
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        self.file(true, false)?
+            .read_at(buf, offset)
+            .map_err(map_io_error)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.file(false, true)?
+            .write_at(buf, offset)
+            .map_err(map_io_error)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let file = self.file(false, true)?;
+        let offset = file.metadata().map_err(map_io_error)?.len();
+        let written = file.write_at(buf, offset).map_err(map_io_error)?;
+        Ok((written, offset + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.file(false, true)?.set_len(len).map_err(map_io_error)
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        let path = self.inode.path()?;
+        if !fs::symlink_metadata(&path)
+            .map_err(map_io_error)?
+            .is_symlink()
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        fs::remove_file(&path).map_err(map_io_error)?;
+        std::os::unix::fs::symlink(target, &path).map_err(map_io_error)?;
+        let metadata = fs::symlink_metadata(&path).map_err(map_io_error)?;
+        self.inode.ino.store(metadata.ino(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Pollable for HostFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        Filesystem, Mountpoint,
+        test_util::{TempDir, list, mount},
+    };
+
+    #[test]
+    fn test_file_io() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+        let file = root
+            .create(
+                "a",
+                NodeType::RegularFile,
+                NodePermission::from_bits_truncate(0o644),
+            )
+            .unwrap();
+        let node = file.entry().as_file().unwrap();
+        assert_eq!(node.write_at(b"hello", 0).unwrap(), 5);
+        assert_eq!(node.append(b" world").unwrap(), (6, 11));
+
+        let mut buf = [0; 16];
+        let read = node.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..read], b"hello world");
+        assert_eq!(fs::read(tmp.0.join("a")).unwrap(), b"hello world");
+
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.node_type, NodeType::RegularFile);
+        assert_eq!(metadata.size, 11);
+        assert_eq!(metadata.mode.bits() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_dir_ops() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+        let sub = root
+            .create(
+                "sub",
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o755),
+            )
+            .unwrap();
+        let file = sub
+            .create("f", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        sub.link("g", &file).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink, 2);
+
+        let link = sub
+            .create("l", NodeType::Symlink, NodePermission::default())
+            .unwrap();
+        link.entry().as_file().unwrap().set_symlink("f").unwrap();
+        assert_eq!(link.read_link().unwrap(), "f");
+
+        assert_eq!(list(&sub), [".", "..", "f", "g", "l"]);
+        assert_eq!(
+            root.unlink("sub", true).unwrap_err(),
+            VfsError::DirectoryNotEmpty
+        );
+
+        sub.rename("f", &root, "moved").unwrap();
+        assert!(tmp.0.join("moved").exists());
+        file.entry().as_file().unwrap().write_at(b"x", 0).unwrap();
+        assert_eq!(file.metadata().unwrap().size, 1);
+        assert_eq!(list(&root), [".", "..", "moved", "sub"]);
+        assert_eq!(root.filesystem().stat().err(), Some(VfsError::Unsupported));
+    }
+
+    #[test]
+    fn test_links() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+        let a = root
+            .create("a", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        let b = root.link("b", &a).unwrap();
+        root.unlink("a", false).unwrap();
+        assert_eq!(b.metadata().unwrap().nlink, 1);
+        assert_eq!(root.lookup_no_follow("b").unwrap().inode(), a.inode());
+
+        let link = root
+            .create("l", NodeType::Symlink, NodePermission::default())
+            .unwrap();
+        link.entry().as_file().unwrap().set_symlink("b").unwrap();
+        assert_eq!(link.metadata().unwrap().inode, link.inode());
+        assert_eq!(root.lookup_no_follow("l").unwrap().inode(), link.inode());
+
+        // Write-only files are still synced and have their times set.
+        b.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o200)),
+            ..Default::default()
+        })
+        .unwrap();
+        let b = root.lookup_no_follow("b").unwrap();
+        b.entry().as_file().unwrap().write_at(b"x", 0).unwrap();
+        b.sync(false).unwrap();
+        b.update_metadata(MetadataUpdate {
+            mtime: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(b.metadata().unwrap().mtime, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_detach() {
+        let tmp = TempDir::new();
+        let root = mount(&tmp);
+        let perm = NodePermission::default();
+        let a = root.create("a", NodeType::RegularFile, perm).unwrap();
+        root.unlink("a", false).unwrap();
+        fs::write(tmp.0.join("a"), "new").unwrap();
+        assert_eq!(a.metadata().unwrap_err(), VfsError::NotFound);
+        assert_eq!(
+            a.entry().as_file().unwrap().write_at(b"x", 0).unwrap_err(),
+            VfsError::NotFound
+        );
+        assert_eq!(fs::read(tmp.0.join("a")).unwrap(), b"new");
+
+        let b = root.create("b", NodeType::RegularFile, perm).unwrap();
+        let c = root.create("c", NodeType::RegularFile, perm).unwrap();
+        root.rename("c", &root, "b").unwrap();
+        assert_eq!(b.metadata().unwrap_err(), VfsError::NotFound);
+        assert_eq!(root.lookup_no_follow("b").unwrap().inode(), c.inode());
+        assert_eq!(c.metadata().unwrap().inode, c.inode());
+    }
+
+    #[test]
+    fn test_release() {
+        let tmp = TempDir::new();
+        let fs = HostFs::new(&tmp.0).unwrap();
+        let weak = Arc::downgrade(&fs);
+        let root = Mountpoint::new_root(&Filesystem::new(fs)).root_location();
+        root.create("f", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        root.unmount().unwrap();
+        drop(root);
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use alloc::{borrow::ToOwned, sync::Arc, vec::Vec};
+use core::any::Any;
+
+use super::{
+    IsoFile, IsoFs,
+    node::{FLAG_MULTI_EXTENT, IsoNode, parse_records},
+};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FilesystemOps, Metadata, MetadataUpdate,
+    Mutex, NodeOps, NodePermission, NodeType, Reference, VfsError, VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT},
+};
+
+/// Directory node of an [`IsoFs`].
+pub struct IsoDir {
+    fs: Arc<IsoFs>,
+    node: IsoNode,
+    this: WeakDirEntry,
+    /// Children, loaded on first use.
+    children: Mutex<Option<Arc<Vec<IsoNode>>>>,
+}
+
+impl IsoDir {
+    pub(super) fn new(fs: Arc<IsoFs>, node: IsoNode, this: WeakDirEntry) -> Self {
+        Self {
+            fs,
+            node,
+            this,
+            children: Mutex::default(),
+        }
+    }
+
+    fn children(&self) -> VfsResult<Arc<Vec<IsoNode>>> {
+        let mut children = self.children.lock();
+        if let Some(children) = &*children {
+            return Ok(children.clone());
+        }
+        let fs = &*self.fs;
+        let data = self.node.read_dir_data(fs)?;
+        let start = self.node.extents.first().map_or(0, |it| it.0);
+        let mut nodes = Vec::new();
+        // The first records of a file made of several extents.
+        let mut pending: Option<IsoNode> = None;
+        for record in parse_records(fs, &data, start)? {
+            if record.is_dot() {
+                continue;
+            }
+            let node = match pending.take() {
+                Some(mut node) => {
+                    let extent = IsoNode::new(fs, &record)?.ok_or(VfsError::InvalidData)?;
+                    node.extents.extend(extent.extents);
+                    node.size += extent.size;
+                    Some(node)
+                }
+                None => IsoNode::new(fs, &record)?,
+            };
+            if record.flags() & FLAG_MULTI_EXTENT != 0 {
+                pending = Some(node.ok_or(VfsError::InvalidData)?);
+            } else if let Some(node) = node {
+                nodes.push(node);
+            }
+        }
+        let nodes = Arc::new(nodes);
+        *children = Some(nodes.clone());
+        Ok(nodes)
+    }
+}
+
+impl NodeOps for IsoDir {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.node.metadata(&self.fs))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for IsoDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let children = self.children()?;
+        let parent_ino = self
+            .this
+            .upgrade()
+            .and_then(|it| it.parent())
+            .map_or(self.node.ino, |it| it.inode());
+        let entries = [
+            (DOT, self.node.ino, NodeType::Directory),
+            (DOTDOT, parent_ino, NodeType::Directory),
+        ]
+        .into_iter()
+        .chain(
+            children
+                .iter()
+                .map(|it| (it.name.as_str(), it.ino, it.node_type)),
+        );
+
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let children = self.children()?;
+        let node = children
+            .iter()
+            .find(|it| it.name == name)
+            .ok_or(VfsError::NotFound)?
+            .clone();
+        let fs = self.fs.clone();
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        Ok(if node.is_dir() {
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(IsoDir::new(fs, node, this))),
+                reference,
+            )
+        } else {
+            let node_type = node.node_type;
+            DirEntry::new_file(
+                FileNode::new(Arc::new(IsoFile::new(fs, node))),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
@@ -0,0 +1,88 @@
+use alloc::sync::Arc;
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::{IsoFs, node::IsoNode};
+use crate::{FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps, VfsError, VfsResult};
+
+/// Non-directory node of an [`IsoFs`].
+///
+/// Reading a Rock Ridge symlink returns its target.
+pub struct IsoFile {
+    fs: Arc<IsoFs>,
+    node: IsoNode,
+}
+
+impl IsoFile {
+    pub(super) fn new(fs: Arc<IsoFs>, node: IsoNode) -> Self {
+        Self { fs, node }
+    }
+}
+
+impl NodeOps for IsoFile {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.node.metadata(&self.fs))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.node.size)
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for IsoFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if let Some(target) = &self.node.symlink {
+            let target = target.as_bytes();
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        self.node.read_at(&self.fs, buf, offset)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for IsoFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,703 @@
+//! Read-only ISO9660 filesystem with the Rock Ridge and Joliet extensions.
+//!
+//! Rock Ridge is preferred when the primary volume has it, as it carries
+//! POSIX names, modes, owners, links, symlinks, device numbers and
+//! timestamps. Otherwise the Unicode names of a Joliet volume are used if
+//! there is one. Plain ISO9660 names are shown in lowercase without their
+//! version suffix, as Linux does by default.
+//!
+//! Files made of several extents are read as the concatenation of their
+//! extents.
+
+mod dir;
+mod file;
+mod node;
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec,
+};
+use core::time::Duration;
+
+use self::node::IsoNode;
+pub use self::{dir::IsoDir, file::IsoFile};
+use super::{RootDir, days_from_civil, le16, le32};
+use crate::{
+    DirEntry, DirNode, FilesystemOps, Reference, StatFs, VfsError, VfsResult,
+    block::{BlockDevice, BufferCache},
+};
+
+const ISOFS_SUPER_MAGIC: u32 = 0x9660;
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Number of volume descriptors looked at before giving up on finding the
+/// terminator.
+const MAX_DESCRIPTORS: u64 = 64;
+const CACHE_CAPACITY: usize = 256;
+const MAX_NAME_LEN: u32 = 255;
+
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_SUPPLEMENTARY: u8 = 2;
+const TYPE_TERMINATOR: u8 = 255;
+/// Escape sequences of the UCS-2 levels of Joliet.
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+/// How names and attributes are stored in the directory tree in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Extension {
+    None,
+    /// Rock Ridge, whose system use areas start after `skip` bytes.
+    RockRidge {
+        skip: usize,
+    },
+    Joliet,
+}
+
+/// An ISO9660 filesystem on a block device.
+pub struct IsoFs {
+    cache: BufferCache,
+    block_size: u64,
+    /// Number of logical blocks of the volume.
+    blocks: u64,
+    extension: Extension,
+    /// The root directory, loaded when mounting.
+    root_node: Option<IsoNode>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl IsoFs {
+    /// Mounts the ISO9660 filesystem on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> VfsResult<Arc<Self>> {
+        let cache = BufferCache::new(device, SECTOR_SIZE as usize, CACHE_CAPACITY)
+            .map_err(|_| VfsError::InvalidData)?;
+        let mut primary = None;
+        let mut joliet = None;
+        for index in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let mut vd = vec![0; SECTOR_SIZE as usize];
+            cache.read_at(index * SECTOR_SIZE, &mut vd)?;
+            if &vd[1..6] != b"CD001" {
+                return Err(VfsError::InvalidData);
+            }
+            match vd[0] {
+                TYPE_PRIMARY if primary.is_none() => primary = Some(vd),
+                TYPE_SUPPLEMENTARY if JOLIET_ESCAPES.contains(&&vd[88..91]) => {
+                    joliet.get_or_insert(vd);
+                }
+                TYPE_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or(VfsError::InvalidData)?;
+        let block_size = le16(&primary, 128) as u64;
+        if !block_size.is_power_of_two() || !(512..=SECTOR_SIZE).contains(&block_size) {
+            return Err(VfsError::InvalidData);
+        }
+
+        let mut fs = Self {
+            cache,
+            block_size,
+            blocks: le32(&primary, 80) as u64,
+            extension: Extension::None,
+            root_node: None,
+            this: Weak::new(),
+            root: RootDir::default(),
+        };
+        let mut root = IsoNode::root(&fs, &primary[156..190])?;
+        if let Some(skip) = root.rock_ridge_skip(&fs)? {
+            fs.extension = Extension::RockRidge { skip };
+            root = IsoNode::root(&fs, &primary[156..190])?;
+        } else if let Some(vd) = joliet {
+            fs.extension = Extension::Joliet;
+            root = IsoNode::root(&fs, &vd[156..190])?;
+        }
+
+        fs.root_node = Some(root);
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            ..fs
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let fs = self.this.upgrade().unwrap();
+        let node = self.root_node.clone().unwrap();
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(IsoDir::new(fs, node, this))),
+            Reference::root(),
+        )
+    }
+
+    /// Reads `buf.len()` bytes at the byte `pos` of the volume.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> VfsResult<()> {
+        if pos + buf.len() as u64 > self.cache.device().len() {
+            return Err(VfsError::InvalidData);
+        }
+        self.cache.read_at(pos, buf)
+    }
+}
+
+impl FilesystemOps for IsoFs {
+    fn name(&self) -> &str {
+        "iso9660"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        Ok(StatFs {
+            fs_type: ISOFS_SUPER_MAGIC,
+            block_size: self.block_size as u32,
+            blocks: self.blocks,
+            blocks_free: 0,
+            blocks_available: 0,
+            file_count: 0,
+            free_file_count: 0,
+            name_length: MAX_NAME_LEN,
+            fragment_size: self.block_size as u32,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// Converts a date and time in UTC, offset by `gmt_offset` quarters of an
+/// hour, to a timestamp.
+fn timestamp(
+    year: i64,
+    month: u32,
+    day: u32,
+    [hour, minute, second]: [u32; 3],
+    nanos: u32,
+    gmt_offset: i8,
+) -> Duration {
+    if !(1..=12).contains(&month) || day == 0 {
+        return Duration::ZERO;
+    }
+    let secs = days_from_civil(year, month, day) * 86400
+        + (hour * 3600 + minute * 60 + second) as i64
+        - gmt_offset as i64 * 900;
+    if secs < 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(secs as u64, nanos)
+}
+
+/// Decodes the 7-byte date and time of directory records.
+fn short_time(date: &[u8]) -> Duration {
+    timestamp(
+        1900 + date[0] as i64,
+        date[1] as u32,
+        date[2] as u32,
+        [date[3] as u32, date[4] as u32, date[5] as u32],
+        0,
+        date[6] as i8,
+    )
+}
+
+/// Decodes the 17-byte date and time of volume descriptors, made of ASCII
+/// digits down to hundredths of seconds.
+fn long_time(date: &[u8]) -> Duration {
+    let field = |range: core::ops::Range<usize>| {
+        date[range]
+            .iter()
+            .try_fold(0, |acc, &it| {
+                it.is_ascii_digit().then(|| acc * 10 + (it - b'0') as u32)
+            })
+            .unwrap_or(0)
+    };
+    timestamp(
+        field(0..4) as i64,
+        field(4..6),
+        field(6..8),
+        [field(8..10), field(10..12), field(12..14)],
+        field(14..16) * 10_000_000,
+        date[16] as i8,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{borrow::ToOwned, string::String, vec::Vec};
+
+    use super::*;
+    use crate::{
+        DeviceId, Filesystem, Location, Mountpoint, NodeType, block::RamDisk, test_util::list,
+    };
+
+    enum Spec {
+        File(&'static str, Vec<u8>, usize),
+        Dir(&'static str, Vec<Spec>),
+        Symlink(&'static str, &'static str),
+        Device(&'static str, u32, u32),
+    }
+
+    impl Spec {
+        fn name(&self) -> &'static str {
+            match self {
+                Spec::File(name, ..)
+                | Spec::Dir(name, _)
+                | Spec::Symlink(name, _)
+                | Spec::Device(name, ..) => name,
+            }
+        }
+    }
+
+    fn both16(value: u16) -> [u8; 4] {
+        let mut buf = [0; 4];
+        buf[..2].copy_from_slice(&value.to_le_bytes());
+        buf[2..].copy_from_slice(&value.to_be_bytes());
+        buf
+    }
+
+    fn both32(value: u32) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&value.to_le_bytes());
+        buf[4..].copy_from_slice(&value.to_be_bytes());
+        buf
+    }
+
+    /// 2001-02-03 04:05:06 at GMT+1.
+    const DATE: [u8; 7] = [101, 2, 3, 5, 5, 6, 4];
+    const DATE_SECS: u64 = 981_173_106;
+
+    /// Builds ISO9660 images, with Rock Ridge entries in the primary volume
+    /// and an optional Joliet volume.
+    struct Builder {
+        image: Vec<u8>,
+        rock_ridge: bool,
+        /// Extents of the files, in the order they are visited.
+        files: Vec<Vec<(u32, u32)>>,
+    }
+
+    impl Builder {
+        fn alloc(&mut self, len: usize) -> u32 {
+            let lba = self.image.len() / 2048;
+            self.image
+                .resize(self.image.len() + len.div_ceil(2048).max(1) * 2048, 0);
+            lba as u32
+        }
+
+        fn record(name: &[u8], lba: u32, len: u32, flags: u8, sua: &[u8]) -> Vec<u8> {
+            let mut record = vec![0; 33];
+            record[2..10].copy_from_slice(&both32(lba));
+            record[10..18].copy_from_slice(&both32(len));
+            record[18..25].copy_from_slice(&DATE);
+            record[25] = flags;
+            record[28..32].copy_from_slice(&both16(1));
+            record[32] = name.len() as u8;
+            record.extend_from_slice(name);
+            if name.len().is_multiple_of(2) {
+                record.push(0);
+            }
+            record.extend_from_slice(sua);
+            if !record.len().is_multiple_of(2) {
+                record.push(0);
+            }
+            record[0] = record.len() as u8;
+            record
+        }
+
+        fn susp(sig: &[u8; 2], data: &[u8]) -> Vec<u8> {
+            let mut entry = vec![sig[0], sig[1], (4 + data.len()) as u8, 1];
+            entry.extend_from_slice(data);
+            entry
+        }
+
+        /// Returns the Rock Ridge entries of a node.
+        fn rock_ridge(&mut self, spec: Option<&Spec>, mode: u32) -> Vec<u8> {
+            let mut px = Vec::new();
+            for value in [mode, 1, 1000, 100, 0] {
+                px.extend_from_slice(&both32(value));
+            }
+            let mut sua = Self::susp(b"PX", &px);
+            let mut tf = vec![0b110];
+            tf.extend_from_slice(&DATE);
+            tf.extend_from_slice(&[121, 1, 1, 0, 0, 0, 0]);
+            sua.extend(Self::susp(b"TF", &tf));
+            match spec {
+                Some(Spec::Symlink(_, target)) => {
+                    let mut sl = vec![0];
+                    for component in target.split('/') {
+                        match component {
+                            "" => sl.extend_from_slice(&[0x08, 0]),
+                            ".." => sl.extend_from_slice(&[0x04, 0]),
+                            _ => {
+                                sl.extend_from_slice(&[0, component.len() as u8]);
+                                sl.extend_from_slice(component.as_bytes());
+                            }
+                        }
+                    }
+                    sua.extend(Self::susp(b"SL", &sl));
+                }
+                Some(Spec::Device(_, major, minor)) => {
+                    let mut pn = both32(*major).to_vec();
+                    pn.extend_from_slice(&both32(*minor));
+                    sua.extend(Self::susp(b"PN", &pn));
+                }
+                _ => {}
+            }
+            if let Some(spec) = spec {
+                let mut nm = vec![0];
+                nm.extend_from_slice(spec.name().as_bytes());
+                let name = Self::susp(b"NM", &nm);
+                if spec.name().starts_with("continued") {
+                    // Move the name to a continuation area.
+                    let lba = self.alloc(name.len());
+                    self.image[lba as usize * 2048 + 100..][..name.len()].copy_from_slice(&name);
+                    let mut ce = both32(lba).to_vec();
+                    ce.extend_from_slice(&both32(100));
+                    ce.extend_from_slice(&both32(name.len() as u32));
+                    sua.extend(Self::susp(b"CE", &ce));
+                } else {
+                    sua.extend(name);
+                }
+            }
+            sua
+        }
+
+        fn iso_name(spec: &Spec, joliet: bool) -> Vec<u8> {
+            let mut name = spec.name().to_owned();
+            if !matches!(spec, Spec::Dir(..)) {
+                name.push_str(";1");
+            }
+            if joliet {
+                name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+            } else {
+                name.to_uppercase().replace(' ', "_").into_bytes()
+            }
+        }
+
+        /// Writes the directory `children` at `lba`, and returns the length
+        /// of the directory.
+        fn write_dir(
+            &mut self,
+            lba: u32,
+            parent: (u32, u32),
+            children: &[Spec],
+            joliet: bool,
+            file_index: &mut usize,
+        ) -> u32 {
+            let rock_ridge = self.rock_ridge && !joliet;
+            let mut dot_sua = Vec::new();
+            if rock_ridge {
+                if parent.0 == lba {
+                    dot_sua = Self::susp(b"SP", &[0xbe, 0xef, 0]);
+                }
+                dot_sua.extend(self.rock_ridge(None, 0o40755));
+            }
+            let sub_dirs: Vec<u32> = children
+                .iter()
+                .map(|it| match it {
+                    Spec::Dir(..) => self.alloc(2048),
+                    _ => 0,
+                })
+                .collect();
+
+            let mut records = Vec::new();
+            let mut dir_lens = Vec::new();
+            for (spec, &sub_lba) in children.iter().zip(&sub_dirs) {
+                let name = Self::iso_name(spec, joliet);
+                let (mode, flags) = match spec {
+                    Spec::File(..) => (0o100640, 0),
+                    Spec::Dir(..) => (0o40755, 2),
+                    Spec::Symlink(..) => (0o120777, 0),
+                    Spec::Device(..) => (0o20600, 0),
+                };
+                let sua = if rock_ridge {
+                    self.rock_ridge(Some(spec), mode)
+                } else {
+                    Vec::new()
+                };
+                match spec {
+                    Spec::File(_, data, extents) => {
+                        if joliet {
+                            // Share the data written for the primary volume.
+                        } else {
+                            let chunk = data.len().div_ceil(*extents).max(1);
+                            let mut file = Vec::new();
+                            for part in data.chunks(chunk) {
+                                // Leave gaps, so that extents are not contiguous.
+                                self.alloc(1);
+                                let lba = self.alloc(part.len());
+                                self.image[lba as usize * 2048..][..part.len()]
+                                    .copy_from_slice(part);
+                                file.push((lba, part.len() as u32));
+                            }
+                            self.files.push(file);
+                        }
+                        let file = self.files[*file_index].clone();
+                        *file_index += 1;
+                        let last = file.len().saturating_sub(1);
+                        for (i, &(lba, len)) in file.iter().enumerate() {
+                            let flags = if i < last { 0x80 } else { 0 };
+                            records.push(Self::record(&name, lba, len, flags, &sua));
+                        }
+                        if file.is_empty() {
+                            records.push(Self::record(&name, 0, 0, 0, &sua));
+                        }
+                    }
+                    Spec::Dir(..) => {
+                        dir_lens.push(records.len());
+                        records.push(Self::record(&name, sub_lba, 0, flags, &sua));
+                    }
+                    _ => records.push(Self::record(&name, 0, 0, 0, &sua)),
+                }
+            }
+
+            let mut index = 0;
+            for (spec, &sub_lba) in children.iter().zip(&sub_dirs) {
+                if let Spec::Dir(_, grandchildren) = spec {
+                    let len =
+                        self.write_dir(sub_lba, (lba, 2048), grandchildren, joliet, file_index);
+                    records[dir_lens[index]][10..18].copy_from_slice(&both32(len));
+                    index += 1;
+                }
+            }
+
+            let mut data = Self::record(&[0], lba, 2048, 2, &dot_sua);
+            data.extend(Self::record(&[1], parent.0, parent.1, 2, &[]));
+            for record in records {
+                data.extend(record);
+            }
+            assert!(data.len() <= 2048);
+            self.image[lba as usize * 2048..][..data.len()].copy_from_slice(&data);
+            2048
+        }
+
+        fn build(tree: &[Spec], rock_ridge: bool, joliet: bool) -> Vec<u8> {
+            let mut builder = Builder {
+                image: vec![0; 19 * 2048],
+                rock_ridge,
+                files: Vec::new(),
+            };
+            let mut descriptors = vec![(TYPE_PRIMARY, false)];
+            if joliet {
+                descriptors.push((TYPE_SUPPLEMENTARY, true));
+            }
+            for (i, &(ty, joliet)) in descriptors.iter().enumerate() {
+                let root = builder.alloc(2048);
+                builder.write_dir(root, (root, 2048), tree, joliet, &mut 0);
+                let vd = &mut builder.image[(16 + i) * 2048..][..2048];
+                vd[0] = ty;
+                vd[1..6].copy_from_slice(b"CD001");
+                vd[6] = 1;
+                vd[40..72].fill(b' ');
+                vd[40..44].copy_from_slice(b"TEST");
+                vd[120..124].copy_from_slice(&both16(1));
+                vd[124..128].copy_from_slice(&both16(1));
+                vd[128..132].copy_from_slice(&both16(2048));
+                if joliet {
+                    vd[88..91].copy_from_slice(b"%/E");
+                }
+                let root = Self::record(&[0], root, 2048, 2, &[]);
+                vd[156..190].copy_from_slice(&root);
+            }
+            let vd = &mut builder.image[(16 + descriptors.len()) * 2048..];
+            vd[0] = TYPE_TERMINATOR;
+            vd[1..6].copy_from_slice(b"CD001");
+            let blocks = (builder.image.len() / 2048) as u32;
+            builder.image[16 * 2048 + 80..][..8].copy_from_slice(&both32(blocks));
+            builder.image
+        }
+    }
+
+    fn tree() -> Vec<Spec> {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        vec![
+            Spec::File("readme.txt", b"hello, iso\n".to_vec(), 1),
+            Spec::File("Multi Extent.bin", data, 3),
+            Spec::File("empty", Vec::new(), 1),
+            Spec::Dir(
+                "docs",
+                vec![
+                    Spec::File("continued-name-in-ce.txt", b"ce".to_vec(), 1),
+                    Spec::Dir("inner", vec![]),
+                ],
+            ),
+            Spec::Symlink("link", "../docs/inner"),
+            Spec::Symlink("abs", "/usr/bin"),
+            Spec::Device("null", 1, 3),
+        ]
+    }
+
+    fn mount(image: Vec<u8>) -> (Arc<IsoFs>, Location) {
+        let fs = IsoFs::new(Arc::new(RamDisk::from_vec(image))).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+        (fs, root)
+    }
+
+    fn read_all(file: &Location) -> Vec<u8> {
+        let file = file.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    fn sorted(dir: &Location) -> Vec<String> {
+        let mut names = list(dir);
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rock_ridge() {
+        let (fs, root) = mount(Builder::build(&tree(), true, true));
+        assert_eq!(fs.extension, Extension::RockRidge { skip: 0 });
+        assert_eq!(
+            sorted(&root),
+            [
+                ".",
+                "..",
+                "Multi Extent.bin",
+                "abs",
+                "docs",
+                "empty",
+                "link",
+                "null",
+                "readme.txt"
+            ]
+        );
+        let file = root.lookup_no_follow("Multi Extent.bin").unwrap();
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(read_all(&file), data);
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.size, 10000);
+        assert_eq!(meta.mode.bits(), 0o640);
+        assert_eq!((meta.uid, meta.gid), (1000, 100));
+        assert_eq!(meta.mtime, Duration::from_secs(DATE_SECS));
+        assert_eq!(meta.atime, Duration::from_secs(1_609_459_200));
+        assert!(read_all(&root.lookup_no_follow("empty").unwrap()).is_empty());
+
+        let link = root.lookup_no_follow("link").unwrap();
+        assert_eq!(link.metadata().unwrap().node_type, NodeType::Symlink);
+        assert_eq!(link.read_link().unwrap(), "../docs/inner");
+        let abs = root.lookup_no_follow("abs").unwrap();
+        assert_eq!(abs.read_link().unwrap(), "/usr/bin");
+        let null = root.lookup_no_follow("null").unwrap().metadata().unwrap();
+        assert_eq!(null.node_type, NodeType::CharacterDevice);
+        assert_eq!(null.rdev, DeviceId::new(1, 3));
+
+        let docs = root.lookup_no_follow("docs").unwrap();
+        assert_eq!(
+            sorted(&docs),
+            [".", "..", "continued-name-in-ce.txt", "inner"]
+        );
+        let inner = docs.lookup_no_follow("inner").unwrap();
+        assert_eq!(inner.metadata().unwrap().node_type, NodeType::Directory);
+        assert_eq!(inner.lookup_no_follow("..").unwrap().inode(), docs.inode());
+        assert_eq!(
+            read_all(&docs.lookup_no_follow("continued-name-in-ce.txt").unwrap()),
+            b"ce"
+        );
+
+        let stat = fs.stat().unwrap();
+        assert_eq!(stat.fs_type, 0x9660);
+        assert_eq!(stat.block_size, 2048);
+        assert_eq!(
+            root.create("new", NodeType::RegularFile, Default::default())
+                .err(),
+            Some(VfsError::ReadOnlyFilesystem)
+        );
+    }
+
+    #[test]
+    fn test_joliet_and_plain() {
+        let (fs, root) = mount(Builder::build(&tree(), false, true));
+        assert_eq!(fs.extension, Extension::Joliet);
+        assert!(list(&root).iter().any(|it| it == "Multi Extent.bin"));
+        let docs = root.lookup_no_follow("docs").unwrap();
+        let file = docs.lookup_no_follow("continued-name-in-ce.txt").unwrap();
+        assert_eq!(read_all(&file), b"ce");
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.mode.bits(), 0o444);
+        assert_eq!(meta.ctime, Duration::from_secs(DATE_SECS));
+
+        let (fs, root) = mount(Builder::build(&tree(), false, false));
+        assert_eq!(fs.extension, Extension::None);
+        assert!(list(&root).iter().any(|it| it == "multi_extent.bin"));
+        let data = read_all(&root.lookup_no_follow("multi_extent.bin").unwrap());
+        assert_eq!(data.len(), 10000);
+        assert_eq!(
+            root.lookup_no_follow("docs")
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .mode
+                .bits(),
+            0o555
+        );
+    }
+
+    /// Replaces the TF entry following `pos` with a CL entry to the directory
+    /// at `lba`, padded to the same length.
+    fn relocate(image: &mut [u8], pos: usize, lba: u32) {
+        let tf = pos + image[pos..].windows(2).position(|it| it == b"TF").unwrap();
+        let len = image[tf + 2] as usize;
+        let mut entry = Builder::susp(b"CL", &both32(lba));
+        entry.extend(Builder::susp(b"PD", &vec![0; len - entry.len() - 4]));
+        image[tf..tf + len].copy_from_slice(&entry);
+    }
+
+    #[test]
+    fn test_relocation() {
+        let mut image = Builder::build(&tree(), true, false);
+        let find = |image: &[u8], name: &[u8]| {
+            image.windows(name.len()).position(|it| it == name).unwrap() - 33
+        };
+        let docs = find(&image, b"DOCS");
+        let docs_lba = le32(&image, docs + 2);
+        let readme = find(&image, b"README.TXT;1");
+        relocate(&mut image, readme, docs_lba);
+        let (_, root) = mount(image.clone());
+        let moved = root.lookup_no_follow("readme.txt").unwrap();
+        assert_eq!(moved.metadata().unwrap().node_type, NodeType::Directory);
+        assert!(list(&moved).iter().any(|it| it == "inner"));
+
+        // Relocations to directories claiming to be relocated themselves
+        // would never end.
+        relocate(&mut image, docs_lba as usize * 2048, docs_lba);
+        let (_, root) = mount(image);
+        assert_eq!(
+            root.lookup_no_follow("readme.txt").err(),
+            Some(VfsError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_lengths() {
+        // Continuation areas are cut at the end of their block.
+        let mut image = Builder::build(&tree(), true, false);
+        let ce = image.windows(2).position(|it| it == b"CE").unwrap();
+        image[ce + 20..ce + 28].copy_from_slice(&both32(u32::MAX));
+        let (_, root) = mount(image.clone());
+        let docs = root.lookup_no_follow("docs").unwrap();
+        assert!(docs.lookup_no_follow("continued-name-in-ce.txt").is_ok());
+
+        // Directories cannot be larger than the volume.
+        let docs = image.windows(4).position(|it| it == b"DOCS").unwrap() - 33;
+        image[docs + 10..docs + 18].copy_from_slice(&both32(u32::MAX));
+        let (_, root) = mount(image);
+        let docs = root.lookup_no_follow("docs").unwrap();
+        assert_eq!(
+            docs.read_dir(0, &mut |_: &str, _, _, _| true).err(),
+            Some(VfsError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_times() {
+        assert_eq!(short_time(&DATE), Duration::from_secs(DATE_SECS));
+        assert_eq!(
+            long_time(b"2001020304050607\x04"),
+            Duration::new(DATE_SECS - 3600, 70_000_000)
+        );
+        assert_eq!(short_time(&[0; 7]), Duration::ZERO);
+        // Fields that are not digits count as zero.
+        assert_eq!(
+            long_time("2001020304050\u{e9}7\x04".as_bytes()),
+            Duration::new(DATE_SECS - 3600 - 6, 0)
+        );
+    }
+}
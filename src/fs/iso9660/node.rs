@@ -0,0 +1,449 @@
+//! Directory records and the nodes they describe.
+
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::time::Duration;
+
+use super::{Extension, IsoFs, le32, long_time, short_time};
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+/// Size of a directory record without its name.
+const RECORD_HEADER_SIZE: usize = 33;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+/// Marks all the records of a file but its last one.
+pub const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Number of continuation areas followed for a single record.
+const MAX_CONTINUATIONS: usize = 16;
+
+const TF_LONG_FORM: u8 = 0x80;
+/// Timestamps that may be recorded by a TF entry, in order.
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+const TF_ATTRIBUTES: u8 = 0x08;
+
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+
+/// A directory record.
+pub struct Record<'a> {
+    /// Position of the record on the volume.
+    pub pos: u64,
+    pub raw: &'a [u8],
+}
+
+impl Record<'_> {
+    pub fn flags(&self) -> u8 {
+        self.raw[25]
+    }
+
+    pub fn identifier(&self) -> &[u8] {
+        &self.raw[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + self.raw[32] as usize]
+    }
+
+    /// Returns whether this is the `.` or `..` record of a directory.
+    pub fn is_dot(&self) -> bool {
+        matches!(self.identifier(), [0] | [1])
+    }
+
+    /// Returns the first byte and the length of the extent.
+    fn extent(&self, fs: &IsoFs) -> (u64, u64) {
+        let lba = le32(self.raw, 2) as u64 + self.raw[1] as u64;
+        (lba * fs.block_size, le32(self.raw, 10) as u64)
+    }
+
+    /// Returns the system use area.
+    fn system_use(&self, skip: usize) -> &[u8] {
+        let start = (RECORD_HEADER_SIZE + self.raw[32] as usize).next_multiple_of(2) + skip;
+        self.raw.get(start..).unwrap_or_default()
+    }
+}
+
+/// Parses the records of directory data at `pos` on the volume.
+///
+/// Records do not cross logical blocks, and the rest of a block after the
+/// last record of the block is zeroed.
+pub fn parse_records<'a>(fs: &IsoFs, data: &'a [u8], pos: u64) -> VfsResult<Vec<Record<'a>>> {
+    let block_size = fs.block_size as usize;
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = data[offset] as usize;
+        if len == 0 {
+            offset = (offset + 1).next_multiple_of(block_size);
+            continue;
+        }
+        let block_end = (offset / block_size + 1) * block_size;
+        if len < RECORD_HEADER_SIZE + 1
+            || offset + len > block_end.min(data.len())
+            || RECORD_HEADER_SIZE + data[offset + 32] as usize > len
+        {
+            return Err(VfsError::InvalidData);
+        }
+        records.push(Record {
+            pos: pos + offset as u64,
+            raw: &data[offset..offset + len],
+        });
+        offset += len;
+    }
+    Ok(records)
+}
+
+/// A System Use Sharing Protocol entry.
+struct SuspEntry {
+    signature: [u8; 2],
+    data: Vec<u8>,
+}
+
+/// Collects the SUSP entries of a record, following continuation areas.
+fn susp_entries(fs: &IsoFs, area: &[u8]) -> VfsResult<Vec<SuspEntry>> {
+    let mut entries = Vec::new();
+    let mut area = area.to_vec();
+    for _ in 0..MAX_CONTINUATIONS {
+        let mut continuation = None;
+        let mut offset = 0;
+        while offset + 4 <= area.len() {
+            let len = area[offset + 2] as usize;
+            if len < 4 || offset + len > area.len() {
+                break;
+            }
+            let signature = [area[offset], area[offset + 1]];
+            let data = &area[offset + 4..offset + len];
+            match &signature {
+                b"ST" => break,
+                b"CE" if data.len() >= 24 => {
+                    continuation = Some((le32(data, 0) as u64, le32(data, 8), le32(data, 16)));
+                }
+                _ => entries.push(SuspEntry {
+                    signature,
+                    data: data.to_vec(),
+                }),
+            }
+            offset += len;
+        }
+        let Some((lba, offset, len)) = continuation else {
+            break;
+        };
+        // Continuation areas do not cross logical blocks.
+        let offset = offset as u64;
+        if offset >= fs.block_size {
+            return Err(VfsError::InvalidData);
+        }
+        area = vec![0; (len as u64).min(fs.block_size - offset) as usize];
+        fs.read_at(lba * fs.block_size + offset, &mut area)?;
+    }
+    Ok(entries)
+}
+
+/// Decodes a name of the directory tree in use.
+fn decode_name(identifier: &[u8], extension: Extension) -> String {
+    let name = if extension == Extension::Joliet {
+        let units: Vec<u16> = identifier
+            .chunks_exact(2)
+            .map(|it| u16::from_be_bytes([it[0], it[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        identifier
+            .iter()
+            .map(|&it| char::from(it).to_ascii_lowercase())
+            .collect()
+    };
+    let name = match name.rsplit_once(';') {
+        Some((name, version)) if version.bytes().all(|it| it.is_ascii_digit()) => name,
+        _ => &name,
+    };
+    let name = if extension == Extension::Joliet {
+        name
+    } else {
+        name.strip_suffix('.').unwrap_or(name)
+    };
+    name.to_string()
+}
+
+/// Assembles the target of a symlink from the components of SL entries.
+fn symlink_target(entries: &[SuspEntry]) -> String {
+    let mut target = String::new();
+    let mut separate = false;
+    for entry in entries.iter().filter(|it| &it.signature == b"SL") {
+        let mut components = entry.data.get(1..).unwrap_or_default();
+        while let [flags, len, rest @ ..] = components {
+            let len = (*len as usize).min(rest.len());
+            let content = String::from_utf8_lossy(&rest[..len]);
+            if flags & SL_ROOT != 0 {
+                target.push('/');
+                separate = false;
+            } else {
+                if separate {
+                    target.push('/');
+                }
+                target.push_str(match flags & (SL_CURRENT | SL_PARENT) {
+                    SL_CURRENT => ".",
+                    SL_PARENT => "..",
+                    _ => &content,
+                });
+                // Continued components go on without a separator.
+                separate = flags & SL_CONTINUE == 0;
+            }
+            components = &rest[len..];
+        }
+    }
+    target
+}
+
+/// A file or directory.
+#[derive(Clone)]
+pub struct IsoNode {
+    pub ino: u64,
+    pub name: String,
+    pub node_type: NodeType,
+    /// First bytes and lengths of the extents of the data.
+    pub extents: Vec<(u64, u64)>,
+    pub size: u64,
+    mode: u16,
+    nlink: u64,
+    uid: u32,
+    gid: u32,
+    rdev: DeviceId,
+    atime: Duration,
+    mtime: Duration,
+    ctime: Duration,
+    /// Target of a Rock Ridge symlink.
+    pub symlink: Option<String>,
+}
+
+impl IsoNode {
+    /// Loads the root directory from the record of a volume descriptor,
+    /// taking the Rock Ridge attributes from its `.` record.
+    pub(super) fn root(fs: &IsoFs, raw: &[u8]) -> VfsResult<Self> {
+        let record = Record { pos: 0, raw };
+        if record.flags() & FLAG_DIRECTORY == 0 {
+            return Err(VfsError::InvalidData);
+        }
+        let (start, _) = record.extent(fs);
+        let mut node = match fs.extension {
+            Extension::RockRidge { .. } => Self::dot(fs, start)?,
+            _ => Self::parse(fs, &record, false)?.ok_or(VfsError::InvalidData)?,
+        };
+        node.name = String::new();
+        Ok(node)
+    }
+
+    /// Loads the directory at `start` from its `.` record.
+    ///
+    /// `.` records cannot be relocated, so a CL entry in them is invalid,
+    /// which also stops relocations from looping.
+    fn dot(fs: &IsoFs, start: u64) -> VfsResult<Self> {
+        let mut raw = [0; 255];
+        fs.read_at(start, &mut raw[..1])?;
+        let len = raw[0] as usize;
+        fs.read_at(start, &mut raw[..len])?;
+        let record = parse_records(fs, &raw[..len], start)?;
+        let record = record.first().ok_or(VfsError::InvalidData)?;
+        if record.identifier() != [0] {
+            return Err(VfsError::InvalidData);
+        }
+        Self::parse(fs, record, false)?.ok_or(VfsError::InvalidData)
+    }
+
+    /// Returns the number of bytes skipped in system use areas if the root
+    /// directory, as loaded without extensions, has Rock Ridge entries.
+    pub(super) fn rock_ridge_skip(&self, fs: &IsoFs) -> VfsResult<Option<usize>> {
+        let start = self.extents.first().map_or(0, |it| it.0);
+        let mut raw = [0; 255];
+        fs.read_at(start, &mut raw[..1])?;
+        let len = raw[0] as usize;
+        fs.read_at(start, &mut raw[..len])?;
+        let records = parse_records(fs, &raw[..len], start)?;
+        let Some(dot) = records.first() else {
+            return Ok(None);
+        };
+        // The SP entry comes first in the `.` record of the root.
+        let area = dot.system_use(0);
+        if area.len() < 7 || &area[..2] != b"SP" || area[4..6] != [0xbe, 0xef] {
+            return Ok(None);
+        }
+        let skip = area[6] as usize;
+        let entries = susp_entries(fs, dot.system_use(skip))?;
+        let has_rock_ridge = entries
+            .iter()
+            .any(|it| matches!(&it.signature, b"RR" | b"PX" | b"NM" | b"TF" | b"SL" | b"PN"));
+        Ok(has_rock_ridge.then_some(skip))
+    }
+
+    /// Describes the node of `record`, or returns `None` if it is hidden.
+    pub fn new(fs: &IsoFs, record: &Record) -> VfsResult<Option<Self>> {
+        Self::parse(fs, record, true)
+    }
+
+    /// Describes the node of `record`, following a CL entry to the
+    /// directory it relocates if `relocatable`.
+    fn parse(fs: &IsoFs, record: &Record, relocatable: bool) -> VfsResult<Option<Self>> {
+        let is_dir = record.flags() & FLAG_DIRECTORY != 0;
+        let (start, size) = record.extent(fs);
+        let time = short_time(&record.raw[18..25]);
+        let mut node = Self {
+            // Directories are numbered after their data, so that their `.`
+            // records and the records of their parents agree.
+            ino: if is_dir { start } else { record.pos },
+            name: decode_name(record.identifier(), fs.extension),
+            node_type: if is_dir {
+                NodeType::Directory
+            } else {
+                NodeType::RegularFile
+            },
+            extents: vec![(start, size)],
+            size,
+            mode: if is_dir { 0o555 } else { 0o444 },
+            nlink: if is_dir { 2 } else { 1 },
+            uid: 0,
+            gid: 0,
+            rdev: DeviceId::default(),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            symlink: None,
+        };
+        let Extension::RockRidge { skip } = fs.extension else {
+            return Ok(Some(node));
+        };
+
+        let entries = susp_entries(fs, record.system_use(skip))?;
+        let mut name = None::<String>;
+        for entry in &entries {
+            let data = &entry.data;
+            match &entry.signature {
+                // Relocated directories are reached through their CL entry.
+                b"RE" => return Ok(None),
+                b"CL" if !relocatable => return Err(VfsError::InvalidData),
+                b"CL" if data.len() >= 8 => {
+                    let mut moved = Self::dot(fs, le32(data, 0) as u64 * fs.block_size)?;
+                    moved.name = node.name;
+                    return Ok(Some(moved));
+                }
+                b"PX" if data.len() >= 32 => {
+                    let mode = le32(data, 0);
+                    node.mode = (mode & 0o7777) as u16;
+                    node.node_type = NodeType::from((mode >> 12) as u8);
+                    node.nlink = le32(data, 8) as u64;
+                    node.uid = le32(data, 16);
+                    node.gid = le32(data, 24);
+                }
+                b"PN" if data.len() >= 16 => {
+                    let (high, low) = (le32(data, 0), le32(data, 8));
+                    node.rdev = if high == 0 && low & !0xff != 0 {
+                        DeviceId::new(low >> 8, low & 0xff)
+                    } else {
+                        DeviceId::new(high, low)
+                    };
+                }
+                b"NM" if !data.is_empty() => {
+                    let part = match data[0] & (SL_CURRENT | SL_PARENT) {
+                        SL_CURRENT => ".".into(),
+                        SL_PARENT => "..".into(),
+                        _ => String::from_utf8_lossy(&data[1..]),
+                    };
+                    name.get_or_insert_default().push_str(&part);
+                }
+                b"TF" if !data.is_empty() => node.set_times(data),
+                _ => {}
+            }
+        }
+        if let Some(name) = name {
+            node.name = name;
+        }
+        if node.node_type == NodeType::Symlink {
+            let target = symlink_target(&entries);
+            node.size = target.len() as u64;
+            node.symlink = Some(target);
+        }
+        Ok(Some(node))
+    }
+
+    /// Applies the timestamps of a TF entry.
+    fn set_times(&mut self, data: &[u8]) {
+        let flags = data[0];
+        let len = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+        let mut stamps = data[1..].chunks_exact(len);
+        for flag in [TF_CREATION, TF_MODIFY, TF_ACCESS, TF_ATTRIBUTES] {
+            if flags & flag == 0 {
+                continue;
+            }
+            let Some(stamp) = stamps.next() else {
+                return;
+            };
+            let time = if len == 17 {
+                long_time(stamp)
+            } else {
+                short_time(stamp)
+            };
+            match flag {
+                TF_MODIFY => self.mtime = time,
+                TF_ACCESS => self.atime = time,
+                TF_ATTRIBUTES => self.ctime = time,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.node_type == NodeType::Directory
+    }
+
+    pub fn metadata(&self, fs: &IsoFs) -> Metadata {
+        Metadata {
+            device: 0,
+            inode: self.ino,
+            nlink: self.nlink,
+            mode: NodePermission::from_bits_truncate(self.mode),
+            node_type: self.node_type,
+            uid: self.uid,
+            gid: self.gid,
+            size: self.size,
+            block_size: fs.block_size,
+            blocks: self.extents.iter().map(|it| it.1.div_ceil(512)).sum(),
+            rdev: self.rdev,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+        }
+    }
+
+    /// Reads the data of the node at `offset`, up to its size.
+    pub fn read_at(&self, fs: &IsoFs, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let mut done = 0;
+        let mut extent_offset = 0;
+        for &(start, len) in &self.extents {
+            let pos = offset + done as u64;
+            if done == buf.len() {
+                break;
+            }
+            if pos < extent_offset + len {
+                let skip = pos - extent_offset;
+                let chunk = ((len - skip) as usize).min(buf.len() - done);
+                fs.read_at(start + skip, &mut buf[done..done + chunk])?;
+                done += chunk;
+            }
+            extent_offset += len;
+        }
+        Ok(done)
+    }
+
+    /// Reads all the data of a directory.
+    pub fn read_dir_data(&self, fs: &IsoFs) -> VfsResult<Vec<u8>> {
+        if self.size > fs.cache.device().len() {
+            return Err(VfsError::InvalidData);
+        }
+        let mut data = vec![0; self.size as usize];
+        let len = self.read_at(fs, &mut data, 0)?;
+        data.truncate(len);
+        Ok(data)
+    }
+}
@@ -0,0 +1,119 @@
+pub mod axfs;
+pub mod devfs;
+pub mod ext4;
+pub mod fat;
+pub mod fuse;
+#[cfg(any(test, feature = "std"))]
+pub mod hostfs;
+pub mod iso9660;
+pub mod overlay;
+pub mod p9;
+pub mod registry;
+pub mod squashfs;
+pub mod staticfs;
+pub mod zip;
+
+use alloc::sync::Arc;
+
+use inherit_methods_macro::inherit_methods;
+
+use crate::{DirEntry, Mutex, VfsResult, WeakDirEntry};
+
+pub struct StatFs {
+    pub fs_type: u32,
+    pub block_size: u32,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    pub blocks_available: u64,
+
+    pub file_count: u64,
+    pub free_file_count: u64,
+
+    pub name_length: u32,
+    pub fragment_size: u32,
+    pub mount_flags: u32,
+}
+
+/// Trait for filesystem operations
+pub trait FilesystemOps: Send + Sync {
+    /// Gets the name of the filesystem
+    fn name(&self) -> &str;
+
+    /// Gets the root directory entry of the filesystem
+    fn root_dir(&self) -> DirEntry;
+
+    /// Returns statistics about the filesystem
+    fn stat(&self) -> VfsResult<StatFs>;
+
+    /// Flushes the filesystem, ensuring all data is written to disk
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Filesystem {
+    ops: Arc<dyn FilesystemOps>,
+}
+
+#[inherit_methods(from = "self.ops")]
+impl Filesystem {
+    pub fn name(&self) -> &str;
+
+    pub fn root_dir(&self) -> DirEntry;
+
+    pub fn stat(&self) -> VfsResult<StatFs>;
+
+    pub fn flush(&self) -> VfsResult<()>;
+}
+
+impl Filesystem {
+    pub fn new(ops: Arc<dyn FilesystemOps>) -> Self {
+        Self { ops }
+    }
+}
+
+/// Root directory entry of a filesystem.
+///
+/// The root holds the filesystem through its node, so the filesystem only
+/// keeps it weakly and builds it again once it was dropped.
+#[derive(Default)]
+pub(crate) struct RootDir(Mutex<Option<WeakDirEntry>>);
+
+impl RootDir {
+    pub fn get_or_init(&self, init: impl FnOnce() -> DirEntry) -> DirEntry {
+        let mut root = self.0.lock();
+        if let Some(entry) = root.as_ref().and_then(WeakDirEntry::upgrade) {
+            return entry;
+        }
+        let entry = init();
+        *root = Some(entry.downgrade());
+        entry
+    }
+}
+
+/// Reads a little-endian `u16` at `offset` in `buf`.
+pub(crate) fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Reads a little-endian `u32` at `offset` in `buf`.
+pub(crate) fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little-endian `u64` at `offset` in `buf`.
+pub(crate) fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Converts a civil date to days since the Unix epoch.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,1418 @@
+//! Overlay filesystem, merging a writable upper layer with read-only lower
+//! layers.
+//!
+//! Whiteouts and opaque directories use the same layout as OCI image layers,
+//! so that unpacked layers can be used as lower layers directly:
+//! - A whiteout is an empty file named `.wh.<name>`, hiding the entry `<name>`
+//!   in the layers below. Names starting with [`WHITEOUT_PREFIX`] are thus
+//!   reserved.
+//! - A directory is opaque if it contains an entry named [`OPAQUE_MARKER`],
+//!   in which case the layers below are not merged into it.
+//!
+//! Non-directories are copied up to the upper layer, with their extended
+//! attributes, on the first write, metadata or extended attribute update.
+//! Renaming a directory that has lower components returns
+//! `CrossesDevices`, so that callers can fall back to copy and delete just like
+//! they do across mountpoints.
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+use hashbrown::{HashMap, HashSet};
+
+use super::RootDir;
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps, Location,
+    Metadata, MetadataUpdate, Mutex, NodeFlags, NodeOps, NodePermission, NodeType, Reference,
+    StatFs, VfsError, VfsResult, WeakDirEntry, XattrFlags,
+    path::{DOT, DOTDOT, MAX_NAME_LEN},
+};
+
+/// Prefix of whiteout entries.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the marker entry that makes a directory opaque.
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+const COPY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Layer index of the upper layer. Lower layers are numbered from 1.
+const UPPER_LAYER: usize = 0;
+
+/// Maps an inode number of a layer into the overlay inode space, by storing
+/// the layer index in the highest bits.
+fn overlay_ino(layer: usize, ino: u64) -> u64 {
+    ((layer as u64) << 56) | (ino & ((1 << 56) - 1))
+}
+
+fn whiteout_name(name: &str) -> String {
+    format!("{WHITEOUT_PREFIX}{name}")
+}
+
+/// Checks that `name` can be hidden by a whiteout, whose name is longer.
+fn check_whiteout(name: &str) -> VfsResult<()> {
+    if WHITEOUT_PREFIX.len() + name.len() > MAX_NAME_LEN {
+        return Err(VfsError::NameTooLong);
+    }
+    Ok(())
+}
+
+fn has_whiteout(dir: &Location, name: &str) -> VfsResult<bool> {
+    if check_whiteout(name).is_err() {
+        return Ok(false);
+    }
+    Ok(lookup_opt(dir, &whiteout_name(name))?.is_some())
+}
+
+fn is_opaque(dir: &Location) -> bool {
+    dir.lookup_no_follow(OPAQUE_MARKER).is_ok()
+}
+
+fn lookup_opt(dir: &Location, name: &str) -> VfsResult<Option<Location>> {
+    match dir.lookup_no_follow(name) {
+        Ok(loc) => Ok(Some(loc)),
+        Err(err) if err.canonicalize() == VfsError::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn read_all(dir: &Location) -> VfsResult<Vec<(String, u64, NodeType)>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let read = dir.read_dir(offset, &mut |name: &str, ino, node_type, next| {
+            entries.push((name.to_owned(), ino, node_type));
+            offset = next;
+            true
+        })?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+/// Removes all whiteouts and the opaque marker from an upper directory, which
+/// must contain nothing else.
+fn clear_whiteouts(dir: &Location) -> VfsResult<()> {
+    for (name, ..) in read_all(dir)? {
+        if name == DOT || name == DOTDOT {
+            continue;
+        }
+        if !name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        dir.unlink(&name, false)?;
+    }
+    Ok(())
+}
+
+fn create_whiteout(dir: &Location, name: &str) -> VfsResult<()> {
+    dir.create(
+        &whiteout_name(name),
+        NodeType::RegularFile,
+        NodePermission::empty(),
+    )
+    .map(|_| ())
+}
+
+fn remove_whiteout(dir: &Location, name: &str) -> VfsResult<()> {
+    if has_whiteout(dir, name)? {
+        dir.unlink(&whiteout_name(name), false)?;
+    }
+    Ok(())
+}
+
+/// Overlay filesystem.
+pub struct OverlayFs {
+    upper: Location,
+    lowers: Vec<Location>,
+    /// Live overlay inodes, keyed by the topmost real node they were found
+    /// at.
+    inodes: Mutex<HashMap<(usize, u64), Weak<OverlayInode>>>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl OverlayFs {
+    /// Creates an overlay filesystem.
+    ///
+    /// `lowers` are ordered from the topmost layer to the bottommost one. The
+    /// result can be mounted with [`crate::Filesystem::new`].
+    pub fn new(upper: Location, lowers: Vec<Location>) -> VfsResult<Arc<Self>> {
+        upper.check_is_dir()?;
+        for lower in &lowers {
+            lower.check_is_dir()?;
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            upper,
+            lowers,
+            inodes: Mutex::default(),
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let upper_root = self.upper.clone();
+        let opaque = is_opaque(&upper_root);
+        let root_inode = Arc::new(OverlayInode {
+            fs: self.this.upgrade().unwrap(),
+            ino: overlay_ino(UPPER_LAYER, upper_root.inode()),
+            node_type: NodeType::Directory,
+            origin: Mutex::new(None),
+            upper: Mutex::new(Some(upper_root)),
+            lowers: if opaque {
+                vec![]
+            } else {
+                self.lowers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, it)| (i + 1, it.clone()))
+                    .collect()
+            },
+        });
+        DirEntry::new_dir(
+            |this| DirNode::new(OverlayDir::new(root_inode, this)),
+            Reference::root(),
+        )
+    }
+
+    /// Returns the overlay node of the given real nodes, reusing the live
+    /// inode if there is one.
+    fn inode(
+        self: &Arc<Self>,
+        mut layers: Vec<(usize, Location)>,
+        parent: &DirEntry,
+        name: &str,
+    ) -> Arc<OverlayInode> {
+        let (layer, top) = layers[0].clone();
+        let key = (Arc::as_ptr(top.mountpoint()) as usize, top.inode());
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&key).and_then(Weak::upgrade) {
+            return inode;
+        }
+
+        let upper = if layer == UPPER_LAYER {
+            Some(layers.remove(0).1)
+        } else {
+            None
+        };
+        if !top.is_dir() {
+            layers.truncate(1);
+        }
+        let inode = Arc::new(OverlayInode {
+            fs: self.clone(),
+            ino: overlay_ino(layer, top.inode()),
+            node_type: top.node_type(),
+            origin: Mutex::new(Some((parent.clone(), name.to_owned()))),
+            upper: Mutex::new(upper),
+            lowers: layers,
+        });
+        inodes.retain(|_, it| it.strong_count() > 0);
+        inodes.insert(key, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Drops the live inode of a removed upper node, since its inode number
+    /// may be reused by the upper filesystem.
+    fn forget(&self, loc: &Location) {
+        self.inodes
+            .lock()
+            .remove(&(Arc::as_ptr(loc.mountpoint()) as usize, loc.inode()));
+    }
+}
+
+impl FilesystemOps for OverlayFs {
+    fn name(&self) -> &str {
+        "overlay"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        self.upper.filesystem().stat()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.upper.filesystem().flush()
+    }
+}
+
+/// State shared by all directory entries referring to the same overlay node.
+struct OverlayInode {
+    fs: Arc<OverlayFs>,
+    ino: u64,
+    node_type: NodeType,
+    /// Parent directory and name, used to locate the node when copying up.
+    /// `None` for the root.
+    origin: Mutex<Option<(DirEntry, String)>>,
+    upper: Mutex<Option<Location>>,
+    /// Lower real nodes from top to bottom, along with their layer indices.
+    ///
+    /// For non-directories only the topmost one is kept.
+    lowers: Vec<(usize, Location)>,
+}
+
+impl OverlayInode {
+    fn upper(&self) -> Option<Location> {
+        self.upper.lock().clone()
+    }
+
+    /// Returns the topmost real node.
+    fn real(&self) -> Location {
+        self.upper().unwrap_or_else(|| self.lowers[0].1.clone())
+    }
+
+    /// Whether writes to the node change data that has to be copied up first.
+    /// Device, fifo and socket nodes pass writes through to the real node and
+    /// are only copied up, like any other node, when their metadata changes.
+    fn has_data(&self) -> bool {
+        !matches!(
+            self.node_type,
+            NodeType::CharacterDevice | NodeType::BlockDevice | NodeType::Fifo | NodeType::Socket
+        )
+    }
+
+    /// Ensures the node exists in the upper layer, copying it and its
+    /// ancestors up if needed.
+    fn copy_up(self: &Arc<Self>) -> VfsResult<Location> {
+        let mut upper = self.upper.lock();
+        if let Some(upper) = upper.as_ref() {
+            return Ok(upper.clone());
+        }
+        let (parent, name) = self.origin.lock().clone().ok_or(VfsError::BadState)?;
+        let parent_upper = parent.downcast::<OverlayDir>()?.inode.copy_up()?;
+
+        let lower = &self.lowers[0].1;
+        let metadata = lower.metadata()?;
+        let loc = parent_upper.create(&name, self.node_type, metadata.mode)?;
+        // A partial copy would shadow the lower node for good.
+        if let Err(err) = Self::copy_contents(lower, &loc, &metadata) {
+            parent_upper.unlink(&name, self.node_type == NodeType::Directory)?;
+            return Err(err);
+        }
+
+        self.fs.inodes.lock().insert(
+            (Arc::as_ptr(loc.mountpoint()) as usize, loc.inode()),
+            Arc::downgrade(self),
+        );
+        *upper = Some(loc.clone());
+        Ok(loc)
+    }
+
+    /// Copies the data and the metadata of the lower node `lower` to the new
+    /// upper node `loc`.
+    fn copy_contents(lower: &Location, loc: &Location, metadata: &Metadata) -> VfsResult<()> {
+        match metadata.node_type {
+            NodeType::RegularFile => {
+                let src = lower.entry().as_file()?;
+                let dst = loc.entry().as_file()?;
+                let mut buf = vec![0; COPY_CHUNK_SIZE];
+                let mut offset = 0;
+                loop {
+                    let read = src.read_at(&mut buf, offset)?;
+                    if read == 0 {
+                        break;
+                    }
+                    dst.write_at(&buf[..read], offset)?;
+                    offset += read as u64;
+                }
+            }
+            NodeType::Symlink => loc.entry().as_file()?.set_symlink(&lower.read_link()?)?,
+            _ => {}
+        }
+        // Filesystems without extended attributes have none to copy.
+        let names = match lower.list_xattr() {
+            Err(VfsError::Unsupported) => Vec::new(),
+            names => names?,
+        };
+        for name in names {
+            loc.entry()
+                .set_xattr(&name, &lower.get_xattr(&name)?, XattrFlags::empty())?;
+        }
+
+        let current = loc.metadata()?;
+        loc.update_metadata(MetadataUpdate {
+            mode: Some(metadata.mode),
+            owner: (current.uid != metadata.uid || current.gid != metadata.gid)
+                .then_some((metadata.uid, metadata.gid)),
+            rdev: matches!(
+                metadata.node_type,
+                NodeType::CharacterDevice | NodeType::BlockDevice
+            )
+            .then_some(metadata.rdev),
+            atime: Some(metadata.atime),
+            mtime: Some(metadata.mtime),
+        })?;
+        Ok(())
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut metadata = self.real().metadata()?;
+        metadata.inode = self.ino;
+        Ok(metadata)
+    }
+
+    fn update_metadata(self: &Arc<Self>, update: MetadataUpdate) -> VfsResult<()> {
+        self.copy_up()?.update_metadata(update)
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        match self.upper() {
+            Some(upper) => upper.sync(data_only),
+            None => Ok(()),
+        }
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.real().entry().get_xattr(name)
+    }
+
+    fn set_xattr(self: &Arc<Self>, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.copy_up()?.entry().set_xattr(name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.real().entry().list_xattr()
+    }
+
+    fn remove_xattr(self: &Arc<Self>, name: &str) -> VfsResult<()> {
+        self.copy_up()?.entry().remove_xattr(name)
+    }
+}
+
+/// Directory of an overlay filesystem.
+pub struct OverlayDir {
+    inode: Arc<OverlayInode>,
+    this: WeakDirEntry,
+    /// Merged entries of the last `read_dir` pass, indexed by offset.
+    entries: Mutex<Vec<(String, u64, NodeType)>>,
+}
+
+impl OverlayDir {
+    fn new(inode: Arc<OverlayInode>, this: WeakDirEntry) -> Arc<Self> {
+        Arc::new(Self {
+            inode,
+            this,
+            entries: Mutex::default(),
+        })
+    }
+
+    fn this(&self) -> VfsResult<DirEntry> {
+        self.this.upgrade().ok_or(VfsError::BadState)
+    }
+
+    /// Returns whether any lower layer contains a visible entry named `name`.
+    fn lower_exists(&self, name: &str) -> VfsResult<bool> {
+        for (_, lower) in &self.inode.lowers {
+            if lookup_opt(lower, name)?.is_some() {
+                return Ok(true);
+            }
+            if has_whiteout(lower, name)? {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the real directories of this node, from top to bottom.
+    fn layers(&self) -> impl Iterator<Item = (usize, Location)> + '_ {
+        self.inode
+            .upper()
+            .map(|it| (UPPER_LAYER, it))
+            .into_iter()
+            .chain(self.inode.lowers.iter().cloned())
+    }
+
+    /// Looks up the real nodes of `name`, from top to bottom.
+    fn lookup_layers(&self, name: &str) -> VfsResult<Vec<(usize, Location)>> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::NotFound);
+        }
+        let mut layers: Vec<(usize, Location)> = vec![];
+        for (layer, dir) in self.layers() {
+            let Some(loc) = lookup_opt(&dir, name)? else {
+                if has_whiteout(&dir, name)? {
+                    break;
+                }
+                continue;
+            };
+            if !layers.is_empty() && !loc.is_dir() {
+                break;
+            }
+            let opaque = !loc.is_dir() || is_opaque(&loc);
+            layers.push((layer, loc));
+            if opaque {
+                break;
+            }
+        }
+        if layers.is_empty() {
+            return Err(VfsError::NotFound);
+        }
+        Ok(layers)
+    }
+
+    fn new_entry(&self, inode: Arc<OverlayInode>, name: &str) -> VfsResult<DirEntry> {
+        let reference = Reference::new(Some(self.this()?), name.to_owned());
+        Ok(if inode.node_type == NodeType::Directory {
+            DirEntry::new_dir(|this| DirNode::new(OverlayDir::new(inode, this)), reference)
+        } else {
+            let node_type = inode.node_type;
+            DirEntry::new_file(
+                FileNode::new(Arc::new(OverlayFile { inode })),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    fn inode_of(entry: &DirEntry) -> VfsResult<Arc<OverlayInode>> {
+        if entry.is_dir() {
+            entry.downcast::<OverlayDir>().map(|it| it.inode.clone())
+        } else {
+            entry.downcast::<OverlayFile>().map(|it| it.inode.clone())
+        }
+    }
+
+    /// Prepares the upper directory for a new entry named `name`, removing
+    /// the whiteout if there is one.
+    fn prepare_upper(&self, name: &str) -> VfsResult<Location> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::InvalidInput);
+        }
+        match self.lookup_layers(name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(err) if err.canonicalize() == VfsError::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let upper = self.inode.copy_up()?;
+        remove_whiteout(&upper, name)?;
+        Ok(upper)
+    }
+
+    fn merge_entries(&self) -> VfsResult<Vec<(String, u64, NodeType)>> {
+        let this = self.this()?;
+        let parent_ino = this.parent().map_or(self.inode.ino, |it| it.inode());
+        let mut result = vec![
+            (DOT.to_owned(), self.inode.ino, NodeType::Directory),
+            (DOTDOT.to_owned(), parent_ino, NodeType::Directory),
+        ];
+        let mut seen = HashSet::new();
+        for (layer, dir) in self.layers() {
+            for (name, ino, node_type) in read_all(&dir)? {
+                if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    if name != OPAQUE_MARKER {
+                        seen.insert(hidden.to_owned());
+                    }
+                    continue;
+                }
+                if name == DOT || name == DOTDOT || !seen.insert(name.clone()) {
+                    continue;
+                }
+                result.push((name, overlay_ino(layer, ino), node_type));
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl NodeOps for OverlayDir {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        self.inode.sync(data_only)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.inode.set_xattr(name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.inode.remove_xattr(name)
+    }
+}
+
+impl DirNodeOps for OverlayDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let mut entries = self.entries.lock();
+        if offset == 0 || entries.is_empty() {
+            *entries = self.merge_entries()?;
+        }
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in entries.iter().enumerate().skip(offset as usize) {
+            if !sink.accept(name, *ino, *node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let layers = self.lookup_layers(name)?;
+        let inode = self.inode.fs.inode(layers, &self.this()?, name);
+        self.new_entry(inode, name)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let upper = self.prepare_upper(name)?;
+        let loc = upper.create(name, node_type, permission)?;
+        if node_type == NodeType::Directory && self.lower_exists(name)? {
+            loc.create(
+                OPAQUE_MARKER,
+                NodeType::RegularFile,
+                NodePermission::empty(),
+            )?;
+        }
+        let inode = self
+            .inode
+            .fs
+            .inode(vec![(UPPER_LAYER, loc)], &self.this()?, name);
+        self.new_entry(inode, name)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        if node.is_dir() {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        let inode = node.downcast::<OverlayFile>()?.inode.clone();
+        if !Arc::ptr_eq(&inode.fs, &self.inode.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        let src = inode.copy_up()?;
+        let upper = self.prepare_upper(name)?;
+        upper.link(name, &src)?;
+        self.new_entry(inode, name)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let entry = self.lookup(name)?;
+        let inode = Self::inode_of(&entry)?;
+        if let Ok(dir) = entry.as_dir()
+            && dir.has_children()?
+        {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        let lower_exists = self.lower_exists(name)?;
+        if lower_exists {
+            check_whiteout(name)?;
+        }
+        if let Some(upper) = inode.upper() {
+            if upper.is_dir() {
+                clear_whiteouts(&upper)?;
+            }
+            self.inode.copy_up()?.unlink(name, upper.is_dir())?;
+            self.inode.fs.forget(&upper);
+        }
+        if lower_exists {
+            create_whiteout(&self.inode.copy_up()?, name)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        if dst_name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::InvalidInput);
+        }
+        let dst = dst_dir.downcast::<OverlayDir>()?;
+        let src_inode = Self::inode_of(&self.lookup(src_name)?)?;
+        if let Ok(layers) = dst.lookup_layers(dst_name)
+            && Arc::ptr_eq(
+                &src_inode,
+                &dst.inode.fs.inode(layers, &dst.this()?, dst_name),
+            )
+        {
+            return Ok(());
+        }
+        let is_dir = src_inode.node_type == NodeType::Directory;
+        if is_dir && !src_inode.lowers.is_empty() {
+            return Err(VfsError::CrossesDevices);
+        }
+        let src_lower_exists = self.lower_exists(src_name)?;
+        if src_lower_exists {
+            check_whiteout(src_name)?;
+        }
+
+        src_inode.copy_up()?;
+        let src_upper = self.inode.copy_up()?;
+        let dst_upper = dst.inode.copy_up()?;
+        remove_whiteout(&dst_upper, dst_name)?;
+        let replaced = lookup_opt(&dst_upper, dst_name)?;
+        if let Some(loc) = &replaced
+            && loc.is_dir()
+        {
+            clear_whiteouts(loc)?;
+        }
+        src_upper.rename(src_name, &dst_upper, dst_name)?;
+        if let Some(loc) = &replaced {
+            self.inode.fs.forget(loc);
+        }
+
+        if is_dir && dst.lower_exists(dst_name)? {
+            let loc = dst_upper.lookup_no_follow(dst_name)?;
+            if !is_opaque(&loc) {
+                loc.create(
+                    OPAQUE_MARKER,
+                    NodeType::RegularFile,
+                    NodePermission::empty(),
+                )?;
+            }
+        }
+        if src_lower_exists {
+            create_whiteout(&src_upper, src_name)?;
+        }
+        *src_inode.origin.lock() = Some((dst.this()?, dst_name.to_owned()));
+        Ok(())
+    }
+}
+
+/// Non-directory node of an overlay filesystem.
+pub struct OverlayFile {
+    inode: Arc<OverlayInode>,
+}
+
+impl OverlayFile {
+    /// Returns the real node to write to, copying up if needed.
+    fn writable(&self) -> VfsResult<Location> {
+        if self.inode.has_data() {
+            self.inode.copy_up()
+        } else {
+            Ok(self.inode.real())
+        }
+    }
+}
+
+impl NodeOps for OverlayFile {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        self.inode.real().len()
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        self.inode.sync(data_only)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        self.inode.real().flags()
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.inode.set_xattr(name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.inode.remove_xattr(name)
+    }
+}
+
+impl FileNodeOps for OverlayFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.inode.real().entry().as_file()?.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.writable()?.entry().as_file()?.write_at(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        self.writable()?.entry().as_file()?.append(buf)
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.writable()?.entry().as_file()?.set_len(len)
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        self.writable()?.entry().as_file()?.set_symlink(target)
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize> {
+        self.inode.real().ioctl(cmd, arg)
+    }
+}
+
+impl Pollable for OverlayFile {
+    fn poll(&self) -> IoEvents {
+        self.inode.real().poll()
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        self.inode.real().register(context, events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeMap;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::{DeviceId, Filesystem, Mountpoint};
+
+    fn no_data() -> VfsError {
+        axerrno::LinuxError::ENODATA.into()
+    }
+
+    /// Minimal in-memory filesystem backing the layers of the tests.
+    struct MemFs {
+        next_ino: AtomicU64,
+        this: Weak<Self>,
+        root: RootDir,
+    }
+
+    impl MemFs {
+        fn mount() -> Location {
+            let fs = Arc::new_cyclic(|this| Self {
+                next_ino: AtomicU64::new(1),
+                this: this.clone(),
+                root: RootDir::default(),
+            });
+            Mountpoint::new_root(&Filesystem::new(fs)).root_location()
+        }
+
+        fn new_root(&self) -> DirEntry {
+            let node = MemNode::new(
+                &self.this.upgrade().unwrap(),
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o755),
+            );
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(MemDir { node, this })),
+                Reference::root(),
+            )
+        }
+    }
+
+    impl FilesystemOps for MemFs {
+        fn name(&self) -> &str {
+            "memfs"
+        }
+
+        fn root_dir(&self) -> DirEntry {
+            self.root.get_or_init(|| self.new_root())
+        }
+
+        fn stat(&self) -> VfsResult<StatFs> {
+            Err(VfsError::Unsupported)
+        }
+    }
+
+    struct MemNode {
+        fs: Arc<MemFs>,
+        metadata: Mutex<Metadata>,
+        /// File content or symlink target.
+        data: Mutex<Vec<u8>>,
+        children: Mutex<BTreeMap<String, Arc<MemNode>>>,
+        xattrs: Mutex<BTreeMap<String, Vec<u8>>>,
+    }
+
+    impl MemNode {
+        fn new(fs: &Arc<MemFs>, node_type: NodeType, mode: NodePermission) -> Arc<Self> {
+            Arc::new(Self {
+                fs: fs.clone(),
+                metadata: Mutex::new(Metadata {
+                    device: 0,
+                    inode: fs.next_ino.fetch_add(1, Ordering::Relaxed),
+                    nlink: 1,
+                    mode,
+                    node_type,
+                    uid: 0,
+                    gid: 0,
+                    size: 0,
+                    block_size: 512,
+                    blocks: 0,
+                    rdev: DeviceId::default(),
+                    atime: Default::default(),
+                    mtime: Default::default(),
+                    ctime: Default::default(),
+                }),
+                data: Mutex::default(),
+                children: Mutex::default(),
+                xattrs: Mutex::default(),
+            })
+        }
+
+        fn node_type(&self) -> NodeType {
+            self.metadata.lock().node_type
+        }
+    }
+
+    impl NodeOps for MemNode {
+        fn inode(&self) -> u64 {
+            self.metadata.lock().inode
+        }
+
+        fn metadata(&self) -> VfsResult<Metadata> {
+            let mut metadata = self.metadata.lock().clone();
+            metadata.size = self.data.lock().len() as u64;
+            Ok(metadata)
+        }
+
+        fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+            let mut metadata = self.metadata.lock();
+            if let Some(mode) = update.mode {
+                metadata.mode = mode;
+            }
+            if let Some((uid, gid)) = update.owner {
+                metadata.uid = uid;
+                metadata.gid = gid;
+            }
+            if let Some(rdev) = update.rdev {
+                metadata.rdev = rdev;
+            }
+            if let Some(atime) = update.atime {
+                metadata.atime = atime;
+            }
+            if let Some(mtime) = update.mtime {
+                metadata.mtime = mtime;
+            }
+            Ok(())
+        }
+
+        fn filesystem(&self) -> &dyn FilesystemOps {
+            &*self.fs
+        }
+
+        fn len(&self) -> VfsResult<u64> {
+            Ok(self.data.lock().len() as u64)
+        }
+
+        fn sync(&self, _data_only: bool) -> VfsResult<()> {
+            Ok(())
+        }
+
+        fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+            self
+        }
+
+        fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+            self.xattrs.lock().get(name).cloned().ok_or_else(no_data)
+        }
+
+        fn set_xattr(&self, name: &str, value: &[u8], _flags: XattrFlags) -> VfsResult<()> {
+            self.xattrs.lock().insert(name.into(), value.into());
+            Ok(())
+        }
+
+        fn list_xattr(&self) -> VfsResult<Vec<String>> {
+            Ok(self.xattrs.lock().keys().cloned().collect())
+        }
+
+        fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+            self.xattrs
+                .lock()
+                .remove(name)
+                .map(drop)
+                .ok_or_else(no_data)
+        }
+    }
+
+    impl FileNodeOps for MemNode {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+            let data = self.data.lock();
+            let start = (offset as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+            let mut data = self.data.lock();
+            let end = offset as usize + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset as usize..end].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+            let mut data = self.data.lock();
+            data.extend_from_slice(buf);
+            Ok((buf.len(), data.len() as u64))
+        }
+
+        fn set_len(&self, len: u64) -> VfsResult<()> {
+            self.data.lock().resize(len as usize, 0);
+            Ok(())
+        }
+
+        fn set_symlink(&self, target: &str) -> VfsResult<()> {
+            if self.node_type() != NodeType::Symlink {
+                return Err(VfsError::InvalidInput);
+            }
+            *self.data.lock() = target.as_bytes().to_vec();
+            Ok(())
+        }
+    }
+
+    impl Pollable for MemNode {
+        fn poll(&self) -> IoEvents {
+            IoEvents::IN | IoEvents::OUT
+        }
+
+        fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+    }
+
+    struct MemDir {
+        node: Arc<MemNode>,
+        this: WeakDirEntry,
+    }
+
+    impl MemDir {
+        fn new_entry(&self, name: &str, node: Arc<MemNode>) -> DirEntry {
+            let reference = Reference::new(self.this.upgrade(), name.to_owned());
+            let node_type = node.node_type();
+            if node_type == NodeType::Directory {
+                DirEntry::new_dir(
+                    |this| DirNode::new(Arc::new(MemDir { node, this })),
+                    reference,
+                )
+            } else {
+                DirEntry::new_file(FileNode::new(node), node_type, reference)
+            }
+        }
+
+        fn node_of(entry: &DirEntry) -> VfsResult<Arc<MemNode>> {
+            if entry.is_dir() {
+                entry.downcast::<MemDir>().map(|it| it.node.clone())
+            } else {
+                entry.downcast::<MemNode>()
+            }
+        }
+    }
+
+    impl NodeOps for MemDir {
+        fn inode(&self) -> u64 {
+            self.node.inode()
+        }
+
+        fn metadata(&self) -> VfsResult<Metadata> {
+            self.node.metadata()
+        }
+
+        fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+            self.node.update_metadata(update)
+        }
+
+        fn filesystem(&self) -> &dyn FilesystemOps {
+            self.node.filesystem()
+        }
+
+        fn sync(&self, data_only: bool) -> VfsResult<()> {
+            self.node.sync(data_only)
+        }
+
+        fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+            self
+        }
+
+        fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+            self.node.get_xattr(name)
+        }
+
+        fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+            self.node.set_xattr(name, value, flags)
+        }
+
+        fn list_xattr(&self) -> VfsResult<Vec<String>> {
+            self.node.list_xattr()
+        }
+
+        fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+            self.node.remove_xattr(name)
+        }
+    }
+
+    impl DirNodeOps for MemDir {
+        fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+            let children = self.node.children.lock();
+            let entries = [
+                (DOT, self.node.inode(), NodeType::Directory),
+                (DOTDOT, self.node.inode(), NodeType::Directory),
+            ]
+            .into_iter()
+            .chain(
+                children
+                    .iter()
+                    .map(|(name, node)| (name.as_str(), node.inode(), node.node_type())),
+            );
+            let mut count = 0;
+            for (i, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+                if !sink.accept(name, ino, node_type, i as u64 + 1) {
+                    break;
+                }
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+            let node = self
+                .node
+                .children
+                .lock()
+                .get(name)
+                .cloned()
+                .ok_or(VfsError::NotFound)?;
+            Ok(self.new_entry(name, node))
+        }
+
+        fn create(
+            &self,
+            name: &str,
+            node_type: NodeType,
+            permission: NodePermission,
+        ) -> VfsResult<DirEntry> {
+            let mut children = self.node.children.lock();
+            if children.contains_key(name) {
+                return Err(VfsError::AlreadyExists);
+            }
+            let node = MemNode::new(&self.node.fs, node_type, permission);
+            children.insert(name.to_owned(), node.clone());
+            Ok(self.new_entry(name, node))
+        }
+
+        fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+            let node = Self::node_of(node)?;
+            let mut children = self.node.children.lock();
+            if children.contains_key(name) {
+                return Err(VfsError::AlreadyExists);
+            }
+            node.metadata.lock().nlink += 1;
+            children.insert(name.to_owned(), node.clone());
+            Ok(self.new_entry(name, node))
+        }
+
+        fn unlink(&self, name: &str) -> VfsResult<()> {
+            let mut children = self.node.children.lock();
+            let node = children.get(name).ok_or(VfsError::NotFound)?;
+            if !node.children.lock().is_empty() {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+            node.metadata.lock().nlink -= 1;
+            children.remove(name);
+            Ok(())
+        }
+
+        fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+            let dst_dir = dst_dir.downcast::<MemDir>()?;
+            let node = self
+                .node
+                .children
+                .lock()
+                .remove(src_name)
+                .ok_or(VfsError::NotFound)?;
+            dst_dir
+                .node
+                .children
+                .lock()
+                .insert(dst_name.to_owned(), node);
+            Ok(())
+        }
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    fn list(dir: &Location) -> Vec<String> {
+        read_all(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(name, ..)| name)
+            .collect()
+    }
+
+    /// Looks up a `/`-separated relative path.
+    fn lookup(dir: &Location, path: &str) -> VfsResult<Location> {
+        path.split('/')
+            .try_fold(dir.clone(), |dir, name| dir.lookup_no_follow(name))
+    }
+
+    fn exists(dir: &Location, path: &str) -> bool {
+        lookup(dir, path).is_ok()
+    }
+
+    fn read(loc: &Location) -> String {
+        let mut buf = vec![0; loc.len().unwrap() as usize];
+        loc.entry().as_file().unwrap().read_at(&mut buf, 0).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn create(dir: &Location, path: &str, node_type: NodeType) -> Location {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (lookup(dir, parent).unwrap(), name),
+            None => (dir.clone(), path),
+        };
+        parent
+            .create(name, node_type, NodePermission::from_bits_truncate(0o755))
+            .unwrap()
+    }
+
+    fn write(dir: &Location, path: &str, content: &str) {
+        let loc = create(dir, path, NodeType::RegularFile);
+        loc.entry()
+            .as_file()
+            .unwrap()
+            .write_at(content.as_bytes(), 0)
+            .unwrap();
+    }
+
+    fn setup() -> (Location, Location, Location) {
+        let upper = MemFs::mount();
+        let lower = MemFs::mount();
+        write(&upper, "b", "upper b");
+        write(&lower, "a", "lower a");
+        create(&lower, "d", NodeType::Directory);
+        write(&lower, "d/x", "");
+        write(&lower, "d/y", "");
+        create(&lower, "e", NodeType::Directory);
+        write(&lower, "e/z", "");
+
+        let fs = OverlayFs::new(upper.clone(), vec![lower.clone()]).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs)).root_location();
+        (upper, lower, root)
+    }
+
+    #[test]
+    fn test_merge_and_copy_up() {
+        let (upper, lower, root) = setup();
+        assert_eq!(sorted(list(&root)), [".", "..", "a", "b", "d", "e"]);
+
+        let a = root.lookup_no_follow("a").unwrap();
+        assert_eq!(read(&a), "lower a");
+        a.entry().as_file().unwrap().write_at(b"UPPER", 0).unwrap();
+        assert_eq!(read(&a), "UPPER a");
+        assert_eq!(read(&lookup(&upper, "a").unwrap()), "UPPER a");
+        assert_eq!(read(&lookup(&lower, "a").unwrap()), "lower a");
+
+        let d = root.lookup_no_follow("d").unwrap();
+        d.lookup_no_follow("x")
+            .unwrap()
+            .update_metadata(MetadataUpdate {
+                mode: Some(NodePermission::from_bits_truncate(0o600)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(exists(&upper, "d/x"));
+        assert_eq!(sorted(list(&d)), [".", "..", "x", "y"]);
+    }
+
+    #[test]
+    fn test_copy_up_device() {
+        let (upper, lower, root) = setup();
+        let rdev = DeviceId::new(1, 3);
+        create(&lower, "null", NodeType::CharacterDevice)
+            .update_metadata(MetadataUpdate {
+                rdev: Some(rdev),
+                ..Default::default()
+            })
+            .unwrap();
+
+        root.lookup_no_follow("null")
+            .unwrap()
+            .update_metadata(MetadataUpdate {
+                mode: Some(NodePermission::from_bits_truncate(0o666)),
+                ..Default::default()
+            })
+            .unwrap();
+        let metadata = lookup(&upper, "null").unwrap().metadata().unwrap();
+        assert_eq!(metadata.node_type, NodeType::CharacterDevice);
+        assert_eq!(metadata.rdev, rdev);
+        assert_eq!(metadata.mode.bits(), 0o666);
+        let metadata = root.lookup_no_follow("null").unwrap().metadata().unwrap();
+        assert_eq!(metadata.mode.bits(), 0o666);
+        let metadata = lookup(&lower, "null").unwrap().metadata().unwrap();
+        assert_eq!(metadata.mode.bits(), 0o755);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let (upper, lower, root) = setup();
+        let flags = XattrFlags::empty();
+        let lower_a = lookup(&lower, "a").unwrap();
+        lower_a.set_xattr("user.lower", b"1", flags).unwrap();
+
+        let a = root.lookup_no_follow("a").unwrap();
+        assert_eq!(a.list_xattr().unwrap(), ["user.lower"]);
+        assert_eq!(a.get_xattr("user.lower").unwrap(), b"1");
+        assert!(!exists(&upper, "a"));
+
+        a.set_xattr("user.upper", b"2", flags).unwrap();
+        let upper_a = lookup(&upper, "a").unwrap();
+        assert_eq!(upper_a.list_xattr().unwrap(), ["user.lower", "user.upper"]);
+        assert_eq!(lower_a.list_xattr().unwrap(), ["user.lower"]);
+        a.remove_xattr("user.lower").unwrap();
+        assert_eq!(a.list_xattr().unwrap(), ["user.upper"]);
+        assert_eq!(a.get_xattr("user.lower").unwrap_err(), no_data());
+        assert_eq!(lower_a.get_xattr("user.lower").unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_failed_copy_up() {
+        let (upper, lower, root) = setup();
+        // Targets that are not UTF-8 cannot be read back for the copy.
+        let link = create(&lower, "link", NodeType::Symlink);
+        link.entry()
+            .as_file()
+            .unwrap()
+            .write_at(b"\xff", 0)
+            .unwrap();
+
+        let link = root.lookup_no_follow("link").unwrap();
+        let chmod = MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o700)),
+            ..Default::default()
+        };
+        assert_eq!(
+            link.update_metadata(chmod).unwrap_err(),
+            VfsError::InvalidData
+        );
+        assert!(!exists(&upper, "link"));
+        assert_eq!(link.metadata().unwrap().size, 1);
+    }
+
+    #[test]
+    fn test_whiteout_and_opaque() {
+        let (upper, lower, root) = setup();
+        let d = root.lookup_no_follow("d").unwrap();
+        d.unlink("x", false).unwrap();
+        assert_eq!(list(&d), [".", "..", "y"]);
+        assert!(exists(&upper, "d/.wh.x"));
+        assert!(exists(&lower, "d/x"));
+
+        assert_eq!(
+            root.unlink("e", true).unwrap_err(),
+            VfsError::DirectoryNotEmpty
+        );
+        let e = root.lookup_no_follow("e").unwrap();
+        e.unlink("z", false).unwrap();
+        root.unlink("e", true).unwrap();
+        assert_eq!(sorted(list(&root)), [".", "..", "a", "b", "d"]);
+
+        let e = root
+            .create(
+                "e",
+                NodeType::Directory,
+                NodePermission::from_bits_truncate(0o755),
+            )
+            .unwrap();
+        assert!(exists(&upper, &format!("e/{OPAQUE_MARKER}")));
+        assert!(!exists(&upper, ".wh.e"));
+        assert_eq!(list(&e), [".", ".."]);
+    }
+
+    #[test]
+    fn test_long_names() {
+        let (upper, lower, root) = setup();
+        let name = "n".repeat(MAX_NAME_LEN);
+        write(&lower, &name, "");
+        write(&upper, &name, "");
+        assert_eq!(
+            root.unlink(&name, false).unwrap_err(),
+            VfsError::NameTooLong
+        );
+        assert_eq!(
+            root.rename(&name, &root, "short").unwrap_err(),
+            VfsError::NameTooLong
+        );
+        assert!(exists(&upper, &name));
+        assert!(root.lookup_no_follow(&name).is_ok());
+    }
+
+    #[test]
+    fn test_rename() {
+        let (upper, _lower, root) = setup();
+        assert_eq!(
+            root.rename("d", &root, "d2").unwrap_err(),
+            VfsError::CrossesDevices
+        );
+
+        assert_eq!(
+            root.rename("a", &root, ".wh.b").unwrap_err(),
+            VfsError::InvalidInput
+        );
+        assert!(!exists(&upper, ".wh.b"));
+
+        root.rename("a", &root, "a2").unwrap();
+        assert_eq!(sorted(list(&root)), [".", "..", "a2", "b", "d", "e"]);
+        assert_eq!(read(&root.lookup_no_follow("a2").unwrap()), "lower a");
+        assert!(exists(&upper, ".wh.a"));
+
+        let d = root.lookup_no_follow("d").unwrap();
+        root.rename("b", &d, "x").unwrap();
+        assert_eq!(read(&d.lookup_no_follow("x").unwrap()), "upper b");
+        assert_eq!(sorted(list(&d)), [".", "..", "x", "y"]);
+    }
+}
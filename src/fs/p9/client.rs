@@ -0,0 +1,777 @@
+use alloc::{
+    borrow::ToOwned,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{any::Any, hint, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+use hashbrown::{HashMap, HashSet};
+
+use super::{Transport, from_errno, wire::*};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps, Metadata,
+    MetadataUpdate, Mutex, NodeOps, NodePermission, NodeType, Reference, StatFs, VfsError,
+    VfsResult, WeakDirEntry, fs::RootDir,
+};
+
+/// Fid of the root directory, attached first.
+const ROOT_FID: u32 = 0;
+
+/// Options for mounting a [`P9Fs`].
+#[derive(Debug, Clone)]
+pub struct P9Options {
+    /// Name of the user attaching.
+    pub uname: String,
+    /// Name of the tree to attach, ignored by servers exporting a single
+    /// one.
+    pub aname: String,
+    /// ID of the user attaching.
+    pub uid: u32,
+    /// ID of the group owning the nodes created.
+    pub gid: u32,
+    /// Largest size of messages to negotiate.
+    pub msize: u32,
+    /// Whether directory entries are cached. Trees also changed by others
+    /// than this client should be mounted without, so that every lookup
+    /// reaches the server.
+    pub cache: bool,
+}
+
+impl Default for P9Options {
+    fn default() -> Self {
+        Self {
+            uname: String::from("root"),
+            aname: String::new(),
+            uid: 0,
+            gid: 0,
+            msize: 65536,
+            cache: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tags {
+    next: u16,
+    /// Tags of the requests waiting for a reply.
+    pending: HashSet<u16>,
+    /// Replies read by another caller than the one waiting for them.
+    replies: HashMap<u16, Vec<u8>>,
+}
+
+/// Multiplexer of concurrent requests over a transport.
+///
+/// Each request is sent under its own tag. Whichever caller waiting for a
+/// reply gets hold of the receiving end reads the next message, and hands
+/// it over to the caller it belongs to.
+struct Client {
+    transport: Arc<dyn Transport>,
+    msize: u32,
+    send: Mutex<()>,
+    recv: Mutex<()>,
+    tags: Mutex<Tags>,
+    /// Next unused fid and the fids clunked.
+    fids: Mutex<(u32, Vec<u32>)>,
+}
+
+impl Client {
+    /// Negotiates the protocol version and the size of messages.
+    fn connect(transport: Arc<dyn Transport>, msize: u32) -> VfsResult<Self> {
+        let mut message = Writer::new(TVERSION, NOTAG);
+        message.u32(msize).str(VERSION);
+        transport.send(&message.finish())?;
+        let reply = read_message(&*transport, msize)?.ok_or(VfsError::ConnectionReset)?;
+        let mut reader = Reader::new(&reply[HEADER_SIZE..]);
+        if reply[4] != TVERSION + 1 {
+            return Err(VfsError::InvalidData);
+        }
+        let msize = reader.u32()?.min(msize);
+        if reader.str()? != VERSION || msize <= IO_HEADER_SIZE {
+            return Err(VfsError::Unsupported);
+        }
+        Ok(Self {
+            transport,
+            msize,
+            send: Mutex::new(()),
+            recv: Mutex::new(()),
+            tags: Mutex::default(),
+            fids: Mutex::new((ROOT_FID + 1, Vec::new())),
+        })
+    }
+
+    /// Returns the largest number of bytes read or written by a request.
+    fn io_size(&self) -> u32 {
+        self.msize - IO_HEADER_SIZE
+    }
+
+    fn alloc_tag(&self) -> VfsResult<u16> {
+        let mut tags = self.tags.lock();
+        if tags.pending.len() >= NOTAG as usize {
+            return Err(VfsError::ResourceBusy);
+        }
+        loop {
+            let tag = tags.next;
+            tags.next = tags.next.wrapping_add(1);
+            if tag != NOTAG && tags.pending.insert(tag) {
+                return Ok(tag);
+            }
+        }
+    }
+
+    /// Sends a request of type `kind` with the fields written by `build`,
+    /// and returns the fields of its reply.
+    fn call(&self, kind: u8, build: impl FnOnce(&mut Writer)) -> VfsResult<Vec<u8>> {
+        let tag = self.alloc_tag()?;
+        let mut message = Writer::new(kind, tag);
+        build(&mut message);
+        let result = if message.len() > self.msize as usize {
+            Err(VfsError::InvalidInput)
+        } else {
+            let message = message.finish();
+            let sent = {
+                let _guard = self.send.lock();
+                self.transport.send(&message)
+            };
+            sent.and_then(|_| self.wait(tag))
+        };
+        self.tags.lock().pending.remove(&tag);
+        let mut reply = result?;
+        let reply_kind = reply[4];
+        reply.drain(..HEADER_SIZE);
+        if reply_kind == kind + 1 {
+            Ok(reply)
+        } else if reply_kind == TLERROR + 1 {
+            Err(from_errno(Reader::new(&reply).u32()?))
+        } else {
+            Err(VfsError::InvalidData)
+        }
+    }
+
+    fn wait(&self, tag: u16) -> VfsResult<Vec<u8>> {
+        loop {
+            if let Some(reply) = self.tags.lock().replies.remove(&tag) {
+                return Ok(reply);
+            }
+            let Some(_guard) = self.recv.try_lock() else {
+                hint::spin_loop();
+                continue;
+            };
+            // The reply may have been read while taking the lock.
+            if let Some(reply) = self.tags.lock().replies.remove(&tag) {
+                return Ok(reply);
+            }
+            let reply =
+                read_message(&*self.transport, self.msize)?.ok_or(VfsError::ConnectionReset)?;
+            let reply_tag = u16::from_le_bytes([reply[5], reply[6]]);
+            if reply_tag == tag {
+                return Ok(reply);
+            }
+            let mut tags = self.tags.lock();
+            if tags.pending.contains(&reply_tag) {
+                tags.replies.insert(reply_tag, reply);
+            }
+        }
+    }
+
+    fn alloc_fid(&self) -> u32 {
+        let mut fids = self.fids.lock();
+        fids.1.pop().unwrap_or_else(|| {
+            fids.0 += 1;
+            fids.0 - 1
+        })
+    }
+
+    fn attach(&self, options: &P9Options) -> VfsResult<()> {
+        self.call(TATTACH, |it| {
+            it.u32(ROOT_FID)
+                .u32(NOFID)
+                .str(&options.uname)
+                .str(&options.aname)
+                .u32(options.uid);
+        })
+        .map(drop)
+    }
+
+    /// Walks from `fid` to its child `name`, or to itself if `None`.
+    fn walk(self: &Arc<Self>, fid: u32, name: Option<&str>) -> VfsResult<Fid> {
+        let id = self.alloc_fid();
+        let result = self.call(TWALK, |it| {
+            it.u32(fid).u32(id).u16(name.is_some() as u16);
+            if let Some(name) = name {
+                it.str(name);
+            }
+        });
+        match result.and_then(|reply| Reader::new(&reply).u16()) {
+            Ok(count) if count == name.is_some() as u16 => Ok(Fid {
+                client: self.clone(),
+                id,
+            }),
+            result => {
+                self.fids.lock().1.push(id);
+                Err(result.err().unwrap_or(VfsError::NotFound))
+            }
+        }
+    }
+
+    fn getattr(&self, fid: u32) -> VfsResult<Metadata> {
+        let reply = self.call(TGETATTR, |it| {
+            it.u32(fid).u64(GETATTR_BASIC);
+        })?;
+        Reader::new(&reply).attr()
+    }
+
+    fn statfs(&self, fid: u32) -> VfsResult<StatFs> {
+        let reply = self.call(TSTATFS, |it| {
+            it.u32(fid);
+        })?;
+        let mut reader = Reader::new(&reply);
+        let fs_type = reader.u32()?;
+        let block_size = reader.u32()?;
+        let blocks = reader.u64()?;
+        let blocks_free = reader.u64()?;
+        let blocks_available = reader.u64()?;
+        let file_count = reader.u64()?;
+        let free_file_count = reader.u64()?;
+        let _fsid = reader.u64()?;
+        let name_length = reader.u32()?;
+        Ok(StatFs {
+            fs_type,
+            block_size,
+            blocks,
+            blocks_free,
+            blocks_available,
+            file_count,
+            free_file_count,
+            name_length,
+            fragment_size: block_size,
+            mount_flags: 0,
+        })
+    }
+
+    fn setattr(&self, fid: u32, update: &MetadataUpdate, size: Option<u64>) -> VfsResult<()> {
+        if update.rdev.is_some() {
+            return Err(VfsError::Unsupported);
+        }
+        let mut valid = 0;
+        if update.mode.is_some() {
+            valid |= SETATTR_MODE;
+        }
+        if update.owner.is_some() {
+            valid |= SETATTR_UID | SETATTR_GID;
+        }
+        if size.is_some() {
+            valid |= SETATTR_SIZE;
+        }
+        if update.atime.is_some() {
+            valid |= SETATTR_ATIME | SETATTR_ATIME_SET;
+        }
+        if update.mtime.is_some() {
+            valid |= SETATTR_MTIME | SETATTR_MTIME_SET;
+        }
+        if valid == 0 {
+            return Ok(());
+        }
+        let (uid, gid) = update.owner.unwrap_or_default();
+        self.call(TSETATTR, |it| {
+            it.u32(fid)
+                .u32(valid)
+                .u32(update.mode.map_or(0, |it| it.bits() as u32))
+                .u32(uid)
+                .u32(gid)
+                .u64(size.unwrap_or(0))
+                .time(update.atime.unwrap_or_default())
+                .time(update.mtime.unwrap_or_default());
+        })
+        .map(drop)
+    }
+
+    fn open(&self, fid: u32, flags: u32) -> VfsResult<()> {
+        self.call(TLOPEN, |it| {
+            it.u32(fid).u32(flags);
+        })
+        .map(drop)
+    }
+
+    fn read(&self, fid: u32, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(self.io_size() as usize);
+            let reply = self.call(TREAD, |it| {
+                it.u32(fid).u64(offset + done as u64).u32(count as u32);
+            })?;
+            let data = Reader::new(&reply).data()?;
+            let len = data.len().min(count);
+            buf[done..done + len].copy_from_slice(&data[..len]);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&self, fid: u32, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let mut done = 0;
+        for chunk in buf.chunks(self.io_size() as usize) {
+            let reply = self.call(TWRITE, |it| {
+                it.u32(fid).u64(offset + done as u64).data(chunk);
+            })?;
+            let len = (Reader::new(&reply).u32()? as usize).min(chunk.len());
+            done += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+/// A fid, clunked when dropped.
+struct Fid {
+    client: Arc<Client>,
+    id: u32,
+}
+
+impl Drop for Fid {
+    fn drop(&mut self) {
+        let _ = self.client.call(TCLUNK, |it| {
+            it.u32(self.id);
+        });
+        self.client.fids.lock().1.push(self.id);
+    }
+}
+
+/// Filesystem mounted from a 9P2000.L server.
+///
+/// Nodes keep a fid walked to them from their parent. Metadata is fetched
+/// from the server whenever it is asked for, and directory entries are
+/// cached only if [`P9Options::cache`] is set.
+pub struct P9Fs {
+    client: Arc<Client>,
+    options: P9Options,
+    root_fid: Arc<Fid>,
+    root_ino: u64,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl P9Fs {
+    /// Attaches to the server at the other end of `transport`.
+    pub fn new(transport: Arc<dyn Transport>, options: P9Options) -> VfsResult<Arc<Self>> {
+        let client = Arc::new(Client::connect(transport, options.msize)?);
+        client.attach(&options)?;
+        let root_fid = Arc::new(Fid {
+            client: client.clone(),
+            id: ROOT_FID,
+        });
+        let metadata = client.getattr(ROOT_FID)?;
+        Ok(Arc::new_cyclic(|this| Self {
+            client,
+            options,
+            root_fid,
+            root_ino: metadata.inode,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let inode = Arc::new(P9Inode {
+            fs: self.this.upgrade().unwrap(),
+            fid: self.root_fid.clone(),
+            ino: self.root_ino,
+        });
+        DirEntry::new_dir(
+            |this| DirNode::new(Arc::new(P9Dir { inode, this })),
+            Reference::root(),
+        )
+    }
+}
+
+impl FilesystemOps for P9Fs {
+    fn name(&self) -> &str {
+        "9p"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        self.client.statfs(ROOT_FID)
+    }
+}
+
+struct P9Inode {
+    fs: Arc<P9Fs>,
+    /// Fid of the node, shared with the filesystem for the root.
+    fid: Arc<Fid>,
+    ino: u64,
+}
+
+impl P9Inode {
+    fn client(&self) -> &Arc<Client> {
+        &self.fs.client
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.client().getattr(self.fid.id)
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.client().setattr(self.fid.id, &update, None)
+    }
+
+    /// Walks to the child `name`.
+    fn walk(&self, name: &str) -> VfsResult<Fid> {
+        self.client().walk(self.fid.id, Some(name))
+    }
+
+    fn unlink_at(&self, name: &str, flags: u32) -> VfsResult<()> {
+        self.client()
+            .call(TUNLINKAT, |it| {
+                it.u32(self.fid.id).str(name).u32(flags);
+            })
+            .map(drop)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> VfsResult<()> {
+        let gid = self.fs.options.gid;
+        self.client()
+            .call(TSYMLINK, |it| {
+                it.u32(self.fid.id).str(name).str(target).u32(gid);
+            })
+            .map(drop)
+    }
+
+    /// Walks to the node itself, and opens the new fid with `flags`.
+    fn open(&self, flags: u32) -> VfsResult<Fid> {
+        let fid = self.client().walk(self.fid.id, None)?;
+        self.client().open(fid.id, flags)?;
+        Ok(fid)
+    }
+}
+
+/// Directory of a [`P9Fs`].
+pub struct P9Dir {
+    inode: Arc<P9Inode>,
+    this: WeakDirEntry,
+}
+
+impl P9Dir {
+    fn new_entry(&self, name: &str, fid: Fid, open: Option<Fid>) -> VfsResult<DirEntry> {
+        let metadata = self.inode.client().getattr(fid.id)?;
+        let inode = Arc::new(P9Inode {
+            fs: self.inode.fs.clone(),
+            fid: Arc::new(fid),
+            ino: metadata.inode,
+        });
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        Ok(if metadata.node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(P9Dir { inode, this })),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Arc::new(P9File {
+                    inode: Mutex::new(inode),
+                    node_type: metadata.node_type,
+                    location: (self.inode.clone(), name.to_owned()),
+                    open: Mutex::new(open.map(|it| (Arc::new(it), true))),
+                })),
+                metadata.node_type,
+                reference,
+            )
+        })
+    }
+
+    fn call(&self, kind: u8, build: impl FnOnce(&mut Writer)) -> VfsResult<Vec<u8>> {
+        self.inode.client().call(kind, build)
+    }
+}
+
+impl NodeOps for P9Dir {
+    fn inode(&self) -> u64 {
+        self.inode.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.inode.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for P9Dir {
+    fn read_dir(&self, mut offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let fid = self.inode.open(O_RDONLY | O_DIRECTORY)?;
+        let io_size = self.inode.client().io_size();
+        let mut count = 0;
+        loop {
+            let reply = self.call(TREADDIR, |it| {
+                it.u32(fid.id).u64(offset).u32(io_size);
+            })?;
+            let mut reader = Reader::new(&reply);
+            let mut entries = Reader::new(reader.data()?);
+            if entries.is_empty() {
+                return Ok(count);
+            }
+            while !entries.is_empty() {
+                let qid = entries.qid()?;
+                let next = entries.u64()?;
+                let node_type = NodeType::from(entries.u8()?);
+                if !sink.accept(entries.str()?, qid.path, node_type, next) {
+                    return Ok(count);
+                }
+                count += 1;
+                offset = next;
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        self.new_entry(name, self.inode.walk(name)?, None)
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.inode.fs.options.cache
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let dir = self.inode.fid.id;
+        let gid = self.inode.fs.options.gid;
+        let mode = permission.bits() as u32;
+        match node_type {
+            NodeType::RegularFile => {
+                // The new fid is left open on the created file.
+                let open = self.inode.client().walk(dir, None)?;
+                self.call(TLCREATE, |it| {
+                    it.u32(open.id)
+                        .str(name)
+                        .u32(O_RDWR | O_CREAT | O_EXCL)
+                        .u32(mode)
+                        .u32(gid);
+                })?;
+                return self.new_entry(name, self.inode.walk(name)?, Some(open));
+            }
+            NodeType::Directory => self.call(TMKDIR, |it| {
+                it.u32(dir).str(name).u32(mode).u32(gid);
+            })?,
+            // The target is set later through `set_symlink`.
+            NodeType::Symlink => {
+                return self
+                    .inode
+                    .symlink(name, ".")
+                    .and_then(|_| self.lookup(name));
+            }
+            NodeType::Fifo
+            | NodeType::CharacterDevice
+            | NodeType::BlockDevice
+            | NodeType::Socket => self.call(TMKNOD, |it| {
+                it.u32(dir)
+                    .str(name)
+                    .u32((node_type as u32) << 12 | mode)
+                    .u32(0)
+                    .u32(0)
+                    .u32(gid);
+            })?,
+            NodeType::Unknown => return Err(VfsError::InvalidInput),
+        };
+        self.lookup(name)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let src = node.downcast::<P9File>()?;
+        let src = src.inode.lock().clone();
+        if !Arc::ptr_eq(&src.fs, &self.inode.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        self.call(TLINK, |it| {
+            it.u32(self.inode.fid.id).u32(src.fid.id).str(name);
+        })?;
+        self.lookup(name)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        match self.inode.unlink_at(name, 0) {
+            Err(VfsError::IsADirectory) => self.inode.unlink_at(name, AT_REMOVEDIR),
+            result => result,
+        }
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir = dst_dir.downcast::<P9Dir>()?;
+        if !Arc::ptr_eq(&dst_dir.inode.fs, &self.inode.fs) {
+            return Err(VfsError::CrossesDevices);
+        }
+        self.call(TRENAMEAT, |it| {
+            it.u32(self.inode.fid.id)
+                .str(src_name)
+                .u32(dst_dir.inode.fid.id)
+                .str(dst_name);
+        })
+        .map(drop)
+    }
+}
+
+/// Non-directory node of a [`P9Fs`].
+pub struct P9File {
+    inode: Mutex<Arc<P9Inode>>,
+    node_type: NodeType,
+    /// Directory and name the node was found at, where symlinks are
+    /// recreated by `set_symlink`.
+    location: (Arc<P9Inode>, String),
+    /// Fid opened for I/O and whether it is writable.
+    open: Mutex<Option<(Arc<Fid>, bool)>>,
+}
+
+impl P9File {
+    fn inode(&self) -> Arc<P9Inode> {
+        self.inode.lock().clone()
+    }
+
+    /// Returns the fid opened for I/O, reopening it for writing if
+    /// requested.
+    fn open(&self, write: bool) -> VfsResult<Arc<Fid>> {
+        let mut guard = self.open.lock();
+        if let Some((fid, writable)) = guard.as_ref()
+            && (*writable || !write)
+        {
+            return Ok(fid.clone());
+        }
+        let inode = self.inode();
+        let (fid, writable) = match inode.open(O_RDWR) {
+            Ok(fid) => (fid, true),
+            Err(VfsError::PermissionDenied | VfsError::ReadOnlyFilesystem) if !write => {
+                (inode.open(O_RDONLY)?, false)
+            }
+            Err(err) => return Err(err),
+        };
+        let fid = Arc::new(fid);
+        *guard = Some((fid.clone(), writable));
+        Ok(fid)
+    }
+
+    fn read_link(&self) -> VfsResult<String> {
+        let inode = self.inode();
+        let reply = inode.client().call(TREADLINK, |it| {
+            it.u32(inode.fid.id);
+        })?;
+        Reader::new(&reply).string()
+    }
+}
+
+impl NodeOps for P9File {
+    fn inode(&self) -> u64 {
+        self.inode().ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inode().metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.inode().update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.location.0.fs
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        if self.node_type != NodeType::RegularFile {
+            return Ok(());
+        }
+        let fid = self.open(false)?;
+        fid.client
+            .call(TFSYNC, |it| {
+                it.u32(fid.id).u32(data_only as u32);
+            })
+            .map(drop)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for P9File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if self.node_type == NodeType::Symlink {
+            let target = self.read_link()?;
+            let target = target.as_bytes();
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        let fid = self.open(false)?;
+        fid.client.read(fid.id, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let fid = self.open(true)?;
+        fid.client.write(fid.id, buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let fid = self.open(true)?;
+        let offset = self.inode().metadata()?.size;
+        let written = fid.client.write(fid.id, buf, offset)?;
+        Ok((written, offset + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        let inode = self.inode();
+        inode
+            .client()
+            .setattr(inode.fid.id, &MetadataUpdate::default(), Some(len))
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        if self.node_type != NodeType::Symlink {
+            return Err(VfsError::InvalidInput);
+        }
+        let (dir, name) = &self.location;
+        dir.unlink_at(name, 0)?;
+        dir.symlink(name, target)?;
+        let fid = dir.walk(name)?;
+        let ino = dir.client().getattr(fid.id)?.inode;
+        *self.inode.lock() = Arc::new(P9Inode {
+            fs: dir.fs.clone(),
+            fid: Arc::new(fid),
+            ino,
+        });
+        Ok(())
+    }
+}
+
+impl Pollable for P9File {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,300 @@
+//! The 9P2000.L protocol, for sharing directories with other systems.
+//!
+//! [`P9Fs`] mounts a directory exported by a 9P server, and [`P9Server`]
+//! exports any [`Location`](crate::Location), both over any [`Transport`]
+//! carrying a byte stream, such as a virtio or TCP socket.
+
+mod client;
+mod server;
+mod wire;
+
+use axerrno::LinuxError;
+
+pub use self::{
+    client::{P9Dir, P9File, P9Fs, P9Options},
+    server::{P9Server, P9ServerOptions},
+};
+use crate::{VfsError, VfsResult};
+
+/// A reliable, ordered byte stream carrying 9P messages.
+pub trait Transport: Send + Sync {
+    /// Writes the whole of `buf`.
+    fn send(&self, buf: &[u8]) -> VfsResult<()>;
+
+    /// Reads at most `buf.len()` bytes into `buf`, returning the number of
+    /// bytes read, or 0 at the end of the stream.
+    fn recv(&self, buf: &mut [u8]) -> VfsResult<usize>;
+}
+
+/// Translates an errno received in `Rlerror` into a [`VfsError`].
+fn from_errno(errno: u32) -> VfsError {
+    VfsError::try_from(-(errno as i32)).map_or(VfsError::Io, VfsError::canonicalize)
+}
+
+/// Translates a [`VfsError`] into the errno sent in `Rlerror`.
+fn to_errno(err: VfsError) -> u32 {
+    LinuxError::from(err).code() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{
+        borrow::ToOwned, collections::VecDeque, format, string::String, sync::Arc, vec, vec::Vec,
+    };
+    use std::{
+        sync::{Condvar, Mutex},
+        thread,
+    };
+
+    use super::{P9Fs, P9Options, P9Server, P9ServerOptions, Transport, wire::*};
+    use crate::{
+        Filesystem, Location, MetadataUpdate, Mountpoint, NodePermission, NodeType, VfsError,
+        VfsResult,
+        test_util::{list, memory_fs},
+    };
+
+    #[derive(Default)]
+    struct Queue {
+        data: Mutex<(VecDeque<u8>, bool)>,
+        ready: Condvar,
+    }
+
+    /// One end of an in-memory byte stream.
+    pub(crate) struct PipeEnd {
+        rx: Arc<Queue>,
+        tx: Arc<Queue>,
+    }
+
+    impl Drop for PipeEnd {
+        fn drop(&mut self) {
+            self.tx.data.lock().unwrap().1 = true;
+            self.tx.ready.notify_all();
+        }
+    }
+
+    impl Transport for PipeEnd {
+        fn send(&self, buf: &[u8]) -> VfsResult<()> {
+            self.tx.data.lock().unwrap().0.extend(buf);
+            self.tx.ready.notify_all();
+            Ok(())
+        }
+
+        fn recv(&self, buf: &mut [u8]) -> VfsResult<usize> {
+            let mut data = self.rx.data.lock().unwrap();
+            while data.0.is_empty() && !data.1 {
+                data = self.rx.ready.wait(data).unwrap();
+            }
+            let len = buf.len().min(data.0.len());
+            for (dst, src) in buf.iter_mut().zip(data.0.drain(..len)) {
+                *dst = src;
+            }
+            Ok(len)
+        }
+    }
+
+    /// Returns both ends of an in-memory byte stream.
+    pub(crate) fn pipe() -> (PipeEnd, PipeEnd) {
+        let (a, b) = (Arc::new(Queue::default()), Arc::new(Queue::default()));
+        (
+            PipeEnd {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            PipeEnd { rx: b, tx: a },
+        )
+    }
+
+    /// Serves `root` from another thread, and mounts it.
+    fn serve(root: Location, options: P9ServerOptions, msize: u32) -> Location {
+        let (client, server) = pipe();
+        let server_ops = P9Server::new(root, options).unwrap();
+        thread::spawn(move || server_ops.serve(&server).unwrap());
+        let options = P9Options {
+            msize,
+            ..Default::default()
+        };
+        let fs = P9Fs::new(Arc::new(client), options).unwrap();
+        Mountpoint::new_root(&Filesystem::new(fs)).root_location()
+    }
+
+    fn read_all(loc: &Location) -> Vec<u8> {
+        let file = loc.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    #[test]
+    fn test_client() {
+        let exported = memory_fs();
+        let root = serve(exported.clone(), P9ServerOptions::default(), 8192);
+        let mode = NodePermission::from_bits_truncate(0o755);
+
+        let dir = root.create("dir", NodeType::Directory, mode).unwrap();
+        let file = dir.create("file", NodeType::RegularFile, mode).unwrap();
+        // Larger than a message, so that I/O is split.
+        let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        file.entry().as_file().unwrap().write_at(&data, 0).unwrap();
+        assert_eq!(read_all(&file), data);
+        assert_eq!(file.metadata().unwrap().size, 20000);
+        let (_, end) = file.entry().as_file().unwrap().append(b"!").unwrap();
+        assert_eq!(end, 20001);
+        file.entry().as_file().unwrap().set_len(5).unwrap();
+        assert_eq!(read_all(&file), &data[..5]);
+        file.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o600)),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(file.metadata().unwrap().mode.bits(), 0o600);
+
+        let link = root.create("link", NodeType::Symlink, mode).unwrap();
+        link.entry()
+            .as_file()
+            .unwrap()
+            .set_symlink("dir/file")
+            .unwrap();
+        assert_eq!(
+            root.lookup_no_follow("link").unwrap().read_link().unwrap(),
+            "dir/file"
+        );
+        let fifo = root.create("fifo", NodeType::Fifo, mode).unwrap();
+        assert_eq!(fifo.metadata().unwrap().node_type, NodeType::Fifo);
+
+        root.link("hard", &file).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink, 2);
+        root.rename("hard", &dir, "moved").unwrap();
+        assert_eq!(
+            read_all(&dir.lookup_no_follow("moved").unwrap()),
+            &data[..5]
+        );
+        assert_eq!(
+            list(&dir)[2..],
+            [String::from("file"), String::from("moved")]
+        );
+        assert_eq!(
+            root.unlink("dir", true).unwrap_err(),
+            VfsError::DirectoryNotEmpty
+        );
+        dir.unlink("moved", false).unwrap();
+        root.unlink("fifo", false).unwrap();
+
+        // Requests from several threads are multiplexed.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let file = file.clone();
+                let expected = data[..5].to_vec();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(read_all(&file), expected);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Without caching, changes made on the server are seen.
+        exported.create("new", NodeType::Directory, mode).unwrap();
+        assert!(root.lookup_no_follow("new").unwrap().is_dir());
+        exported.unlink("new", true).unwrap();
+        assert_eq!(
+            root.lookup_no_follow("new").unwrap_err(),
+            VfsError::NotFound
+        );
+        assert_eq!(root.filesystem().stat().unwrap().block_size, 4096);
+    }
+
+    #[test]
+    fn test_server() {
+        let exported = memory_fs();
+        let mode = NodePermission::from_bits_truncate(0o755);
+        exported
+            .create("secret", NodeType::RegularFile, mode)
+            .unwrap();
+        let sub = exported.create("sub", NodeType::Directory, mode).unwrap();
+        let file = sub.create("file", NodeType::RegularFile, mode).unwrap();
+        file.entry()
+            .as_file()
+            .unwrap()
+            .write_at(b"data", 0)
+            .unwrap();
+        for i in 0..100 {
+            sub.create(&format!("{i:03}"), NodeType::Directory, mode)
+                .unwrap();
+        }
+
+        // Walks stop at the exported directory.
+        let (client, server) = pipe();
+        let server_ops = P9Server::new(sub.clone(), P9ServerOptions::default()).unwrap();
+        thread::spawn(move || server_ops.serve(&server).unwrap());
+        let call = |message: Writer| {
+            client.send(&message.finish()).unwrap();
+            let reply = read_message(&client, 65536).unwrap().unwrap();
+            (reply[4], reply[HEADER_SIZE..].to_vec())
+        };
+        let mut message = Writer::new(TVERSION, NOTAG);
+        message.u32(4096).str(VERSION);
+        assert_eq!(Reader::new(&call(message).1).u32().unwrap(), 4096);
+        let mut message = Writer::new(TATTACH, 1);
+        message.u32(0).u32(NOFID).str("root").str("").u32(0);
+        assert_eq!(call(message).0, TATTACH + 1);
+        let mut message = Writer::new(TWALK, 1);
+        message.u32(0).u32(1).u16(2).str("..").str("secret");
+        let (kind, reply) = call(message);
+        assert_eq!((kind, Reader::new(&reply).u16().unwrap()), (TWALK + 1, 1));
+        let mut message = Writer::new(TCLUNK, 1);
+        message.u32(1);
+        let (kind, reply) = call(message);
+        assert_eq!(kind, TLERROR + 1);
+        assert_eq!(
+            super::from_errno(Reader::new(&reply).u32().unwrap()),
+            VfsError::BadFileDescriptor
+        );
+
+        // Small messages split directories over several `Treaddir`.
+        let options = P9ServerOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let root = serve(sub, options, 512);
+        let mut names = Vec::new();
+        let mut offsets = Vec::new();
+        root.read_dir(0, &mut |name: &str, _, _, offset| {
+            names.push(name.to_owned());
+            offsets.push(offset);
+            true
+        })
+        .unwrap();
+        assert_eq!(names.len(), 103);
+        // Offsets are those of the exported directory.
+        let mut rest = Vec::new();
+        root.read_dir(offsets[51], &mut |name: &str, _, _, _| {
+            rest.push(name.to_owned());
+            true
+        })
+        .unwrap();
+        assert_eq!(rest, names[52..]);
+
+        let file = root.lookup_no_follow("file").unwrap();
+        assert_eq!(read_all(&file), b"data");
+        assert_eq!(file.metadata().unwrap().size, 4);
+        assert_eq!(
+            file.entry()
+                .as_file()
+                .unwrap()
+                .write_at(b"x", 0)
+                .unwrap_err(),
+            VfsError::ReadOnlyFilesystem
+        );
+        assert_eq!(
+            root.create("new", NodeType::RegularFile, mode).unwrap_err(),
+            VfsError::ReadOnlyFilesystem
+        );
+        assert_eq!(
+            root.unlink("file", false).unwrap_err(),
+            VfsError::ReadOnlyFilesystem
+        );
+    }
+}
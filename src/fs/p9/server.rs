@@ -0,0 +1,485 @@
+use alloc::{sync::Arc, vec};
+use core::time::Duration;
+
+use hashbrown::HashMap;
+
+use super::{Transport, to_errno, wire::*};
+use crate::{
+    DeviceId, Location, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult,
+    path::verify_entry_name,
+};
+
+/// Lock status and type of the replies to `Tlock` and `Tgetlock`.
+const LOCK_SUCCESS: u8 = 0;
+const F_UNLCK: u8 = 2;
+
+/// Options for a [`P9Server`].
+#[derive(Debug, Clone)]
+pub struct P9ServerOptions {
+    /// Whether requests changing the tree are refused.
+    pub read_only: bool,
+    /// Largest size of messages accepted.
+    pub msize: u32,
+    /// Source of the current time, used when clients set timestamps to it.
+    pub clock: Option<fn() -> Duration>,
+}
+
+impl Default for P9ServerOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            msize: 65536,
+            clock: None,
+        }
+    }
+}
+
+/// Server exporting a directory over 9P2000.L.
+///
+/// Walks never leave the exported directory, but do enter the filesystems
+/// mounted below it. Requests of a connection are handled in order, and
+/// locks are granted without being tracked.
+pub struct P9Server {
+    root: Location,
+    options: P9ServerOptions,
+}
+
+impl P9Server {
+    /// Creates a server exporting `root`.
+    pub fn new(root: Location, options: P9ServerOptions) -> VfsResult<Self> {
+        root.check_is_dir()?;
+        Ok(Self { root, options })
+    }
+
+    /// Handles the requests received from `transport` until the end of the
+    /// stream. Each connection has its own fids.
+    pub fn serve(&self, transport: &dyn Transport) -> VfsResult<()> {
+        let mut session = Session {
+            server: self,
+            msize: self.options.msize,
+            fids: HashMap::new(),
+        };
+        while let Some(message) = read_message(transport, self.options.msize)? {
+            let (kind, tag) = (message[4], u16::from_le_bytes([message[5], message[6]]));
+            let mut reply = Writer::new(kind + 1, tag);
+            let mut args = Reader::new(&message[HEADER_SIZE..]);
+            if let Err(err) = session.handle(kind, &mut args, &mut reply) {
+                reply = Writer::new(TLERROR + 1, tag);
+                reply.u32(to_errno(err));
+            }
+            transport.send(&reply.finish())?;
+        }
+        Ok(())
+    }
+}
+
+struct Fid {
+    loc: Location,
+    /// Flags the fid was opened with.
+    open: Option<u32>,
+}
+
+struct Session<'a> {
+    server: &'a P9Server,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+fn qid(loc: &Location) -> Qid {
+    Qid::new(loc.node_type(), loc.inode())
+}
+
+fn permission(mode: u32) -> NodePermission {
+    NodePermission::from_bits_truncate(mode as u16)
+}
+
+impl Session<'_> {
+    fn fid(&self, args: &mut Reader) -> VfsResult<&Fid> {
+        let fid = args.u32()?;
+        self.fids.get(&fid).ok_or(VfsError::BadFileDescriptor)
+    }
+
+    fn loc(&self, args: &mut Reader) -> VfsResult<Location> {
+        self.fid(args).map(|it| it.loc.clone())
+    }
+
+    /// Returns the location and flags of a fid opened for reading or
+    /// writing.
+    fn opened(&self, args: &mut Reader, write: bool) -> VfsResult<(Location, u32)> {
+        let fid = self.fid(args)?;
+        let flags = fid.open.ok_or(VfsError::BadFileDescriptor)?;
+        let access = flags & O_ACCMODE;
+        if (write && access == O_RDONLY) || (!write && access == O_WRONLY) {
+            return Err(VfsError::BadFileDescriptor);
+        }
+        Ok((fid.loc.clone(), flags))
+    }
+
+    fn check_writable(&self) -> VfsResult<()> {
+        if self.server.options.read_only {
+            Err(VfsError::ReadOnlyFilesystem)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_root(&self, loc: &Location) -> bool {
+        let root = &self.server.root;
+        loc.ptr_eq(root)
+            || (Arc::ptr_eq(loc.mountpoint(), root.mountpoint()) && loc.inode() == root.inode())
+    }
+
+    /// Walks from `loc` to its child `name`.
+    fn step(&self, loc: &Location, name: &str) -> VfsResult<Location> {
+        if name.is_empty() || name.contains('/') {
+            return Err(VfsError::InvalidInput);
+        }
+        if name == ".." && self.is_root(loc) {
+            return Ok(loc.clone());
+        }
+        loc.check_is_dir()?;
+        loc.lookup_no_follow(name)
+    }
+
+    /// Creates a node in `dir` as requested by `args`, returning it and
+    /// the flags it is opened with.
+    fn create(
+        &self,
+        dir: Location,
+        args: &mut Reader,
+        node_type: NodeType,
+    ) -> VfsResult<(Location, Option<u32>)> {
+        self.check_writable()?;
+        let name = args.str()?;
+        verify_entry_name(name)?;
+        Ok(match node_type {
+            NodeType::Symlink => {
+                let target = args.str()?;
+                let loc = dir.create(name, node_type, permission(0o777))?;
+                loc.entry().as_file()?.set_symlink(target)?;
+                (loc, None)
+            }
+            NodeType::RegularFile => {
+                let flags = args.u32()?;
+                let loc = dir.create(name, node_type, permission(args.u32()?))?;
+                (loc, Some(flags))
+            }
+            NodeType::Directory => (dir.create(name, node_type, permission(args.u32()?))?, None),
+            _ => {
+                let mode = args.u32()?;
+                let node_type = NodeType::from((mode >> 12 & 0o17) as u8);
+                if matches!(node_type, NodeType::Unknown | NodeType::Directory) {
+                    return Err(VfsError::InvalidInput);
+                }
+                let rdev = DeviceId::new(args.u32()?, args.u32()?);
+                let loc = dir.create(name, node_type, permission(mode))?;
+                if matches!(node_type, NodeType::CharacterDevice | NodeType::BlockDevice) {
+                    loc.update_metadata(MetadataUpdate {
+                        rdev: Some(rdev),
+                        ..Default::default()
+                    })?;
+                }
+                (loc, None)
+            }
+        })
+    }
+
+    fn setattr(&self, args: &mut Reader) -> VfsResult<()> {
+        self.check_writable()?;
+        let loc = self.loc(args)?;
+        let valid = args.u32()?;
+        let mode = args.u32()?;
+        let (uid, gid) = (args.u32()?, args.u32()?);
+        let size = args.u64()?;
+        let (atime, mtime) = (args.time()?, args.time()?);
+        let now = self.server.options.clock.map(|clock| clock());
+        let time = |set, given, time_set| {
+            if valid & set == 0 {
+                None
+            } else if valid & time_set != 0 {
+                Some(given)
+            } else {
+                now
+            }
+        };
+        let owner = if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            let metadata = loc.metadata()?;
+            Some((
+                if valid & SETATTR_UID != 0 {
+                    uid
+                } else {
+                    metadata.uid
+                },
+                if valid & SETATTR_GID != 0 {
+                    gid
+                } else {
+                    metadata.gid
+                },
+            ))
+        } else {
+            None
+        };
+        if valid & SETATTR_SIZE != 0 {
+            loc.entry().as_file()?.set_len(size)?;
+        }
+        let update = MetadataUpdate {
+            mode: (valid & SETATTR_MODE != 0).then(|| permission(mode)),
+            owner,
+            rdev: None,
+            atime: time(SETATTR_ATIME, atime, SETATTR_ATIME_SET),
+            mtime: time(SETATTR_MTIME, mtime, SETATTR_MTIME_SET),
+        };
+        if update.mode.is_some()
+            || update.owner.is_some()
+            || update.atime.is_some()
+            || update.mtime.is_some()
+        {
+            loc.update_metadata(update)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, kind: u8, args: &mut Reader, reply: &mut Writer) -> VfsResult<()> {
+        let io_size = self.msize - IO_HEADER_SIZE;
+        match kind {
+            TVERSION => {
+                self.msize = args.u32()?.min(self.server.options.msize);
+                let version = args.str()?;
+                self.fids.clear();
+                if self.msize <= IO_HEADER_SIZE {
+                    return Err(VfsError::InvalidInput);
+                }
+                let version = if version.starts_with(VERSION) {
+                    VERSION
+                } else {
+                    "unknown"
+                };
+                reply.u32(self.msize).str(version);
+            }
+            TAUTH => return Err(VfsError::OperationNotSupported),
+            TATTACH => {
+                let fid = args.u32()?;
+                if self.fids.contains_key(&fid) {
+                    return Err(VfsError::BadFileDescriptor);
+                }
+                let loc = self.server.root.clone();
+                reply.qid(&qid(&loc));
+                self.fids.insert(fid, Fid { loc, open: None });
+            }
+            TFLUSH => {
+                // The request flushed was answered before this one.
+            }
+            TWALK => {
+                let from = self.loc(args)?;
+                let new = args.u32()?;
+                let count = args.u16()? as usize;
+                if count > MAX_WALK {
+                    return Err(VfsError::InvalidInput);
+                }
+                if self.fids.get(&new).is_some_and(|it| !it.loc.ptr_eq(&from)) {
+                    return Err(VfsError::BadFileDescriptor);
+                }
+                let mut loc = from;
+                let mut qids = vec![];
+                for i in 0..count {
+                    match args.str().and_then(|name| self.step(&loc, name)) {
+                        Ok(next) => loc = next,
+                        Err(err) if i == 0 => return Err(err),
+                        Err(_) => break,
+                    }
+                    qids.push(qid(&loc));
+                }
+                reply.u16(qids.len() as u16);
+                for qid in &qids {
+                    reply.qid(qid);
+                }
+                if qids.len() == count {
+                    self.fids.insert(new, Fid { loc, open: None });
+                }
+            }
+            TCLUNK => {
+                let fid = args.u32()?;
+                self.fids.remove(&fid).ok_or(VfsError::BadFileDescriptor)?;
+            }
+            TREMOVE => {
+                let fid = args.u32()?;
+                let loc = self
+                    .fids
+                    .remove(&fid)
+                    .ok_or(VfsError::BadFileDescriptor)?
+                    .loc;
+                self.check_writable()?;
+                if self.is_root(&loc) {
+                    return Err(VfsError::ResourceBusy);
+                }
+                let parent = loc.parent().ok_or(VfsError::ResourceBusy)?;
+                parent.unlink(loc.name(), loc.is_dir())?;
+            }
+            TGETATTR => {
+                reply.attr(&self.loc(args)?.metadata()?);
+            }
+            TSETATTR => self.setattr(args)?,
+            TLOPEN => {
+                let fid = args.u32()?;
+                let flags = args.u32()?;
+                let entry = self.fids.get(&fid).ok_or(VfsError::BadFileDescriptor)?;
+                if entry.open.is_some() {
+                    return Err(VfsError::BadFileDescriptor);
+                }
+                let loc = entry.loc.clone();
+                if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+                    self.check_writable()?;
+                    if loc.is_dir() {
+                        return Err(VfsError::IsADirectory);
+                    }
+                }
+                if flags & O_TRUNC != 0 {
+                    loc.entry().as_file()?.set_len(0)?;
+                }
+                reply.qid(&qid(&loc)).u32(io_size);
+                self.fids.get_mut(&fid).unwrap().open = Some(flags);
+            }
+            TLCREATE => {
+                let fid = args.u32()?;
+                let dir = match self.fids.get(&fid) {
+                    Some(Fid { loc, open: None }) => loc.clone(),
+                    _ => return Err(VfsError::BadFileDescriptor),
+                };
+                let (loc, flags) = self.create(dir, args, NodeType::RegularFile)?;
+                reply.qid(&qid(&loc)).u32(io_size);
+                self.fids.insert(fid, Fid { loc, open: flags });
+            }
+            TMKDIR | TSYMLINK | TMKNOD => {
+                let node_type = match kind {
+                    TMKDIR => NodeType::Directory,
+                    TSYMLINK => NodeType::Symlink,
+                    _ => NodeType::Unknown,
+                };
+                let dir = self.loc(args)?;
+                let (loc, _) = self.create(dir, args, node_type)?;
+                reply.qid(&qid(&loc));
+            }
+            TRENAME => {
+                self.check_writable()?;
+                let fid = args.u32()?;
+                let loc = self
+                    .fids
+                    .get(&fid)
+                    .ok_or(VfsError::BadFileDescriptor)?
+                    .loc
+                    .clone();
+                let dst = self.loc(args)?;
+                let name = args.str()?;
+                verify_entry_name(name)?;
+                let parent = loc.parent().filter(|_| !self.is_root(&loc));
+                let parent = parent.ok_or(VfsError::ResourceBusy)?;
+                parent.rename(loc.name(), &dst, name)?;
+                let moved = dst.lookup_no_follow(name)?;
+                self.fids.get_mut(&fid).unwrap().loc = moved;
+            }
+            TRENAMEAT => {
+                self.check_writable()?;
+                let src = self.loc(args)?;
+                let src_name = args.str()?;
+                let dst = self.loc(args)?;
+                let dst_name = args.str()?;
+                verify_entry_name(src_name)?;
+                verify_entry_name(dst_name)?;
+                src.rename(src_name, &dst, dst_name)?;
+            }
+            TUNLINKAT => {
+                self.check_writable()?;
+                let dir = self.loc(args)?;
+                let name = args.str()?;
+                verify_entry_name(name)?;
+                dir.unlink(name, args.u32()? & AT_REMOVEDIR != 0)?;
+            }
+            TLINK => {
+                self.check_writable()?;
+                let dir = self.loc(args)?;
+                let target = self.loc(args)?;
+                let name = args.str()?;
+                verify_entry_name(name)?;
+                dir.link(name, &target)?;
+            }
+            TREADLINK => {
+                reply.str(&self.loc(args)?.read_link()?);
+            }
+            TREAD => {
+                let (loc, _) = self.opened(args, false)?;
+                let offset = args.u64()?;
+                let count = args.u32()?.min(io_size);
+                let mut buf = vec![0; count as usize];
+                let len = loc.entry().as_file()?.read_at(&mut buf, offset)?;
+                reply.data(&buf[..len]);
+            }
+            TWRITE => {
+                let (loc, flags) = self.opened(args, true)?;
+                let offset = args.u64()?;
+                let data = args.data()?;
+                let file = loc.entry().as_file()?;
+                let len = if flags & O_APPEND != 0 {
+                    file.append(data)?.0
+                } else {
+                    file.write_at(data, offset)?
+                };
+                reply.u32(len as u32);
+            }
+            TREADDIR => {
+                let (loc, _) = self.opened(args, false)?;
+                let offset = args.u64()?;
+                let count = args.u32()?.min(io_size) as usize;
+                let mut entries = Writer::new(0, 0);
+                loc.read_dir(offset, &mut |name: &str, ino, node_type, next| {
+                    // Qid, offset, type and name.
+                    if entries.len() - HEADER_SIZE + 24 + name.len() > count {
+                        return false;
+                    }
+                    entries
+                        .qid(&Qid::new(node_type, ino))
+                        .u64(next)
+                        .u8(node_type as u8)
+                        .str(name);
+                    true
+                })?;
+                reply.data(&entries.finish()[HEADER_SIZE..]);
+            }
+            TFSYNC => {
+                let loc = self.loc(args)?;
+                loc.sync(args.u32().unwrap_or(0) != 0)?;
+            }
+            TSTATFS => {
+                let stat = self.loc(args)?.filesystem().stat()?;
+                reply
+                    .u32(stat.fs_type)
+                    .u32(stat.block_size)
+                    .u64(stat.blocks)
+                    .u64(stat.blocks_free)
+                    .u64(stat.blocks_available)
+                    .u64(stat.file_count)
+                    .u64(stat.free_file_count)
+                    .u64(0)
+                    .u32(stat.name_length);
+            }
+            TLOCK => {
+                self.loc(args)?;
+                reply.u8(LOCK_SUCCESS);
+            }
+            TGETLOCK => {
+                self.loc(args)?;
+                let _kind = args.u8()?;
+                let (start, length) = (args.u64()?, args.u64()?);
+                let proc_id = args.u32()?;
+                reply
+                    .u8(F_UNLCK)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .str(args.str()?);
+            }
+            TXATTRWALK | TXATTRCREATE => return Err(VfsError::OperationNotSupported),
+            _ => return Err(VfsError::Unsupported),
+        }
+        Ok(())
+    }
+}
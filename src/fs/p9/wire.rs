@@ -0,0 +1,305 @@
+//! Encoding of 9P2000.L messages.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::time::Duration;
+
+use super::Transport;
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+pub const TLERROR: u8 = 6;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TMKNOD: u8 = 18;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+/// Size of the header of messages: size, type and tag.
+pub const HEADER_SIZE: usize = 7;
+/// Room left in messages for the header and fields of `Rread` and
+/// `Twrite`.
+pub const IO_HEADER_SIZE: u32 = 24;
+pub const VERSION: &str = "9P2000.L";
+pub const NOTAG: u16 = !0;
+pub const NOFID: u32 = !0;
+/// Largest number of names walked by a single `Twalk`.
+pub const MAX_WALK: usize = 16;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_DIRECTORY: u32 = 0o200000;
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+pub const GETATTR_BASIC: u64 = 0x7ff;
+
+pub const SETATTR_MODE: u32 = 0x1;
+pub const SETATTR_UID: u32 = 0x2;
+pub const SETATTR_GID: u32 = 0x4;
+pub const SETATTR_SIZE: u32 = 0x8;
+pub const SETATTR_ATIME: u32 = 0x10;
+pub const SETATTR_MTIME: u32 = 0x20;
+pub const SETATTR_ATIME_SET: u32 = 0x80;
+pub const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Unique identification of a file on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn new(node_type: NodeType, path: u64) -> Self {
+        let kind = match node_type {
+            NodeType::Directory => QTDIR,
+            NodeType::Symlink => QTSYMLINK,
+            _ => 0,
+        };
+        Self {
+            kind,
+            version: 0,
+            path,
+        }
+    }
+}
+
+/// Builder of a message.
+pub struct Writer(Vec<u8>);
+
+impl Writer {
+    pub fn new(kind: u8, tag: u16) -> Self {
+        let mut buf = vec![0; 4];
+        buf.push(kind);
+        buf.extend_from_slice(&tag.to_le_bytes());
+        Self(buf)
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.raw(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.raw(&value.to_le_bytes())
+    }
+
+    /// Appends bytes as they are.
+    pub fn raw(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16).raw(value.as_bytes())
+    }
+
+    /// Appends a count of bytes followed by the bytes.
+    pub fn data(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32).raw(value)
+    }
+
+    pub fn qid(&mut self, qid: &Qid) -> &mut Self {
+        self.u8(qid.kind).u32(qid.version).u64(qid.path)
+    }
+
+    pub fn time(&mut self, time: Duration) -> &mut Self {
+        self.u64(time.as_secs()).u64(time.subsec_nanos() as u64)
+    }
+
+    /// Appends the fields of `Rgetattr` describing `metadata`.
+    pub fn attr(&mut self, metadata: &Metadata) -> &mut Self {
+        let mode = (metadata.node_type as u32) << 12 | metadata.mode.bits() as u32;
+        self.u64(GETATTR_BASIC)
+            .qid(&Qid::new(metadata.node_type, metadata.inode))
+            .u32(mode)
+            .u32(metadata.uid)
+            .u32(metadata.gid)
+            .u64(metadata.nlink)
+            .u64(metadata.rdev.0)
+            .u64(metadata.size)
+            .u64(metadata.block_size)
+            .u64(metadata.blocks)
+            .time(metadata.atime)
+            .time(metadata.mtime)
+            .time(metadata.ctime)
+            // Birth time, generation and data version.
+            .time(Duration::ZERO)
+            .u64(0)
+            .u64(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the message, with its size filled in.
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Parser of the fields of a message.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> VfsResult<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(VfsError::InvalidData);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> VfsResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> VfsResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> VfsResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> VfsResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn str(&mut self) -> VfsResult<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| VfsError::InvalidData)
+    }
+
+    pub fn string(&mut self) -> VfsResult<String> {
+        self.str().map(String::from)
+    }
+
+    /// Reads a count of bytes followed by the bytes.
+    pub fn data(&mut self) -> VfsResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn qid(&mut self) -> VfsResult<Qid> {
+        Ok(Qid {
+            kind: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+
+    pub fn time(&mut self) -> VfsResult<Duration> {
+        let secs = self.u64()?;
+        let nsecs = self.u64()?;
+        Ok(Duration::new(secs, nsecs.min(999_999_999) as u32))
+    }
+
+    /// Reads the fields of `Rgetattr`.
+    pub fn attr(&mut self) -> VfsResult<Metadata> {
+        let _valid = self.u64()?;
+        let qid = self.qid()?;
+        let mode = self.u32()?;
+        let metadata = Metadata {
+            device: 0,
+            inode: qid.path,
+            mode: NodePermission::from_bits_truncate(mode as u16),
+            node_type: NodeType::from((mode >> 12 & 0o17) as u8),
+            uid: self.u32()?,
+            gid: self.u32()?,
+            nlink: self.u64()?,
+            rdev: DeviceId(self.u64()?),
+            size: self.u64()?,
+            block_size: self.u64()?,
+            blocks: self.u64()?,
+            atime: self.time()?,
+            mtime: self.time()?,
+            ctime: self.time()?,
+        };
+        Ok(metadata)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// Reads a whole message from `transport`, of at most `max_size` bytes.
+///
+/// Returns `None` at the end of the stream between messages.
+pub fn read_message(transport: &dyn Transport, max_size: u32) -> VfsResult<Option<Vec<u8>>> {
+    let mut size = [0; 4];
+    if !read_exact(transport, &mut size, true)? {
+        return Ok(None);
+    }
+    let size = u32::from_le_bytes(size);
+    if size < HEADER_SIZE as u32 || size > max_size {
+        return Err(VfsError::InvalidData);
+    }
+    let mut message = vec![0; size as usize];
+    message[..4].copy_from_slice(&size.to_le_bytes());
+    read_exact(transport, &mut message[4..], false)?;
+    Ok(Some(message))
+}
+
+/// Fills `buf` from `transport`, returning `false` if the stream ended
+/// before any byte was read and `eof_ok` is set.
+fn read_exact(transport: &dyn Transport, buf: &mut [u8], eof_ok: bool) -> VfsResult<bool> {
+    let mut done = 0;
+    while done < buf.len() {
+        match transport.recv(&mut buf[done..])? {
+            0 if done == 0 && eof_ok => return Ok(false),
+            0 => return Err(VfsError::UnexpectedEof),
+            n => done += n,
+        }
+    }
+    Ok(true)
+}
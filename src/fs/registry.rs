@@ -0,0 +1,312 @@
+//! Registry of filesystem types, for mounting block devices by type name or
+//! by detecting their filesystem.
+//!
+//! Each type may come with a [`Prober`] recognizing its superblock and a
+//! [`MountFn`] mounting a device. Mount sources in the `UUID=`, `LABEL=`,
+//! `PARTUUID=` and `PARTLABEL=` forms are resolved by probing the block
+//! devices in a device directory.
+
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    Filesystem, Location, Mutex, NodeType, VfsError, VfsResult,
+    axfs::AxFs,
+    block::{BUILTIN_PROBERS, BlockDevice, Partition, ProbeInfo, Prober},
+    ext4::Ext4Fs,
+    fat::FatFs,
+    iso9660::IsoFs,
+    path::{DOT, DOTDOT},
+    squashfs::SquashFs,
+};
+
+/// A function mounting a filesystem stored on a device.
+pub type MountFn = fn(Arc<dyn BlockDevice>) -> VfsResult<Filesystem>;
+
+struct FsType {
+    name: &'static str,
+    prober: Option<Prober>,
+    mount: Option<MountFn>,
+}
+
+/// A set of known filesystem types.
+pub struct FsRegistry {
+    types: Mutex<Vec<FsType>>,
+}
+
+impl Default for FsRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FsRegistry {
+    /// Creates a registry knowing the probers of
+    /// [`BUILTIN_PROBERS`](crate::block::BUILTIN_PROBERS) and the disk
+    /// filesystems of this crate.
+    pub fn new() -> Self {
+        let registry = Self {
+            types: Mutex::default(),
+        };
+        for &(name, prober) in BUILTIN_PROBERS {
+            registry.register(name, Some(prober), None);
+        }
+        registry.register(
+            "axfs",
+            None,
+            Some(|device| Ok(Filesystem::new(AxFs::new(device)?))),
+        );
+        for name in ["ext2", "ext3", "ext4"] {
+            registry.register(
+                name,
+                None,
+                Some(|device| Ok(Filesystem::new(Ext4Fs::new(device)?))),
+            );
+        }
+        registry.register(
+            "iso9660",
+            None,
+            Some(|device| Ok(Filesystem::new(IsoFs::new(device)?))),
+        );
+        registry.register(
+            "squashfs",
+            None,
+            Some(|device| Ok(Filesystem::new(SquashFs::new(device)?))),
+        );
+        registry.register(
+            "vfat",
+            None,
+            Some(|device| Ok(Filesystem::new(FatFs::new(device)?))),
+        );
+        registry
+    }
+
+    /// Registers the filesystem type `name`.
+    ///
+    /// If the type is already known, the given callbacks replace its
+    /// existing ones, and missing callbacks are kept. Probers run in the
+    /// order their types were first registered.
+    pub fn register(&self, name: &'static str, prober: Option<Prober>, mount: Option<MountFn>) {
+        let mut types = self.types.lock();
+        match types.iter_mut().find(|it| it.name == name) {
+            Some(ty) => {
+                ty.prober = prober.or(ty.prober);
+                ty.mount = mount.or(ty.mount);
+            }
+            None => types.push(FsType {
+                name,
+                prober,
+                mount,
+            }),
+        }
+    }
+
+    /// Returns whether `name` can be mounted.
+    pub fn is_mountable(&self, name: &str) -> bool {
+        self.mount_fn(name).is_some()
+    }
+
+    fn mount_fn(&self, name: &str) -> Option<MountFn> {
+        self.types
+            .lock()
+            .iter()
+            .find(|it| it.name == name)
+            .and_then(|it| it.mount)
+    }
+
+    /// Recognizes the filesystem on `device` with the registered probers.
+    pub fn probe(&self, device: &dyn BlockDevice) -> VfsResult<Option<ProbeInfo>> {
+        let probers: Vec<_> = self
+            .types
+            .lock()
+            .iter()
+            .filter_map(|it| it.prober)
+            .collect();
+        for prober in probers {
+            if let Some(info) = prober(device)? {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Mounts `device` as `fs_type`, or as whatever filesystem is detected
+    /// on it if `fs_type` is `None`.
+    pub fn mount(
+        &self,
+        device: Arc<dyn BlockDevice>,
+        fs_type: Option<&str>,
+    ) -> VfsResult<Filesystem> {
+        let name = match fs_type {
+            Some(name) => name,
+            None => self.probe(&*device)?.ok_or(VfsError::InvalidInput)?.fs_type,
+        };
+        let mount = self.mount_fn(name).ok_or(VfsError::NoSuchDevice)?;
+        mount(device)
+    }
+
+    /// Resolves a mount source to a block device in the directory `dev`.
+    ///
+    /// `source` is either a tag such as `UUID=...` or `LABEL="..."`, or the
+    /// name of a device node in `dev`, optionally prefixed with `/dev/`.
+    /// UUIDs are compared case-insensitively. If several devices match,
+    /// the first one in directory order is returned.
+    pub fn resolve_source(&self, dev: &Location, source: &str) -> VfsResult<Arc<dyn BlockDevice>> {
+        let Some((tag, value)) = source.split_once('=') else {
+            let name = source.strip_prefix("/dev/").unwrap_or(source);
+            return dev.lookup_no_follow(name)?.block_device();
+        };
+        let value = value
+            .strip_prefix('"')
+            .and_then(|it| it.strip_suffix('"'))
+            .unwrap_or(value);
+        let matches = |device: &Arc<dyn BlockDevice>| -> VfsResult<bool> {
+            let partition = || device.clone().into_any().downcast::<Partition>().ok();
+            Ok(match tag {
+                "UUID" => self
+                    .probe(&**device)?
+                    .and_then(|it| it.uuid)
+                    .is_some_and(|it| it.eq_ignore_ascii_case(value)),
+                "LABEL" => self
+                    .probe(&**device)?
+                    .and_then(|it| it.label)
+                    .is_some_and(|it| it == value),
+                "PARTUUID" => {
+                    partition().is_some_and(|it| it.info().uuid.eq_ignore_ascii_case(value))
+                }
+                "PARTLABEL" => {
+                    partition().is_some_and(|it| it.info().label.as_deref() == Some(value))
+                }
+                _ => return Err(VfsError::InvalidInput),
+            })
+        };
+        for name in block_devices(dev)? {
+            let device = dev.lookup_no_follow(&name)?.block_device()?;
+            if matches(&device)? {
+                return Ok(device);
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    /// Resolves `source` in the directory `dev` and mounts it, see
+    /// [`FsRegistry::resolve_source`] and [`FsRegistry::mount`].
+    pub fn mount_source(
+        &self,
+        dev: &Location,
+        source: &str,
+        fs_type: Option<&str>,
+    ) -> VfsResult<Filesystem> {
+        self.mount(self.resolve_source(dev, source)?, fs_type)
+    }
+}
+
+/// Lists the names of the block device nodes in `dev`.
+fn block_devices(dev: &Location) -> VfsResult<Vec<String>> {
+    let mut names = Vec::new();
+    let mut offset = 0;
+    loop {
+        let read = dev.read_dir(offset, &mut |name: &str, _, node_type, next| {
+            if node_type == NodeType::BlockDevice && name != DOT && name != DOTDOT {
+                names.push(name.to_owned());
+            }
+            offset = next;
+            true
+        })?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{
+        DeviceId, Mountpoint,
+        block::{RamDisk, add_partitions},
+        devfs::DevFs,
+    };
+
+    fn fat_image(size: usize, serial: u32, label: &[u8; 11]) -> Vec<u8> {
+        let mut image = vec![0; size];
+        image[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        image[11..14].copy_from_slice(&[0, 2, 4]);
+        image[14] = 1;
+        image[16] = 2;
+        image[22] = 8;
+        image[38] = 0x29;
+        image[39..43].copy_from_slice(&serial.to_le_bytes());
+        image[43..54].copy_from_slice(label);
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        image
+    }
+
+    fn mount_dummy(_device: Arc<dyn BlockDevice>) -> VfsResult<Filesystem> {
+        Ok(Filesystem::new(DevFs::new()))
+    }
+
+    #[test]
+    fn test_resolve_and_mount() {
+        let devfs = DevFs::new();
+        let disk = |image| Arc::new(RamDisk::from_vec(image));
+        devfs
+            .add_block_device(
+                "sda",
+                DeviceId::new(8, 0),
+                disk(fat_image(8192, 0xdead_beef, b"DATA       ")),
+            )
+            .unwrap();
+        devfs
+            .add_block_device(
+                "sdb",
+                DeviceId::new(8, 16),
+                disk(fat_image(8192, 0x0102_0304, b"NO NAME    ")),
+            )
+            .unwrap();
+
+        // An MBR disk with a single partition.
+        let mut image = vec![0; 8192];
+        image[440..444].copy_from_slice(&0x1122_3344_u32.to_le_bytes());
+        image[446 + 4] = 0x83;
+        image[446 + 8..446 + 12].copy_from_slice(&2u32.to_le_bytes());
+        image[446 + 12..446 + 16].copy_from_slice(&8u32.to_le_bytes());
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let sdc = disk(image);
+        devfs
+            .add_block_device("sdc", DeviceId::new(8, 32), sdc.clone())
+            .unwrap();
+        add_partitions(&devfs, "sdc", DeviceId::new(8, 32), sdc).unwrap();
+
+        let dev = Mountpoint::new_root(&Filesystem::new(devfs)).root_location();
+        let registry = FsRegistry::new();
+        let len = |source: &str| registry.resolve_source(&dev, source).map(|it| it.len());
+        assert_eq!(len("LABEL=DATA"), Ok(8192));
+        assert_eq!(len("UUID=\"0102-0304\""), Ok(8192));
+        assert_eq!(len("UUID=dead-BEEF"), Ok(8192));
+        assert_eq!(len("PARTUUID=11223344-01"), Ok(4096));
+        assert_eq!(len("/dev/sdc1"), Ok(4096));
+        assert_eq!(len("LABEL=missing"), Err(VfsError::NotFound));
+        assert_eq!(len("SERIAL=1"), Err(VfsError::InvalidInput));
+
+        // Not a complete FAT volume.
+        assert_eq!(
+            registry.mount_source(&dev, "LABEL=DATA", None).err(),
+            Some(VfsError::InvalidData)
+        );
+        assert_eq!(
+            registry.mount_source(&dev, "sda", Some("ext9")).err(),
+            Some(VfsError::NoSuchDevice)
+        );
+        registry.register("vfat", None, Some(mount_dummy));
+        assert!(registry.is_mountable("vfat"));
+        let fs = registry.mount_source(&dev, "LABEL=DATA", None).unwrap();
+        assert_eq!(fs.name(), "devfs");
+        assert_eq!(
+            registry.mount_source(&dev, "sdc1", None).err(),
+            Some(VfsError::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Cache of decompressed blocks.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use hashbrown::HashMap;
+
+use crate::{Mutex, VfsResult};
+
+/// A decompressed block, along with the position following it on disk.
+pub type Block = (Arc<[u8]>, u64);
+
+struct Entry {
+    block: Block,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<u64, Entry>,
+    /// Total size of the cached data.
+    size: usize,
+    clock: u64,
+}
+
+/// A cache of decompressed blocks keyed by their position on disk.
+///
+/// Once the cached data exceeds `capacity` bytes, the least recently used
+/// blocks are evicted.
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Returns the block at `pos`, loading it with `load` if it is not
+    /// cached.
+    pub fn get(&self, pos: u64, load: impl FnOnce() -> VfsResult<Block>) -> VfsResult<Block> {
+        {
+            let mut inner = self.inner.lock();
+            inner.clock += 1;
+            let now = inner.clock;
+            if let Some(entry) = inner.entries.get_mut(&pos) {
+                entry.last_used = now;
+                return Ok(entry.block.clone());
+            }
+        }
+
+        // Blocks are loaded without holding the lock, so that readers of
+        // other blocks are not held up by the decompression.
+        let block = load()?;
+        let mut inner = self.inner.lock();
+        let now = inner.clock;
+        let len = block.0.len();
+        if let Some(old) = inner.entries.insert(
+            pos,
+            Entry {
+                block: block.clone(),
+                last_used: now,
+            },
+        ) {
+            inner.size -= old.block.0.len();
+        }
+        inner.size += len;
+        if inner.size > self.capacity {
+            let mut candidates: Vec<_> = inner
+                .entries
+                .iter()
+                .filter(|&(&it, _)| it != pos)
+                .map(|(&pos, entry)| (entry.last_used, pos))
+                .collect();
+            candidates.sort_unstable();
+            for (_, pos) in candidates {
+                if inner.size <= self.capacity {
+                    break;
+                }
+                let entry = inner.entries.remove(&pos).unwrap();
+                inner.size -= entry.block.0.len();
+            }
+        }
+        Ok(block)
+    }
+
+    /// Returns the total size of the cached data.
+    #[cfg(test)]
+    pub fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_eviction() {
+        let cache = BlockCache::new(250);
+        let load = |pos: u64| move || Ok((vec![pos as u8; 100].into(), pos + 100));
+        cache.get(0, load(0)).unwrap();
+        cache.get(100, load(100)).unwrap();
+        cache.get(0, || unreachable!()).unwrap();
+        // Blocks are evicted from the least recently used one.
+        cache.get(200, load(200)).unwrap();
+        assert_eq!(cache.size(), 200);
+        assert_eq!(cache.get(0, || unreachable!()).unwrap().0[0], 0);
+        assert_eq!(cache.get(200, || unreachable!()).unwrap().1, 300);
+        assert_eq!(cache.get(100, load(100)).unwrap().0[0], 100);
+    }
+}
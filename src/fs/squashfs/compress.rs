@@ -0,0 +1,69 @@
+//! Decompression of metadata and data blocks.
+
+use alloc::vec::Vec;
+
+use crate::{VfsError, VfsResult};
+
+/// The compression algorithms of squashfs 4.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compressor {
+    Gzip,
+    Lzma,
+    Lzo,
+    Xz,
+    Lz4,
+    Zstd,
+}
+
+impl Compressor {
+    pub fn from_id(id: u16) -> VfsResult<Self> {
+        Ok(match id {
+            1 => Self::Gzip,
+            2 => Self::Lzma,
+            3 => Self::Lzo,
+            4 => Self::Xz,
+            5 => Self::Lz4,
+            6 => Self::Zstd,
+            _ => return Err(VfsError::InvalidData),
+        })
+    }
+
+    /// Returns whether the cargo feature of the algorithm is enabled.
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Self::Gzip => cfg!(feature = "gzip"),
+            Self::Lz4 => cfg!(feature = "lz4"),
+            Self::Zstd => cfg!(feature = "zstd"),
+            Self::Lzma | Self::Lzo | Self::Xz => false,
+        }
+    }
+
+    /// Decompresses `input`, which must expand to at most `max` bytes.
+    pub fn decompress(self, input: &[u8], max: usize) -> VfsResult<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Self::Gzip => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(input, max)
+                .map_err(|_| VfsError::InvalidData),
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let mut output = alloc::vec![0; max];
+                let len = lz4_flex::block::decompress_into(input, &mut output)
+                    .map_err(|_| VfsError::InvalidData)?;
+                output.truncate(len);
+                Ok(output)
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                let mut output = Vec::with_capacity(max);
+                ruzstd::decoding::FrameDecoder::new()
+                    .decode_all_to_vec(input, &mut output)
+                    .map_err(|_| VfsError::InvalidData)?;
+                Ok(output)
+            }
+            _ => {
+                let _ = (input, max);
+                Err(VfsError::Unsupported)
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+
+use super::{
+    MAX_NAME_LEN, METADATA_SIZE, Reader, SquashFs,
+    inode::{DIR_SIZE_OFFSET, DirInfo, IndexEntry, Inode, Kind, node_type_of},
+};
+use crate::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps,
+    NodePermission, NodeType, Reference, VfsError, VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT},
+};
+
+/// Maximum number of entries following a directory header.
+const MAX_HEADER_ENTRIES: u32 = 256;
+
+/// An entry of a directory listing.
+struct RawEntry {
+    name: Vec<u8>,
+    /// Reference of the inode in the inode table.
+    inode_ref: u64,
+    ino: u32,
+    ty: u16,
+    /// Position following the entry in the listing.
+    end: u32,
+}
+
+/// A reader of the entries of a directory listing, which are grouped under
+/// headers giving the metadata block of their inodes.
+struct Listing<'a> {
+    reader: Reader<'a>,
+    pos: u32,
+    size: u32,
+    /// Entries left under the current header.
+    remaining: u32,
+    inode_block: u32,
+    base_ino: u32,
+}
+
+impl<'a> Listing<'a> {
+    /// Starts reading the listing of `dir` at position `pos`, where a header
+    /// must start, which is stored in the metadata block at `block` in the
+    /// directory table.
+    fn new(fs: &'a SquashFs, dir: &DirInfo, block: u32, pos: u32) -> VfsResult<Self> {
+        let offset = (dir.offset as usize + pos as usize) % METADATA_SIZE;
+        Ok(Self {
+            reader: Reader::new(fs, fs.sb.dir_table + block as u64, offset)?,
+            pos,
+            size: dir.size,
+            remaining: 0,
+            inode_block: 0,
+            base_ino: 0,
+        })
+    }
+
+    fn next(&mut self) -> VfsResult<Option<RawEntry>> {
+        while self.remaining == 0 {
+            if self.pos >= self.size {
+                return Ok(None);
+            }
+            self.remaining = self.reader.u32()? + 1;
+            self.inode_block = self.reader.u32()?;
+            self.base_ino = self.reader.u32()?;
+            self.pos += 12;
+            if self.remaining > MAX_HEADER_ENTRIES {
+                return Err(VfsError::InvalidData);
+            }
+        }
+        if self.pos >= self.size {
+            return Err(VfsError::InvalidData);
+        }
+        let offset = self.reader.u16()?;
+        let delta = self.reader.u16()? as i16;
+        let ty = self.reader.u16()?;
+        let len = self.reader.u16()? as u32 + 1;
+        if len > MAX_NAME_LEN {
+            return Err(VfsError::InvalidData);
+        }
+        let name = self.reader.bytes(len as usize)?;
+        self.pos += 8 + len;
+        self.remaining -= 1;
+        Ok(Some(RawEntry {
+            name,
+            inode_ref: (self.inode_block as u64) << 16 | offset as u64,
+            ino: self.base_ino.wrapping_add_signed(delta as i32),
+            ty,
+            end: self.pos,
+        }))
+    }
+}
+
+/// Directory node of a [`SquashFs`].
+pub struct SquashDir {
+    fs: Arc<SquashFs>,
+    inode: Inode,
+    this: WeakDirEntry,
+}
+
+impl SquashDir {
+    pub(super) fn new(fs: Arc<SquashFs>, inode: Inode, this: WeakDirEntry) -> Self {
+        Self { fs, inode, this }
+    }
+
+    fn info(&self) -> &DirInfo {
+        match &self.inode.kind {
+            Kind::Dir(dir) => dir,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Starts reading the listing from the last header the index locates
+    /// that `accept` holds for, or from the start.
+    fn listing(&self, accept: impl Fn(&IndexEntry) -> bool) -> VfsResult<Listing<'_>> {
+        let dir = self.info();
+        let (block, pos) = dir
+            .index
+            .iter()
+            .take_while(|it| accept(it))
+            .last()
+            .map_or((dir.block, 0), |it| (it.block, it.index));
+        Listing::new(&self.fs, dir, block, pos)
+    }
+}
+
+impl NodeOps for SquashDir {
+    fn inode(&self) -> u64 {
+        self.inode.ino as u64
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.inode.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.fs.get_xattr(self.inode.xattr, name)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.fs.list_xattr(self.inode.xattr)
+    }
+}
+
+impl DirNodeOps for SquashDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        // As in Linux, `.` and `..` are at offsets 0 and 1, and the other
+        // entries are addressed by their position in the listing plus the
+        // size the dots count for in the size of directories.
+        let ino = self.inode.ino;
+        let dir = self.info();
+        // The parent of the root directory is past the last inode.
+        let parent = if (1..=self.fs.sb.inode_count).contains(&dir.parent) {
+            dir.parent
+        } else {
+            ino
+        };
+        let mut count = 0;
+        for (name, ino, next) in [(DOT, ino, 1), (DOTDOT, parent, DIR_SIZE_OFFSET)] {
+            if offset < next as u64 {
+                if !sink.accept(name, ino as u64, NodeType::Directory, next as u64) {
+                    return Ok(count);
+                }
+                count += 1;
+            }
+        }
+
+        let target = offset.saturating_sub(DIR_SIZE_OFFSET as u64);
+        let mut listing = self.listing(|it| (it.index as u64) <= target)?;
+        while let Some(entry) = listing.next()? {
+            if (entry.end as u64) <= target {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&entry.name);
+            let next = (entry.end + DIR_SIZE_OFFSET) as u64;
+            if !sink.accept(&name, entry.ino as u64, node_type_of(entry.ty), next) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let key = name.as_bytes();
+        // Entries are sorted by name, and so are the entries of the index.
+        let mut listing = self.listing(|it| it.name.as_slice() <= key)?;
+        while let Some(entry) = listing.next()? {
+            if entry.name.as_slice() > key {
+                break;
+            }
+            if entry.name == key {
+                let inode = self.fs.inode(entry.inode_ref)?;
+                let reference = Reference::new(self.this.upgrade(), name.to_owned());
+                return Ok(self.fs.open(inode, reference));
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
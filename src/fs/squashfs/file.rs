@@ -0,0 +1,139 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{any::Any, task::Context};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::{
+    DATA_UNCOMPRESSED, SquashFs,
+    inode::{FileInfo, Inode, Kind},
+};
+use crate::{FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeOps, VfsError, VfsResult};
+
+/// Non-directory node of a [`SquashFs`].
+///
+/// Reading a symlink returns its target.
+pub struct SquashFile {
+    fs: Arc<SquashFs>,
+    inode: Inode,
+}
+
+impl SquashFile {
+    pub(super) fn new(fs: Arc<SquashFs>, inode: Inode) -> Self {
+        Self { fs, inode }
+    }
+
+    fn read_file(&self, file: &FileInfo, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if offset >= file.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((file.size - offset) as usize);
+        let block_size = self.fs.block_size() as u64;
+        let mut read = 0;
+        while read < len {
+            let pos = offset + read as u64;
+            let index = (pos / block_size) as usize;
+            let start = (pos % block_size) as usize;
+            // The data of the block, and the position of the block in it.
+            let (data, base) = match file.blocks.get(index) {
+                // Sparse blocks are stored as blocks of size zero.
+                Some(&(_, size)) if size & !DATA_UNCOMPRESSED == 0 => (None, 0),
+                Some(&(pos, size)) => (Some(self.fs.data_block(pos, size)?), 0),
+                None => {
+                    let (fragment, tail) = file.fragment.ok_or(VfsError::InvalidData)?;
+                    let (pos, size) = self.fs.fragment(fragment)?;
+                    (Some(self.fs.data_block(pos, size)?), tail as usize)
+                }
+            };
+            let chunk = (len - read).min(block_size as usize - start);
+            let dst = &mut buf[read..read + chunk];
+            match data {
+                Some(data) => {
+                    let src = data
+                        .get(base + start..base + start + chunk)
+                        .ok_or(VfsError::InvalidData)?;
+                    dst.copy_from_slice(src);
+                }
+                None => dst.fill(0),
+            }
+            read += chunk;
+        }
+        Ok(len)
+    }
+}
+
+impl NodeOps for SquashFile {
+    fn inode(&self) -> u64 {
+        self.inode.ino as u64
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.inode.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.inode.size())
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.fs.get_xattr(self.inode.xattr, name)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.fs.list_xattr(self.inode.xattr)
+    }
+}
+
+impl FileNodeOps for SquashFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        match &self.inode.kind {
+            Kind::File(file) => self.read_file(file, buf, offset),
+            Kind::Symlink(target) => {
+                let start = (offset as usize).min(target.len());
+                let len = buf.len().min(target.len() - start);
+                buf[..len].copy_from_slice(&target[start..start + len]);
+                Ok(len)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for SquashFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
@@ -0,0 +1,290 @@
+//! On-disk inodes.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::{DATA_UNCOMPRESSED, MAX_NAME_LEN, NO_INDEX, Reader, SquashFs};
+use crate::{DeviceId, Metadata, NodePermission, NodeType, VfsError, VfsResult};
+
+const TYPE_DIR: u16 = 1;
+const TYPE_FILE: u16 = 2;
+const TYPE_SYMLINK: u16 = 3;
+const TYPE_BLOCK_DEVICE: u16 = 4;
+const TYPE_CHAR_DEVICE: u16 = 5;
+const TYPE_FIFO: u16 = 6;
+const TYPE_SOCKET: u16 = 7;
+/// Offset from the basic inode types to their extended variants.
+const TYPE_EXTENDED: u16 = 7;
+
+/// Size of the `.` and `..` entries counted in the size of directories,
+/// which are not stored.
+pub const DIR_SIZE_OFFSET: u32 = 3;
+const MAX_SYMLINK_LEN: u32 = 4096;
+
+/// Returns the node type of the basic inode type `ty`, which is also used
+/// by directory entries.
+pub fn node_type_of(ty: u16) -> NodeType {
+    match ty {
+        TYPE_DIR => NodeType::Directory,
+        TYPE_FILE => NodeType::RegularFile,
+        TYPE_SYMLINK => NodeType::Symlink,
+        TYPE_BLOCK_DEVICE => NodeType::BlockDevice,
+        TYPE_CHAR_DEVICE => NodeType::CharacterDevice,
+        TYPE_FIFO => NodeType::Fifo,
+        TYPE_SOCKET => NodeType::Socket,
+        _ => NodeType::Unknown,
+    }
+}
+
+/// An entry of a directory index, locating the first directory header of
+/// a metadata block.
+#[derive(Clone)]
+pub struct IndexEntry {
+    /// Position of the header in the listing.
+    pub index: u32,
+    /// Position of the metadata block in the directory table.
+    pub block: u32,
+    /// Name of the first entry after the header.
+    pub name: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct DirInfo {
+    /// Position of the metadata block of the listing in the directory
+    /// table.
+    pub block: u32,
+    /// Offset of the listing in the metadata block.
+    pub offset: u16,
+    /// Size of the listing.
+    pub size: u32,
+    pub parent: u32,
+    pub index: Vec<IndexEntry>,
+}
+
+#[derive(Clone)]
+pub struct FileInfo {
+    pub size: u64,
+    /// Position and size on disk of each full block.
+    pub blocks: Vec<(u64, u32)>,
+    /// Fragment holding the tail of the file, and the offset of the tail in
+    /// the fragment.
+    pub fragment: Option<(u32, u32)>,
+}
+
+#[derive(Clone)]
+pub enum Kind {
+    Dir(DirInfo),
+    File(FileInfo),
+    Symlink(Vec<u8>),
+    Device(DeviceId),
+    Ipc,
+}
+
+/// An inode read from the inode table.
+#[derive(Clone)]
+pub struct Inode {
+    pub ino: u32,
+    pub node_type: NodeType,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    nlink: u32,
+    /// Index of the extended attribute set.
+    pub xattr: u32,
+    block_size: u32,
+    pub kind: Kind,
+}
+
+impl Inode {
+    pub fn read(fs: &SquashFs, reader: &mut Reader) -> VfsResult<Self> {
+        let ty = reader.u16()?;
+        let mode = reader.u16()?;
+        let uid = fs.id(reader.u16()?)?;
+        let gid = fs.id(reader.u16()?)?;
+        let mtime = reader.u32()?;
+        let ino = reader.u32()?;
+        if ino == 0 || ino > fs.sb.inode_count {
+            return Err(VfsError::InvalidData);
+        }
+        let basic = match ty {
+            TYPE_DIR..=TYPE_SOCKET => ty,
+            _ => ty
+                .checked_sub(TYPE_EXTENDED)
+                .filter(|it| (TYPE_DIR..=TYPE_SOCKET).contains(it))
+                .ok_or(VfsError::InvalidData)?,
+        };
+        let extended = ty != basic;
+
+        let mut xattr = NO_INDEX;
+        let mut nlink = 1;
+        let kind = match (basic, extended) {
+            (TYPE_DIR, false) => {
+                let block = reader.u32()?;
+                nlink = reader.u32()?;
+                let size = reader.u16()? as u32;
+                let offset = reader.u16()?;
+                let parent = reader.u32()?;
+                Kind::Dir(DirInfo {
+                    block,
+                    offset,
+                    size: size
+                        .checked_sub(DIR_SIZE_OFFSET)
+                        .ok_or(VfsError::InvalidData)?,
+                    parent,
+                    index: Vec::new(),
+                })
+            }
+            (TYPE_DIR, true) => {
+                nlink = reader.u32()?;
+                let size = reader.u32()?;
+                let block = reader.u32()?;
+                let parent = reader.u32()?;
+                let count = reader.u16()?;
+                let offset = reader.u16()?;
+                xattr = reader.u32()?;
+                let mut index = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let position = reader.u32()?;
+                    let block = reader.u32()?;
+                    let len = reader.u32()? + 1;
+                    if len > MAX_NAME_LEN {
+                        return Err(VfsError::InvalidData);
+                    }
+                    index.push(IndexEntry {
+                        index: position,
+                        block,
+                        name: reader.bytes(len as usize)?,
+                    });
+                }
+                Kind::Dir(DirInfo {
+                    block,
+                    offset,
+                    size: size
+                        .checked_sub(DIR_SIZE_OFFSET)
+                        .ok_or(VfsError::InvalidData)?,
+                    parent,
+                    index,
+                })
+            }
+            (TYPE_FILE, _) => {
+                let (start, size, fragment, offset);
+                if extended {
+                    start = reader.u64()?;
+                    size = reader.u64()?;
+                    let _sparse = reader.u64()?;
+                    nlink = reader.u32()?;
+                    fragment = reader.u32()?;
+                    offset = reader.u32()?;
+                    xattr = reader.u32()?;
+                } else {
+                    start = reader.u32()? as u64;
+                    fragment = reader.u32()?;
+                    offset = reader.u32()?;
+                    size = reader.u32()? as u64;
+                }
+                let block_size = fs.block_size() as u64;
+                let count = if fragment == NO_INDEX {
+                    size.div_ceil(block_size)
+                } else {
+                    size / block_size
+                };
+                // Each block has its size stored in the inode table.
+                if count * 4 > fs.sb.dir_table - fs.sb.inode_table {
+                    return Err(VfsError::InvalidData);
+                }
+                let mut blocks = Vec::with_capacity(count as usize);
+                let mut pos = start;
+                for _ in 0..count {
+                    let len = reader.u32()?;
+                    blocks.push((pos, len));
+                    pos += (len & !DATA_UNCOMPRESSED) as u64;
+                }
+                Kind::File(FileInfo {
+                    size,
+                    blocks,
+                    fragment: (fragment != NO_INDEX).then_some((fragment, offset)),
+                })
+            }
+            (TYPE_SYMLINK, _) => {
+                nlink = reader.u32()?;
+                let len = reader.u32()?;
+                if len > MAX_SYMLINK_LEN {
+                    return Err(VfsError::InvalidData);
+                }
+                let target = reader.bytes(len as usize)?;
+                if extended {
+                    xattr = reader.u32()?;
+                }
+                Kind::Symlink(target)
+            }
+            (TYPE_BLOCK_DEVICE | TYPE_CHAR_DEVICE, _) => {
+                nlink = reader.u32()?;
+                let dev = reader.u32()?;
+                if extended {
+                    xattr = reader.u32()?;
+                }
+                Kind::Device(DeviceId::new(
+                    (dev & 0xfff00) >> 8,
+                    (dev & 0xff) | ((dev >> 12) & 0xfff00),
+                ))
+            }
+            _ => {
+                nlink = reader.u32()?;
+                if extended {
+                    xattr = reader.u32()?;
+                }
+                Kind::Ipc
+            }
+        };
+        Ok(Self {
+            ino,
+            node_type: node_type_of(basic),
+            mode,
+            uid,
+            gid,
+            mtime,
+            nlink,
+            xattr,
+            block_size: fs.block_size(),
+            kind,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.kind {
+            Kind::Dir(dir) => (dir.size + DIR_SIZE_OFFSET) as u64,
+            Kind::File(file) => file.size,
+            Kind::Symlink(target) => target.len() as u64,
+            Kind::Device(_) | Kind::Ipc => 0,
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let rdev = match self.kind {
+            Kind::Device(rdev) => rdev,
+            _ => DeviceId::default(),
+        };
+        let blocks = match &self.kind {
+            Kind::File(file) => file.size.div_ceil(512),
+            _ => 0,
+        };
+        let time = Duration::from_secs(self.mtime as u64);
+        Metadata {
+            device: 0,
+            inode: self.ino as u64,
+            nlink: self.nlink as u64,
+            mode: NodePermission::from_bits_truncate(self.mode & 0o7777),
+            node_type: self.node_type,
+            uid: self.uid,
+            gid: self.gid,
+            size: self.size(),
+            block_size: self.block_size as u64,
+            blocks,
+            rdev,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        }
+    }
+}
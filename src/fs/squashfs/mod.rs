@@ -0,0 +1,1251 @@
+//! Read-only squashfs 4.0 filesystem.
+//!
+//! Blocks compressed with gzip, lz4 or zstd can be read when the cargo
+//! feature of the same name is enabled, and images using any other
+//! compressor are refused at mount time. Decompressed blocks are kept in a
+//! cache of bounded size shared by the whole filesystem.
+//!
+//! Directories are looked up through their index when they have one.
+
+mod cache;
+mod compress;
+mod dir;
+mod file;
+mod inode;
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use self::{
+    cache::{Block, BlockCache},
+    compress::Compressor,
+    inode::{Inode, Kind},
+};
+pub use self::{dir::SquashDir, file::SquashFile};
+use super::{RootDir, le16, le32, le64};
+use crate::{
+    DirEntry, DirNode, FileNode, FilesystemOps, Reference, StatFs, VfsError, VfsResult,
+    block::{BlockDevice, read_bytes},
+};
+
+const MAGIC: &[u8] = b"hsqs";
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+const DEFAULT_CACHE_CAPACITY: usize = 8 << 20;
+const MAX_NAME_LEN: u32 = 256;
+
+/// Size of the data of a metadata block.
+const METADATA_SIZE: usize = 8192;
+/// Flag of metadata block headers marking uncompressed blocks.
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Flag of data block sizes marking uncompressed blocks.
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+/// Index marking the absence of a fragment or of extended attributes.
+const NO_INDEX: u32 = u32::MAX;
+/// Position marking the absence of an optional table.
+const NO_TABLE: u64 = u64::MAX;
+
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+const XATTR_ID_ENTRY_SIZE: usize = 16;
+/// Flag of the type of extended attributes whose value is stored elsewhere.
+const XATTR_VALUE_OOL: u16 = 0x100;
+const XATTR_PREFIXES: [&str; 3] = ["user.", "trusted.", "security."];
+const XATTR_SIZE_MAX: usize = 65536;
+
+/// The fields of the superblock used by the driver.
+struct Superblock {
+    inode_count: u32,
+    block_size: u32,
+    fragment_count: u32,
+    compressor: Compressor,
+    id_count: u16,
+    root_inode: u64,
+    bytes_used: u64,
+    id_table: u64,
+    xattr_id_table: u64,
+    inode_table: u64,
+    dir_table: u64,
+    fragment_table: u64,
+}
+
+impl Superblock {
+    fn parse(sb: &[u8]) -> VfsResult<Self> {
+        if &sb[..4] != MAGIC || le16(sb, 28) != 4 {
+            return Err(VfsError::InvalidData);
+        }
+        let superblock = Self {
+            inode_count: le32(sb, 4),
+            block_size: le32(sb, 12),
+            fragment_count: le32(sb, 16),
+            compressor: Compressor::from_id(le16(sb, 20))?,
+            id_count: le16(sb, 26),
+            root_inode: le64(sb, 32),
+            bytes_used: le64(sb, 40),
+            id_table: le64(sb, 48),
+            xattr_id_table: le64(sb, 56),
+            inode_table: le64(sb, 64),
+            dir_table: le64(sb, 72),
+            fragment_table: le64(sb, 80),
+        };
+        if !superblock.block_size.is_power_of_two()
+            || !(4096..=1 << 20).contains(&superblock.block_size)
+            || le16(sb, 22) as u32 != superblock.block_size.trailing_zeros()
+            || superblock.inode_table > superblock.dir_table
+            || superblock.dir_table > superblock.bytes_used
+        {
+            return Err(VfsError::InvalidData);
+        }
+        if !superblock.compressor.is_enabled() {
+            return Err(VfsError::Unsupported);
+        }
+        Ok(superblock)
+    }
+}
+
+/// A reader of the stream of metadata blocks making up a table.
+struct Reader<'a> {
+    fs: &'a SquashFs,
+    data: Arc<[u8]>,
+    offset: usize,
+    /// Position of the next metadata block.
+    next: u64,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading at `offset` in the data of the metadata block at
+    /// `pos`.
+    fn new(fs: &'a SquashFs, pos: u64, offset: usize) -> VfsResult<Self> {
+        let (data, next) = fs.metadata_block(pos)?;
+        if offset > data.len() {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(Self {
+            fs,
+            data,
+            offset,
+            next,
+        })
+    }
+
+    fn read(&mut self, mut buf: &mut [u8]) -> VfsResult<()> {
+        while !buf.is_empty() {
+            if self.offset == self.data.len() {
+                (self.data, self.next) = self.fs.metadata_block(self.next)?;
+                self.offset = 0;
+            }
+            let len = buf.len().min(self.data.len() - self.offset);
+            buf[..len].copy_from_slice(&self.data[self.offset..self.offset + len]);
+            self.offset += len;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    fn bytes(&mut self, len: usize) -> VfsResult<Vec<u8>> {
+        let mut buf = alloc::vec![0; len];
+        self.read(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u16(&mut self) -> VfsResult<u16> {
+        let mut buf = [0; 2];
+        self.read(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u32(&mut self) -> VfsResult<u32> {
+        let mut buf = [0; 4];
+        self.read(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> VfsResult<u64> {
+        let mut buf = [0; 8];
+        self.read(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// The location of the extended attributes.
+struct XattrTable {
+    /// Start of the keys and values.
+    start: u64,
+    count: u32,
+    /// Metadata blocks of the table of attribute sets.
+    blocks: Vec<u64>,
+}
+
+/// A squashfs filesystem on a block device.
+pub struct SquashFs {
+    device: Arc<dyn BlockDevice>,
+    sb: Superblock,
+    cache: BlockCache,
+    ids: Vec<u32>,
+    /// Metadata blocks of the fragment table.
+    fragment_blocks: Vec<u64>,
+    xattrs: Option<XattrTable>,
+    /// The root directory, loaded when mounting.
+    root_node: Option<Inode>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl SquashFs {
+    /// Mounts the filesystem on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> VfsResult<Arc<Self>> {
+        Self::with_cache_capacity(device, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Mounts the filesystem on `device`, caching around `capacity` bytes
+    /// of decompressed blocks.
+    pub fn with_cache_capacity(
+        device: Arc<dyn BlockDevice>,
+        capacity: usize,
+    ) -> VfsResult<Arc<Self>> {
+        let sb = read_bytes(&*device, 0, SUPERBLOCK_SIZE)?.ok_or(VfsError::InvalidData)?;
+        let sb = Superblock::parse(&sb)?;
+        if sb.bytes_used > device.len() {
+            return Err(VfsError::InvalidData);
+        }
+        let mut fs = Self {
+            device,
+            sb,
+            cache: BlockCache::new(capacity),
+            ids: Vec::new(),
+            fragment_blocks: Vec::new(),
+            xattrs: None,
+            root_node: None,
+            this: Weak::new(),
+            root: RootDir::default(),
+        };
+
+        let id_count = fs.sb.id_count as usize;
+        let mut ids = Vec::with_capacity(id_count);
+        for pos in fs.table_blocks(fs.sb.id_table, id_count * 4)? {
+            let mut reader = Reader::new(&fs, pos, 0)?;
+            for _ in 0..(id_count - ids.len()).min(METADATA_SIZE / 4) {
+                ids.push(reader.u32()?);
+            }
+        }
+        fs.ids = ids;
+        if fs.sb.fragment_count != 0 {
+            let size = fs.sb.fragment_count as usize * FRAGMENT_ENTRY_SIZE;
+            fs.fragment_blocks = fs.table_blocks(fs.sb.fragment_table, size)?;
+        }
+        if fs.sb.xattr_id_table != NO_TABLE {
+            let header = fs.read_raw(fs.sb.xattr_id_table, 16)?;
+            let count = le32(&header, 8);
+            let size = count as usize * XATTR_ID_ENTRY_SIZE;
+            fs.xattrs = Some(XattrTable {
+                start: le64(&header, 0),
+                count,
+                blocks: fs.table_blocks(fs.sb.xattr_id_table + 16, size)?,
+            });
+        }
+
+        let inode = fs.inode(fs.sb.root_inode)?;
+        if !matches!(inode.kind, Kind::Dir(_)) {
+            return Err(VfsError::InvalidData);
+        }
+        fs.root_node = Some(inode);
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            ..fs
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let inode = self.root_node.clone().unwrap();
+        self.this.upgrade().unwrap().open(inode, Reference::root())
+    }
+
+    fn block_size(&self) -> u32 {
+        self.sb.block_size
+    }
+
+    /// Reads `len` bytes at `pos`, which must lie within the image.
+    fn read_raw(&self, pos: u64, len: usize) -> VfsResult<Vec<u8>> {
+        if pos.saturating_add(len as u64) > self.sb.bytes_used {
+            return Err(VfsError::InvalidData);
+        }
+        read_bytes(&*self.device, pos, len)?.ok_or(VfsError::InvalidData)
+    }
+
+    /// Reads the positions of the metadata blocks of a table of `size`
+    /// bytes, which are listed at `pos`.
+    fn table_blocks(&self, pos: u64, size: usize) -> VfsResult<Vec<u64>> {
+        let count = size.div_ceil(METADATA_SIZE);
+        let raw = self.read_raw(pos, count * 8)?;
+        Ok(raw.chunks_exact(8).map(|it| le64(it, 0)).collect())
+    }
+
+    /// Returns the data of the metadata block at `pos`, and the position of
+    /// the next one.
+    fn metadata_block(&self, pos: u64) -> VfsResult<Block> {
+        self.cache.get(pos, || {
+            let header = le16(&self.read_raw(pos, 2)?, 0);
+            let len = (header & !METADATA_UNCOMPRESSED) as usize;
+            if len == 0 || len > METADATA_SIZE {
+                return Err(VfsError::InvalidData);
+            }
+            let raw = self.read_raw(pos + 2, len)?;
+            let data = if header & METADATA_UNCOMPRESSED != 0 {
+                raw
+            } else {
+                self.sb.compressor.decompress(&raw, METADATA_SIZE)?
+            };
+            Ok((data.into(), pos + 2 + len as u64))
+        })
+    }
+
+    /// Returns the data of the data block at `pos`, whose size on disk is
+    /// given by `size`.
+    fn data_block(&self, pos: u64, size: u32) -> VfsResult<Arc<[u8]>> {
+        let (data, _) = self.cache.get(pos, || {
+            let len = (size & !DATA_UNCOMPRESSED) as usize;
+            if len > self.block_size() as usize {
+                return Err(VfsError::InvalidData);
+            }
+            let raw = self.read_raw(pos, len)?;
+            let data = if size & DATA_UNCOMPRESSED != 0 {
+                raw
+            } else {
+                self.sb
+                    .compressor
+                    .decompress(&raw, self.block_size() as usize)?
+            };
+            Ok((data.into(), pos + len as u64))
+        })?;
+        Ok(data)
+    }
+
+    /// Starts reading entry `index` of a table whose entries of `size`
+    /// bytes are stored in the metadata blocks `blocks`.
+    fn table_entry(&self, blocks: &[u64], index: u32, size: usize) -> VfsResult<Reader<'_>> {
+        let per_block = METADATA_SIZE / size;
+        let pos = *blocks
+            .get(index as usize / per_block)
+            .ok_or(VfsError::InvalidData)?;
+        Reader::new(self, pos, index as usize % per_block * size)
+    }
+
+    /// Returns the id at `index` in the id table.
+    fn id(&self, index: u16) -> VfsResult<u32> {
+        self.ids
+            .get(index as usize)
+            .copied()
+            .ok_or(VfsError::InvalidData)
+    }
+
+    /// Returns the block holding fragment `index`, as its position and size
+    /// on disk.
+    fn fragment(&self, index: u32) -> VfsResult<(u64, u32)> {
+        if index >= self.sb.fragment_count {
+            return Err(VfsError::InvalidData);
+        }
+        let mut reader = self.table_entry(&self.fragment_blocks, index, FRAGMENT_ENTRY_SIZE)?;
+        Ok((reader.u64()?, reader.u32()?))
+    }
+
+    /// Reads the inode referenced by `reference`, which holds the position
+    /// of its metadata block in the inode table and its offset in the block.
+    fn inode(&self, reference: u64) -> VfsResult<Inode> {
+        let mut reader = Reader::new(
+            self,
+            self.sb.inode_table + (reference >> 16),
+            (reference & 0xffff) as usize,
+        )?;
+        Inode::read(self, &mut reader)
+    }
+
+    fn open(self: &Arc<Self>, inode: Inode, reference: Reference) -> DirEntry {
+        let fs = self.clone();
+        if matches!(inode.kind, Kind::Dir(_)) {
+            return DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(SquashDir::new(fs, inode, this))),
+                reference,
+            );
+        }
+        let node_type = inode.node_type;
+        DirEntry::new_file(
+            FileNode::new(Arc::new(SquashFile::new(fs, inode))),
+            node_type,
+            reference,
+        )
+    }
+
+    /// Reads the extended attribute set `index`, as pairs of full names and
+    /// values.
+    fn xattrs(&self, index: u32) -> VfsResult<Vec<(String, Vec<u8>)>> {
+        if index == NO_INDEX {
+            return Ok(Vec::new());
+        }
+        let table = self.xattrs.as_ref().ok_or(VfsError::InvalidData)?;
+        if index >= table.count {
+            return Err(VfsError::InvalidData);
+        }
+        let mut reader = self.table_entry(&table.blocks, index, XATTR_ID_ENTRY_SIZE)?;
+        let (reference, count) = (reader.u64()?, reader.u32()?);
+
+        let at = |reference: u64| {
+            Reader::new(
+                self,
+                table.start + (reference >> 16),
+                (reference & 0xffff) as usize,
+            )
+        };
+        let read_value = |reader: &mut Reader, len: usize| {
+            if len > XATTR_SIZE_MAX {
+                return Err(VfsError::InvalidData);
+            }
+            reader.bytes(len)
+        };
+        let mut reader = at(reference)?;
+        let mut xattrs = Vec::with_capacity(count.min(256) as usize);
+        for _ in 0..count {
+            let kind = reader.u16()?;
+            let len = reader.u16()? as usize;
+            let prefix = XATTR_PREFIXES
+                .get((kind & !XATTR_VALUE_OOL) as usize)
+                .ok_or(VfsError::InvalidData)?;
+            let name = String::from_utf8_lossy(&reader.bytes(len)?).into_owned();
+            let len = reader.u32()? as usize;
+            let value = if kind & XATTR_VALUE_OOL != 0 {
+                if len != 8 {
+                    return Err(VfsError::InvalidData);
+                }
+                let mut value = at(reader.u64()?)?;
+                let len = value.u32()? as usize;
+                read_value(&mut value, len)?
+            } else {
+                read_value(&mut reader, len)?
+            };
+            xattrs.push((alloc::format!("{prefix}{name}"), value));
+        }
+        Ok(xattrs)
+    }
+
+    fn get_xattr(&self, index: u32, name: &str) -> VfsResult<Vec<u8>> {
+        self.xattrs(index)?
+            .into_iter()
+            .find_map(|(key, value)| (key == name).then_some(value))
+            .ok_or_else(|| axerrno::LinuxError::ENODATA.into())
+    }
+
+    fn list_xattr(&self, index: u32) -> VfsResult<Vec<String>> {
+        Ok(self
+            .xattrs(index)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+}
+
+impl FilesystemOps for SquashFs {
+    fn name(&self) -> &str {
+        "squashfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let block_size = self.block_size();
+        Ok(StatFs {
+            fs_type: SQUASHFS_MAGIC,
+            block_size,
+            blocks: self.sb.bytes_used.div_ceil(block_size as u64),
+            blocks_free: 0,
+            blocks_available: 0,
+            file_count: self.sb.inode_count as u64,
+            free_file_count: 0,
+            name_length: MAX_NAME_LEN,
+            fragment_size: block_size,
+            mount_flags: 0,
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "gzip", feature = "lz4", feature = "zstd")))]
+mod test {
+    use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        Filesystem, Location, MetadataUpdate, Mountpoint, NodeType, block::RamDisk, test_util::list,
+    };
+
+    const BLOCK_SIZE: usize = 4096;
+    const MTIME: u32 = 1_700_000_000;
+    /// Ids of the owners of the root directory and of the other nodes.
+    const IDS: [u32; 3] = [0, 1000, 100];
+
+    enum Content {
+        File(Vec<u8>),
+        Dir(Vec<Spec>),
+        Symlink(&'static str),
+        Device(u32, u32),
+        Fifo,
+    }
+
+    struct Spec {
+        name: String,
+        content: Content,
+        xattrs: Vec<(u16, &'static str, Vec<u8>)>,
+    }
+
+    fn spec(name: &str, content: Content) -> Spec {
+        Spec {
+            name: name.to_owned(),
+            content,
+            xattrs: Vec::new(),
+        }
+    }
+
+    impl Spec {
+        fn xattr(mut self, kind: u16, name: &'static str, value: &[u8]) -> Self {
+            self.xattrs.push((kind, name, value.to_vec()));
+            self
+        }
+
+        fn count(&self) -> u32 {
+            match &self.content {
+                Content::Dir(children) => 1 + children.iter().map(Spec::count).sum::<u32>(),
+                _ => 1,
+            }
+        }
+    }
+
+    /// Compresses `data` with compressor `id`, unless it does not shrink.
+    fn compress(id: u16, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match id {
+            #[cfg(feature = "gzip")]
+            1 => miniz_oxide::deflate::compress_to_vec_zlib(data, 6),
+            #[cfg(feature = "lz4")]
+            5 => lz4_flex::block::compress(data),
+            #[cfg(feature = "zstd")]
+            6 => {
+                ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+            }
+            _ => unreachable!(),
+        };
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+
+    /// A table made of metadata blocks.
+    struct Meta {
+        compressor: u16,
+        out: Vec<u8>,
+        cur: Vec<u8>,
+        /// Positions of the flushed blocks.
+        blocks: Vec<u64>,
+    }
+
+    impl Meta {
+        fn new(compressor: u16) -> Self {
+            Self {
+                compressor,
+                out: Vec::new(),
+                cur: Vec::new(),
+                blocks: Vec::new(),
+            }
+        }
+
+        /// Returns the position of the current block and the offset in it.
+        fn position(&self) -> (u32, u16) {
+            (self.out.len() as u32, self.cur.len() as u16)
+        }
+
+        fn reference(&self) -> u64 {
+            let (block, offset) = self.position();
+            (block as u64) << 16 | offset as u64
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            self.cur.extend_from_slice(data);
+            while self.cur.len() >= METADATA_SIZE {
+                let rest = self.cur.split_off(METADATA_SIZE);
+                self.flush();
+                self.cur = rest;
+            }
+        }
+
+        fn flush(&mut self) {
+            self.blocks.push(self.out.len() as u64);
+            let data = core::mem::take(&mut self.cur);
+            match compress(self.compressor, &data) {
+                Some(compressed) => {
+                    self.out
+                        .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+                    self.out.extend_from_slice(&compressed);
+                }
+                None => {
+                    let header = data.len() as u16 | METADATA_UNCOMPRESSED;
+                    self.out.extend_from_slice(&header.to_le_bytes());
+                    self.out.extend_from_slice(&data);
+                }
+            }
+        }
+
+        fn finish(mut self) -> (Vec<u8>, Vec<u64>) {
+            if !self.cur.is_empty() {
+                self.flush();
+            }
+            (self.out, self.blocks)
+        }
+    }
+
+    /// Positions in the listing and in the directory table of the headers
+    /// starting metadata blocks, with the first name following them.
+    type Index<'a> = Vec<(u32, u32, &'a [u8])>;
+
+    /// Builds squashfs images in the layout of mksquashfs.
+    struct Builder {
+        compressor: u16,
+        image: Vec<u8>,
+        inodes: Meta,
+        dirs: Meta,
+        /// Tails of files waiting to be written as a fragment block.
+        fragment: Vec<u8>,
+        fragments: Vec<(u64, u32)>,
+        xattrs: Vec<Vec<(u16, &'static str, Vec<u8>)>>,
+        next_ino: u32,
+        inode_count: u32,
+    }
+
+    impl Builder {
+        fn build(compressor: u16, root: Spec) -> Vec<u8> {
+            let mut builder = Self {
+                compressor,
+                image: vec![0; SUPERBLOCK_SIZE],
+                inodes: Meta::new(compressor),
+                dirs: Meta::new(compressor),
+                fragment: Vec::new(),
+                fragments: Vec::new(),
+                xattrs: Vec::new(),
+                next_ino: 2,
+                inode_count: root.count(),
+            };
+            let count = builder.inode_count;
+            let (root_ref, _) = builder.write(&root, 1, count + 1);
+            builder.flush_fragment();
+            builder.finish(root_ref)
+        }
+
+        /// Writes a data block, returning its size on disk.
+        fn write_block(&mut self, data: &[u8]) -> u32 {
+            match compress(self.compressor, data) {
+                Some(compressed) => {
+                    self.image.extend_from_slice(&compressed);
+                    compressed.len() as u32
+                }
+                None => {
+                    self.image.extend_from_slice(data);
+                    data.len() as u32 | DATA_UNCOMPRESSED
+                }
+            }
+        }
+
+        fn flush_fragment(&mut self) {
+            if !self.fragment.is_empty() {
+                let pos = self.image.len() as u64;
+                let data = core::mem::take(&mut self.fragment);
+                let size = self.write_block(&data);
+                self.fragments.push((pos, size));
+            }
+        }
+
+        fn header(&mut self, ty: u16, spec: &Spec, ino: u32, root: bool) -> bool {
+            let id = if root { 0u16 } else { 1 };
+            let mode: u16 = match spec.content {
+                Content::Dir(_) => 0o755,
+                _ => 0o644,
+            };
+            let extended = !spec.xattrs.is_empty() || ty > 7;
+            let ty = if extended && ty <= 7 { ty + 7 } else { ty };
+            let mut raw = Vec::new();
+            raw.extend_from_slice(&ty.to_le_bytes());
+            raw.extend_from_slice(&mode.to_le_bytes());
+            raw.extend_from_slice(&id.to_le_bytes());
+            raw.extend_from_slice(&(id * 2).to_le_bytes());
+            raw.extend_from_slice(&MTIME.to_le_bytes());
+            raw.extend_from_slice(&ino.to_le_bytes());
+            self.inodes.write(&raw);
+            extended
+        }
+
+        fn xattr_index(&mut self, spec: &Spec) -> u32 {
+            if spec.xattrs.is_empty() {
+                return NO_INDEX;
+            }
+            self.xattrs.push(spec.xattrs.clone());
+            self.xattrs.len() as u32 - 1
+        }
+
+        /// Writes the node `spec` as inode `ino`, returning the reference of
+        /// its inode and its basic type.
+        fn write(&mut self, spec: &Spec, ino: u32, parent: u32) -> (u64, u16) {
+            let xattr = self.xattr_index(spec);
+            let mut inode = Vec::new();
+            let (ty, reference) = match &spec.content {
+                Content::File(data) => {
+                    let start = self.image.len() as u64;
+                    let full = data.len() / BLOCK_SIZE * BLOCK_SIZE;
+                    let mut sizes = Vec::new();
+                    for block in data[..full].chunks(BLOCK_SIZE) {
+                        if block.iter().all(|&it| it == 0) {
+                            sizes.push(0);
+                        } else {
+                            sizes.push(self.write_block(block));
+                        }
+                    }
+                    let tail = &data[full..];
+                    let (fragment, offset) = if tail.is_empty() {
+                        (NO_INDEX, 0)
+                    } else {
+                        if self.fragment.len() + tail.len() > BLOCK_SIZE {
+                            self.flush_fragment();
+                        }
+                        let offset = self.fragment.len() as u32;
+                        self.fragment.extend_from_slice(tail);
+                        (self.fragments.len() as u32, offset)
+                    };
+                    let reference = self.inodes.reference();
+                    if self.header(2, spec, ino, false) {
+                        inode.extend_from_slice(&start.to_le_bytes());
+                        inode.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                        inode.extend_from_slice(&0u64.to_le_bytes());
+                        inode.extend_from_slice(&1u32.to_le_bytes());
+                        inode.extend_from_slice(&fragment.to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&xattr.to_le_bytes());
+                    } else {
+                        inode.extend_from_slice(&(start as u32).to_le_bytes());
+                        inode.extend_from_slice(&fragment.to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    }
+                    for size in sizes {
+                        inode.extend_from_slice(&size.to_le_bytes());
+                    }
+                    (2, reference)
+                }
+                Content::Dir(children) => {
+                    let mut children: Vec<_> = children.iter().collect();
+                    children.sort_by(|a, b| a.name.cmp(&b.name));
+                    let first = self.next_ino;
+                    self.next_ino += children.len() as u32;
+                    let entries: Vec<_> = children
+                        .iter()
+                        .enumerate()
+                        .map(|(i, child)| {
+                            let (reference, ty) = self.write(child, first + i as u32, ino);
+                            (child.name.as_bytes(), reference, first + i as u32, ty)
+                        })
+                        .collect();
+                    let (block, offset) = self.dirs.position();
+                    let (size, index) = self.write_listing(&entries);
+                    let nlink = 2 + children
+                        .iter()
+                        .filter(|it| matches!(it.content, Content::Dir(_)))
+                        .count() as u32;
+
+                    let reference = self.inodes.reference();
+                    let root = ino == 1;
+                    let ty = if index.is_empty() { 1 } else { 8 };
+                    if self.header(ty, spec, ino, root) {
+                        inode.extend_from_slice(&nlink.to_le_bytes());
+                        inode.extend_from_slice(&(size + 3).to_le_bytes());
+                        inode.extend_from_slice(&block.to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                        inode.extend_from_slice(&(index.len() as u16).to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&xattr.to_le_bytes());
+                        for (position, block, name) in index {
+                            inode.extend_from_slice(&position.to_le_bytes());
+                            inode.extend_from_slice(&block.to_le_bytes());
+                            inode.extend_from_slice(&(name.len() as u32 - 1).to_le_bytes());
+                            inode.extend_from_slice(name);
+                        }
+                    } else {
+                        inode.extend_from_slice(&block.to_le_bytes());
+                        inode.extend_from_slice(&nlink.to_le_bytes());
+                        inode.extend_from_slice(&(size as u16 + 3).to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                    }
+                    (1, reference)
+                }
+                Content::Symlink(target) => {
+                    let reference = self.inodes.reference();
+                    let extended = self.header(3, spec, ino, false);
+                    inode.extend_from_slice(&1u32.to_le_bytes());
+                    inode.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                    inode.extend_from_slice(target.as_bytes());
+                    if extended {
+                        inode.extend_from_slice(&xattr.to_le_bytes());
+                    }
+                    (3, reference)
+                }
+                &Content::Device(major, minor) => {
+                    let reference = self.inodes.reference();
+                    let extended = self.header(5, spec, ino, false);
+                    let dev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+                    inode.extend_from_slice(&1u32.to_le_bytes());
+                    inode.extend_from_slice(&dev.to_le_bytes());
+                    if extended {
+                        inode.extend_from_slice(&xattr.to_le_bytes());
+                    }
+                    (5, reference)
+                }
+                Content::Fifo => {
+                    let reference = self.inodes.reference();
+                    let extended = self.header(6, spec, ino, false);
+                    inode.extend_from_slice(&1u32.to_le_bytes());
+                    if extended {
+                        inode.extend_from_slice(&xattr.to_le_bytes());
+                    }
+                    (6, reference)
+                }
+            };
+            self.inodes.write(&inode);
+            (reference, ty)
+        }
+
+        /// Writes the listing of a directory, returning its size and its
+        /// index.
+        fn write_listing<'a>(&mut self, entries: &[(&'a [u8], u64, u32, u16)]) -> (u32, Index<'a>) {
+            let mut size = 0;
+            let mut index = Vec::new();
+            let mut last_block = self.dirs.position().0;
+            let mut i = 0;
+            while i < entries.len() {
+                // A header starts at each new metadata block of the
+                // directory or of the inodes, and every 256 entries.
+                let block = self.dirs.position().0;
+                let (_, first_ref, first_ino, _) = entries[i];
+                let count = entries[i..]
+                    .iter()
+                    .take(256)
+                    .take_while(|it| it.1 >> 16 == first_ref >> 16)
+                    .scan(self.dirs.cur.len() + 12, |len, it| {
+                        *len += 8 + it.0.len();
+                        Some(*len)
+                    })
+                    .take_while(|&len| len <= METADATA_SIZE)
+                    .count()
+                    .max(1);
+                if block != last_block {
+                    index.push((size, block, entries[i].0));
+                    last_block = block;
+                }
+                let mut raw = Vec::new();
+                raw.extend_from_slice(&(count as u32 - 1).to_le_bytes());
+                raw.extend_from_slice(&((first_ref >> 16) as u32).to_le_bytes());
+                raw.extend_from_slice(&first_ino.to_le_bytes());
+                for &(name, reference, ino, ty) in &entries[i..i + count] {
+                    raw.extend_from_slice(&(reference as u16).to_le_bytes());
+                    raw.extend_from_slice(&((ino - first_ino) as u16).to_le_bytes());
+                    raw.extend_from_slice(&ty.to_le_bytes());
+                    raw.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+                    raw.extend_from_slice(name);
+                }
+                self.dirs.write(&raw);
+                size += raw.len() as u32;
+                i += count;
+            }
+            (size, index)
+        }
+
+        fn finish(mut self, root_ref: u64) -> Vec<u8> {
+            let (inodes, _) = core::mem::replace(&mut self.inodes, Meta::new(0)).finish();
+            let (dirs, _) = core::mem::replace(&mut self.dirs, Meta::new(0)).finish();
+            let inode_table = self.image.len() as u64;
+            self.image.extend_from_slice(&inodes);
+            let dir_table = self.image.len() as u64;
+            self.image.extend_from_slice(&dirs);
+
+            let mut fragments = Meta::new(self.compressor);
+            for &(pos, size) in &self.fragments {
+                fragments.write(&pos.to_le_bytes());
+                fragments.write(&size.to_le_bytes());
+                fragments.write(&0u32.to_le_bytes());
+            }
+            let fragment_table = self.table(fragments);
+            let mut ids = Meta::new(self.compressor);
+            for id in IDS {
+                ids.write(&id.to_le_bytes());
+            }
+            let id_table = self.table(ids);
+
+            let xattr_table = self.image.len() as u64;
+            let mut values = Meta::new(self.compressor);
+            let mut sets = Vec::new();
+            for xattrs in &self.xattrs {
+                // Long values are stored before the set, and referenced
+                // from it.
+                let mut long = Vec::new();
+                for (_, _, value) in xattrs {
+                    if value.len() > 100 {
+                        long.push(values.reference());
+                        values.write(&(value.len() as u32).to_le_bytes());
+                        values.write(value);
+                    }
+                }
+                let mut long = long.into_iter();
+                let reference = values.reference();
+                let start = values.out.len() + values.cur.len();
+                for (kind, name, value) in xattrs {
+                    let ool = value.len() > 100;
+                    let kind = if ool { kind | XATTR_VALUE_OOL } else { *kind };
+                    values.write(&kind.to_le_bytes());
+                    values.write(&(name.len() as u16).to_le_bytes());
+                    values.write(name.as_bytes());
+                    if ool {
+                        values.write(&8u32.to_le_bytes());
+                        values.write(&long.next().unwrap().to_le_bytes());
+                    } else {
+                        values.write(&(value.len() as u32).to_le_bytes());
+                        values.write(value);
+                    }
+                }
+                let size = values.out.len() + values.cur.len() - start;
+                sets.push((reference, xattrs.len() as u32, size as u32));
+            }
+            let (values, _) = values.finish();
+            self.image.extend_from_slice(&values);
+            let mut ids = Meta::new(self.compressor);
+            for (reference, count, size) in sets {
+                ids.write(&reference.to_le_bytes());
+                ids.write(&count.to_le_bytes());
+                ids.write(&size.to_le_bytes());
+            }
+            let (ids, blocks) = ids.finish();
+            let ids_start = self.image.len() as u64;
+            self.image.extend_from_slice(&ids);
+            let xattr_id_table = self.image.len() as u64;
+            self.image.extend_from_slice(&xattr_table.to_le_bytes());
+            self.image
+                .extend_from_slice(&(self.xattrs.len() as u32).to_le_bytes());
+            self.image.extend_from_slice(&0u32.to_le_bytes());
+            for block in blocks {
+                self.image
+                    .extend_from_slice(&(ids_start + block).to_le_bytes());
+            }
+
+            let bytes_used = self.image.len() as u64;
+            self.image
+                .resize(bytes_used.next_multiple_of(4096) as usize, 0);
+            let sb = &mut self.image[..SUPERBLOCK_SIZE];
+            sb[..4].copy_from_slice(MAGIC);
+            sb[4..8].copy_from_slice(&self.inode_count.to_le_bytes());
+            sb[8..12].copy_from_slice(&MTIME.to_le_bytes());
+            sb[12..16].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+            sb[16..20].copy_from_slice(&(self.fragments.len() as u32).to_le_bytes());
+            sb[20..22].copy_from_slice(&self.compressor.to_le_bytes());
+            sb[22..24].copy_from_slice(&(BLOCK_SIZE.trailing_zeros() as u16).to_le_bytes());
+            sb[26..28].copy_from_slice(&(IDS.len() as u16).to_le_bytes());
+            sb[28..30].copy_from_slice(&4u16.to_le_bytes());
+            sb[32..40].copy_from_slice(&root_ref.to_le_bytes());
+            sb[40..48].copy_from_slice(&bytes_used.to_le_bytes());
+            sb[48..56].copy_from_slice(&id_table.to_le_bytes());
+            sb[56..64].copy_from_slice(&xattr_id_table.to_le_bytes());
+            sb[64..72].copy_from_slice(&inode_table.to_le_bytes());
+            sb[72..80].copy_from_slice(&dir_table.to_le_bytes());
+            sb[80..88].copy_from_slice(&fragment_table.to_le_bytes());
+            sb[88..96].copy_from_slice(&NO_TABLE.to_le_bytes());
+            self.image
+        }
+
+        /// Writes a lookup table, returning the position of the list of its
+        /// metadata blocks.
+        fn table(&mut self, meta: Meta) -> u64 {
+            let (data, blocks) = meta.finish();
+            let start = self.image.len() as u64;
+            self.image.extend_from_slice(&data);
+            let pos = self.image.len() as u64;
+            for block in blocks {
+                self.image.extend_from_slice(&(start + block).to_le_bytes());
+            }
+            pos
+        }
+    }
+
+    fn compressors() -> Vec<u16> {
+        let mut compressors = Vec::new();
+        if cfg!(feature = "gzip") {
+            compressors.push(1);
+        }
+        if cfg!(feature = "lz4") {
+            compressors.push(5);
+        }
+        if cfg!(feature = "zstd") {
+            compressors.push(6);
+        }
+        compressors
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Bytes that do not compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn big_name(i: usize) -> String {
+        alloc::format!("file-with-a-long-name-{i:04}")
+    }
+
+    fn tree() -> Spec {
+        let big = (0..600)
+            .map(|i| {
+                spec(
+                    &big_name(i),
+                    Content::File(alloc::format!("{i}").into_bytes()),
+                )
+            })
+            .collect();
+        let mut sparse = vec![0; 2 * BLOCK_SIZE];
+        sparse.extend_from_slice(b"end");
+        spec(
+            "",
+            Content::Dir(vec![
+                spec("small.txt", Content::File(b"hello, squashfs\n".to_vec()))
+                    .xattr(0, "comment", b"hi")
+                    .xattr(2, "selinux", &[b's'; 200]),
+                spec("data.bin", Content::File(pattern(3 * BLOCK_SIZE + 100))),
+                spec("noise.bin", Content::File(noise(BLOCK_SIZE + 10))),
+                spec("exact.bin", Content::File(pattern(BLOCK_SIZE))),
+                spec("sparse", Content::File(sparse)),
+                spec("empty", Content::File(Vec::new())),
+                spec("big", Content::Dir(big)),
+                spec(
+                    "dir",
+                    Content::Dir(vec![spec(
+                        "sub",
+                        Content::Dir(vec![spec("deep", Content::File(b"deep\n".to_vec()))]),
+                    )]),
+                )
+                .xattr(1, "x", b"y"),
+                spec("link", Content::Symlink("small.txt")),
+                spec("null", Content::Device(1, 3)),
+                spec("fifo", Content::Fifo),
+            ]),
+        )
+    }
+
+    fn mount(image: Vec<u8>) -> (Arc<SquashFs>, Location) {
+        let fs = SquashFs::new(Arc::new(RamDisk::from_vec(image))).unwrap();
+        let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+        (fs, root)
+    }
+
+    fn read_all(file: &Location) -> Vec<u8> {
+        let file = file.entry().as_file().unwrap();
+        let mut buf = vec![0; file.len().unwrap() as usize];
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), buf.len());
+        buf
+    }
+
+    fn resolve(root: &Location, path: &str) -> Location {
+        path.split('/').fold(root.clone(), |dir, name| {
+            dir.lookup_no_follow(name).unwrap()
+        })
+    }
+
+    #[test]
+    fn test_squashfs() {
+        for compressor in compressors() {
+            let (_fs, root) = mount(Builder::build(compressor, tree()));
+            let mut names = list(&root);
+            names.sort();
+            assert_eq!(
+                names,
+                [
+                    ".",
+                    "..",
+                    "big",
+                    "data.bin",
+                    "dir",
+                    "empty",
+                    "exact.bin",
+                    "fifo",
+                    "link",
+                    "noise.bin",
+                    "null",
+                    "small.txt",
+                    "sparse"
+                ]
+            );
+
+            assert_eq!(read_all(&resolve(&root, "small.txt")), b"hello, squashfs\n");
+            assert_eq!(
+                read_all(&resolve(&root, "data.bin")),
+                pattern(3 * BLOCK_SIZE + 100)
+            );
+            assert_eq!(
+                read_all(&resolve(&root, "noise.bin")),
+                noise(BLOCK_SIZE + 10)
+            );
+            assert_eq!(read_all(&resolve(&root, "exact.bin")), pattern(BLOCK_SIZE));
+            assert!(read_all(&resolve(&root, "empty")).is_empty());
+            let sparse = read_all(&resolve(&root, "sparse"));
+            assert!(sparse[..2 * BLOCK_SIZE].iter().all(|&it| it == 0));
+            assert_eq!(&sparse[2 * BLOCK_SIZE..], b"end");
+            assert_eq!(read_all(&resolve(&root, "dir/sub/deep")), b"deep\n");
+
+            // Reads crossing blocks and ending in the fragment.
+            let file = resolve(&root, "data.bin");
+            let mut buf = [0; 200];
+            let offset = 3 * BLOCK_SIZE - 50;
+            assert_eq!(
+                file.entry()
+                    .as_file()
+                    .unwrap()
+                    .read_at(&mut buf, offset as u64)
+                    .unwrap(),
+                150
+            );
+            assert_eq!(&buf[..150], &pattern(3 * BLOCK_SIZE + 100)[offset..]);
+
+            assert_eq!(resolve(&root, "link").read_link().unwrap(), "small.txt");
+            let null = resolve(&root, "null").metadata().unwrap();
+            assert_eq!(null.node_type, NodeType::CharacterDevice);
+            assert_eq!((null.rdev.major(), null.rdev.minor()), (1, 3));
+            assert_eq!(
+                resolve(&root, "fifo").metadata().unwrap().node_type,
+                NodeType::Fifo
+            );
+
+            let big = resolve(&root, "big");
+            assert_eq!(list(&big).len(), 602);
+            for i in [0, 57, 311, 599] {
+                assert_eq!(
+                    read_all(&big.lookup_no_follow(&big_name(i)).unwrap()),
+                    alloc::format!("{i}").as_bytes()
+                );
+            }
+            for name in ["file-with-a-long-name-0600", "a", "zzz"] {
+                assert_eq!(big.lookup_no_follow(name).err(), Some(VfsError::NotFound));
+            }
+
+            let dir = resolve(&root, "dir");
+            assert_eq!(resolve(&dir, "sub/..").inode(), dir.inode());
+            assert_eq!(
+                root.create("new", NodeType::RegularFile, Default::default())
+                    .err(),
+                Some(VfsError::ReadOnlyFilesystem)
+            );
+            assert_eq!(
+                dir.update_metadata(MetadataUpdate::default()).err(),
+                Some(VfsError::ReadOnlyFilesystem)
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_dir_offsets() {
+        for compressor in compressors() {
+            let (_fs, root) = mount(Builder::build(compressor, tree()));
+            let big = resolve(&root, "big");
+            for dir in [root.clone(), big] {
+                let all = list(&dir);
+                // Resuming from every offset yields the remaining entries.
+                let mut offsets = Vec::new();
+                dir.read_dir(0, &mut |_: &str, _, _, next| {
+                    offsets.push(next);
+                    true
+                })
+                .unwrap();
+                for (i, &offset) in offsets.iter().enumerate() {
+                    let mut rest: Vec<String> = Vec::new();
+                    dir.read_dir(offset, &mut |name: &str, _, _, _| {
+                        rest.push(name.into());
+                        false
+                    })
+                    .unwrap();
+                    assert_eq!(rest.first(), all.get(i + 1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        for compressor in compressors() {
+            let image = Builder::build(compressor, tree());
+            let (fs, root) = mount(image.clone());
+            let meta = resolve(&root, "small.txt").metadata().unwrap();
+            assert_eq!(meta.size, 16);
+            assert_eq!(meta.nlink, 1);
+            assert_eq!(meta.mode.bits(), 0o644);
+            assert_eq!((meta.uid, meta.gid), (1000, 100));
+            assert_eq!(meta.mtime.as_secs(), MTIME as u64);
+            let meta = root.metadata().unwrap();
+            assert_eq!((meta.uid, meta.gid, meta.nlink), (0, 0, 4));
+            assert_eq!(fs.stat().unwrap().fs_type, 0x7371_7368);
+
+            let small = resolve(&root, "small.txt");
+            assert_eq!(
+                small.list_xattr().unwrap(),
+                ["user.comment", "security.selinux"]
+            );
+            assert_eq!(small.get_xattr("user.comment").unwrap(), b"hi");
+            assert_eq!(
+                small.get_xattr("security.selinux").unwrap(),
+                vec![b's'; 200]
+            );
+            assert_eq!(
+                small.get_xattr("user.missing").err(),
+                Some(axerrno::LinuxError::ENODATA.into())
+            );
+            let dir = resolve(&root, "dir");
+            assert_eq!(dir.list_xattr().unwrap(), ["trusted.x"]);
+            assert_eq!(dir.get_xattr("trusted.x").unwrap(), b"y");
+            assert_eq!(resolve(&root, "big").list_xattr().unwrap(), [""; 0]);
+
+            // Compressors without their feature are refused.
+            let mut image = image;
+            image[20] = 4;
+            assert_eq!(
+                SquashFs::new(Arc::new(RamDisk::from_vec(image))).err(),
+                Some(VfsError::Unsupported)
+            );
+        }
+    }
+
+    #[test]
+    fn test_bounded_cache() {
+        for compressor in compressors() {
+            let image = Builder::build(compressor, tree());
+            let capacity = 3 * METADATA_SIZE;
+            let fs = SquashFs::with_cache_capacity(Arc::new(RamDisk::from_vec(image)), capacity)
+                .unwrap();
+            let root = Mountpoint::new_root(&Filesystem::new(fs.clone())).root_location();
+            let big = resolve(&root, "big");
+            for i in 0..600 {
+                let file = big.lookup_no_follow(&big_name(i)).unwrap();
+                assert_eq!(read_all(&file), alloc::format!("{i}").as_bytes());
+            }
+            assert_eq!(
+                read_all(&resolve(&root, "data.bin")),
+                pattern(3 * BLOCK_SIZE + 100)
+            );
+            assert!(fs.cache.size() <= capacity);
+        }
+    }
+}
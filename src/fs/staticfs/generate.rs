@@ -0,0 +1,90 @@
+//! Generator of the source of static trees.
+//!
+//! It only depends on `std`, so that build scripts, this crate's included,
+//! can pull it in with `#[path]` as well.
+
+use std::{
+    fmt::Write,
+    format, fs,
+    io::{self, Error, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    string::String,
+    vec::Vec,
+};
+
+/// Generates Rust source for a [`StaticNode`](super::StaticNode) tree
+/// mirroring the host directory `root`, meant to be written from a build
+/// script and pulled in with `include!`.
+///
+/// The expression refers to this crate as `::axfs_ng_vfs` and embeds file
+/// contents with `include_bytes!` on absolute paths. Inode numbers are
+/// assigned in depth-first order, starting from 1 for the root. Special files
+/// are rejected since they can not be represented.
+pub fn generate(root: &Path) -> io::Result<String> {
+    fn node(out: &mut String, path: &Path, name: &str, ino: &mut u64) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        *ino += 1;
+        write!(
+            out,
+            "::axfs_ng_vfs::staticfs::StaticNode {{ name: {name:?}, ino: {ino}, mode: {:#o}, \
+             mtime: {}, kind: ",
+            metadata.mode() & 0o7777,
+            metadata.mtime().max(0)
+        )
+        .unwrap();
+        if file_type.is_dir() {
+            let mut children = fs::read_dir(path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    let name = entry.file_name().into_string().map_err(|_| {
+                        Error::new(ErrorKind::InvalidData, "file name is not valid UTF-8")
+                    })?;
+                    Ok((name, entry.path()))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            children.sort_unstable();
+            out.push_str("::axfs_ng_vfs::staticfs::StaticKind::Dir(&[");
+            for (name, path) in children {
+                node(out, &path, &name, ino)?;
+                out.push_str(", ");
+            }
+            out.push_str("])");
+        } else if file_type.is_file() {
+            let path = fs::canonicalize(path)?;
+            let path = path
+                .to_str()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "path is not valid UTF-8"))?;
+            write!(
+                out,
+                "::axfs_ng_vfs::staticfs::StaticKind::File(include_bytes!({path:?}))"
+            )
+            .unwrap();
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target.to_str().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "symlink target is not valid UTF-8")
+            })?;
+            write!(
+                out,
+                "::axfs_ng_vfs::staticfs::StaticKind::Symlink({target:?})"
+            )
+            .unwrap();
+        } else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported file type: {}", path.display()),
+            ));
+        }
+        out.push_str(" }");
+        Ok(())
+    }
+
+    if !fs::metadata(root)?.is_dir() {
+        return Err(Error::from(ErrorKind::NotADirectory));
+    }
+    let mut out = String::new();
+    node(&mut out, root, "", &mut 0)?;
+    Ok(out)
+}
@@ -0,0 +1,563 @@
+//! Read-only filesystem serving a tree embedded in the binary.
+//!
+//! The tree is made of `&'static` [`StaticNode`]s, usually generated at build
+//! time from a host directory with [`generate`]:
+//!
+//! ```ignore
+//! // build.rs
+//! let code = axfs_ng_vfs::staticfs::generate("rootfs".as_ref()).unwrap();
+//! std::fs::write(out_dir.join("rootfs.rs"), code).unwrap();
+//! println!("cargo:rerun-if-changed=rootfs");
+//!
+//! // in the crate
+//! static ROOTFS: StaticNode = include!(concat!(env!("OUT_DIR"), "/rootfs.rs"));
+//! let fs = StaticFs::new(&ROOTFS)?;
+//! ```
+//!
+//! File contents are read straight from the embedded slices, and nothing of
+//! the tree is copied. The entries of all nodes are built along with the root
+//! and kept in the dentry cache, so that lookups do not allocate. Should the
+//! cache be dropped, as on unmounting, a lookup is a binary search over the
+//! sorted children that builds the entries of the node found again.
+
+#[cfg(any(test, feature = "std"))]
+mod generate;
+
+use alloc::{
+    borrow::ToOwned,
+    sync::{Arc, Weak},
+};
+use core::{any::Any, task::Context, time::Duration};
+
+use axpoll::{IoEvents, Pollable};
+
+use super::RootDir;
+use crate::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, NodeOps, NodePermission, NodeType, Reference, StatFs, VfsError,
+    VfsResult, WeakDirEntry,
+    path::{DOT, DOTDOT, MAX_NAME_LEN},
+};
+
+#[cfg(any(test, feature = "std"))]
+pub use self::generate::generate;
+
+/// A node of an embedded tree.
+#[derive(Debug)]
+pub struct StaticNode {
+    /// Name of the node, empty for the root.
+    pub name: &'static str,
+    /// Inode number, unique within the tree.
+    pub ino: u64,
+    /// Permission bits.
+    pub mode: u16,
+    /// Modification time, in seconds since the epoch.
+    pub mtime: u64,
+    pub kind: StaticKind,
+}
+
+/// Contents of a [`StaticNode`].
+#[derive(Debug)]
+pub enum StaticKind {
+    /// A directory, whose children must be sorted by name.
+    Dir(&'static [StaticNode]),
+    /// A regular file.
+    File(&'static [u8]),
+    /// A symbolic link and its target.
+    Symlink(&'static str),
+}
+
+impl StaticNode {
+    fn node_type(&self) -> NodeType {
+        match self.kind {
+            StaticKind::Dir(_) => NodeType::Directory,
+            StaticKind::File(_) => NodeType::RegularFile,
+            StaticKind::Symlink(_) => NodeType::Symlink,
+        }
+    }
+
+    fn data(&self) -> &'static [u8] {
+        match self.kind {
+            StaticKind::Dir(_) => &[],
+            StaticKind::File(data) => data,
+            StaticKind::Symlink(target) => target.as_bytes(),
+        }
+    }
+
+    fn children(&self) -> &'static [StaticNode] {
+        match self.kind {
+            StaticKind::Dir(children) => children,
+            _ => &[],
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let size = self.data().len() as u64;
+        let nlink = match self.kind {
+            StaticKind::Dir(children) => {
+                2 + children
+                    .iter()
+                    .filter(|it| matches!(it.kind, StaticKind::Dir(_)))
+                    .count() as u64
+            }
+            _ => 1,
+        };
+        let mtime = Duration::from_secs(self.mtime);
+        Metadata {
+            device: 0,
+            inode: self.ino,
+            nlink,
+            mode: NodePermission::from_bits_truncate(self.mode),
+            node_type: self.node_type(),
+            uid: 0,
+            gid: 0,
+            size,
+            block_size: 512,
+            blocks: size.div_ceil(512),
+            rdev: DeviceId::default(),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+        }
+    }
+}
+
+/// A read-only filesystem over an embedded tree.
+pub struct StaticFs {
+    tree: &'static StaticNode,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl StaticFs {
+    /// Creates a filesystem serving the tree rooted at `tree`.
+    ///
+    /// Returns `InvalidInput` if the children of a directory are not sorted
+    /// by name without duplicates, as lookups rely on it.
+    pub fn new(tree: &'static StaticNode) -> VfsResult<Arc<Self>> {
+        fn is_sorted(node: &StaticNode) -> bool {
+            let children = node.children();
+            children.windows(2).all(|it| it[0].name < it[1].name) && children.iter().all(is_sorted)
+        }
+
+        if !matches!(tree.kind, StaticKind::Dir(_)) {
+            return Err(VfsError::NotADirectory);
+        }
+        if !is_sorted(tree) {
+            return Err(VfsError::InvalidInput);
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            tree,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        let fs = self.this.upgrade().unwrap();
+        new_entry(&fs, self.tree, self.tree, Reference::root())
+    }
+}
+
+/// Builds the entry of `node`, a child of `parent`, and puts the entries of
+/// its descendants in the dentry cache.
+fn new_entry(
+    fs: &Arc<StaticFs>,
+    node: &'static StaticNode,
+    parent: &'static StaticNode,
+    reference: Reference,
+) -> DirEntry {
+    let StaticKind::Dir(children) = node.kind else {
+        return DirEntry::new_file(
+            FileNode::new(Arc::new(StaticFile {
+                fs: fs.clone(),
+                node,
+            })),
+            node.node_type(),
+            reference,
+        );
+    };
+    let entry = DirEntry::new_dir(
+        |this| {
+            DirNode::new(Arc::new(StaticDir {
+                fs: fs.clone(),
+                node,
+                parent,
+                this,
+            }))
+        },
+        reference,
+    );
+    let dir = entry.as_dir().unwrap();
+    for child in children {
+        let reference = Reference::new(Some(entry.clone()), child.name.to_owned());
+        dir.insert_cache(child.name.to_owned(), new_entry(fs, child, node, reference));
+    }
+    entry
+}
+
+impl FilesystemOps for StaticFs {
+    fn name(&self) -> &str {
+        "staticfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        fn count(node: &StaticNode) -> (u64, u64) {
+            node.children()
+                .iter()
+                .map(count)
+                .fold((1, node.metadata().blocks), |acc, it| {
+                    (acc.0 + it.0, acc.1 + it.1)
+                })
+        }
+        let (files, blocks) = count(self.tree);
+        Ok(StatFs {
+            fs_type: 0,
+            block_size: 512,
+            blocks,
+            blocks_free: 0,
+            blocks_available: 0,
+            file_count: files,
+            free_file_count: 0,
+            name_length: MAX_NAME_LEN as u32,
+            fragment_size: 512,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// Directory of a [`StaticFs`].
+pub struct StaticDir {
+    fs: Arc<StaticFs>,
+    node: &'static StaticNode,
+    parent: &'static StaticNode,
+    this: WeakDirEntry,
+}
+
+impl NodeOps for StaticDir {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.node.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for StaticDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let entries = [
+            (DOT, self.node.ino, NodeType::Directory),
+            (DOTDOT, self.parent.ino, NodeType::Directory),
+        ]
+        .into_iter()
+        .chain(
+            self.node
+                .children()
+                .iter()
+                .map(|it| (it.name, it.ino, it.node_type())),
+        );
+
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let children = self.node.children();
+        let node = children
+            .binary_search_by(|it| it.name.cmp(name))
+            .map(|index| &children[index])
+            .map_err(|_| VfsError::NotFound)?;
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        Ok(new_entry(&self.fs, node, self.node, reference))
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+/// Non-directory node of a [`StaticFs`].
+pub struct StaticFile {
+    fs: Arc<StaticFs>,
+    node: &'static StaticNode,
+}
+
+impl StaticFile {
+    /// Returns the embedded contents of the file.
+    pub fn data(&self) -> &'static [u8] {
+        self.node.data()
+    }
+}
+
+impl NodeOps for StaticFile {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.node.metadata())
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.node.data().len() as u64)
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for StaticFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let data = self.node.data();
+        let start = offset.min(data.len() as u64) as usize;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for StaticFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        Filesystem, Mountpoint,
+        test_util::{TempDir, list},
+    };
+
+    static TREE: StaticNode = StaticNode {
+        name: "",
+        ino: 1,
+        mode: 0o755,
+        mtime: 0,
+        kind: StaticKind::Dir(&[
+            StaticNode {
+                name: "etc",
+                ino: 2,
+                mode: 0o755,
+                mtime: 0,
+                kind: StaticKind::Dir(&[StaticNode {
+                    name: "hostname",
+                    ino: 3,
+                    mode: 0o644,
+                    mtime: 1000,
+                    kind: StaticKind::File(b"starry\n"),
+                }]),
+            },
+            StaticNode {
+                name: "hostname",
+                ino: 4,
+                mode: 0o777,
+                mtime: 0,
+                kind: StaticKind::Symlink("etc/hostname"),
+            },
+        ]),
+    };
+
+    #[test]
+    fn test_static_fs() {
+        let fs = Filesystem::new(StaticFs::new(&TREE).unwrap());
+        let root = Mountpoint::new_root(&fs).root_location();
+        assert_eq!(list(&root), [".", "..", "etc", "hostname"]);
+        assert_eq!(root.metadata().unwrap().nlink, 3);
+
+        let link = root.lookup_no_follow("hostname").unwrap();
+        assert_eq!(link.read_link().unwrap(), "etc/hostname");
+        assert_eq!(link.metadata().unwrap().inode, 4);
+
+        let file = root
+            .lookup_no_follow("etc")
+            .and_then(|it| it.lookup_no_follow("hostname"))
+            .unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!((metadata.inode, metadata.size), (3, 7));
+        assert_eq!(metadata.mtime.as_secs(), 1000);
+        let mut buf = [0; 16];
+        let node = file.entry().as_file().unwrap();
+        assert_eq!(node.read_at(&mut buf, 2).unwrap(), 5);
+        assert_eq!(&buf[..5], b"arry\n");
+        assert_eq!(
+            node.write_at(b"x", 0).err(),
+            Some(VfsError::ReadOnlyFilesystem)
+        );
+        assert_eq!(
+            root.lookup_no_follow("missing").err(),
+            Some(VfsError::NotFound)
+        );
+        assert_eq!(file.get_xattr("user.a").err(), Some(VfsError::Unsupported));
+    }
+
+    #[test]
+    fn test_cached_entries() {
+        let fs = Filesystem::new(StaticFs::new(&TREE).unwrap());
+        let root = Mountpoint::new_root(&fs).root_location();
+        // Every node is found in the dentry cache without a lookup.
+        let etc = root.entry().as_dir().unwrap().lookup_cache("etc").unwrap();
+        let file = etc.as_dir().unwrap().lookup_cache("hostname").unwrap();
+        assert_eq!(file.inode(), 3);
+        assert!(root.lookup_no_follow("etc").unwrap().entry().ptr_eq(&etc));
+
+        root.entry().as_dir().unwrap().forget();
+        let etc = root.lookup_no_follow("etc").unwrap();
+        assert!(
+            etc.entry()
+                .as_dir()
+                .unwrap()
+                .lookup_cache("hostname")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_unsorted() {
+        static UNSORTED: StaticNode = StaticNode {
+            name: "",
+            ino: 1,
+            mode: 0o755,
+            mtime: 0,
+            kind: StaticKind::Dir(&[StaticNode {
+                name: "dir",
+                ino: 2,
+                mode: 0o755,
+                mtime: 0,
+                kind: StaticKind::Dir(&[
+                    StaticNode {
+                        name: "b",
+                        ino: 3,
+                        mode: 0o644,
+                        mtime: 0,
+                        kind: StaticKind::File(b""),
+                    },
+                    StaticNode {
+                        name: "a",
+                        ino: 4,
+                        mode: 0o644,
+                        mtime: 0,
+                        kind: StaticKind::File(b""),
+                    },
+                ]),
+            }]),
+        };
+        assert_eq!(StaticFs::new(&UNSORTED).err(), Some(VfsError::InvalidInput));
+    }
+
+    #[test]
+    fn test_generated_tree() {
+        static TESTDATA: StaticNode = include!(concat!(env!("OUT_DIR"), "/staticfs_testdata.rs"));
+        let fs = Filesystem::new(StaticFs::new(&TESTDATA).unwrap());
+        let root = Mountpoint::new_root(&fs).root_location();
+        assert_eq!(list(&root), [".", "..", "etc", "hostname"]);
+
+        let link = root.lookup_no_follow("hostname").unwrap();
+        assert_eq!(link.read_link().unwrap(), "etc/hostname");
+        let file = root
+            .lookup_no_follow("etc")
+            .and_then(|it| it.lookup_no_follow("hostname"))
+            .unwrap();
+        assert_eq!(file.inode(), 3);
+        let file = file.entry().downcast::<StaticFile>().unwrap();
+        assert_eq!(file.data(), b"starry\n");
+    }
+
+    #[test]
+    fn test_generate() {
+        let tmp = TempDir::new();
+        std::fs::create_dir(tmp.0.join("b")).unwrap();
+        std::fs::write(tmp.0.join("b/file"), "data").unwrap();
+        std::os::unix::fs::symlink("b/file", tmp.0.join("a")).unwrap();
+        let code = generate(&tmp.0).unwrap();
+
+        let link = code.find("name: \"a\", ino: 2").unwrap();
+        let dir = code.find("name: \"b\", ino: 3").unwrap();
+        let file = code.find("name: \"file\", ino: 4").unwrap();
+        assert!(link < dir && dir < file);
+        assert!(code.contains("StaticKind::Symlink(\"b/file\")"));
+        let path = std::fs::canonicalize(tmp.0.join("b/file")).unwrap();
+        assert!(code.contains(&std::format!(
+            "include_bytes!({:?})",
+            path.to_str().unwrap()
+        )));
+        assert!(code.starts_with("::axfs_ng_vfs::staticfs::StaticNode { name: \"\", ino: 1"));
+        assert_eq!(code.matches("StaticNode {").count(), 4);
+    }
+}
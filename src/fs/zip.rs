@@ -0,0 +1,917 @@
+//! Read-only filesystem over a zip archive.
+//!
+//! The central directory is parsed once when the filesystem is created, and
+//! the directory tree (including directories only implied by file paths) is
+//! kept in memory. File data is read from the backing [`FileNode`] on demand:
+//! stored entries are read in place, while deflated entries keep a streaming
+//! decompressor per open node, so that sequential reads continue where the
+//! previous one stopped and only seeking backwards restarts decompression.
+
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{any::Any, ops::Range, task::Context, time::Duration};
+
+use axpoll::{IoEvents, Pollable};
+use miniz_oxide::{
+    DataFormat, MZError, MZFlush, MZStatus,
+    inflate::stream::{InflateState, inflate},
+};
+
+use super::{RootDir, days_from_civil};
+use crate::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, Mutex, NodeOps, NodePermission, NodeType, Reference, StatFs,
+    VfsError, VfsResult, WeakDirEntry,
+    archive::ArchiveSource,
+    path::{DOT, DOTDOT, MAX_NAME_LEN},
+};
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const EOCD_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_EOCD_SIZE: usize = 56;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const CENTRAL_SIZE: usize = 46;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const LOCAL_SIZE: usize = 30;
+
+const EXTRA_ZIP64: u16 = 0x0001;
+const EXTRA_TIMESTAMP: u16 = 0x5455;
+const EXTRA_UNIX: u16 = 0x7875;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+const FLAG_ENCRYPTED: u16 = 0x0001;
+const HOST_UNIX: u8 = 3;
+const DOS_ATTR_READONLY: u32 = 0x01;
+const DOS_ATTR_DIRECTORY: u32 = 0x10;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+const ROOT_INO: u64 = 1;
+const INPUT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Reads a little-endian integer of `N` bytes at `offset` of `buf`.
+fn le<const N: usize>(buf: &[u8], offset: usize) -> VfsResult<u64> {
+    let bytes = buf.get(offset..offset + N).ok_or(VfsError::InvalidData)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &it| (acc << 8) | it as u64))
+}
+
+fn read_at(source: &FileNode, offset: u64, len: usize) -> VfsResult<Vec<u8>> {
+    let mut buf = vec![0; len];
+    source
+        .read_exact_at(&mut buf, offset)
+        .map_err(|err| match err {
+            VfsError::UnexpectedEof => VfsError::InvalidData,
+            err => err,
+        })?;
+    Ok(buf)
+}
+
+/// Converts a MS-DOS date and time into a timestamp, interpreting it as UTC.
+fn dos_time(date: u16, time: u16) -> Duration {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as u32;
+    let day = (date & 0x1f).max(1) as u32;
+    let days = days_from_civil(year, month, day);
+
+    let secs =
+        (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    Duration::from_secs((days * 86400 + secs) as u64)
+}
+
+/// Location and encoding of the data of a file in the archive.
+struct EntryData {
+    method: u16,
+    encrypted: bool,
+    compressed_size: u64,
+    local_header: u64,
+}
+
+enum Kind {
+    Dir { children: BTreeMap<String, usize> },
+    File(EntryData),
+}
+
+struct ZipNode {
+    parent: usize,
+    node_type: NodeType,
+    mode: NodePermission,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: Duration,
+    kind: Kind,
+}
+
+impl ZipNode {
+    fn new_dir(parent: usize) -> Self {
+        Self {
+            parent,
+            node_type: NodeType::Directory,
+            mode: NodePermission::from_bits_truncate(0o755),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            mtime: Duration::ZERO,
+            kind: Kind::Dir {
+                children: BTreeMap::new(),
+            },
+        }
+    }
+
+    fn children(&self) -> &BTreeMap<String, usize> {
+        match &self.kind {
+            Kind::Dir { children } => children,
+            Kind::File(_) => unreachable!(),
+        }
+    }
+}
+
+/// An entry of the central directory.
+struct CentralEntry {
+    name: String,
+    node: ZipNode,
+}
+
+fn parse_central_entry(buf: &[u8]) -> VfsResult<(CentralEntry, usize)> {
+    if le::<4>(buf, 0)? as u32 != CENTRAL_SIGNATURE {
+        return Err(VfsError::InvalidData);
+    }
+    let host = (le::<2>(buf, 4)? >> 8) as u8;
+    let flags = le::<2>(buf, 8)? as u16;
+    let method = le::<2>(buf, 10)? as u16;
+    let time = le::<2>(buf, 12)? as u16;
+    let date = le::<2>(buf, 14)? as u16;
+    let mut compressed_size = le::<4>(buf, 20)?;
+    let mut size = le::<4>(buf, 24)?;
+    let name_len = le::<2>(buf, 28)? as usize;
+    let extra_len = le::<2>(buf, 30)? as usize;
+    let comment_len = le::<2>(buf, 32)? as usize;
+    let external = le::<4>(buf, 38)? as u32;
+    let mut local_header = le::<4>(buf, 42)?;
+
+    let name_end = CENTRAL_SIZE + name_len;
+    let name = buf
+        .get(CENTRAL_SIZE..name_end)
+        .ok_or(VfsError::InvalidData)?;
+    let name = String::from_utf8_lossy(name).into_owned();
+    let extra = buf
+        .get(name_end..name_end + extra_len)
+        .ok_or(VfsError::InvalidData)?;
+
+    let mut mtime = dos_time(date, time);
+    let (mut uid, mut gid) = (0, 0);
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let id = le::<2>(rest, 0)? as u16;
+        let len = le::<2>(rest, 2)? as usize;
+        let data = rest.get(4..4 + len).ok_or(VfsError::InvalidData)?;
+        match id {
+            EXTRA_ZIP64 => {
+                let mut fields = data.chunks_exact(8).map(|it| le::<8>(it, 0));
+                for field in [&mut size, &mut compressed_size, &mut local_header] {
+                    if *field == u32::MAX as u64 {
+                        *field = fields.next().ok_or(VfsError::InvalidData)??;
+                    }
+                }
+            }
+            EXTRA_TIMESTAMP if data.first().is_some_and(|it| it & 1 != 0) => {
+                mtime = Duration::from_secs(le::<4>(data, 1)?);
+            }
+            EXTRA_UNIX if data.first() == Some(&1) => {
+                let uid_len = *data.get(1).ok_or(VfsError::InvalidData)? as usize;
+                if uid_len == 4 && data.get(6) == Some(&4) {
+                    uid = le::<4>(data, 2)? as u32;
+                    gid = le::<4>(data, 7)? as u32;
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[4 + len..];
+    }
+
+    let unix_mode = if host == HOST_UNIX { external >> 16 } else { 0 };
+    let node_type = if name.ends_with('/')
+        || unix_mode & S_IFMT == S_IFDIR
+        || (unix_mode == 0 && external & DOS_ATTR_DIRECTORY != 0)
+    {
+        NodeType::Directory
+    } else if unix_mode & S_IFMT == S_IFLNK {
+        NodeType::Symlink
+    } else {
+        NodeType::RegularFile
+    };
+    let mode = if unix_mode != 0 {
+        unix_mode & 0o7777
+    } else if node_type == NodeType::Directory {
+        0o755
+    } else if external & DOS_ATTR_READONLY != 0 {
+        0o444
+    } else {
+        0o644
+    };
+
+    let kind = if node_type == NodeType::Directory {
+        Kind::Dir {
+            children: BTreeMap::new(),
+        }
+    } else {
+        Kind::File(EntryData {
+            method,
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+            compressed_size,
+            local_header,
+        })
+    };
+    let entry = CentralEntry {
+        name,
+        node: ZipNode {
+            parent: 0,
+            node_type,
+            mode: NodePermission::from_bits_truncate(mode as u16),
+            uid,
+            gid,
+            size: if node_type == NodeType::Directory {
+                0
+            } else {
+                size
+            },
+            mtime,
+            kind,
+        },
+    };
+    let len = name_end + extra_len + comment_len;
+    if len > buf.len() {
+        return Err(VfsError::InvalidData);
+    }
+    Ok((entry, len))
+}
+
+/// Locates the central directory, returning its offset and size.
+fn find_central_directory(source: &FileNode) -> VfsResult<(u64, u64)> {
+    let len = source.len()?;
+    let tail_len = len.min((EOCD_SIZE + u16::MAX as usize) as u64);
+    let tail = read_at(source, len - tail_len, tail_len as usize)?;
+    let eocd = (0..=tail.len().saturating_sub(EOCD_SIZE))
+        .rev()
+        .find(|&i| le::<4>(&tail, i).is_ok_and(|it| it as u32 == EOCD_SIGNATURE))
+        .ok_or(VfsError::InvalidData)?;
+    let eocd_offset = len - tail_len + eocd as u64;
+    let eocd = &tail[eocd..];
+
+    let mut size = le::<4>(eocd, 12)?;
+    let mut offset = le::<4>(eocd, 16)?;
+    if (size == u32::MAX as u64 || offset == u32::MAX as u64)
+        && eocd_offset >= ZIP64_LOCATOR_SIZE as u64
+    {
+        let locator = read_at(
+            source,
+            eocd_offset - ZIP64_LOCATOR_SIZE as u64,
+            ZIP64_LOCATOR_SIZE,
+        )?;
+        if le::<4>(&locator, 0)? as u32 == ZIP64_LOCATOR_SIGNATURE {
+            let eocd64 = read_at(source, le::<8>(&locator, 8)?, ZIP64_EOCD_SIZE)?;
+            if le::<4>(&eocd64, 0)? as u32 != ZIP64_EOCD_SIGNATURE {
+                return Err(VfsError::InvalidData);
+            }
+            size = le::<8>(&eocd64, 40)?;
+            offset = le::<8>(&eocd64, 48)?;
+        }
+    }
+    if offset.checked_add(size).is_none_or(|end| end > eocd_offset) {
+        return Err(VfsError::InvalidData);
+    }
+    Ok((offset, size))
+}
+
+/// A read-only filesystem backed by a zip archive.
+pub struct ZipFs {
+    source: FileNode,
+    /// All nodes of the tree, indexed by inode number minus one.
+    nodes: Vec<ZipNode>,
+    this: Weak<Self>,
+    root: RootDir,
+}
+
+impl ZipFs {
+    /// Creates a filesystem from the zip archive stored in `source`.
+    ///
+    /// Entries with `..` components or overlong names are ignored, as are
+    /// later duplicates of an entry.
+    pub fn new(source: FileNode) -> VfsResult<Arc<Self>> {
+        let (offset, size) = find_central_directory(&source)?;
+        let size = usize::try_from(size).map_err(|_| VfsError::InvalidData)?;
+        let central = read_at(&source, offset, size)?;
+
+        let mut nodes = vec![ZipNode::new_dir(0)];
+        let mut rest = &central[..];
+        while !rest.is_empty() {
+            let (entry, len) = parse_central_entry(rest)?;
+            rest = &rest[len..];
+            insert_entry(&mut nodes, entry);
+        }
+
+        Ok(Arc::new_cyclic(|this| Self {
+            source,
+            nodes,
+            this: this.clone(),
+            root: RootDir::default(),
+        }))
+    }
+
+    fn new_root(&self) -> DirEntry {
+        DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(ZipDir {
+                    fs: self.this.upgrade().unwrap(),
+                    index: 0,
+                    this,
+                }))
+            },
+            Reference::root(),
+        )
+    }
+
+    fn metadata(&self, index: usize) -> Metadata {
+        let node = &self.nodes[index];
+        let (nlink, blocks) = match &node.kind {
+            Kind::Dir { children } => (
+                2 + children
+                    .values()
+                    .filter(|&&it| self.nodes[it].node_type == NodeType::Directory)
+                    .count() as u64,
+                0,
+            ),
+            Kind::File(data) => (1, data.compressed_size.div_ceil(512)),
+        };
+        Metadata {
+            device: 0,
+            inode: index as u64 + ROOT_INO,
+            nlink,
+            mode: node.mode,
+            node_type: node.node_type,
+            uid: node.uid,
+            gid: node.gid,
+            size: node.size,
+            block_size: 512,
+            blocks,
+            rdev: DeviceId::default(),
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+        }
+    }
+}
+
+fn add_child(nodes: &mut Vec<ZipNode>, dir: usize, name: &str, node: ZipNode) -> usize {
+    let index = nodes.len();
+    nodes.push(node);
+    if let Kind::Dir { children } = &mut nodes[dir].kind {
+        children.insert(name.to_owned(), index);
+    }
+    index
+}
+
+fn insert_entry(nodes: &mut Vec<ZipNode>, entry: CentralEntry) {
+    let components: Vec<&str> = entry
+        .name
+        .split('/')
+        .filter(|it| !it.is_empty() && *it != DOT)
+        .collect();
+    let Some((&name, parents)) = components.split_last() else {
+        return;
+    };
+    if components
+        .iter()
+        .any(|it| *it == DOTDOT || it.len() > MAX_NAME_LEN)
+    {
+        return;
+    }
+
+    let mut dir = 0;
+    for &parent in parents {
+        dir = match nodes[dir].children().get(parent) {
+            Some(&index) if nodes[index].node_type == NodeType::Directory => index,
+            Some(_) => return,
+            None => add_child(nodes, dir, parent, ZipNode::new_dir(dir)),
+        };
+    }
+    match nodes[dir].children().get(name) {
+        // An explicit entry for a directory implied earlier.
+        Some(&index)
+            if nodes[index].node_type == NodeType::Directory
+                && entry.node.node_type == NodeType::Directory =>
+        {
+            let node = &mut nodes[index];
+            node.mode = entry.node.mode;
+            node.uid = entry.node.uid;
+            node.gid = entry.node.gid;
+            node.mtime = entry.node.mtime;
+        }
+        Some(_) => {}
+        None => {
+            add_child(
+                nodes,
+                dir,
+                name,
+                ZipNode {
+                    parent: dir,
+                    ..entry.node
+                },
+            );
+        }
+    }
+}
+
+impl FilesystemOps for ZipFs {
+    fn name(&self) -> &str {
+        "zip"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.get_or_init(|| self.new_root())
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let len = self.source.len()?;
+        Ok(StatFs {
+            fs_type: 0,
+            block_size: 512,
+            blocks: len.div_ceil(512),
+            blocks_free: 0,
+            blocks_available: 0,
+            file_count: self.nodes.len() as u64,
+            free_file_count: 0,
+            name_length: MAX_NAME_LEN as u32,
+            fragment_size: 512,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// Directory of a [`ZipFs`].
+pub struct ZipDir {
+    fs: Arc<ZipFs>,
+    index: usize,
+    this: WeakDirEntry,
+}
+
+impl NodeOps for ZipDir {
+    fn inode(&self) -> u64 {
+        self.index as u64 + ROOT_INO
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.fs.metadata(self.index))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl DirNodeOps for ZipDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let nodes = &self.fs.nodes;
+        let node = &nodes[self.index];
+        let entries = [
+            (DOT, self.index, NodeType::Directory),
+            (DOTDOT, node.parent, NodeType::Directory),
+        ]
+        .into_iter()
+        .chain(
+            node.children()
+                .iter()
+                .map(|(name, &index)| (name.as_str(), index, nodes[index].node_type)),
+        );
+
+        let mut count = 0;
+        for (i, (name, index, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, index as u64 + ROOT_INO, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let index = *self.fs.nodes[self.index]
+            .children()
+            .get(name)
+            .ok_or(VfsError::NotFound)?;
+        let fs = self.fs.clone();
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        let node_type = fs.nodes[index].node_type;
+        Ok(if node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Arc::new(ZipDir { fs, index, this })),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Arc::new(ZipFile {
+                    fs,
+                    index,
+                    state: Mutex::default(),
+                })),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+/// Streaming decompressor of a deflated entry.
+struct Inflater {
+    state: Box<InflateState>,
+    input: Vec<u8>,
+    /// Range of `input` not consumed yet.
+    pending: Range<usize>,
+    /// Offset of the next compressed byte to read, relative to the data start.
+    input_pos: u64,
+    /// Offset of the next decompressed byte.
+    output_pos: u64,
+}
+
+impl Inflater {
+    fn new() -> Self {
+        Self {
+            state: InflateState::new_boxed(DataFormat::Raw),
+            input: vec![0; INPUT_CHUNK_SIZE],
+            pending: 0..0,
+            input_pos: 0,
+            output_pos: 0,
+        }
+    }
+
+    /// Decompresses into `buf` starting from `offset`, which must not exceed
+    /// the size of the entry.
+    fn read(
+        &mut self,
+        source: &FileNode,
+        data: &EntryData,
+        data_offset: u64,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> VfsResult<usize> {
+        if offset < self.output_pos {
+            self.state.reset(DataFormat::Raw);
+            self.pending = 0..0;
+            self.input_pos = 0;
+            self.output_pos = 0;
+        }
+        let mut discard = [0; 512];
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.pending.is_empty() && self.input_pos < data.compressed_size {
+                let len = (data.compressed_size - self.input_pos).min(self.input.len() as u64);
+                let input = &mut self.input[..len as usize];
+                source.read_exact_at(input, data_offset + self.input_pos)?;
+                self.input_pos += len;
+                self.pending = 0..len as usize;
+            }
+            let output = if self.output_pos < offset {
+                let len = (offset - self.output_pos).min(discard.len() as u64);
+                &mut discard[..len as usize]
+            } else {
+                &mut buf[filled..]
+            };
+            let result = inflate(
+                &mut self.state,
+                &self.input[self.pending.clone()],
+                output,
+                MZFlush::None,
+            );
+            self.pending.start += result.bytes_consumed;
+            if self.output_pos >= offset {
+                filled += result.bytes_written;
+            }
+            self.output_pos += result.bytes_written as u64;
+            match result.status {
+                Ok(MZStatus::StreamEnd) => break,
+                Ok(_) | Err(MZError::Buf)
+                    if result.bytes_consumed > 0 || result.bytes_written > 0 => {}
+                // No progress is possible, the stream is truncated or corrupted.
+                _ => return Err(VfsError::InvalidData),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+#[derive(Default)]
+struct FileState {
+    /// Offset of the entry data, known after reading the local header.
+    data_offset: Option<u64>,
+    inflater: Option<Inflater>,
+}
+
+/// Non-directory node of a [`ZipFs`].
+pub struct ZipFile {
+    fs: Arc<ZipFs>,
+    index: usize,
+    state: Mutex<FileState>,
+}
+
+impl ZipFile {
+    fn data(&self) -> &EntryData {
+        match &self.fs.nodes[self.index].kind {
+            Kind::File(data) => data,
+            Kind::Dir { .. } => unreachable!(),
+        }
+    }
+}
+
+impl NodeOps for ZipFile {
+    fn inode(&self) -> u64 {
+        self.index as u64 + ROOT_INO
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(self.fs.metadata(self.index))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl FileNodeOps for ZipFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let size = self.fs.nodes[self.index].size;
+        if offset >= size {
+            return Ok(0);
+        }
+        let buf_len = buf.len().min((size - offset) as usize);
+        let buf = &mut buf[..buf_len];
+
+        let data = self.data();
+        if data.encrypted {
+            return Err(VfsError::Unsupported);
+        }
+        let source = &self.fs.source;
+        let mut state = self.state.lock();
+        let data_offset = match state.data_offset {
+            Some(it) => it,
+            None => {
+                let header = read_at(source, data.local_header, LOCAL_SIZE)?;
+                if le::<4>(&header, 0)? as u32 != LOCAL_SIGNATURE {
+                    return Err(VfsError::InvalidData);
+                }
+                let it = data.local_header
+                    + LOCAL_SIZE as u64
+                    + le::<2>(&header, 26)?
+                    + le::<2>(&header, 28)?;
+                *state.data_offset.insert(it)
+            }
+        };
+        match data.method {
+            METHOD_STORED => {
+                if offset + buf.len() as u64 > data.compressed_size {
+                    return Err(VfsError::InvalidData);
+                }
+                source.read_exact_at(buf, data_offset + offset)?;
+                Ok(buf.len())
+            }
+            METHOD_DEFLATE => state.inflater.get_or_insert_with(Inflater::new).read(
+                source,
+                data,
+                data_offset,
+                buf,
+                offset,
+            ),
+            _ => Err(VfsError::Unsupported),
+        }
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for ZipFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use std::fs;
+
+    use miniz_oxide::deflate::compress_to_vec;
+
+    use super::*;
+    use crate::{
+        Filesystem, Location, Mountpoint,
+        test_util::{TempDir, list, mount},
+    };
+
+    /// Builds a zip archive of `(name, unix mode, data, deflate)` entries.
+    fn build_zip(entries: &[(&str, u32, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, mode, data, deflate) in entries {
+            let compressed = if deflate {
+                compress_to_vec(data, 6)
+            } else {
+                data.to_vec()
+            };
+            let method = if deflate {
+                METHOD_DEFLATE
+            } else {
+                METHOD_STORED
+            };
+            let offset = out.len() as u32;
+            let common = |buf: &mut Vec<u8>| {
+                buf.extend_from_slice(&20u16.to_le_bytes());
+                buf.extend_from_slice(&0u16.to_le_bytes());
+                buf.extend_from_slice(&method.to_le_bytes());
+                // 2001-02-03 04:05:06
+                buf.extend_from_slice(&(4u16 << 11 | 5 << 5 | 3).to_le_bytes());
+                buf.extend_from_slice(&(21u16 << 9 | 2 << 5 | 3).to_le_bytes());
+                buf.extend_from_slice(&0u32.to_le_bytes());
+                buf.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&0u16.to_le_bytes());
+            };
+            out.extend_from_slice(&LOCAL_SIGNATURE.to_le_bytes());
+            common(&mut out);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&compressed);
+
+            central.extend_from_slice(&CENTRAL_SIGNATURE.to_le_bytes());
+            central.extend_from_slice(&((HOST_UNIX as u16) << 8 | 20).to_le_bytes());
+            common(&mut central);
+            central.extend_from_slice(&[0; 6]);
+            central.extend_from_slice(&(mode << 16).to_le_bytes());
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&EOCD_SIGNATURE.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    fn mount_zip(tmp: &TempDir, data: &[u8]) -> Location {
+        fs::write(tmp.0.join("test.zip"), data).unwrap();
+        let file = mount(tmp).lookup_no_follow("test.zip").unwrap();
+        let source = FileNode::new(file.entry().as_file().unwrap().inner().clone());
+        let fs = Filesystem::new(ZipFs::new(source).unwrap());
+        Mountpoint::new_root(&fs).root_location()
+    }
+
+    #[test]
+    fn test_zip() {
+        let big: Vec<u8> = (0..100_000u32).flat_map(|it| it.to_le_bytes()).collect();
+        let data = build_zip(&[
+            ("dir/", 0o040750, b"", false),
+            ("dir/stored.txt", 0o100644, b"stored data", false),
+            ("dir/sub/big.bin", 0o100600, &big, true),
+            ("link", 0o120777, b"dir/stored.txt", false),
+            ("../escape", 0o100644, b"bad", false),
+        ]);
+        let tmp = TempDir::new();
+        let root = mount_zip(&tmp, &data);
+
+        assert_eq!(list(&root), [".", "..", "dir", "link"]);
+        let dir = root.lookup_no_follow("dir").unwrap();
+        assert_eq!(list(&dir), [".", "..", "stored.txt", "sub"]);
+        let metadata = dir.metadata().unwrap();
+        assert_eq!(metadata.mode.bits(), 0o750);
+        assert_eq!(metadata.nlink, 3);
+        assert_eq!(metadata.mtime.as_secs(), 981_173_106);
+
+        let link = root.lookup_no_follow("link").unwrap();
+        assert_eq!(link.node_type(), NodeType::Symlink);
+        assert_eq!(link.read_link().unwrap(), "dir/stored.txt");
+
+        let stored = dir.lookup_no_follow("stored.txt").unwrap();
+        let mut buf = [0; 6];
+        let file = stored.entry().as_file().unwrap();
+        assert_eq!(file.read_at(&mut buf, 7).unwrap(), 4);
+        assert_eq!(&buf[..4], b"data");
+
+        let big_file = dir
+            .lookup_no_follow("sub")
+            .and_then(|it| it.lookup_no_follow("big.bin"))
+            .unwrap();
+        assert_eq!(big_file.metadata().unwrap().size, big.len() as u64);
+        let file = big_file.entry().as_file().unwrap();
+        let mut buf = vec![0; 1000];
+        // Sequential, forward and backward reads.
+        for offset in [0, 1000, 2000, 300_000, 123, 399_500] {
+            let read = file.read_at(&mut buf, offset).unwrap();
+            let end = (offset as usize + read).min(big.len());
+            assert_eq!(&buf[..read], &big[offset as usize..end]);
+        }
+
+        assert_eq!(
+            root.create(
+                "new",
+                NodeType::RegularFile,
+                NodePermission::from_bits_truncate(0o644)
+            )
+            .err(),
+            Some(VfsError::ReadOnlyFilesystem)
+        );
+        assert_eq!(
+            file.write_at(b"x", 0).err(),
+            Some(VfsError::ReadOnlyFilesystem)
+        );
+        assert!(!list(&root).contains(&"escape".to_string()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let tmp = TempDir::new();
+        fs::write(tmp.0.join("bad.zip"), b"not a zip archive").unwrap();
+        let file = mount(&tmp).lookup_no_follow("bad.zip").unwrap();
+        let source = FileNode::new(file.entry().as_file().unwrap().inner().clone());
+        assert_eq!(ZipFs::new(source).err(), Some(VfsError::InvalidData));
+    }
+}
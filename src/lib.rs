@@ -1,13 +1,27 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+// Lets the tests include trees generated for `::axfs_ng_vfs`.
+#[cfg(test)]
+extern crate self as axfs_ng_vfs;
 
+pub mod acl;
+pub mod archive;
+pub mod block;
+mod cred;
 mod fs;
 mod mount;
 mod node;
 pub mod path;
+mod socket;
+#[cfg(test)]
+mod test_util;
 mod types;
+pub mod wasi;
 
+pub use cred::*;
 pub use fs::*;
 pub use mount::*;
 pub use node::*;
@@ -2,6 +2,7 @@ use alloc::{
     string::String,
     sync::{Arc, Weak},
     vec,
+    vec::Vec,
 };
 use core::{
     iter, mem,
@@ -14,9 +15,11 @@ use hashbrown::HashMap;
 use inherit_methods_macro::inherit_methods;
 
 use crate::{
-    path::{PathBuf, DOT, DOTDOT},
-    DirEntry, DirEntrySink, Filesystem, FilesystemOps, Metadata, MetadataUpdate, Mutex, MutexGuard,
-    NodeFlags, NodePermission, NodeType, OpenOptions, ReferenceKey, TypeMap, VfsError, VfsResult,
+    Access, Capabilities, Credentials, DirEntry, DirEntrySink, Filesystem, FilesystemOps, Metadata,
+    MetadataUpdate, Mutex, MutexGuard, NodeFlags, NodePermission, NodeType, OpenOptions,
+    Protection, ReferenceKey, TypeMap, VfsError, VfsResult, XattrFlags,
+    block::BlockDevice,
+    path::{DOT, DOTDOT, PathBuf},
 };
 
 #[derive(Debug)]
@@ -29,6 +32,9 @@ pub struct Mountpoint {
     children: Mutex<HashMap<ReferenceKey, Weak<Self>>>,
     /// Device ID
     device: u64,
+    /// Restrictions of the VFS the mountpoint belongs to, shared by all its
+    /// mountpoints.
+    protection: Arc<Mutex<Protection>>,
 }
 
 impl Mountpoint {
@@ -36,11 +42,15 @@ impl Mountpoint {
         static DEVICE_COUNTER: AtomicU64 = AtomicU64::new(1);
 
         let root = fs.root_dir();
+        let protection = location_in_parent
+            .as_ref()
+            .map_or_else(Arc::default, |it| it.mountpoint.protection.clone());
         Arc::new(Self {
             root,
             location: location_in_parent,
             children: Mutex::default(),
             device: DEVICE_COUNTER.fetch_add(1, Ordering::Relaxed),
+            protection,
         })
     }
 
@@ -78,12 +88,24 @@ impl Mountpoint {
     pub fn device(self: &Arc<Self>) -> u64 {
         self.device
     }
+
+    /// Returns the restrictions of the VFS.
+    pub fn protection(&self) -> Protection {
+        *self.protection.lock()
+    }
+
+    /// Sets the restrictions of the VFS, for all its mountpoints.
+    pub fn set_protection(&self, protection: Protection) {
+        *self.protection.lock() = protection;
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Location {
     mountpoint: Arc<Mountpoint>,
     entry: DirEntry,
+    /// Credentials checked by the operations, if any.
+    cred: Option<Arc<Credentials>>,
 }
 
 #[inherit_methods(from = "self.entry")]
@@ -92,8 +114,6 @@ impl Location {
 
     pub fn filesystem(&self) -> &dyn FilesystemOps;
 
-    pub fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()>;
-
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> VfsResult<u64>;
 
@@ -111,18 +131,179 @@ impl Location {
 
     pub fn ioctl(&self, cmd: u32, arg: usize) -> VfsResult<usize>;
 
+    pub fn block_device(&self) -> VfsResult<Arc<dyn BlockDevice>>;
+
     pub fn flags(&self) -> NodeFlags;
 
     pub fn user_data(&self) -> MutexGuard<'_, TypeMap>;
+
+    pub fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>>;
+
+    pub fn list_xattr(&self) -> VfsResult<Vec<String>>;
+
+    pub fn check_access(&self, cred: &Credentials, access: Access) -> VfsResult<()>;
 }
 
 impl Location {
     pub fn new(mountpoint: Arc<Mountpoint>, entry: DirEntry) -> Self {
-        Self { mountpoint, entry }
+        Self {
+            mountpoint,
+            entry,
+            cred: None,
+        }
     }
 
     fn wrap(&self, entry: DirEntry) -> Self {
-        Self::new(self.mountpoint.clone(), entry)
+        Self {
+            mountpoint: self.mountpoint.clone(),
+            entry,
+            cred: self.cred.clone(),
+        }
+    }
+
+    /// Returns the location with `cred` checked by its operations and those
+    /// of the locations it leads to.
+    pub fn with_credentials(mut self, cred: Arc<Credentials>) -> Self {
+        self.cred = Some(cred);
+        self
+    }
+
+    pub fn credentials(&self) -> Option<&Arc<Credentials>> {
+        self.cred.as_ref()
+    }
+
+    /// Checks that the credentials of the location, if any, are granted
+    /// `access` to it.
+    fn check(&self, access: Access) -> VfsResult<()> {
+        match &self.cred {
+            Some(cred) => self.entry.check_access(cred, access),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the credentials of the location, if any, may act as the
+    /// owner of the node.
+    fn check_owner(&self) -> VfsResult<()> {
+        match &self.cred {
+            Some(cred) if !cred.owns(self.entry.metadata()?.uid) => {
+                Err(VfsError::OperationNotPermitted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_capable(&self, caps: Capabilities) -> VfsResult<()> {
+        match &self.cred {
+            Some(cred) if !cred.caps.contains(caps) => Err(VfsError::OperationNotPermitted),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the credentials of the location, if any, for changing the
+    /// extended attribute `name`.
+    fn check_xattr(&self, name: &str) -> VfsResult<()> {
+        if name.starts_with("user.") {
+            self.check(Access::WRITE)
+        } else if name.starts_with("trusted.") {
+            self.check_capable(Capabilities::SYS_ADMIN)
+        } else if name.starts_with("system.posix_acl_") {
+            self.check_owner()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the credentials of this directory, if any, may remove
+    /// `entry` from it, which takes owning either of them if the directory
+    /// is sticky.
+    fn check_sticky(&self, entry: &DirEntry) -> VfsResult<()> {
+        let Some(cred) = &self.cred else {
+            return Ok(());
+        };
+        let dir = self.entry.metadata()?;
+        if !dir.mode.contains(NodePermission::STICKY)
+            || cred.owns(dir.uid)
+            || cred.owns(entry.metadata()?.uid)
+        {
+            Ok(())
+        } else {
+            Err(VfsError::OperationNotPermitted)
+        }
+    }
+
+    /// Checks [`Protection::hardlinks`] for linking `node`.
+    fn check_hardlink(&self, node: &DirEntry) -> VfsResult<()> {
+        let Some(cred) = &self.cred else {
+            return Ok(());
+        };
+        if !self.mountpoint.protection().hardlinks {
+            return Ok(());
+        }
+        let metadata = node.metadata()?;
+        let setgid_exec = NodePermission::SET_GID | NodePermission::GROUP_EXEC;
+        let safe = metadata.node_type == NodeType::RegularFile
+            && !metadata.mode.contains(NodePermission::SET_UID)
+            && !metadata.mode.contains(setgid_exec)
+            && node
+                .check_access(cred, Access::READ | Access::WRITE)
+                .is_ok();
+        if safe || cred.owns(metadata.uid) {
+            Ok(())
+        } else {
+            Err(VfsError::OperationNotPermitted)
+        }
+    }
+
+    /// Checks [`Protection::fifos`] and [`Protection::regular`] for opening
+    /// `entry` of this directory with `create`.
+    fn check_protected_open(&self, cred: &Credentials, entry: &DirEntry) -> VfsResult<()> {
+        let protection = self.mountpoint.protection();
+        let level = match entry.node_type() {
+            NodeType::Fifo => protection.fifos,
+            NodeType::RegularFile => protection.regular,
+            _ => 0,
+        };
+        if level == 0 {
+            return Ok(());
+        }
+        let dir = self.entry.metadata()?;
+        let node = entry.metadata()?;
+        if !dir.mode.contains(NodePermission::STICKY)
+            || node.uid == dir.uid
+            || node.uid == cred.fsuid
+        {
+            return Ok(());
+        }
+        if dir.mode.contains(NodePermission::OTHER_WRITE)
+            || (level >= 2 && dir.mode.contains(NodePermission::GROUP_WRITE))
+        {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Reads the target of this symlink for following it.
+    ///
+    /// With credentials, this enforces [`Protection::symlinks`].
+    pub fn follow_link(&self) -> VfsResult<String> {
+        if let Some(cred) = &self.cred
+            && self.mountpoint.protection().symlinks
+        {
+            let link = self.entry.metadata()?;
+            if cred.fsuid != link.uid
+                && let Some(parent) = self.parent()
+            {
+                let dir = parent.entry.metadata()?;
+                if dir
+                    .mode
+                    .contains(NodePermission::STICKY | NodePermission::OTHER_WRITE)
+                    && dir.uid != link.uid
+                {
+                    return Err(VfsError::PermissionDenied);
+                }
+            }
+        }
+        self.read_link()
     }
 
     pub fn mountpoint(&self) -> &Arc<Mountpoint> {
@@ -145,7 +326,12 @@ impl Location {
         if !self.is_root_of_mount() {
             return Some(self.wrap(self.entry.parent().unwrap()));
         }
-        self.mountpoint.location()?.parent()
+        let location = self.mountpoint.location()?;
+        Location {
+            cred: self.cred.clone(),
+            ..location
+        }
+        .parent()
     }
 
     pub fn is_root(&self) -> bool {
@@ -166,6 +352,50 @@ impl Location {
         Ok(metadata)
     }
 
+    /// Updates the metadata of the node.
+    ///
+    /// With credentials, changing the mode or the times takes owning the
+    /// node, and changing the owner takes `CHOWN` except for owners moving
+    /// nodes to groups they belong to. The set-group-ID bit is cleared when
+    /// set by callers outside the group without `FSETID`.
+    pub fn update_metadata(&self, mut update: MetadataUpdate) -> VfsResult<()> {
+        if let Some(cred) = &self.cred {
+            let metadata = self.entry.metadata()?;
+            if update.mode.is_some()
+                || update.rdev.is_some()
+                || update.atime.is_some()
+                || update.mtime.is_some()
+            {
+                self.check_owner()?;
+            }
+            let chown = cred.caps.contains(Capabilities::CHOWN);
+            let (uid, gid) = update.owner.unwrap_or((metadata.uid, metadata.gid));
+            if !chown
+                && (uid != metadata.uid
+                    || (gid != metadata.gid && (cred.fsuid != metadata.uid || !cred.in_group(gid))))
+            {
+                return Err(VfsError::OperationNotPermitted);
+            }
+            if let Some(mode) = &mut update.mode
+                && !cred.in_group(gid)
+                && !cred.caps.contains(Capabilities::FSETID)
+            {
+                mode.remove(NodePermission::SET_GID);
+            }
+        }
+        self.entry.update_metadata(update)
+    }
+
+    pub fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.check_xattr(name)?;
+        self.entry.set_xattr(name, value, flags)
+    }
+
+    pub fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.check_xattr(name)?;
+        self.entry.remove_xattr(name)
+    }
+
     pub fn absolute_path(&self) -> VfsResult<PathBuf> {
         let mut components = vec![];
         let mut cur = self.clone();
@@ -196,17 +426,26 @@ impl Location {
         };
         let mountpoint = mountpoint.effective_mountpoint();
         let entry = mountpoint.root.clone();
-        Self::new(mountpoint, entry)
+        Self {
+            mountpoint,
+            entry,
+            cred: self.cred,
+        }
     }
 
+    /// Looks up `name` in this directory, taking search permission with
+    /// credentials.
     pub fn lookup_no_follow(&self, name: &str) -> VfsResult<Self> {
+        if self.cred.is_some() {
+            self.check_is_dir()?;
+            self.check(Access::EXECUTE)?;
+        }
         Ok(match name {
             DOT => self.clone(),
             DOTDOT => self.parent().unwrap_or_else(|| self.clone()),
-            _ => {
-                let loc = Self::new(self.mountpoint.clone(), self.entry.as_dir()?.lookup(name)?);
-                loc.resolve_mountpoint()
-            }
+            _ => self
+                .wrap(self.entry.as_dir()?.lookup(name)?)
+                .resolve_mountpoint(),
         })
     }
 
@@ -216,20 +455,48 @@ impl Location {
         node_type: NodeType,
         permission: NodePermission,
     ) -> VfsResult<Self> {
-        self.entry
-            .as_dir()?
-            .create(name, node_type, permission)
-            .map(|entry| self.wrap(entry))
+        let dir = self.entry.as_dir()?;
+        self.check(Access::WRITE | Access::EXECUTE)?;
+        let entry = match self.new_owner()? {
+            Some(owner) => dir.open_file(
+                name,
+                &OpenOptions {
+                    create: true,
+                    create_new: true,
+                    node_type,
+                    permission,
+                    user: Some(owner),
+                },
+            )?,
+            None => dir.create(name, node_type, permission)?,
+        };
+        Ok(self.wrap(entry))
+    }
+
+    /// Returns the owner of the nodes created in this directory with
+    /// credentials: the caller, and the group of the directory if it has
+    /// the set-group-ID bit.
+    fn new_owner(&self) -> VfsResult<Option<(u32, u32)>> {
+        let Some(cred) = &self.cred else {
+            return Ok(None);
+        };
+        let metadata = self.entry.metadata()?;
+        let gid = if metadata.mode.contains(NodePermission::SET_GID) {
+            metadata.gid
+        } else {
+            cred.fsgid
+        };
+        Ok(Some((cred.fsuid, gid)))
     }
 
     pub fn link(&self, name: &str, node: &Self) -> VfsResult<Self> {
         if !Arc::ptr_eq(&self.mountpoint, &node.mountpoint) {
             return Err(VfsError::CrossesDevices);
         }
-        self.entry
-            .as_dir()?
-            .link(name, &node.entry)
-            .map(|entry| self.wrap(entry))
+        let dir = self.entry.as_dir()?;
+        self.check(Access::WRITE | Access::EXECUTE)?;
+        self.check_hardlink(&node.entry)?;
+        dir.link(name, &node.entry).map(|entry| self.wrap(entry))
     }
 
     pub fn rename(&self, src_name: &str, dst_dir: &Self, dst_name: &str) -> VfsResult<()> {
@@ -240,19 +507,69 @@ impl Location {
         if src_entry.is_ancestor_of(&dst_dir.entry)? {
             return Err(VfsError::InvalidInput);
         }
+        self.check(Access::WRITE | Access::EXECUTE)?;
+        dst_dir.check(Access::WRITE | Access::EXECUTE)?;
+        // Moving a directory elsewhere changes its `..` entry.
+        if src_entry.is_dir() && !self.entry.ptr_eq(&dst_dir.entry) {
+            self.wrap(src_entry.clone()).check(Access::WRITE)?;
+        }
+        if self.cred.is_some() {
+            self.check_sticky(&src_entry)?;
+            if let Ok(dst_entry) = dst_dir.entry.as_dir()?.lookup(dst_name) {
+                dst_dir.check_sticky(&dst_entry)?;
+            }
+        }
         self.entry
             .as_dir()?
             .rename(src_name, dst_dir.entry.as_dir()?, dst_name)
     }
 
+    /// Unlinks the entry `name` of this directory.
+    ///
+    /// With credentials, this takes write and search permission on the
+    /// directory, and owning either of them if it is sticky.
     pub fn unlink(&self, name: &str, is_dir: bool) -> VfsResult<()> {
-        self.entry.as_dir()?.unlink(name, is_dir)
+        let dir = self.entry.as_dir()?;
+        self.check(Access::WRITE | Access::EXECUTE)?;
+        let entry = dir.lookup(name)?;
+        self.check_sticky(&entry)?;
+        dir.unlink(name, is_dir)
     }
 
+    /// Opens the entry `name` of this directory, creating it as told by
+    /// `options`.
+    ///
+    /// With credentials, this takes search permission on the directory,
+    /// and write permission too if the entry is created, which then belongs
+    /// to the caller unless `options.user` says otherwise. Giving it to
+    /// anyone else takes `CHOWN`. Opening existing nodes with `create` is
+    /// subject to [`Protection::fifos`] and [`Protection::regular`]. Access
+    /// to the node itself is left to the caller to check.
     pub fn open_file(&self, name: &str, options: &OpenOptions) -> VfsResult<Location> {
-        self.entry
-            .as_dir()?
-            .open_file(name, options)
+        let dir = self.entry.as_dir()?;
+        if let Some(cred) = &self.cred {
+            self.check(Access::EXECUTE)?;
+            if options.create {
+                match dir.lookup(name) {
+                    Ok(entry) if !options.create_new => self.check_protected_open(cred, &entry)?,
+                    Err(err) if err.canonicalize() == VfsError::NotFound => {
+                        self.check(Access::WRITE)?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let owner = self.new_owner()?;
+        if let (Some(user), Some(owner)) = (options.user, owner)
+            && user != owner
+        {
+            self.check_capable(Capabilities::CHOWN)?;
+        }
+        let options = OpenOptions {
+            user: options.user.or(owner),
+            ..*options
+        };
+        dir.open_file(name, &options)
             .map(|entry| self.wrap(entry).resolve_mountpoint())
     }
 
@@ -261,6 +578,7 @@ impl Location {
     }
 
     pub fn mount(&self, fs: &Filesystem) -> VfsResult<Arc<Mountpoint>> {
+        self.check_capable(Capabilities::SYS_ADMIN)?;
         let mut mountpoint = self.entry.as_dir()?.mountpoint.lock();
         if mountpoint.is_some() {
             return Err(VfsError::ResourceBusy);
@@ -275,6 +593,7 @@ impl Location {
     }
 
     pub fn unmount(&self) -> VfsResult<()> {
+        self.check_capable(Capabilities::SYS_ADMIN)?;
         if !self.is_root_of_mount() {
             return Err(VfsError::InvalidInput);
         }
@@ -309,3 +628,84 @@ impl Pollable for Location {
 
     fn register(&self, context: &mut Context<'_>, events: IoEvents);
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{
+        Credentials, Filesystem,
+        block::RamDisk,
+        fs::fat::FatFs,
+        test_util::{fat_image, memory_fs},
+    };
+
+    #[test]
+    fn test_open_file_owner() {
+        let root = memory_fs();
+        root.update_metadata(MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o777)),
+            ..Default::default()
+        })
+        .unwrap();
+        let user = Arc::new(Credentials::new(1000, 1000, vec![]));
+        let as_user = root.clone().with_credentials(user);
+        let owner = |loc: &Location| loc.metadata().map(|it| (it.uid, it.gid)).unwrap();
+
+        let options = OpenOptions {
+            create: true,
+            ..Default::default()
+        };
+        let file = as_user.open_file("mine", &options).unwrap();
+        assert_eq!(owner(&file), (1000, 1000));
+
+        // Creating nodes for someone else takes `CHOWN`.
+        let for_root = OpenOptions {
+            user: Some((0, 0)),
+            ..options.clone()
+        };
+        assert_eq!(
+            as_user.open_file("root", &for_root).err(),
+            Some(VfsError::OperationNotPermitted)
+        );
+        assert!(root.lookup_no_follow("root").is_err());
+        let for_self = OpenOptions {
+            user: Some((1000, 1000)),
+            ..options.clone()
+        };
+        as_user.open_file("self", &for_self).unwrap();
+
+        let admin = Arc::new(Credentials {
+            caps: Capabilities::CHOWN,
+            ..Credentials::new(1000, 1000, vec![])
+        });
+        let file = root
+            .clone()
+            .with_credentials(admin)
+            .open_file("root", &for_root)
+            .unwrap();
+        assert_eq!(owner(&file), (0, 0));
+    }
+
+    #[test]
+    fn test_create_unowned() {
+        // Nodes created with credentials on filesystems unable to record
+        // owners belong to the owner of the mount.
+        let disk = Arc::new(RamDisk::from_vec(fat_image(8192, 4, false)));
+        let fs = Filesystem::new(FatFs::new(disk).unwrap());
+        let root = Mountpoint::new_root(&fs).root_location();
+        let cred = Credentials {
+            caps: Capabilities::DAC_OVERRIDE,
+            ..Credentials::new(1000, 1000, vec![])
+        };
+        let file = root
+            .clone()
+            .with_credentials(Arc::new(cred))
+            .create("file", NodeType::RegularFile, NodePermission::default())
+            .unwrap();
+        let metadata = root.metadata().unwrap();
+        let owner = file.metadata().map(|it| (it.uid, it.gid));
+        assert_eq!(owner, Ok((metadata.uid, metadata.gid)));
+    }
+}
@@ -9,8 +9,10 @@ use hashbrown::HashMap;
 use super::DirEntry;
 use crate::{
     MetadataUpdate, Mountpoint, Mutex, MutexGuard, NodeOps, NodePermission, NodeType, VfsError,
-    VfsResult,
+    VfsResult, XattrFlags,
+    acl::{self, ACL_ACCESS, ACL_DEFAULT},
     path::{DOT, DOTDOT, MAX_NAME_LEN, verify_entry_name},
+    socket,
 };
 
 /// A trait for a sink that can receive directory entries.
@@ -164,17 +166,15 @@ impl DirNode {
     }
 
     fn lookup_locked(&self, name: &str, children: &mut DirChildren) -> VfsResult<DirEntry> {
-        use hashbrown::hash_map::Entry;
-        match children.entry(name.to_owned()) {
-            Entry::Occupied(e) => Ok(e.get().clone()),
-            Entry::Vacant(e) => {
-                let node = self.ops.lookup(name)?;
-                if self.ops.is_cacheable() {
-                    e.insert(node.clone());
-                }
-                Ok(node)
-            }
+        // Cached entries are found without allocating the name.
+        if let Some(entry) = children.get(name) {
+            return Ok(entry.clone());
         }
+        let node = self.ops.lookup(name)?;
+        if self.ops.is_cacheable() {
+            children.insert(name.to_owned(), node.clone());
+        }
+        Ok(node)
     }
 
     /// Looks up a directory entry by name.
@@ -235,6 +235,7 @@ impl DirNode {
 
         self.ops.unlink(name).inspect(|_| {
             Self::forget_entry(&mut children, name);
+            socket::unbind_socket(&entry);
         })
     }
 
@@ -257,9 +258,56 @@ impl DirNode {
         name: &str,
         node_type: NodeType,
         permission: NodePermission,
+        owner: Option<(u32, u32)>,
         children: &mut DirChildren,
     ) -> VfsResult<DirEntry> {
+        // Nodes get the default ACL of the directory as their access ACL,
+        // and as their own default ACL if they are directories. Symlinks
+        // have no ACL.
+        let default = match node_type {
+            NodeType::Symlink => None,
+            _ => acl::read(&*self.ops, ACL_DEFAULT)?,
+        };
+        let (access, permission) = match &default {
+            Some(default) => {
+                let (access, permission) = default.inherit(permission);
+                (Some(access), permission)
+            }
+            None => (None, permission),
+        };
         let entry = self.ops.create(name, node_type, permission)?;
+        let setup = || {
+            if owner.is_some() {
+                // Filesystems without owners keep their own.
+                match entry.update_metadata(MetadataUpdate {
+                    owner,
+                    ..Default::default()
+                }) {
+                    Ok(()) | Err(VfsError::Unsupported) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if let Some(access) = access.filter(|it| !it.is_minimal()) {
+                entry
+                    .0
+                    .node
+                    .set_xattr(ACL_ACCESS, &access.to_bytes(), XattrFlags::empty())?;
+            }
+            if let Some(default) = &default
+                && node_type == NodeType::Directory
+            {
+                entry
+                    .0
+                    .node
+                    .set_xattr(ACL_DEFAULT, &default.to_bytes(), XattrFlags::empty())?;
+            }
+            VfsResult::Ok(())
+        };
+        if let Err(err) = setup() {
+            // Nodes are not left behind half set up.
+            let _ = self.ops.unlink(name);
+            return Err(err);
+        }
         children.insert(name.to_owned(), entry.clone());
         Ok(entry)
     }
@@ -272,7 +320,7 @@ impl DirNode {
         permission: NodePermission,
     ) -> VfsResult<DirEntry> {
         verify_entry_name(name)?;
-        self.create_locked(name, node_type, permission, &mut self.cache.lock())
+        self.create_locked(name, node_type, permission, None, &mut self.cache.lock())
     }
 
     fn lock_both_cache<'a>(
@@ -299,12 +347,16 @@ impl DirNode {
         let (mut src_children, mut dst_children) = self.lock_both_cache(dst_dir);
 
         let src = self.lookup_locked(src_name, &mut src_children)?;
+        let mut replaced = None;
         if let Ok(dst) = dst_dir.lookup_locked(
             dst_name,
             dst_children
                 .as_mut()
                 .map_or_else(|| src_children.deref_mut(), DerefMut::deref_mut),
-        ) {
+        ) && !dst.ptr_eq(&src)
+        {
+            // Names of the same node, e.g. differing only in case on
+            // case-insensitive filesystems, are left to the filesystem.
             if src.node_type() == NodeType::Directory {
                 if let Ok(dir) = dst.as_dir()
                     && dir.has_children()?
@@ -314,6 +366,7 @@ impl DirNode {
             } else if dst.node_type() == NodeType::Directory {
                 return Err(VfsError::IsADirectory);
             }
+            replaced = Some(dst);
         }
         drop(src_children);
         drop(dst_children);
@@ -327,6 +380,10 @@ impl DirNode {
                     .map_or_else(|| src_children.deref_mut(), DerefMut::deref_mut),
                 dst_name,
             );
+            drop((src_children, dst_children));
+            if let Some(replaced) = &replaced {
+                socket::unbind_socket(replaced);
+            }
         })
     }
 
@@ -345,15 +402,13 @@ impl DirNode {
             Err(err) if err.canonicalize() == VfsError::NotFound && options.create => {}
             Err(err) => return Err(err),
         }
-        let entry =
-            self.create_locked(name, options.node_type, options.permission, &mut children)?;
-        if options.user.is_some() {
-            entry.update_metadata(MetadataUpdate {
-                owner: options.user,
-                ..Default::default()
-            })?;
-        }
-        Ok(entry)
+        self.create_locked(
+            name,
+            options.node_type,
+            options.permission,
+            options.user,
+            &mut children,
+        )
     }
 
     pub fn mountpoint(&self) -> Option<Arc<Mountpoint>> {
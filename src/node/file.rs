@@ -4,7 +4,7 @@ use core::ops::Deref;
 use axpoll::Pollable;
 
 use super::NodeOps;
-use crate::{VfsError, VfsResult};
+use crate::{VfsError, VfsResult, block::BlockDevice};
 
 pub trait FileNodeOps: NodeOps + Pollable {
     /// Reads a number of bytes starting from a given offset.
@@ -29,6 +29,11 @@ pub trait FileNodeOps: NodeOps + Pollable {
     fn ioctl(&self, _cmd: u32, _arg: usize) -> VfsResult<usize> {
         Err(VfsError::NotATty)
     }
+
+    /// Returns the block device behind a block special file, for mounting.
+    fn block_device(&self) -> VfsResult<Arc<dyn BlockDevice>> {
+        Err(axerrno::LinuxError::ENOTBLK.into())
+    }
 }
 
 #[repr(transparent)]
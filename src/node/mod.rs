@@ -17,13 +17,17 @@ use core::{
 };
 use smallvec::SmallVec;
 
+use axerrno::LinuxError;
 use axpoll::{IoEvents, Pollable};
 pub use dir::*;
 pub use file::*;
 use inherit_methods_macro::inherit_methods;
 
 use crate::{
-    FilesystemOps, Metadata, MetadataUpdate, Mutex, MutexGuard, NodeType, VfsError, VfsResult,
+    Access, Capabilities, Credentials, FilesystemOps, Metadata, MetadataUpdate, Mutex, MutexGuard,
+    NodePermission, NodeType, VfsError, VfsResult,
+    acl::{self, ACL_ACCESS, ACL_DEFAULT, Acl},
+    block::BlockDevice,
     path::PathBuf,
 };
 
@@ -57,6 +61,41 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Flags of [`NodeOps::set_xattr`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct XattrFlags: u32 {
+        /// Fails if the attribute already exists.
+        const CREATE = 0x0001;
+        /// Fails if the attribute does not exist.
+        const REPLACE = 0x0002;
+    }
+}
+
+/// Maximum length of the name of an extended attribute.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Maximum size of the value of an extended attribute.
+pub const XATTR_SIZE_MAX: usize = 65536;
+/// Maximum total size of the names of the extended attributes of a node,
+/// each counted with a terminating NUL.
+pub const XATTR_LIST_MAX: usize = 65536;
+
+/// Namespaces of extended attributes.
+const XATTR_NAMESPACES: [&str; 4] = ["user.", "trusted.", "security.", "system."];
+
+/// Checks that `name` is the name of an extended attribute in a known
+/// namespace.
+fn check_xattr_name(name: &str) -> VfsResult<()> {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(VfsError::OutOfRange);
+    }
+    match XATTR_NAMESPACES.iter().find(|it| name.starts_with(*it)) {
+        Some(prefix) if name.len() == prefix.len() => Err(VfsError::InvalidInput),
+        Some(_) => Ok(()),
+        None => Err(VfsError::OperationNotSupported),
+    }
+}
+
 /// Filesystem node operationss
 #[allow(clippy::len_without_is_empty)]
 pub trait NodeOps: Send + Sync + 'static {
@@ -87,6 +126,30 @@ pub trait NodeOps: Send + Sync + 'static {
     fn flags(&self) -> NodeFlags {
         NodeFlags::empty()
     }
+
+    /// Gets the value of the extended attribute `name`.
+    ///
+    /// Missing attributes are reported with `ENODATA`.
+    fn get_xattr(&self, _name: &str) -> VfsResult<Vec<u8>> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Sets the value of the extended attribute `name`.
+    fn set_xattr(&self, _name: &str, _value: &[u8], _flags: XattrFlags) -> VfsResult<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Lists the names of the extended attributes.
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Removes the extended attribute `name`.
+    ///
+    /// Missing attributes are reported with `ENODATA`.
+    fn remove_xattr(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::Unsupported)
+    }
 }
 
 enum Node {
@@ -228,8 +291,6 @@ impl DirEntry {
 
     pub fn filesystem(&self) -> &dyn FilesystemOps;
 
-    pub fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()>;
-
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> VfsResult<u64>;
 
@@ -257,6 +318,49 @@ impl DirEntry {
         }))
     }
 
+    pub fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mode = update.mode;
+        self.0.node.update_metadata(update)?;
+        // The access ACL follows the permission bits.
+        if let Some(mode) = mode
+            && let Some(mut acl) = acl::read(&*self.0.node, ACL_ACCESS)?
+        {
+            acl.chmod(mode);
+            self.0
+                .node
+                .set_xattr(ACL_ACCESS, &acl.to_bytes(), XattrFlags::empty())?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the caller of `cred` is granted `access` to the node, by
+    /// its access ACL or else its permission bits, or by its capabilities.
+    pub fn check_access(&self, cred: &Credentials, access: Access) -> VfsResult<()> {
+        let metadata = self.metadata()?;
+        let acl =
+            acl::read(&*self.0.node, ACL_ACCESS)?.unwrap_or_else(|| Acl::from_mode(metadata.mode));
+        if acl.permits((metadata.uid, metadata.gid), cred, access) {
+            return Ok(());
+        }
+        let is_dir = self.is_dir();
+        // Executing needs one execute bit set even for the superuser.
+        let executable = is_dir || metadata.mode.bits() & 0o111 != 0;
+        if cred.caps.contains(Capabilities::DAC_OVERRIDE)
+            && (executable || !access.contains(Access::EXECUTE))
+        {
+            return Ok(());
+        }
+        let searchable = if is_dir {
+            Access::READ | Access::EXECUTE
+        } else {
+            Access::READ
+        };
+        if cred.caps.contains(Capabilities::DAC_READ_SEARCH) && searchable.contains(access) {
+            return Ok(());
+        }
+        Err(VfsError::PermissionDenied)
+    }
+
     pub fn metadata(&self) -> VfsResult<Metadata> {
         self.0.node.metadata().map(|mut metadata| {
             metadata.node_type = self.0.node_type;
@@ -380,9 +484,111 @@ impl DirEntry {
         }
     }
 
+    pub fn block_device(&self) -> VfsResult<Arc<dyn BlockDevice>> {
+        match &self.0.node {
+            Node::File(file) if self.0.node_type == NodeType::BlockDevice => file.block_device(),
+            _ => Err(LinuxError::ENOTBLK.into()),
+        }
+    }
+
     pub fn user_data(&self) -> MutexGuard<'_, TypeMap> {
         self.0.user_data.lock()
     }
+
+    /// Whether attributes of the `user.` namespace may be set on the node,
+    /// which is only the case of regular files and directories.
+    fn allows_user_xattr(&self, name: &str) -> bool {
+        !name.starts_with("user.")
+            || matches!(
+                self.node_type(),
+                NodeType::RegularFile | NodeType::Directory
+            )
+    }
+
+    pub fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        check_xattr_name(name)?;
+        if !self.allows_user_xattr(name) {
+            return Err(LinuxError::ENODATA.into());
+        }
+        self.0.node.get_xattr(name)
+    }
+
+    pub fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        check_xattr_name(name)?;
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(VfsError::ArgumentListTooLong);
+        }
+        if flags.contains(XattrFlags::CREATE | XattrFlags::REPLACE) {
+            return Err(VfsError::InvalidInput);
+        }
+        if !self.allows_user_xattr(name) {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        match name {
+            ACL_ACCESS => {
+                // The permission bits follow the access ACL, which is only
+                // kept if it says more than them.
+                let acl = Acl::parse(value)?;
+                let special = self.0.node.metadata()?.mode
+                    & (NodePermission::SET_UID | NodePermission::SET_GID | NodePermission::STICKY);
+                let old = acl::read(&*self.0.node, name)?;
+                if acl.is_minimal() {
+                    if flags.contains(XattrFlags::CREATE) && old.is_some() {
+                        return Err(VfsError::AlreadyExists);
+                    }
+                    if flags.contains(XattrFlags::REPLACE) && old.is_none() {
+                        return Err(LinuxError::ENODATA.into());
+                    }
+                    if old.is_some() {
+                        self.0.node.remove_xattr(name)?;
+                    }
+                } else {
+                    self.0.node.set_xattr(name, value, flags)?;
+                }
+                let result = self.0.node.update_metadata(MetadataUpdate {
+                    mode: Some(acl.mode() | special),
+                    ..Default::default()
+                });
+                if result.is_err() {
+                    // Put the previous ACL back, so that they stay in sync.
+                    let _ = match &old {
+                        Some(old) => {
+                            self.0
+                                .node
+                                .set_xattr(name, &old.to_bytes(), XattrFlags::empty())
+                        }
+                        None if !acl.is_minimal() => self.0.node.remove_xattr(name),
+                        None => Ok(()),
+                    };
+                }
+                return result;
+            }
+            ACL_DEFAULT => {
+                if !self.is_dir() {
+                    return Err(VfsError::PermissionDenied);
+                }
+                Acl::parse(value)?;
+            }
+            _ => {}
+        }
+        self.0.node.set_xattr(name, value, flags)
+    }
+
+    pub fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        let names = self.0.node.list_xattr()?;
+        if names.iter().map(|it| it.len() + 1).sum::<usize>() > XATTR_LIST_MAX {
+            return Err(VfsError::ArgumentListTooLong);
+        }
+        Ok(names)
+    }
+
+    pub fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        check_xattr_name(name)?;
+        if !self.allows_user_xattr(name) {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        self.0.node.remove_xattr(name)
+    }
 }
 
 impl Pollable for DirEntry {
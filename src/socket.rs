@@ -0,0 +1,182 @@
+//! Binding of Unix domain sockets to filesystem nodes.
+//!
+//! The socket object is not stored in the dentry (which may be evicted at any
+//! time), but in a global registry keyed by the identity of the underlying
+//! inode. This makes the binding independent of the filesystem
+//! implementation, and it survives dentry cache eviction.
+
+use alloc::{collections::BTreeMap, sync::Arc, sync::Weak};
+use core::any::Any;
+
+use crate::{
+    DirEntry, Location, Mutex, NodePermission, NodeType, OpenOptions, VfsError, VfsResult,
+    WeakDirEntry,
+};
+
+/// Identity of an inode: (address of the filesystem root, inode number).
+type SocketKey = (usize, u64);
+
+struct Binding {
+    /// Root of the filesystem, held so that its address is not reused by
+    /// another filesystem while the binding is registered.
+    root: WeakDirEntry,
+    socket: Weak<dyn Any + Send + Sync>,
+}
+
+impl Binding {
+    fn is_alive(&self) -> bool {
+        self.socket.strong_count() > 0 && self.root.upgrade().is_some()
+    }
+}
+
+static BOUND_SOCKETS: Mutex<BTreeMap<SocketKey, Binding>> = Mutex::new(BTreeMap::new());
+
+fn fs_root(entry: &DirEntry) -> DirEntry {
+    let mut root = entry.clone();
+    while let Some(parent) = root.parent() {
+        root = parent;
+    }
+    root
+}
+
+fn socket_key(entry: &DirEntry) -> SocketKey {
+    (fs_root(entry).as_ptr(), entry.inode())
+}
+
+/// Removes the binding of a socket node once its last link is gone.
+pub(crate) fn unbind_socket(entry: &DirEntry) {
+    if entry.node_type() == NodeType::Socket && !entry.metadata().is_ok_and(|it| it.nlink > 0) {
+        BOUND_SOCKETS.lock().remove(&socket_key(entry));
+    }
+}
+
+impl Location {
+    /// Creates a socket node named `name` in this directory and binds `socket`
+    /// to it.
+    ///
+    /// Returns `AlreadyExists` if an entry with the same name exists. Only a
+    /// weak reference to `socket` is kept, so the binding goes away when the
+    /// socket itself is dropped.
+    pub fn bind_socket<T: Any + Send + Sync>(
+        &self,
+        name: &str,
+        permission: NodePermission,
+        socket: &Arc<T>,
+    ) -> VfsResult<Location> {
+        let location = self.open_file(
+            name,
+            &OpenOptions {
+                create: true,
+                create_new: true,
+                node_type: NodeType::Socket,
+                permission,
+                user: None,
+            },
+        )?;
+        let socket: Arc<dyn Any + Send + Sync> = socket.clone();
+        let binding = Binding {
+            root: fs_root(location.entry()).downgrade(),
+            socket: Arc::downgrade(&socket),
+        };
+        let mut sockets = BOUND_SOCKETS.lock();
+        sockets.retain(|_, binding| binding.is_alive());
+        sockets.insert(socket_key(location.entry()), binding);
+        Ok(location)
+    }
+
+    /// Retrieves the socket object bound to this node.
+    ///
+    /// Returns `ConnectionRefused` if the node is not a socket, nothing is
+    /// bound to it, the bound socket has been dropped or it is not of type
+    /// `T`.
+    pub fn connect_socket<T: Any + Send + Sync>(&self) -> VfsResult<Arc<T>> {
+        if self.node_type() != NodeType::Socket {
+            return Err(VfsError::ConnectionRefused);
+        }
+        let key = socket_key(self.entry());
+        let mut sockets = BOUND_SOCKETS.lock();
+        let Some(socket) = sockets.get(&key).and_then(|it| it.socket.upgrade()) else {
+            sockets.remove(&key);
+            return Err(VfsError::ConnectionRefused);
+        };
+        socket.downcast().map_err(|_| VfsError::ConnectionRefused)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::memory_fs;
+
+    struct Socket(u32);
+
+    #[test]
+    fn test_bind_and_connect() {
+        let root = memory_fs();
+        let perm = NodePermission::from_bits_truncate(0o755);
+        let socket = Arc::new(Socket(1));
+        let sock = root.bind_socket("sock", perm, &socket).unwrap();
+        assert_eq!(sock.connect_socket::<Socket>().unwrap().0, 1);
+        assert_eq!(
+            root.lookup_no_follow("sock")
+                .unwrap()
+                .connect_socket::<Socket>()
+                .unwrap()
+                .0,
+            1
+        );
+        assert_eq!(
+            sock.connect_socket::<u32>().err(),
+            Some(VfsError::ConnectionRefused)
+        );
+        assert_eq!(
+            root.bind_socket("sock", perm, &Arc::new(Socket(2))).err(),
+            Some(VfsError::AlreadyExists)
+        );
+
+        drop(socket);
+        assert_eq!(
+            sock.connect_socket::<Socket>().err(),
+            Some(VfsError::ConnectionRefused)
+        );
+    }
+
+    #[test]
+    fn test_prune_on_bind() {
+        let root = memory_fs();
+        let perm = NodePermission::from_bits_truncate(0o755);
+        let sock = root.bind_socket("a", perm, &Arc::new(Socket(1))).unwrap();
+        let key = socket_key(sock.entry());
+        let socket = Arc::new(Socket(2));
+        root.bind_socket("b", perm, &socket).unwrap();
+        assert!(!BOUND_SOCKETS.lock().contains_key(&key));
+    }
+
+    #[test]
+    fn test_unbind() {
+        let root = memory_fs();
+        let perm = NodePermission::from_bits_truncate(0o755);
+        let socket = Arc::new(Socket(1));
+
+        // Unlinked through the directory node itself.
+        let sock = root.bind_socket("a", perm, &socket).unwrap();
+        root.entry().as_dir().unwrap().unlink("a", false).unwrap();
+        assert_eq!(
+            sock.connect_socket::<Socket>().err(),
+            Some(VfsError::ConnectionRefused)
+        );
+
+        // Replaced by a rename, while a hard link keeps it bound.
+        let sock = root.bind_socket("b", perm, &socket).unwrap();
+        root.link("c", &sock).unwrap();
+        root.create("d", NodeType::RegularFile, perm).unwrap();
+        root.rename("d", &root, "b").unwrap();
+        assert!(sock.connect_socket::<Socket>().is_ok());
+        root.create("d", NodeType::RegularFile, perm).unwrap();
+        root.rename("d", &root, "c").unwrap();
+        assert_eq!(
+            sock.connect_socket::<Socket>().err(),
+            Some(VfsError::ConnectionRefused)
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Helpers shared by the tests of the crate.
+
+use alloc::{string::String, string::ToString, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, path::PathBuf};
+
+use crate::{
+    Filesystem, Location, Mountpoint,
+    block::RamDisk,
+    fs::{
+        axfs::{self, AxFs},
+        fat::{
+            BOOT_SIGNATURE, FSINFO_LEAD_SIGNATURE, FSINFO_STRUCT_SIGNATURE, FSINFO_TRAIL_SIGNATURE,
+        },
+        hostfs::HostFs,
+    },
+};
+
+/// Temporary host directory, removed on drop.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(std::format!(
+            "axfs-ng-vfs-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Mounts the host directory `dir` through a [`HostFs`].
+pub fn mount(dir: &TempDir) -> Location {
+    let fs = Filesystem::new(HostFs::new(&dir.0).unwrap());
+    Mountpoint::new_root(&fs).root_location()
+}
+
+/// Returns a writable in-memory filesystem.
+pub fn memory_fs() -> Location {
+    let disk = Arc::new(RamDisk::new(4 << 20));
+    axfs::mkfs(disk.clone(), &Default::default()).unwrap();
+    let fs = Filesystem::new(AxFs::new(disk).unwrap());
+    Mountpoint::new_root(&fs).root_location()
+}
+
+/// Formats a FAT image of `sectors` 512-byte sectors.
+pub fn fat_image(sectors: u32, sectors_per_cluster: u8, fat32: bool) -> Vec<u8> {
+    let (reserved, root_entries) = if fat32 { (32, 0) } else { (1, 512) };
+    let root_sectors = root_entries * 32 / 512;
+    let mut fat_sectors = 1;
+    let clusters = loop {
+        let data = sectors - reserved - 2 * fat_sectors - root_sectors;
+        let clusters = data / sectors_per_cluster as u32;
+        let bits = match (fat32, clusters < 4085) {
+            (true, _) => 32,
+            (false, true) => 12,
+            (false, false) => 16,
+        };
+        let needed = ((clusters + 2) * bits).div_ceil(8 * 512);
+        if needed <= fat_sectors {
+            break clusters;
+        }
+        fat_sectors = needed;
+    };
+
+    let mut image = vec![0; sectors as usize * 512];
+    let bs = &mut image[..512];
+    bs[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    bs[3..11].copy_from_slice(b"MSWIN4.1");
+    bs[11..13].copy_from_slice(&512u16.to_le_bytes());
+    bs[13] = sectors_per_cluster;
+    bs[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    bs[16] = 2;
+    bs[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    bs[21] = 0xf8;
+    bs[32..36].copy_from_slice(&sectors.to_le_bytes());
+    if fat32 {
+        bs[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+        bs[44..48].copy_from_slice(&2u32.to_le_bytes());
+        bs[48..50].copy_from_slice(&1u16.to_le_bytes());
+    } else {
+        bs[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    }
+    bs[510..512].copy_from_slice(&BOOT_SIGNATURE);
+
+    let fat_start = reserved as usize * 512;
+    for copy in 0..2 {
+        let fat = &mut image[fat_start + copy * fat_sectors as usize * 512..];
+        if fat32 {
+            fat[..12].copy_from_slice(&[
+                0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f,
+            ]);
+        } else {
+            fat[..4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]);
+        }
+    }
+    if fat32 {
+        let fsinfo = &mut image[512..1024];
+        fsinfo[..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&FSINFO_TRAIL_SIGNATURE.to_le_bytes());
+    }
+    image
+}
+
+/// Lists the names in the directory `dir`, in the order it gives them.
+pub fn list(dir: &Location) -> Vec<String> {
+    let mut names = vec![];
+    dir.read_dir(0, &mut |name: &str, _, _, _| {
+        names.push(name.to_string());
+        true
+    })
+    .unwrap();
+    names
+}
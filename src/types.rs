@@ -63,6 +63,17 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    /// Kinds of access to a node, as granted to each class of users by
+    /// [`NodePermission`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Access: u16 {
+        const READ = 0o4;
+        const WRITE = 0o2;
+        const EXECUTE = 0o1;
+    }
+}
+
 impl Default for NodePermission {
     fn default() -> Self {
         Self::from_bits_truncate(0o666)
@@ -110,6 +121,9 @@ pub struct MetadataUpdate {
     pub mode: Option<NodePermission>,
     /// The owner (uid, gid)
     pub owner: Option<(u32, u32)>,
+    /// Device ID of device nodes. Filesystems unable to record it return
+    /// `Unsupported`.
+    pub rdev: Option<DeviceId>,
 
     /// Time of last access
     pub atime: Option<Duration>,
@@ -0,0 +1,827 @@
+//! The filesystem functions of WASI preview1, over [`Location`]s.
+//!
+//! [`Wasi`] holds the file descriptors of a WebAssembly instance, and has a
+//! method for each `fd_*` and `path_*` function, which the runtime calls with
+//! the arguments it read from the memory of the instance. Paths are resolved
+//! under the directory descriptor they are relative to: absolute paths, and
+//! `..` or symbolic links leading out of that directory, fail with
+//! [`Errno::NotCapable`].
+
+mod types;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::time::Duration;
+
+pub use self::types::*;
+use crate::{Location, Metadata, MetadataUpdate, Mutex, NodePermission, NodeType};
+
+/// Index of a file descriptor.
+pub type Fd = u32;
+
+pub type WasiResult<T> = Result<T, Errno>;
+
+/// Descriptor given to the first preopened directory, after the standard
+/// streams.
+const FIRST_FD: Fd = 3;
+/// Largest number of symbolic links followed while resolving a path.
+const MAX_SYMLINKS: usize = 40;
+/// Size of a `dirent` written by `fd_readdir`, without its name.
+pub const DIRENT_SIZE: usize = 24;
+
+/// Options for a [`Wasi`].
+#[derive(Debug, Clone, Default)]
+pub struct WasiOptions {
+    /// Source of the current time, used by `*_filestat_set_times` with
+    /// `ATIM_NOW` or `MTIM_NOW`.
+    pub clock: Option<fn() -> Duration>,
+}
+
+struct Descriptor {
+    loc: Location,
+    /// Name the directory was preopened as.
+    preopen: Option<String>,
+    rights: Rights,
+    inheriting: Rights,
+    flags: FdFlags,
+    /// Offset of `fd_read` and `fd_write`.
+    offset: u64,
+}
+
+/// File descriptors of a WebAssembly instance.
+pub struct Wasi {
+    options: WasiOptions,
+    fds: Mutex<BTreeMap<Fd, Arc<Mutex<Descriptor>>>>,
+}
+
+fn filestat(metadata: Metadata) -> Filestat {
+    Filestat {
+        dev: metadata.device,
+        ino: metadata.inode,
+        filetype: metadata.node_type.into(),
+        nlink: metadata.nlink,
+        size: metadata.size,
+        atim: metadata.atime.as_nanos() as u64,
+        mtim: metadata.mtime.as_nanos() as u64,
+        ctim: metadata.ctime.as_nanos() as u64,
+    }
+}
+
+/// Splits `path` into components, last first.
+fn components(path: &str) -> impl Iterator<Item = String> {
+    path.rsplit('/')
+        .filter(|it| !it.is_empty())
+        .map(String::from)
+}
+
+/// Resolves `path` under `base`, following the symbolic links met on the
+/// way, and the one it names if `follow` is set.
+fn resolve(base: &Location, path: &str, follow: bool) -> WasiResult<Location> {
+    if path.is_empty() {
+        return Err(Errno::NoEnt);
+    }
+    if path.starts_with('/') {
+        return Err(Errno::NotCapable);
+    }
+    let must_be_dir = path.ends_with('/');
+    // Directories walked through, which `..` goes back up.
+    let mut stack = vec![base.clone()];
+    let mut pending: Vec<String> = components(path).collect();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        let dir = stack.last().unwrap();
+        dir.check_is_dir()?;
+        match name.as_str() {
+            "." => {}
+            ".." => {
+                if stack.len() == 1 {
+                    return Err(Errno::NotCapable);
+                }
+                stack.pop();
+            }
+            _ => {
+                let loc = dir.lookup_no_follow(&name)?;
+                if loc.node_type() != NodeType::Symlink
+                    || (pending.is_empty() && !follow && !must_be_dir)
+                {
+                    stack.push(loc);
+                    continue;
+                }
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Errno::Loop);
+                }
+                let target = loc.follow_link()?;
+                if target.starts_with('/') {
+                    return Err(Errno::NotCapable);
+                }
+                if target.is_empty() {
+                    return Err(Errno::NoEnt);
+                }
+                pending.extend(components(&target));
+            }
+        }
+    }
+    let loc = stack.pop().unwrap();
+    if must_be_dir {
+        loc.check_is_dir()?;
+    }
+    Ok(loc)
+}
+
+/// Resolves the directory containing the last component of `path` under
+/// `base`, returning it and that component.
+fn resolve_parent<'a>(base: &Location, path: &'a str) -> WasiResult<(Location, &'a str)> {
+    if path.starts_with('/') {
+        return Err(Errno::NotCapable);
+    }
+    let (dir, name) = match path.trim_end_matches('/').rsplit_once('/') {
+        Some((dir, name)) => (resolve(base, dir, true)?, name),
+        None => (base.clone(), path.trim_end_matches('/')),
+    };
+    match name {
+        "" => Err(Errno::NoEnt),
+        "." | ".." => Err(Errno::Inval),
+        _ => {
+            dir.check_is_dir()?;
+            Ok((dir, name))
+        }
+    }
+}
+
+impl Wasi {
+    pub fn new(options: WasiOptions) -> Self {
+        Self {
+            options,
+            fds: Mutex::default(),
+        }
+    }
+
+    /// Gives the instance access to `dir`, as `name`.
+    pub fn preopen(&self, dir: Location, name: &str) -> WasiResult<Fd> {
+        dir.check_is_dir()?;
+        Ok(self.insert(Descriptor {
+            loc: dir,
+            preopen: Some(name.to_string()),
+            rights: Rights::DIRECTORY,
+            inheriting: Rights::DIRECTORY | Rights::FILE,
+            flags: FdFlags::empty(),
+            offset: 0,
+        }))
+    }
+
+    /// Inserts a descriptor under the lowest unused index.
+    fn insert(&self, desc: Descriptor) -> Fd {
+        let mut fds = self.fds.lock();
+        let fd = (FIRST_FD..)
+            .find(|it| !fds.contains_key(it))
+            .unwrap_or(FIRST_FD);
+        fds.insert(fd, Arc::new(Mutex::new(desc)));
+        fd
+    }
+
+    /// Returns the descriptor `fd`, if it has all of `rights`.
+    fn get(&self, fd: Fd, rights: Rights) -> WasiResult<Arc<Mutex<Descriptor>>> {
+        let desc = self.fds.lock().get(&fd).cloned().ok_or(Errno::BadF)?;
+        if !desc.lock().rights.contains(rights) {
+            return Err(Errno::NotCapable);
+        }
+        Ok(desc)
+    }
+
+    /// Returns the location of `fd`, if it has all of `rights`.
+    fn loc(&self, fd: Fd, rights: Rights) -> WasiResult<Location> {
+        Ok(self.get(fd, rights)?.lock().loc.clone())
+    }
+
+    /// Returns the directory `fd`, if it has all of `rights`.
+    fn dir(&self, fd: Fd, rights: Rights) -> WasiResult<Location> {
+        let loc = self.loc(fd, rights)?;
+        loc.check_is_dir()?;
+        Ok(loc)
+    }
+
+    fn times(&self, atim: u64, mtim: u64, flags: FstFlags) -> WasiResult<MetadataUpdate> {
+        if flags.contains(FstFlags::ATIM | FstFlags::ATIM_NOW)
+            || flags.contains(FstFlags::MTIM | FstFlags::MTIM_NOW)
+        {
+            return Err(Errno::Inval);
+        }
+        let now = || self.options.clock.map(|clock| clock()).ok_or(Errno::NoSys);
+        let time = |value: u64, set: FstFlags, set_now: FstFlags| {
+            if flags.contains(set) {
+                Ok(Some(Duration::from_nanos(value)))
+            } else if flags.contains(set_now) {
+                now().map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        Ok(MetadataUpdate {
+            atime: time(atim, FstFlags::ATIM, FstFlags::ATIM_NOW)?,
+            mtime: time(mtim, FstFlags::MTIM, FstFlags::MTIM_NOW)?,
+            ..Default::default()
+        })
+    }
+
+    pub fn fd_prestat_get(&self, fd: Fd) -> WasiResult<usize> {
+        let desc = self.get(fd, Rights::empty())?;
+        let desc = desc.lock();
+        desc.preopen.as_ref().map(String::len).ok_or(Errno::BadF)
+    }
+
+    pub fn fd_prestat_dir_name(&self, fd: Fd, buf: &mut [u8]) -> WasiResult<()> {
+        let desc = self.get(fd, Rights::empty())?;
+        let desc = desc.lock();
+        let name = desc.preopen.as_ref().ok_or(Errno::BadF)?;
+        buf.get_mut(..name.len())
+            .ok_or(Errno::NameTooLong)?
+            .copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    pub fn fd_close(&self, fd: Fd) -> WasiResult<()> {
+        self.fds.lock().remove(&fd).map(drop).ok_or(Errno::BadF)
+    }
+
+    pub fn fd_renumber(&self, fd: Fd, to: Fd) -> WasiResult<()> {
+        let mut fds = self.fds.lock();
+        if !fds.contains_key(&to) {
+            return Err(Errno::BadF);
+        }
+        let desc = fds.remove(&fd).ok_or(Errno::BadF)?;
+        fds.insert(to, desc);
+        Ok(())
+    }
+
+    pub fn fd_fdstat_get(&self, fd: Fd) -> WasiResult<Fdstat> {
+        let desc = self.get(fd, Rights::empty())?;
+        let desc = desc.lock();
+        Ok(Fdstat {
+            filetype: desc.loc.node_type().into(),
+            flags: desc.flags,
+            rights_base: desc.rights,
+            rights_inheriting: desc.inheriting,
+        })
+    }
+
+    pub fn fd_fdstat_set_flags(&self, fd: Fd, flags: FdFlags) -> WasiResult<()> {
+        self.get(fd, Rights::FD_FDSTAT_SET_FLAGS)?.lock().flags = flags;
+        Ok(())
+    }
+
+    /// Drops rights of `fd`, which can never be gained back.
+    pub fn fd_fdstat_set_rights(&self, fd: Fd, base: Rights, inheriting: Rights) -> WasiResult<()> {
+        let desc = self.get(fd, Rights::empty())?;
+        let mut desc = desc.lock();
+        if !desc.rights.contains(base) || !desc.inheriting.contains(inheriting) {
+            return Err(Errno::NotCapable);
+        }
+        desc.rights = base;
+        desc.inheriting = inheriting;
+        Ok(())
+    }
+
+    pub fn fd_filestat_get(&self, fd: Fd) -> WasiResult<Filestat> {
+        Ok(filestat(self.loc(fd, Rights::FD_FILESTAT_GET)?.metadata()?))
+    }
+
+    pub fn fd_filestat_set_size(&self, fd: Fd, size: u64) -> WasiResult<()> {
+        let loc = self.loc(fd, Rights::FD_FILESTAT_SET_SIZE)?;
+        Ok(loc.entry().as_file()?.set_len(size)?)
+    }
+
+    pub fn fd_filestat_set_times(
+        &self,
+        fd: Fd,
+        atim: u64,
+        mtim: u64,
+        flags: FstFlags,
+    ) -> WasiResult<()> {
+        let loc = self.loc(fd, Rights::FD_FILESTAT_SET_TIMES)?;
+        Ok(loc.update_metadata(self.times(atim, mtim, flags)?)?)
+    }
+
+    /// Grows the file `fd` to cover `len` bytes from `offset`.
+    pub fn fd_allocate(&self, fd: Fd, offset: u64, len: u64) -> WasiResult<()> {
+        let loc = self.loc(fd, Rights::FD_ALLOCATE)?;
+        let file = loc.entry().as_file()?;
+        let end = offset.checked_add(len).ok_or(Errno::FBig)?;
+        if file.len()? < end {
+            file.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    pub fn fd_sync(&self, fd: Fd) -> WasiResult<()> {
+        Ok(self.loc(fd, Rights::FD_SYNC)?.sync(false)?)
+    }
+
+    pub fn fd_datasync(&self, fd: Fd) -> WasiResult<()> {
+        Ok(self.loc(fd, Rights::FD_DATASYNC)?.sync(true)?)
+    }
+
+    pub fn fd_pread(&self, fd: Fd, iovs: &mut [&mut [u8]], offset: u64) -> WasiResult<usize> {
+        let loc = self.loc(fd, Rights::FD_READ | Rights::FD_SEEK)?;
+        read(&loc, iovs, offset)
+    }
+
+    pub fn fd_read(&self, fd: Fd, iovs: &mut [&mut [u8]]) -> WasiResult<usize> {
+        let desc = self.get(fd, Rights::FD_READ)?;
+        let mut desc = desc.lock();
+        let read = read(&desc.loc, iovs, desc.offset)?;
+        desc.offset += read as u64;
+        Ok(read)
+    }
+
+    pub fn fd_pwrite(&self, fd: Fd, iovs: &[&[u8]], offset: u64) -> WasiResult<usize> {
+        let desc = self.get(fd, Rights::FD_WRITE | Rights::FD_SEEK)?;
+        let desc = desc.lock();
+        let (written, _) = write(&desc, iovs, offset)?;
+        Ok(written)
+    }
+
+    /// Writes at the offset of `fd`, or at the end of the file if it was
+    /// opened with [`FdFlags::APPEND`].
+    pub fn fd_write(&self, fd: Fd, iovs: &[&[u8]]) -> WasiResult<usize> {
+        let desc = self.get(fd, Rights::FD_WRITE)?;
+        let mut desc = desc.lock();
+        let (written, end) = write(&desc, iovs, desc.offset)?;
+        desc.offset = end;
+        Ok(written)
+    }
+
+    pub fn fd_seek(&self, fd: Fd, delta: i64, whence: Whence) -> WasiResult<u64> {
+        let rights = if delta == 0 && whence == Whence::Cur {
+            Rights::FD_TELL
+        } else {
+            Rights::FD_SEEK
+        };
+        let desc = self.get(fd, rights)?;
+        let mut desc = desc.lock();
+        let file = desc.loc.entry().as_file().map_err(|_| Errno::BadF)?;
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Cur => desc.offset,
+            Whence::End => file.len()?,
+        };
+        desc.offset = base.checked_add_signed(delta).ok_or(Errno::Inval)?;
+        Ok(desc.offset)
+    }
+
+    pub fn fd_tell(&self, fd: Fd) -> WasiResult<u64> {
+        Ok(self.get(fd, Rights::FD_TELL)?.lock().offset)
+    }
+
+    /// Fills `buf` with the entries of the directory `fd` from `cookie` on.
+    ///
+    /// Each entry is a `dirent` followed by the name, and has the cookie of
+    /// the next entry. The last entry is cut short if it does not fit, so
+    /// that the directory has been read to its end once fewer than
+    /// `buf.len()` bytes are returned.
+    pub fn fd_readdir(&self, fd: Fd, buf: &mut [u8], cookie: u64) -> WasiResult<usize> {
+        let dir = self.dir(fd, Rights::FD_READDIR)?;
+        let mut used = 0;
+        dir.read_dir(cookie, &mut |name: &str,
+                                   ino: u64,
+                                   node_type: NodeType,
+                                   next: u64| {
+            let mut dirent = [0; DIRENT_SIZE];
+            dirent[..8].copy_from_slice(&next.to_le_bytes());
+            dirent[8..16].copy_from_slice(&ino.to_le_bytes());
+            dirent[16..20].copy_from_slice(&(name.len() as u32).to_le_bytes());
+            dirent[20] = FileType::from(node_type) as u8;
+            for part in [&dirent[..], name.as_bytes()] {
+                let len = part.len().min(buf.len() - used);
+                buf[used..used + len].copy_from_slice(&part[..len]);
+                used += len;
+            }
+            used < buf.len()
+        })?;
+        Ok(used)
+    }
+
+    pub fn path_create_directory(&self, fd: Fd, path: &str) -> WasiResult<()> {
+        let dir = self.dir(fd, Rights::PATH_CREATE_DIRECTORY)?;
+        let (parent, name) = resolve_parent(&dir, path)?;
+        let mode = NodePermission::from_bits_truncate(0o755);
+        parent.create(name, NodeType::Directory, mode)?;
+        Ok(())
+    }
+
+    pub fn path_filestat_get(
+        &self,
+        fd: Fd,
+        flags: LookupFlags,
+        path: &str,
+    ) -> WasiResult<Filestat> {
+        let dir = self.dir(fd, Rights::PATH_FILESTAT_GET)?;
+        let follow = flags.contains(LookupFlags::SYMLINK_FOLLOW);
+        Ok(filestat(resolve(&dir, path, follow)?.metadata()?))
+    }
+
+    pub fn path_filestat_set_times(
+        &self,
+        fd: Fd,
+        flags: LookupFlags,
+        path: &str,
+        atim: u64,
+        mtim: u64,
+        fst_flags: FstFlags,
+    ) -> WasiResult<()> {
+        let dir = self.dir(fd, Rights::PATH_FILESTAT_SET_TIMES)?;
+        let follow = flags.contains(LookupFlags::SYMLINK_FOLLOW);
+        let loc = resolve(&dir, path, follow)?;
+        Ok(loc.update_metadata(self.times(atim, mtim, fst_flags)?)?)
+    }
+
+    pub fn path_link(
+        &self,
+        old_fd: Fd,
+        old_flags: LookupFlags,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> WasiResult<()> {
+        let old_dir = self.dir(old_fd, Rights::PATH_LINK_SOURCE)?;
+        let new_dir = self.dir(new_fd, Rights::PATH_LINK_TARGET)?;
+        let follow = old_flags.contains(LookupFlags::SYMLINK_FOLLOW);
+        let src = resolve(&old_dir, old_path, follow)?;
+        if src.is_dir() {
+            return Err(Errno::Perm);
+        }
+        let (parent, name) = resolve_parent(&new_dir, new_path)?;
+        parent.link(name, &src)?;
+        Ok(())
+    }
+
+    /// Opens, and creates if asked to, the file at `path`.
+    ///
+    /// The new descriptor gets the rights asked for that apply to the type
+    /// of the file, all of which `fd` must be able to pass on.
+    #[allow(clippy::too_many_arguments)]
+    pub fn path_open(
+        &self,
+        fd: Fd,
+        dir_flags: LookupFlags,
+        path: &str,
+        oflags: OFlags,
+        rights_base: Rights,
+        rights_inheriting: Rights,
+        fd_flags: FdFlags,
+    ) -> WasiResult<Fd> {
+        let mut needed = Rights::PATH_OPEN;
+        if oflags.contains(OFlags::CREAT) {
+            needed |= Rights::PATH_CREATE_FILE;
+        }
+        if oflags.contains(OFlags::TRUNC) {
+            needed |= Rights::PATH_FILESTAT_SET_SIZE;
+        }
+        let desc = self.get(fd, needed)?;
+        let (dir, inheriting) = {
+            let desc = desc.lock();
+            (desc.loc.clone(), desc.inheriting)
+        };
+        dir.check_is_dir()?;
+        if !inheriting.contains(rights_base | rights_inheriting) {
+            return Err(Errno::NotCapable);
+        }
+        if oflags.contains(OFlags::CREAT | OFlags::DIRECTORY) {
+            return Err(Errno::Inval);
+        }
+
+        // Exclusive creation does not follow a symbolic link in its place.
+        let follow = dir_flags.contains(LookupFlags::SYMLINK_FOLLOW)
+            && !oflags.contains(OFlags::CREAT | OFlags::EXCL);
+        let loc = match resolve(&dir, path, follow) {
+            Ok(_) if oflags.contains(OFlags::CREAT | OFlags::EXCL) => return Err(Errno::Exist),
+            Ok(loc) => loc,
+            Err(Errno::NoEnt) if oflags.contains(OFlags::CREAT) => {
+                let (parent, name) = resolve_parent(&dir, path)?;
+                let mode = NodePermission::from_bits_truncate(0o644);
+                parent.create(name, NodeType::RegularFile, mode)?
+            }
+            Err(err) => return Err(err),
+        };
+        let applicable = match loc.node_type() {
+            NodeType::Directory => Rights::DIRECTORY,
+            NodeType::Symlink => return Err(Errno::Loop),
+            _ if oflags.contains(OFlags::DIRECTORY) => return Err(Errno::NotDir),
+            _ => Rights::FILE,
+        };
+        if oflags.contains(OFlags::TRUNC) {
+            loc.entry().as_file()?.set_len(0)?;
+        }
+        Ok(self.insert(Descriptor {
+            loc,
+            preopen: None,
+            rights: rights_base & applicable,
+            inheriting: rights_inheriting,
+            flags: fd_flags,
+            offset: 0,
+        }))
+    }
+
+    /// Reads the target of the symbolic link at `path` into `buf`, cut
+    /// short if it does not fit.
+    pub fn path_readlink(&self, fd: Fd, path: &str, buf: &mut [u8]) -> WasiResult<usize> {
+        let dir = self.dir(fd, Rights::PATH_READLINK)?;
+        let loc = resolve(&dir, path, false)?;
+        if loc.node_type() != NodeType::Symlink {
+            return Err(Errno::Inval);
+        }
+        let target = loc.read_link()?;
+        let len = target.len().min(buf.len());
+        buf[..len].copy_from_slice(&target.as_bytes()[..len]);
+        Ok(len)
+    }
+
+    pub fn path_remove_directory(&self, fd: Fd, path: &str) -> WasiResult<()> {
+        let dir = self.dir(fd, Rights::PATH_REMOVE_DIRECTORY)?;
+        let (parent, name) = resolve_parent(&dir, path)?;
+        parent.lookup_no_follow(name)?.check_is_dir()?;
+        Ok(parent.unlink(name, true)?)
+    }
+
+    pub fn path_rename(
+        &self,
+        fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> WasiResult<()> {
+        let old_dir = self.dir(fd, Rights::PATH_RENAME_SOURCE)?;
+        let new_dir = self.dir(new_fd, Rights::PATH_RENAME_TARGET)?;
+        let (old_parent, old_name) = resolve_parent(&old_dir, old_path)?;
+        let (new_parent, new_name) = resolve_parent(&new_dir, new_path)?;
+        Ok(old_parent.rename(old_name, &new_parent, new_name)?)
+    }
+
+    /// Creates a symbolic link at `new_path` to `old_path`, which is only
+    /// checked against the sandbox when followed.
+    pub fn path_symlink(&self, old_path: &str, fd: Fd, new_path: &str) -> WasiResult<()> {
+        let dir = self.dir(fd, Rights::PATH_SYMLINK)?;
+        let (parent, name) = resolve_parent(&dir, new_path)?;
+        let mode = NodePermission::from_bits_truncate(0o777);
+        let link = parent.create(name, NodeType::Symlink, mode)?;
+        Ok(link.entry().as_file()?.set_symlink(old_path)?)
+    }
+
+    pub fn path_unlink_file(&self, fd: Fd, path: &str) -> WasiResult<()> {
+        let dir = self.dir(fd, Rights::PATH_UNLINK_FILE)?;
+        let (parent, name) = resolve_parent(&dir, path)?;
+        if parent.lookup_no_follow(name)?.is_dir() {
+            return Err(Errno::IsDir);
+        }
+        if path.ends_with('/') {
+            return Err(Errno::NotDir);
+        }
+        Ok(parent.unlink(name, false)?)
+    }
+}
+
+fn read(loc: &Location, iovs: &mut [&mut [u8]], mut offset: u64) -> WasiResult<usize> {
+    let file = loc.entry().as_file()?;
+    let mut done = 0;
+    for iov in iovs {
+        let read = file.read_at(iov, offset)?;
+        done += read;
+        offset += read as u64;
+        if read < iov.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Writes `iovs` to the file of `desc` at `offset`, returning the number of
+/// bytes written and the offset after them.
+fn write(desc: &Descriptor, iovs: &[&[u8]], mut offset: u64) -> WasiResult<(usize, u64)> {
+    let file = desc.loc.entry().as_file()?;
+    let mut done = 0;
+    for iov in iovs {
+        let written = if desc.flags.contains(FdFlags::APPEND) {
+            let (written, end) = file.append(iov)?;
+            offset = end;
+            written
+        } else {
+            let written = file.write_at(iov, offset)?;
+            offset += written as u64;
+            written
+        };
+        done += written;
+        if written < iov.len() {
+            break;
+        }
+    }
+    if desc.flags.intersects(FdFlags::DSYNC | FdFlags::SYNC) {
+        file.sync(!desc.flags.contains(FdFlags::SYNC))?;
+    }
+    Ok((done, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{borrow::ToOwned, format, string::String, vec, vec::Vec};
+
+    use super::*;
+    use crate::{VfsError, test_util::memory_fs};
+
+    const ALL: Rights = Rights::DIRECTORY.union(Rights::FILE);
+
+    fn open(wasi: &Wasi, dir: Fd, path: &str, oflags: OFlags, flags: FdFlags) -> WasiResult<Fd> {
+        let follow = LookupFlags::SYMLINK_FOLLOW;
+        wasi.path_open(dir, follow, path, oflags, ALL, ALL, flags)
+    }
+
+    /// Reads the names in the directory `fd` through a buffer of `size`
+    /// bytes.
+    fn read_dir(wasi: &Wasi, fd: Fd, size: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let mut buf = vec![0; size];
+            let len = wasi.fd_readdir(fd, &mut buf, cookie).unwrap();
+            let mut rest = &buf[..len];
+            while rest.len() >= DIRENT_SIZE {
+                let name_len = u32::from_le_bytes(rest[16..20].try_into().unwrap()) as usize;
+                let Some(name) = rest.get(DIRENT_SIZE..DIRENT_SIZE + name_len) else {
+                    break;
+                };
+                names.push(String::from_utf8(name.to_vec()).unwrap());
+                cookie = u64::from_le_bytes(rest[..8].try_into().unwrap());
+                rest = &rest[DIRENT_SIZE + name_len..];
+            }
+            if len < size {
+                return names;
+            }
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let root = memory_fs();
+        let mode = NodePermission::from_bits_truncate(0o755);
+        let sandbox = root.create("sandbox", NodeType::Directory, mode).unwrap();
+        let wasi = Wasi::new(WasiOptions::default());
+        let dir = wasi.preopen(sandbox.clone(), "/data").unwrap();
+        assert_eq!(dir, FIRST_FD);
+        assert_eq!(wasi.fd_prestat_get(dir), Ok(5));
+        let mut name = [0; 5];
+        wasi.fd_prestat_dir_name(dir, &mut name).unwrap();
+        assert_eq!(&name, b"/data");
+
+        wasi.path_create_directory(dir, "sub").unwrap();
+        let fd = open(&wasi, dir, "sub/file", OFlags::CREAT, FdFlags::empty()).unwrap();
+        assert_eq!(wasi.fd_write(fd, &[b"hello", b" world"]), Ok(11));
+        assert_eq!(wasi.fd_pwrite(fd, &[b"W"], 6), Ok(1));
+        assert_eq!(wasi.fd_seek(fd, -5, Whence::End), Ok(6));
+        let mut buf = [0; 16];
+        assert_eq!(wasi.fd_read(fd, &mut [&mut buf]), Ok(5));
+        assert_eq!(&buf[..5], b"World");
+        assert_eq!(wasi.fd_tell(fd), Ok(11));
+        wasi.fd_close(fd).unwrap();
+        assert_eq!(wasi.fd_close(fd), Err(Errno::BadF));
+        assert_eq!(
+            open(
+                &wasi,
+                dir,
+                "sub/file",
+                OFlags::CREAT | OFlags::EXCL,
+                FdFlags::empty()
+            ),
+            Err(Errno::Exist)
+        );
+
+        let fd = open(&wasi, dir, "sub/file", OFlags::empty(), FdFlags::APPEND).unwrap();
+        wasi.fd_write(fd, &[b"!"]).unwrap();
+        assert_eq!(wasi.fd_filestat_get(fd).unwrap().size, 12);
+        wasi.fd_filestat_set_size(fd, 5).unwrap();
+        let stat = wasi
+            .path_filestat_get(dir, LookupFlags::empty(), "sub/file")
+            .unwrap();
+        assert_eq!((stat.filetype, stat.size), (FileType::RegularFile, 5));
+
+        // Rights only shrink.
+        let fd = wasi
+            .path_open(
+                dir,
+                LookupFlags::empty(),
+                "sub/file",
+                OFlags::empty(),
+                Rights::FD_READ,
+                Rights::empty(),
+                FdFlags::empty(),
+            )
+            .unwrap();
+        assert_eq!(wasi.fd_write(fd, &[b"x"]), Err(Errno::NotCapable));
+        assert_eq!(wasi.fd_fdstat_get(fd).unwrap().rights_base, Rights::FD_READ);
+        assert_eq!(
+            wasi.fd_fdstat_set_rights(fd, Rights::FD_WRITE, Rights::empty()),
+            Err(Errno::NotCapable)
+        );
+
+        wasi.path_symlink("sub/file", dir, "link").unwrap();
+        let mut target = [0; 32];
+        let len = wasi.path_readlink(dir, "link", &mut target).unwrap();
+        assert_eq!(&target[..len], b"sub/file");
+        let stat = wasi
+            .path_filestat_get(dir, LookupFlags::SYMLINK_FOLLOW, "link")
+            .unwrap();
+        assert_eq!(stat.filetype, FileType::RegularFile);
+        assert_eq!(
+            wasi.path_open(
+                dir,
+                LookupFlags::empty(),
+                "link",
+                OFlags::empty(),
+                ALL,
+                ALL,
+                FdFlags::empty()
+            ),
+            Err(Errno::Loop)
+        );
+        wasi.path_link(dir, LookupFlags::empty(), "sub/file", dir, "hard")
+            .unwrap();
+        wasi.path_rename(dir, "hard", dir, "sub/moved").unwrap();
+        assert_eq!(wasi.path_unlink_file(dir, "sub"), Err(Errno::IsDir));
+        assert_eq!(wasi.path_remove_directory(dir, "sub"), Err(Errno::NotEmpty));
+        wasi.path_unlink_file(dir, "sub/moved").unwrap();
+        assert_eq!(
+            wasi.path_filestat_get(dir, LookupFlags::empty(), "sub/moved"),
+            Err(Errno::NoEnt)
+        );
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let root = memory_fs();
+        let mode = NodePermission::from_bits_truncate(0o755);
+        root.create("secret", NodeType::RegularFile, mode).unwrap();
+        let sandbox = root.create("sandbox", NodeType::Directory, mode).unwrap();
+        let sub = sandbox.create("sub", NodeType::Directory, mode).unwrap();
+        let wasi = Wasi::new(WasiOptions::default());
+        let dir = wasi.preopen(sandbox, ".").unwrap();
+
+        for path in ["../secret", "/secret", "sub/../../secret"] {
+            assert_eq!(
+                open(&wasi, dir, path, OFlags::empty(), FdFlags::empty()),
+                Err(Errno::NotCapable)
+            );
+        }
+        assert!(
+            open(
+                &wasi,
+                dir,
+                "sub/../sub/.",
+                OFlags::DIRECTORY,
+                FdFlags::empty()
+            )
+            .is_ok()
+        );
+        // Links out of the sandbox can be made, but not followed.
+        wasi.path_symlink("../secret", dir, "up").unwrap();
+        wasi.path_symlink("/secret", dir, "abs").unwrap();
+        wasi.path_symlink("sub/../up", dir, "indirect").unwrap();
+        for path in ["up", "abs", "indirect"] {
+            assert_eq!(
+                wasi.path_filestat_get(dir, LookupFlags::SYMLINK_FOLLOW, path),
+                Err(Errno::NotCapable)
+            );
+        }
+        wasi.path_symlink("loop", dir, "loop").unwrap();
+        assert_eq!(
+            open(&wasi, dir, "loop", OFlags::empty(), FdFlags::empty()),
+            Err(Errno::Loop)
+        );
+        // A directory opened below is a sandbox of its own.
+        let sub_fd = open(&wasi, dir, "sub", OFlags::DIRECTORY, FdFlags::empty()).unwrap();
+        assert_eq!(
+            wasi.path_filestat_get(sub_fd, LookupFlags::empty(), "../up"),
+            Err(Errno::NotCapable)
+        );
+        assert_eq!(
+            wasi.path_rename(sub_fd, "../up", dir, "x"),
+            Err(Errno::NotCapable)
+        );
+
+        // Directories are listed through small buffers with cookies.
+        for i in 0..50 {
+            sub.create(&format!("file{i:02}"), NodeType::RegularFile, mode)
+                .unwrap();
+        }
+        let names = read_dir(&wasi, sub_fd, 100);
+        let mut expected = vec![".".to_owned(), "..".to_owned()];
+        expected.extend((0..50).map(|i| format!("file{i:02}")));
+        assert_eq!(names, expected);
+
+        assert_eq!(Errno::from(VfsError::DirectoryNotEmpty), Errno::NotEmpty);
+        assert_eq!(Errno::from(VfsError::ReadOnlyFilesystem), Errno::RoFs);
+        assert_eq!(Errno::from(VfsError::Unsupported), Errno::NoSys);
+    }
+}
@@ -0,0 +1,295 @@
+use axerrno::LinuxError;
+
+use crate::{NodeType, VfsError};
+
+/// Error codes of WASI functions.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    TooBig = 1,
+    Access = 2,
+    AddrInUse = 3,
+    Again = 6,
+    BadF = 8,
+    Busy = 10,
+    ConnRefused = 14,
+    ConnReset = 15,
+    Exist = 20,
+    Fault = 21,
+    FBig = 22,
+    IlSeq = 25,
+    InProgress = 26,
+    Intr = 27,
+    Inval = 28,
+    Io = 29,
+    IsConn = 30,
+    IsDir = 31,
+    Loop = 32,
+    MFile = 33,
+    MLink = 34,
+    NameTooLong = 37,
+    NFile = 41,
+    NoDev = 43,
+    NoEnt = 44,
+    NoExec = 45,
+    NoMem = 48,
+    NoSpc = 51,
+    NoSys = 52,
+    NotConn = 53,
+    NotDir = 54,
+    NotEmpty = 55,
+    NotSock = 57,
+    NotSup = 58,
+    NoTty = 59,
+    NxIo = 60,
+    Overflow = 61,
+    Perm = 63,
+    Pipe = 64,
+    Range = 68,
+    RoFs = 69,
+    SPipe = 70,
+    Srch = 71,
+    TimedOut = 73,
+    TxtBsy = 74,
+    XDev = 75,
+    /// Lacking the rights, or escaping the preopened directories.
+    NotCapable = 76,
+}
+
+impl From<VfsError> for Errno {
+    fn from(err: VfsError) -> Self {
+        use LinuxError::*;
+        match LinuxError::from(err) {
+            E2BIG => Self::TooBig,
+            EACCES => Self::Access,
+            EADDRINUSE => Self::AddrInUse,
+            EAGAIN => Self::Again,
+            EBADF => Self::BadF,
+            EBUSY => Self::Busy,
+            ECONNREFUSED => Self::ConnRefused,
+            ECONNRESET => Self::ConnReset,
+            EEXIST => Self::Exist,
+            EFAULT => Self::Fault,
+            EFBIG => Self::FBig,
+            EILSEQ => Self::IlSeq,
+            EINPROGRESS => Self::InProgress,
+            EINTR => Self::Intr,
+            EINVAL => Self::Inval,
+            EISCONN => Self::IsConn,
+            EISDIR => Self::IsDir,
+            ELOOP => Self::Loop,
+            EMFILE => Self::MFile,
+            EMLINK => Self::MLink,
+            ENAMETOOLONG => Self::NameTooLong,
+            ENFILE => Self::NFile,
+            ENODEV => Self::NoDev,
+            ENOENT => Self::NoEnt,
+            ENOEXEC => Self::NoExec,
+            ENOMEM => Self::NoMem,
+            ENOSPC => Self::NoSpc,
+            ENOSYS => Self::NoSys,
+            ENOTCONN => Self::NotConn,
+            ENOTDIR => Self::NotDir,
+            ENOTEMPTY => Self::NotEmpty,
+            ENOTSOCK => Self::NotSock,
+            EOPNOTSUPP => Self::NotSup,
+            ENOTTY => Self::NoTty,
+            ENXIO => Self::NxIo,
+            EOVERFLOW => Self::Overflow,
+            EPERM => Self::Perm,
+            EPIPE => Self::Pipe,
+            ERANGE => Self::Range,
+            EROFS => Self::RoFs,
+            ESPIPE => Self::SPipe,
+            ESRCH => Self::Srch,
+            ETIMEDOUT => Self::TimedOut,
+            ETXTBSY => Self::TxtBsy,
+            EXDEV => Self::XDev,
+            _ => Self::Io,
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Operations allowed on a file descriptor.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u64 {
+        const FD_DATASYNC = 1 << 0;
+        const FD_READ = 1 << 1;
+        const FD_SEEK = 1 << 2;
+        const FD_FDSTAT_SET_FLAGS = 1 << 3;
+        const FD_SYNC = 1 << 4;
+        const FD_TELL = 1 << 5;
+        const FD_WRITE = 1 << 6;
+        const FD_ADVISE = 1 << 7;
+        const FD_ALLOCATE = 1 << 8;
+        const PATH_CREATE_DIRECTORY = 1 << 9;
+        const PATH_CREATE_FILE = 1 << 10;
+        const PATH_LINK_SOURCE = 1 << 11;
+        const PATH_LINK_TARGET = 1 << 12;
+        const PATH_OPEN = 1 << 13;
+        const FD_READDIR = 1 << 14;
+        const PATH_READLINK = 1 << 15;
+        const PATH_RENAME_SOURCE = 1 << 16;
+        const PATH_RENAME_TARGET = 1 << 17;
+        const PATH_FILESTAT_GET = 1 << 18;
+        const PATH_FILESTAT_SET_SIZE = 1 << 19;
+        const PATH_FILESTAT_SET_TIMES = 1 << 20;
+        const FD_FILESTAT_GET = 1 << 21;
+        const FD_FILESTAT_SET_SIZE = 1 << 22;
+        const FD_FILESTAT_SET_TIMES = 1 << 23;
+        const PATH_SYMLINK = 1 << 24;
+        const PATH_REMOVE_DIRECTORY = 1 << 25;
+        const PATH_UNLINK_FILE = 1 << 26;
+        const POLL_FD_READWRITE = 1 << 27;
+        const SOCK_SHUTDOWN = 1 << 28;
+        const SOCK_ACCEPT = 1 << 29;
+    }
+}
+
+impl Rights {
+    /// Rights meaningful on regular files.
+    pub const FILE: Self = Self::FD_DATASYNC
+        .union(Self::FD_READ)
+        .union(Self::FD_SEEK)
+        .union(Self::FD_FDSTAT_SET_FLAGS)
+        .union(Self::FD_SYNC)
+        .union(Self::FD_TELL)
+        .union(Self::FD_WRITE)
+        .union(Self::FD_ADVISE)
+        .union(Self::FD_ALLOCATE)
+        .union(Self::FD_FILESTAT_GET)
+        .union(Self::FD_FILESTAT_SET_SIZE)
+        .union(Self::FD_FILESTAT_SET_TIMES)
+        .union(Self::POLL_FD_READWRITE);
+
+    /// Rights meaningful on directories.
+    pub const DIRECTORY: Self = Self::FD_FDSTAT_SET_FLAGS
+        .union(Self::FD_SYNC)
+        .union(Self::FD_ADVISE)
+        .union(Self::PATH_CREATE_DIRECTORY)
+        .union(Self::PATH_CREATE_FILE)
+        .union(Self::PATH_LINK_SOURCE)
+        .union(Self::PATH_LINK_TARGET)
+        .union(Self::PATH_OPEN)
+        .union(Self::FD_READDIR)
+        .union(Self::PATH_READLINK)
+        .union(Self::PATH_RENAME_SOURCE)
+        .union(Self::PATH_RENAME_TARGET)
+        .union(Self::PATH_FILESTAT_GET)
+        .union(Self::PATH_FILESTAT_SET_SIZE)
+        .union(Self::PATH_FILESTAT_SET_TIMES)
+        .union(Self::FD_FILESTAT_GET)
+        .union(Self::FD_FILESTAT_SET_TIMES)
+        .union(Self::PATH_SYMLINK)
+        .union(Self::PATH_REMOVE_DIRECTORY)
+        .union(Self::PATH_UNLINK_FILE)
+        .union(Self::POLL_FD_READWRITE);
+}
+
+bitflags::bitflags! {
+    /// Flags of `path_*` functions on how the path is resolved.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LookupFlags: u32 {
+        /// Follows a symbolic link as the last component.
+        const SYMLINK_FOLLOW = 1 << 0;
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags of `path_open`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OFlags: u16 {
+        const CREAT = 1 << 0;
+        const DIRECTORY = 1 << 1;
+        const EXCL = 1 << 2;
+        const TRUNC = 1 << 3;
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags of file descriptors.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct FdFlags: u16 {
+        const APPEND = 1 << 0;
+        const DSYNC = 1 << 1;
+        const NONBLOCK = 1 << 2;
+        const RSYNC = 1 << 3;
+        const SYNC = 1 << 4;
+    }
+}
+
+bitflags::bitflags! {
+    /// Which timestamps `*_filestat_set_times` change.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FstFlags: u16 {
+        /// Sets the access time to the value given.
+        const ATIM = 1 << 0;
+        /// Sets the access time to the current time.
+        const ATIM_NOW = 1 << 1;
+        /// Sets the modification time to the value given.
+        const MTIM = 1 << 2;
+        /// Sets the modification time to the current time.
+        const MTIM_NOW = 1 << 3;
+    }
+}
+
+/// Type of a file as seen by WASI.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Unknown = 0,
+    BlockDevice = 1,
+    CharacterDevice = 2,
+    Directory = 3,
+    RegularFile = 4,
+    SocketDgram = 5,
+    SocketStream = 6,
+    SymbolicLink = 7,
+}
+
+impl From<NodeType> for FileType {
+    fn from(node_type: NodeType) -> Self {
+        match node_type {
+            NodeType::BlockDevice => Self::BlockDevice,
+            NodeType::CharacterDevice => Self::CharacterDevice,
+            NodeType::Directory => Self::Directory,
+            NodeType::RegularFile => Self::RegularFile,
+            NodeType::Socket => Self::SocketStream,
+            NodeType::Symlink => Self::SymbolicLink,
+            NodeType::Fifo | NodeType::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// Origin of `fd_seek`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set = 0,
+    Cur = 1,
+    End = 2,
+}
+
+/// Result of `fd_filestat_get` and `path_filestat_get`, with times in
+/// nanoseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filestat {
+    pub dev: u64,
+    pub ino: u64,
+    pub filetype: FileType,
+    pub nlink: u64,
+    pub size: u64,
+    pub atim: u64,
+    pub mtim: u64,
+    pub ctim: u64,
+}
+
+/// Result of `fd_fdstat_get`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fdstat {
+    pub filetype: FileType,
+    pub flags: FdFlags,
+    pub rights_base: Rights,
+    pub rights_inheriting: Rights,
+}